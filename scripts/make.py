@@ -283,6 +283,7 @@ class BuildMan:
     usage_group = {
         "break-lock": "lock",
         "with-lock": "lock",
+        "cache_check": "cache",
         "key_change-passphrase": "key",
         "key_change-location": "key",
         "key_export": "key",
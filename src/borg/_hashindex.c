@@ -68,6 +68,9 @@ typedef struct {
 #ifndef BORG_NO_PYTHON
     /* buckets may be backed by a Python buffer. If buckets_buffer.buf is NULL then this is not used. */
     Py_buffer buckets_buffer;
+    /* if not NULL, buckets_buffer is a writable mapping of the whole index file (header and buckets), and
+     * resize_cb(size) returns a new mapping of <size> bytes to resize into (see hashindex_map). */
+    PyObject *resize_cb;
 #endif
 } HashIndex;
 
@@ -126,6 +129,10 @@ static int hash_sizes[] = {
 #ifndef BORG_NO_PYTHON
 static HashIndex *hashindex_read(PyObject *file_py, int permit_compact, int legacy);
 static void hashindex_write(HashIndex *index, PyObject *file_py, int legacy);
+static HashIndex *hashindex_map(PyObject *map_py, PyObject *resize_cb, int permit_compact);
+static HashIndex *hashindex_map_init(PyObject *resize_cb, int capacity, int key_size, int value_size);
+static PyObject *hashindex_mapping(HashIndex *index);
+static void hashindex_sync_header(HashIndex *index);
 #endif
 
 static uint64_t hashindex_compact(HashIndex *index);
@@ -212,6 +219,10 @@ hashindex_lookup(HashIndex *index, const unsigned char *key, int *start_idx)
     return -1;
 }
 
+#ifndef BORG_NO_PYTHON
+static int hashindex_resize_mapped(HashIndex *index, int capacity);
+#endif
+
 static int
 hashindex_resize(HashIndex *index, int capacity)
 {
@@ -219,6 +230,11 @@ hashindex_resize(HashIndex *index, int capacity)
     unsigned char *key = NULL;
     int32_t key_size = index->key_size;
 
+#ifndef BORG_NO_PYTHON
+    if(index->resize_cb) {
+        return hashindex_resize_mapped(index, capacity);
+    }
+#endif
     if(!(new = hashindex_init(capacity, key_size, index->value_size))) {
         return 0;
     }
@@ -538,6 +554,7 @@ hashindex_read(PyObject *file_py, int permit_compact, int legacy)
     index->bucket_size = index->key_size + index->value_size;
     index->lower_limit = get_lower_limit(index->num_buckets);
     index->upper_limit = get_upper_limit(index->num_buckets);
+    index->resize_cb = NULL;
 
     /*
      * For indices read from disk we don't malloc() the buckets ourselves,
@@ -632,6 +649,7 @@ hashindex_init(int capacity, int key_size, int value_size)
     index->min_empty = get_min_empty(index->num_buckets);
 #ifndef BORG_NO_PYTHON
     index->buckets_buffer.buf = NULL;
+    index->resize_cb = NULL;
 #endif
     for(i = 0; i < capacity; i++) {
         BUCKET_MARK_EMPTY(index, i);
@@ -643,6 +661,9 @@ static void
 hashindex_free(HashIndex *index)
 {
     hashindex_free_buckets(index);
+#ifndef BORG_NO_PYTHON
+    Py_XDECREF(index->resize_cb);
+#endif
     free(index);
 }
 
@@ -731,6 +752,220 @@ hashindex_write(HashIndex *index, PyObject *file_py, int legacy)
 }
 #endif
 
+#ifndef BORG_NO_PYTHON
+/*
+ * Memory-mapped indexes
+ *
+ * The on-disk format is the header followed by the buckets, just like the in-memory layout, so an index file
+ * can be used in place through a writable mapping of it (e.g. a Python mmap object). Only the pages in use are
+ * resident and only changed pages are written back, the header is updated by hashindex_sync_header.
+ *
+ * Resizing a mapped index does not allocate the new table on the heap, it calls resize_cb(size) instead, which
+ * must return a new writable mapping of <size> bytes (usually of a new file), and rehashes into that.
+ */
+
+static int
+hashindex_init_mapped_buckets(HashIndex *index, int capacity)
+{
+    int i;
+
+    if(index->buckets_buffer.len != (Py_ssize_t)sizeof(HashHeader) + (Py_ssize_t)capacity * index->bucket_size) {
+        PyErr_Format(PyExc_ValueError, "Incorrect mapping length (expected %zd, got %zd)",
+                     (Py_ssize_t)sizeof(HashHeader) + (Py_ssize_t)capacity * index->bucket_size,
+                     index->buckets_buffer.len);
+        return 0;
+    }
+    index->buckets = (unsigned char *)index->buckets_buffer.buf + sizeof(HashHeader);
+    index->num_entries = 0;
+    index->num_buckets = capacity;
+    index->num_empty = capacity;
+    index->lower_limit = get_lower_limit(index->num_buckets);
+    index->upper_limit = get_upper_limit(index->num_buckets);
+    index->min_empty = get_min_empty(index->num_buckets);
+    for(i = 0; i < capacity; i++) {
+        BUCKET_MARK_EMPTY(index, i);
+    }
+    hashindex_sync_header(index);
+    return 1;
+}
+
+static int
+hashindex_get_mapping(Py_buffer *buffer, PyObject *resize_cb, Py_ssize_t size)
+{
+    PyObject *map_py;
+    int rc;
+
+    map_py = PyObject_CallFunction(resize_cb, "n", size);
+    if(!map_py) {
+        assert(PyErr_Occurred());
+        return 0;
+    }
+    /* the buffer holds a reference to the mapping */
+    rc = PyObject_GetBuffer(map_py, buffer, PyBUF_WRITABLE);
+    Py_DECREF(map_py);
+    return rc == 0;
+}
+
+static int
+hashindex_resize_mapped(HashIndex *index, int capacity)
+{
+    HashIndex new;
+    unsigned char *key = NULL;
+
+    capacity = fit_size(capacity);
+    new.key_size = index->key_size;
+    new.value_size = index->value_size;
+    new.bucket_size = index->bucket_size;
+    new.resize_cb = NULL;
+    if(!hashindex_get_mapping(&new.buckets_buffer, index->resize_cb,
+                              sizeof(HashHeader) + (Py_ssize_t)capacity * index->bucket_size)) {
+        return 0;
+    }
+    if(!hashindex_init_mapped_buckets(&new, capacity)) {
+        PyBuffer_Release(&new.buckets_buffer);
+        return 0;
+    }
+    while((key = hashindex_next_key(index, key))) {
+        if(!hashindex_set(&new, key, key + index->key_size)) {
+            /* This can only happen if there's a bug in the code calculating capacity */
+            PyBuffer_Release(&new.buckets_buffer);
+            return 0;
+        }
+    }
+    assert(index->num_entries == new.num_entries);
+
+    PyBuffer_Release(&index->buckets_buffer);
+    index->buckets_buffer = new.buckets_buffer;
+    index->buckets = new.buckets;
+    index->num_buckets = new.num_buckets;
+    index->num_empty = new.num_empty;
+    index->lower_limit = new.lower_limit;
+    index->upper_limit = new.upper_limit;
+    index->min_empty = new.min_empty;
+    hashindex_sync_header(index);
+    return 1;
+}
+
+static HashIndex *
+hashindex_map(PyObject *map_py, PyObject *resize_cb, int permit_compact)
+{
+    Py_ssize_t buckets_length;
+    HashIndex *index;
+    HashHeader *header;
+
+    if(!(index = malloc(sizeof(HashIndex)))) {
+        PyErr_NoMemory();
+        return NULL;
+    }
+    if(PyObject_GetBuffer(map_py, &index->buckets_buffer, PyBUF_WRITABLE) < 0) {
+        free(index);
+        return NULL;
+    }
+    index->resize_cb = NULL;
+
+    if(index->buckets_buffer.len < (Py_ssize_t)sizeof(HashHeader)) {
+        PyErr_Format(PyExc_ValueError, "Could not read header (expected %zu, but mapped %zd bytes)",
+                     sizeof(HashHeader), index->buckets_buffer.len);
+        goto fail;
+    }
+    header = (HashHeader *)index->buckets_buffer.buf;
+    if(memcmp(header->magic, MAGIC, MAGIC_LEN)) {
+        PyErr_Format(PyExc_ValueError, "Unknown MAGIC in header");
+        goto fail;
+    }
+    if((int)_le32toh(header->version) != 2) {
+        PyErr_Format(PyExc_ValueError, "Unsupported header version (expected %d, got %d)",
+                     2, (int)_le32toh(header->version));
+        goto fail;
+    }
+    index->num_entries = _le32toh(header->num_entries);
+    index->num_buckets = _le32toh(header->num_buckets);
+    index->num_empty = _le32toh(header->num_empty);
+    index->key_size = _le32toh(header->key_size);
+    index->value_size = _le32toh(header->value_size);
+    index->bucket_size = index->key_size + index->value_size;
+    buckets_length = (Py_ssize_t)index->num_buckets * index->bucket_size;
+    if(index->buckets_buffer.len != (Py_ssize_t)sizeof(HashHeader) + buckets_length) {
+        PyErr_Format(PyExc_ValueError, "Incorrect file length (expected %zd, got %zd)",
+                     sizeof(HashHeader) + buckets_length, index->buckets_buffer.len);
+        goto fail;
+    }
+    index->buckets = (unsigned char *)index->buckets_buffer.buf + sizeof(HashHeader);
+    index->lower_limit = get_lower_limit(index->num_buckets);
+    index->upper_limit = get_upper_limit(index->num_buckets);
+    index->min_empty = get_min_empty(index->num_buckets);
+    Py_INCREF(resize_cb);
+    index->resize_cb = resize_cb;
+
+    if(!permit_compact) {
+        if(index->num_empty < index->min_empty) {
+            /* too many tombstones here / not enough empty buckets, do a same-size rebuild */
+            if(!hashindex_resize(index, index->num_buckets)) {
+                goto fail;
+            }
+        }
+    }
+    return index;
+
+fail:
+    hashindex_free(index);
+    return NULL;
+}
+
+static HashIndex *
+hashindex_map_init(PyObject *resize_cb, int capacity, int key_size, int value_size)
+{
+    HashIndex *index;
+
+    capacity = fit_size(capacity);
+    if(!(index = malloc(sizeof(HashIndex)))) {
+        PyErr_NoMemory();
+        return NULL;
+    }
+    index->key_size = key_size;
+    index->value_size = value_size;
+    index->bucket_size = key_size + value_size;
+    index->resize_cb = NULL;
+    if(!hashindex_get_mapping(&index->buckets_buffer, resize_cb,
+                              sizeof(HashHeader) + (Py_ssize_t)capacity * index->bucket_size)) {
+        free(index);
+        return NULL;
+    }
+    if(!hashindex_init_mapped_buckets(index, capacity)) {
+        hashindex_free(index);
+        return NULL;
+    }
+    Py_INCREF(resize_cb);
+    index->resize_cb = resize_cb;
+    return index;
+}
+
+static PyObject *
+hashindex_mapping(HashIndex *index)
+{
+    /* return (a new reference to) the object mapping the index file, or None if the index is not mapped */
+    PyObject *map_py = index->resize_cb ? index->buckets_buffer.obj : Py_None;
+    Py_INCREF(map_py);
+    return map_py;
+}
+
+static void
+hashindex_sync_header(HashIndex *index)
+{
+    /* update the header of a mapped index file, see write_hashheader */
+    HashHeader *header = (HashHeader *)index->buckets_buffer.buf;
+
+    memset(header, 0, sizeof(HashHeader));
+    memcpy(header->magic, MAGIC, MAGIC_LEN);
+    header->version = _htole32(2);
+    header->num_entries = _htole32(index->num_entries);
+    header->num_buckets = _htole32(index->num_buckets);
+    header->num_empty = _htole32(index->num_empty);
+    header->key_size = _htole32(index->key_size);
+    header->value_size = _htole32(index->value_size);
+}
+#endif
+
 static const unsigned char *
 hashindex_get(HashIndex *index, const unsigned char *key)
 {
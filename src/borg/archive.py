@@ -7,7 +7,7 @@
 import time
 from collections import OrderedDict, defaultdict
 from contextlib import contextmanager
-from datetime import timedelta
+from datetime import datetime, timedelta, timezone
 from functools import partial
 from getpass import getuser
 from io import BytesIO
@@ -22,6 +22,7 @@
 
 from . import xattr
 from .chunker import get_chunker, Chunk
+from .chunkindex import RepositoryChunkIndex
 from .cache import ChunkListEntry
 from .crypto.key import key_factory, UnsupportedPayloadError
 from .compress import CompressionSpec
@@ -31,7 +32,7 @@
 from .helpers import BackupOSError, BackupPermissionError, BackupFileNotFoundError, BackupIOError
 from .hashindex import ChunkIndex, ChunkIndexEntry, CacheSynchronizer
 from .helpers import HardLinkManager
-from .helpers import ChunkIteratorFileWrapper, open_item
+from .helpers import ChunkIteratorFileWrapper, open_item, chunkit
 from .helpers import Error, IntegrityError, set_ec
 from .platform import uid2user, user2uid, gid2group, group2gid
 from .helpers import parse_timestamp, archive_ts_now
@@ -50,7 +51,7 @@
 from .patterns import PathPrefixPattern, FnmatchPattern, IECommand
 from .item import Item, ArchiveItem, ItemDiff
 from .platform import acl_get, acl_set, set_flags, get_flags, swidth, hostname
-from .remote import cache_if_remote
+from .remote import cache_if_remote, RemoteRepository
 from .repository import Repository, LIST_SCAN_LIMIT
 from .repoobj import RepoObj
 
@@ -302,7 +303,8 @@ def unpack_many(self, ids, *, filter=None, preload=False):
 
     def fetch_many(self, ids, is_preloaded=False, ro_type=None):
         assert ro_type is not None
-        for id_, cdata in zip(ids, self.repository.get_many(ids, is_preloaded=is_preloaded)):
+        metadata = ro_type == ROBJ_ARCHIVE_STREAM
+        for id_, cdata in zip(ids, self.repository.get_many(ids, is_preloaded=is_preloaded, metadata=metadata)):
             _, data = self.repo_objs.parse(id_, cdata, ro_type=ro_type)
             yield data
 
@@ -406,7 +408,7 @@ def archive_get_items(metadata, *, repo_objs, repository):
     if "item_ptrs" in metadata:  # looks like a v2+ archive
         assert "items" not in metadata
         items = []
-        for id, cdata in zip(metadata.item_ptrs, repository.get_many(metadata.item_ptrs)):
+        for id, cdata in zip(metadata.item_ptrs, repository.get_many(metadata.item_ptrs, metadata=True)):
             _, data = repo_objs.parse(id, cdata, ro_type=ROBJ_ARCHIVE_CHUNKIDS)
             ids = msgpack.unpackb(data)
             items.extend(ids)
@@ -521,7 +523,7 @@ def __init__(
             self.load(info.id)
 
     def _load_meta(self, id):
-        cdata = self.repository.get(id)
+        cdata = self.repository.get(id, metadata=True)
         _, data = self.repo_objs.parse(id, cdata, ro_type=ROBJ_ARCHIVE_META)
         archive = self.key.unpack_archive(data)
         metadata = ArchiveItem(internal_dict=archive)
@@ -728,7 +730,7 @@ def add(id):
                 msg="Calculating statistics for archive %s ... %%3.0f%%%%" % arch_name_escd,
                 msgid="archive.calc_stats",
             )
-            for id, chunk in zip(self.metadata.items, self.repository.get_many(self.metadata.items)):
+            for id, chunk in zip(self.metadata.items, self.repository.get_many(self.metadata.items, metadata=True)):
                 pi.show(increase=1)
                 add(id)
                 _, data = self.repo_objs.parse(id, chunk, ro_type=ROBJ_ARCHIVE_STREAM)
@@ -1116,6 +1118,24 @@ def chunk_decref(id, size, stats):
             logger.warning("forced deletion succeeded, but the deleted archive was corrupted.")
             logger.warning("borg check --repair is required to free all space.")
 
+    def retention_lock(self, until):
+        """Lock this archive (its metadata and all chunks it references) in the repository until *until*."""
+        metadata = self._load_meta(self.id)
+        ids = {self.id, *metadata.item_ptrs}
+        unpacker = msgpack.Unpacker(use_list=False)
+        for items_id, data in zip(metadata.items, self.repository.get_many(metadata.items)):
+            ids.add(items_id)
+            _, data = self.repo_objs.parse(items_id, data, ro_type=ROBJ_ARCHIVE_STREAM)
+            unpacker.feed(data)
+            for item in unpacker:
+                item = Item(internal_dict=item)
+                if "chunks" in item:
+                    ids.update(chunk_id for chunk_id, _ in item.chunks)
+        batches = list(chunkit(ids, RETENTION_LOCK_BATCH))
+        for i, batch in enumerate(batches, 1):
+            # the repository writes the locks once, with the last batch
+            self.repository.retention_lock(batch, int(until.timestamp()), flush=i == len(batches))
+
     @staticmethod
     def compare_archives_iter(
         archive1: "Archive", archive2: "Archive", matcher=None, can_compare_chunk_ids=False
@@ -1875,6 +1895,7 @@ def check(
             match=match, first=first, last=last, sort_by=sort_by, older=older, oldest=oldest, newer=newer, newest=newest
         )
         self.orphan_chunks_check()
+        self.chunk_index_check()
         self.finish()
         if self.error_found:
             logger.error("Archive consistency check complete, problems found.")
@@ -2071,6 +2092,10 @@ def rebuild_refcounts(
         """
         # Exclude the manifest from chunks (manifest entry might be already deleted from self.chunks)
         self.chunks.pop(Manifest.MANIFEST_ID, None)
+        # Exclude the objects of the repository chunk index, they are referenced by the manifest
+        chunk_index = self.manifest.config.get("chunk_index", {})
+        for id_ in chunk_index.get("snapshot", []) + chunk_index.get("deltas", []):
+            self.chunks.pop(id_, None)
 
         def mark_as_possibly_superseded(id_):
             if self.chunks.get(id_, ChunkIndexEntry(0, 0)).refcount == 0:
@@ -2341,12 +2366,48 @@ def orphan_chunks_check(self):
                 logger.info(
                     "Deleting %d orphaned and %d superseded objects..." % (len(orphaned), len(self.possibly_superseded))
                 )
+                locked = 0
                 for id_ in unused:
-                    self.repository.delete(id_)
+                    try:
+                        self.repository.delete(id_)
+                    except Repository.RetentionLocked:
+                        locked += 1
+                if locked:
+                    logger.info(f"Kept {locked} retention locked objects.")
                 logger.info("Finished deleting orphaned/superseded objects.")
         else:
             logger.info("Orphaned objects check skipped (needs all archives checked).")
 
+    def chunk_index_check(self):
+        """Compare the repository chunk index to the rebuilt reference counts"""
+        if not self.check_all:
+            return
+        chunk_index = RepositoryChunkIndex(self.manifest)
+        head = chunk_index.head(self.manifest.archives.get_raw_dict())
+        if head is None:
+            logger.info("Repository chunk index is stale (or not present), skipping its check.")
+            return
+        try:
+            index = chunk_index.load(head)
+        except (Repository.ObjectNotFound, IntegrityErrorBase) as exc:
+            logger.error("Repository chunk index is damaged: %s", exc)
+            index = None
+        else:
+            refcounts = {id_: entry.refcount for id_, entry in self.chunks.iteritems() if entry.refcount}
+            if refcounts != {id_: entry.refcount for id_, entry in index.iteritems()}:
+                logger.error("Repository chunk index does not match the archives.")
+                index = None
+        if index is None:
+            self.error_found = True
+            if self.repair:
+                logger.info("Removing the repository chunk index.")
+                for id_ in head["snapshot"] + head["deltas"]:
+                    try:
+                        self.repository.delete(id_)
+                    except Repository.ObjectNotFound:
+                        pass
+                self.manifest.config.pop("chunk_index", None)
+
     def finish(self):
         if self.repair:
             logger.info("Writing Manifest.")
@@ -2355,6 +2416,16 @@ def finish(self):
             self.repository.commit(compact=False)
 
 
+def retention_locked(repository, archive_infos):
+    """Return {archive id: until} of the archives which are retention locked (see Archive.retention_lock)."""
+    ids = [info.id for info in archive_infos]
+    try:
+        untils = repository.get_retention(ids) if ids else []
+    except RemoteRepository.RPCServerOutdated:
+        return {}  # old servers do not support retention locks
+    return {id: datetime.fromtimestamp(until, timezone.utc) for id, until in zip(ids, untils) if until}
+
+
 class ArchiveRecreater:
     class Interrupted(Exception):
         def __init__(self, metadata=None):
@@ -66,6 +66,7 @@ def get_func(args):
 
 
 from .benchmark_cmd import BenchmarkMixIn
+from .cache_cmds import CacheMixIn
 from .check_cmd import CheckMixIn
 from .compact_cmd import CompactMixIn
 from .config_cmd import ConfigMixIn
@@ -88,6 +89,8 @@ def get_func(args):
 from .rinfo_cmd import RInfoMixIn
 from .rdelete_cmd import RDeleteMixIn
 from .rlist_cmd import RListMixIn
+from .rmirror_cmd import RMirrorMixIn
+from .rspace_cmd import RSpaceMixIn
 from .serve_cmd import ServeMixIn
 from .tar_cmds import TarMixIn
 from .transfer_cmd import TransferMixIn
@@ -96,6 +99,7 @@ def get_func(args):
 
 class Archiver(
     BenchmarkMixIn,
+    CacheMixIn,
     CheckMixIn,
     CompactMixIn,
     ConfigMixIn,
@@ -118,6 +122,8 @@ class Archiver(
     RDeleteMixIn,
     RInfoMixIn,
     RListMixIn,
+    RMirrorMixIn,
+    RSpaceMixIn,
     ServeMixIn,
     TarMixIn,
     TransferMixIn,
@@ -334,6 +340,7 @@ def build_parser(self):
         subparsers = parser.add_subparsers(title="required arguments", metavar="<command>")
 
         self.build_parser_benchmarks(subparsers, common_parser, mid_common_parser)
+        self.build_parser_cache(subparsers, common_parser, mid_common_parser)
         self.build_parser_check(subparsers, common_parser, mid_common_parser)
         self.build_parser_compact(subparsers, common_parser, mid_common_parser)
         self.build_parser_config(subparsers, common_parser, mid_common_parser)
@@ -354,6 +361,8 @@ def build_parser(self):
         self.build_parser_rdelete(subparsers, common_parser, mid_common_parser)
         self.build_parser_rinfo(subparsers, common_parser, mid_common_parser)
         self.build_parser_rlist(subparsers, common_parser, mid_common_parser)
+        self.build_parser_rmirror(subparsers, common_parser, mid_common_parser)
+        self.build_parser_rspace(subparsers, common_parser, mid_common_parser)
         self.build_parser_recreate(subparsers, common_parser, mid_common_parser)
         self.build_parser_rename(subparsers, common_parser, mid_common_parser)
         self.build_parser_serve(subparsers, common_parser, mid_common_parser)
@@ -382,7 +391,14 @@ def get_args(self, argv, cmd):
                 # client is allowed to specify the allowlisted options,
                 # everything else comes from the forced "borg serve" command (or the defaults).
                 # stuff from denylist must never be used from the client.
-                denylist = {"restrict_to_paths", "restrict_to_repositories", "append_only", "storage_quota", "umask"}
+                denylist = {
+                    "restrict_to_paths",
+                    "restrict_to_repositories",
+                    "append_only",
+                    "storage_quota",
+                    "allow_mirror",
+                    "umask",
+                }
                 allowlist = {"debug_topics", "lock_wait", "log_level"}
                 not_present = object()
                 for attr_name in allowlist:
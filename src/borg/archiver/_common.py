@@ -9,13 +9,14 @@
 from ..cache import Cache, assert_secure
 from ..helpers import Error
 from ..helpers import SortBySpec, positive_int_validator, location_validator, Location, relative_time_marker_validator
-from ..helpers import Highlander
+from ..helpers import Highlander, parse_rpc_compression, parse_ratelimit, parse_file_size
 from ..helpers.nanorst import rst_to_terminal
 from ..manifest import Manifest, AI_HUMAN_SORT_KEYS
 from ..patterns import PatternMatcher
 from ..remote import RemoteRepository
 from ..repository import Repository
 from ..repoobj import RepoObj, RepoObj1
+from ..storage import get_store
 from ..patterns import (
     ArgparsePatternAction,
     ArgparseExcludeFileAction,
@@ -29,8 +30,20 @@
 logger = create_logger(__name__)
 
 
-def get_repository(location, *, create, exclusive, lock_wait, lock, append_only, make_parent_dirs, storage_quota, args):
-    if location.proto in ("ssh", "socket"):
+def get_repository(
+    location,
+    *,
+    create,
+    exclusive,
+    lock_wait,
+    lock,
+    append_only,
+    make_parent_dirs,
+    storage_quota,
+    args,
+    concurrent=False,
+):
+    if location.proto in ("ssh", "socket", "http", "https"):
         repository = RemoteRepository(
             location,
             create=create,
@@ -40,6 +53,7 @@ def get_repository(location, *, create, exclusive, lock_wait, lock, append_only,
             append_only=append_only,
             make_parent_dirs=make_parent_dirs,
             args=args,
+            concurrent=concurrent,
         )
 
     else:
@@ -52,6 +66,8 @@ def get_repository(location, *, create, exclusive, lock_wait, lock, append_only,
             append_only=append_only,
             make_parent_dirs=make_parent_dirs,
             storage_quota=storage_quota,
+            store=get_store(location, args),
+            concurrent=concurrent,
         )
     return repository
 
@@ -73,13 +89,21 @@ def compat_check(*, create, manifest, key, cache, compatibility, decorator_name)
 
 
 def with_repository(
-    create=False, lock=True, exclusive=False, manifest=True, cache=False, secure=True, compatibility=None
+    create=False,
+    create_option=False,
+    lock=True,
+    exclusive=False,
+    manifest=True,
+    cache=False,
+    secure=True,
+    compatibility=None,
 ):
     """
     Method decorator for subcommand-handling methods: do_XYZ(self, args, repository, …)
 
     If a parameter (where allowed) is a str the attribute named of args is used instead.
     :param create: create repository
+    :param create_option: create repository if the --create option was given (args.create)
     :param lock: lock repository
     :param exclusive: (bool) lock repository exclusively (for writing)
     :param manifest: load manifest and repo_objs (key), pass them as keyword arguments
@@ -118,10 +142,12 @@ def wrapper(self, args, **kwargs):
             append_only = getattr(args, "append_only", False)
             storage_quota = getattr(args, "storage_quota", None)
             make_parent_dirs = getattr(args, "make_parent_dirs", False)
+            # concurrent writers and online compaction only lock the repository shared
+            concurrent = exclusive and (getattr(args, "concurrent", False) or getattr(args, "online", False))
 
             repository = get_repository(
                 location,
-                create=create,
+                create=create or (create_option and args.create),
                 exclusive=exclusive,
                 lock_wait=self.lock_wait,
                 lock=lock,
@@ -129,6 +155,7 @@ def wrapper(self, args, **kwargs):
                 make_parent_dirs=make_parent_dirs,
                 storage_quota=storage_quota,
                 args=args,
+                concurrent=concurrent,
             )
 
             with repository:
@@ -533,9 +560,19 @@ def define_common_options(add_common_option):
         "--upload-ratelimit",
         metavar="RATE",
         dest="upload_ratelimit",
-        type=int,
+        type=parse_ratelimit,
         action=Highlander,
-        help="set network upload rate limit in kiByte/s (default: 0=unlimited)",
+        help="set network upload rate limit in kiByte/s (default: 0=unlimited), "
+        "use RATE,HH:MM-HH:MM=RATE,... for other rates during some hours of the day",
+    )
+    add_common_option(
+        "--download-ratelimit",
+        metavar="RATE",
+        dest="download_ratelimit",
+        type=parse_ratelimit,
+        action=Highlander,
+        help="set network download rate limit in kiByte/s (default: 0=unlimited), "
+        "use RATE,HH:MM-HH:MM=RATE,... for other rates during some hours of the day",
     )
     add_common_option(
         "--upload-buffer",
@@ -545,6 +582,36 @@ def define_common_options(add_common_option):
         action=Highlander,
         help="set network upload buffer size in MiB. (default: 0=no buffer)",
     )
+    add_common_option(
+        "--metadata-cache",
+        metavar="SIZE",
+        dest="metadata_cache",
+        type=parse_file_size,
+        default=0,
+        action=Highlander,
+        help="keep up to SIZE of archive metadata of a remote repository in a persistent local cache "
+        "(default: 0=no cache), this speeds up mount, list, diff, info",
+    )
+    add_common_option(
+        "--rpc-compression",
+        metavar="SPEC",
+        dest="rpc_compression",
+        type=parse_rpc_compression,
+        default="zstd,3",
+        action=Highlander,
+        help="compress the communication with a remote repository: none or zstd[,L] (default: %(default)s, "
+        "use none if the connection already compresses, like ssh -C)",
+    )
+    add_common_option(
+        "--reconnect-timeout",
+        metavar="SECONDS",
+        dest="reconnect_timeout",
+        type=int,
+        default=RECONNECT_TIMEOUT,
+        action=Highlander,
+        help="try to reconnect for at most SECONDS if the connection to a remote repository drops "
+        "(default: %(default)d, 0=do not reconnect)",
+    )
     add_common_option(
         "--debug-profile",
         metavar="FILE",
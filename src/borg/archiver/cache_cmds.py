@@ -0,0 +1,80 @@
+import argparse
+import functools
+
+from ._common import with_repository
+from ..cache import CacheChecker
+from ..constants import *  # NOQA
+from ..helpers import set_ec, EXIT_WARNING
+from ..manifest import Manifest
+
+from ..logger import create_logger
+
+logger = create_logger()
+
+
+class CacheMixIn:
+    @with_repository(compatibility=(Manifest.Operation.READ,))
+    def do_cache_check(self, args, repository, manifest):
+        """Check the local cache"""
+        checker = CacheChecker(manifest, lock_wait=self.lock_wait)
+        if not checker.check(repair=args.repair):
+            set_ec(EXIT_WARNING)
+
+    def build_parser_cache(self, subparsers, common_parser, mid_common_parser):
+        from ._common import process_epilog
+
+        subparser = subparsers.add_parser(
+            "cache",
+            parents=[mid_common_parser],
+            add_help=False,
+            description="Manage the local cache of a repository",
+            epilog="",
+            formatter_class=argparse.RawDescriptionHelpFormatter,
+            help="manage the local cache",
+        )
+
+        cache_parsers = subparser.add_subparsers(title="required arguments", metavar="<command>")
+        subparser.set_defaults(fallback_func=functools.partial(self.do_subcommand_help, subparser))
+
+        cache_check_epilog = process_epilog(
+            """
+        This command verifies the local cache of a repository against the repository.
+        It is meant for when the cache is suspected to be out of sync, e.g. after a
+        crash or after restoring the client from an image. Unlike deleting the cache
+        with ``borg rdelete --cache-only``, ``--repair`` only fixes what is wrong.
+
+        The following is checked:
+
+        - the integrity data in the cache config and that the cache files match it.
+        - the chunks index (for the ``sqlite`` cache implementation, the chunks in its
+          database): its reference counts and sizes must match the ones computed
+          from the archives. This is only checked if the cache is in sync with the
+          repository, otherwise it gets updated anyway when the cache is used next time.
+        - the archive indexes in ``chunks.archive.d``: stale ones (of archives not in the
+          repository anymore) and ones not matching their archive are reported.
+        - the files caches: their entries must be well-formed and must only reference
+          chunks present in the repository.
+        - the database of the ``sqlite`` cache implementation, using SQLite's integrity check.
+
+        This needs to read the metadata of all archives, like a cache sync does.
+
+        With ``--repair``, wrong chunks index entries are corrected (a damaged chunks index
+        is rebuilt), wrong and stale archive indexes and invalid files cache entries are
+        removed, a damaged cache database is discarded and the integrity data is updated.
+        Damaged archives can not be repaired here (use ``borg check --repair``), as long as
+        there are any, the chunks index can not be checked and the exit code is a warning.
+        """
+        )
+        subparser = cache_parsers.add_parser(
+            "check",
+            parents=[common_parser],
+            add_help=False,
+            description=self.do_cache_check.__doc__,
+            epilog=cache_check_epilog,
+            formatter_class=argparse.RawDescriptionHelpFormatter,
+            help="check the local cache",
+        )
+        subparser.set_defaults(func=self.do_cache_check)
+        subparser.add_argument(
+            "--repair", dest="repair", action="store_true", help="attempt to repair any inconsistencies found"
+        )
@@ -3,7 +3,8 @@
 from ..archive import ArchiveChecker
 from ..constants import *  # NOQA
 from ..helpers import set_ec, EXIT_WARNING, CancelledByUser, CommandError
-from ..helpers import yes
+from ..helpers import yes, json_print
+from ..remote import RemoteRepository
 
 from ..logger import create_logger
 
@@ -37,30 +38,47 @@ def do_check(self, args, repository):
             )
         if args.repair and args.max_duration:
             raise CommandError("--repair does not allow --max-duration argument.")
+        if args.scrub and (args.archives_only or args.max_duration):
+            raise CommandError("--scrub contradicts --archives-only and --max-duration arguments.")
         if args.max_duration and not args.repo_only:
             # when doing a partial repo check, we can only check crc32 checksums in segment files,
             # we can't build a fresh repo index in memory to verify the on-disk index against it.
             # thus, we should not do an archives check based on a unknown-quality on-disk repo index.
             # also, there is no max_duration support in the archives check code anyway.
             raise CommandError("--repository-only is required for --max-duration support.")
+        check = {}
+        if args.scrub:
+            scrub_ok = repository.scrub()
+            if not scrub_ok:
+                set_ec(EXIT_WARNING)
+            check["scrub"] = {"ok": scrub_ok}
         if not args.archives_only:
-            if not repository.check(repair=args.repair, max_duration=args.max_duration):
+            repository_ok = repository.check(repair=args.repair, max_duration=args.max_duration)
+            if not repository_ok:
+                set_ec(EXIT_WARNING)
+            try:
+                check["repository"] = repository.check_summary()
+            except RemoteRepository.RPCServerOutdated:
+                check["repository"] = {"ok": repository_ok}
+        if not args.repo_only:
+            archives_ok = ArchiveChecker().check(
+                repository,
+                verify_data=args.verify_data,
+                repair=args.repair,
+                match=args.match_archives,
+                sort_by=args.sort_by or "ts",
+                first=args.first,
+                last=args.last,
+                older=args.older,
+                newer=args.newer,
+                oldest=args.oldest,
+                newest=args.newest,
+            )
+            if not archives_ok:
                 set_ec(EXIT_WARNING)
-        if not args.repo_only and not ArchiveChecker().check(
-            repository,
-            verify_data=args.verify_data,
-            repair=args.repair,
-            match=args.match_archives,
-            sort_by=args.sort_by or "ts",
-            first=args.first,
-            last=args.last,
-            older=args.older,
-            newer=args.newer,
-            oldest=args.oldest,
-            newest=args.newest,
-        ):
-            set_ec(EXIT_WARNING)
-            return
+            check["archives"] = {"ok": archives_ok}
+        if args.json:
+            json_print({"repository": repository, "check": check})
 
     def build_parser_check(self, subparsers, common_parser, mid_common_parser):
         from ._common import process_epilog
@@ -121,6 +139,37 @@ def build_parser_check(self, subparsers, common_parser, mid_common_parser):
         encrypted repositories against attackers without access to the keys. You can
         not use ``--verify-data`` with ``--repository-only``.
 
+        Progress and results
+        ++++++++++++++++++++
+
+        With ``--progress``, the repository check shows which segment it is checking
+        and how many errors it found so far, also when the check runs on the server of a
+        remote repository. The progress is shown at least once per minute, so a slow
+        check can be told apart from a hung one. Errors are logged when they are found;
+        use ``--log-json`` to get both as JSON lines.
+
+        ``--json`` outputs a summary when the check is finished: for the repository check
+        the number of segments checked, the damaged segments, the number of errors found
+        and the duration; for the archives check and ``--scrub`` whether they found
+        no problems.
+
+        Parity data and scrubbing
+        +++++++++++++++++++++++++
+
+        A repository can keep Reed-Solomon parity data for its segment files, so that
+        damaged parts of them (e.g. due to bit rot) can be rebuilt without any data loss.
+        This is disabled by default, enable it with ``borg config parity_shards 2``: then,
+        of each 8 consecutive 4 KiB blocks of a segment file, any 2 can be rebuilt. The
+        number of blocks per group can be set using ``parity_data_shards`` (default: 8).
+        The parity data needs ``parity_shards / parity_data_shards`` of additional space.
+
+        Pass ``--scrub`` to verify all segment files using their parity data before the
+        repository check. Damaged segment files are rebuilt and missing parity data
+        (e.g. of segment files written before enabling it) is created. Scrubbing does not
+        lose data and does not need ``--repair``. Damage that can not be rebuilt is left
+        for ``--repair``, which also uses the parity data first, before it falls back to
+        salvaging the intact objects of a damaged segment file.
+
         About repair mode
         +++++++++++++++++
 
@@ -151,8 +200,9 @@ def build_parser_check(self, subparsers, common_parser, mid_common_parser):
 
         In practice, repair mode hooks into both the repository and archive checks:
 
-        1. When checking the repository's consistency, repair mode will try to recover
-           as many objects from segments with integrity errors as possible, and ensure
+        1. When checking the repository's consistency, repair mode will rebuild segments
+           with integrity errors from their parity data (if any) or else try to recover
+           as many objects from them as possible, and ensure
            that the index is consistent with the data stored in the segments.
 
         2. When checking the consistency and correctness of archives, repair mode might
@@ -207,6 +257,12 @@ def build_parser_check(self, subparsers, common_parser, mid_common_parser):
         subparser.add_argument(
             "--repair", dest="repair", action="store_true", help="attempt to repair any inconsistencies found"
         )
+        subparser.add_argument(
+            "--scrub",
+            dest="scrub",
+            action="store_true",
+            help="rebuild damaged segment files from their parity data and create missing parity data",
+        )
         subparser.add_argument(
             "--max-duration",
             metavar="SECONDS",
@@ -216,4 +272,5 @@ def build_parser_check(self, subparsers, common_parser, mid_common_parser):
             action=Highlander,
             help="do only a partial repo check for max. SECONDS seconds (Default: unlimited)",
         )
+        subparser.add_argument("--json", action="store_true", help="output a summary of the check as JSON")
         define_archive_filters_group(subparser)
@@ -1,4 +1,5 @@
 import argparse
+import time
 
 from ._common import with_repository, Highlander
 from ..constants import *  # NOQA
@@ -13,10 +14,15 @@ class CompactMixIn:
     @with_repository(manifest=False, exclusive=True)
     def do_compact(self, args, repository):
         """compact segment files in the repository"""
+        threshold = args.threshold / 100
+        if args.online:
+            while repository.compact_slice(threshold=threshold, max_duration=args.slice_duration):
+                # give the writers a chance to commit (they need the commit lock)
+                time.sleep(args.slice_duration)
+            return
         # see the comment in do_with_lock about why we do it like this:
         data = repository.get(Manifest.MANIFEST_ID)
         repository.put(Manifest.MANIFEST_ID, data)
-        threshold = args.threshold / 100
         repository.commit(compact=True, threshold=threshold)
 
     def build_parser_compact(self, subparsers, common_parser, mid_common_parser):
@@ -40,6 +46,14 @@ def build_parser_compact(self, subparsers, common_parser, mid_common_parser):
         given by the ``--threshold`` option. If omitted, a threshold of 10% is used.
         When using ``--verbose``, borg will output an estimate of the freed space.
 
+        Usually, borg compact locks the repository exclusively while it works, so other
+        borg commands using the repository have to wait. With ``--online``, it only locks
+        the repository shared and compacts in slices of about ``--slice-duration`` seconds,
+        pausing as long between the slices, so e.g. ``borg create --concurrent`` and
+        ``borg extract`` can keep running. Each slice switches the repository to the
+        compacted segments atomically. While concurrent writers have a transaction in
+        progress, online compaction waits for them to finish it.
+
         See :ref:`separate_compaction` in Additional Notes for more details.
         """
         )
@@ -62,3 +76,18 @@ def build_parser_compact(self, subparsers, common_parser, mid_common_parser):
             action=Highlander,
             help="set minimum threshold for saved space in PERCENT (Default: 10)",
         )
+        subparser.add_argument(
+            "--online",
+            dest="online",
+            action="store_true",
+            help="compact in slices, not blocking other borg commands using the repository",
+        )
+        subparser.add_argument(
+            "--slice-duration",
+            metavar="SECONDS",
+            dest="slice_duration",
+            type=int,
+            default=10,
+            action=Highlander,
+            help="with --online: compact for about SECONDS, then pause as long (Default: 10)",
+        )
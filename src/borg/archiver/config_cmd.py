@@ -27,6 +27,15 @@ def repo_validate(section, name, value=None, check_value=True):
                         int(value)
                     except ValueError:
                         raise ValueError("Invalid value") from None
+            elif name in ["parity_shards", "parity_data_shards"]:
+                if check_value:
+                    try:
+                        shards = int(value)
+                    except ValueError:
+                        raise ValueError("Invalid value") from None
+                    minimum = 0 if name == "parity_shards" else 1
+                    if not minimum <= shards <= 255:
+                        raise ValueError("Invalid value: %s must be in %d..255" % (name, minimum))
             elif name in ["max_segment_size", "additional_free_space", "storage_quota"]:
                 if check_value:
                     try:
@@ -39,6 +48,14 @@ def repo_validate(section, name, value=None, check_value=True):
                     elif name == "max_segment_size":
                         if parse_file_size(value) >= MAX_SEGMENT_SIZE_LIMIT:
                             raise ValueError("Invalid value: max_segment_size >= %d" % MAX_SEGMENT_SIZE_LIMIT)
+            elif name in ["data_placement"]:
+                if check_value and value not in DATA_PLACEMENTS:
+                    raise ValueError("Invalid value: data_placement must be one of %s" % ", ".join(DATA_PLACEMENTS))
+            elif name in ["index_mode"]:
+                if check_value and value not in INDEX_MODES:
+                    raise ValueError("Invalid value: index_mode must be one of %s" % ", ".join(INDEX_MODES))
+            elif name in ["data_roots", "draining_data_roots"]:
+                raise ValueError("Use --add-data-root or --drain-data-root to change the data roots")
             elif name in ["append_only"]:
                 if check_value and value not in ["0", "1"]:
                     raise ValueError("Invalid value")
@@ -51,8 +68,11 @@ def repo_validate(section, name, value=None, check_value=True):
         def cache_validate(section, name, value=None, check_value=True):
             if section not in ["cache"]:
                 raise ValueError("Invalid section")
-            # currently, we do not support setting anything in the cache via borg config.
-            raise ValueError("Invalid name")
+            if name in ["index_mode"]:
+                if check_value and value not in INDEX_MODES:
+                    raise ValueError("Invalid value: index_mode must be one of %s" % ", ".join(INDEX_MODES))
+            else:
+                raise ValueError("Invalid name")
 
         def list_config(config):
             default_values = {
@@ -62,6 +82,10 @@ def list_config(config):
                 "additional_free_space": "0",
                 "storage_quota": repository.storage_quota,
                 "append_only": repository.append_only,
+                "parity_shards": str(DEFAULT_PARITY_SHARDS),
+                "parity_data_shards": str(DEFAULT_PARITY_DATA_SHARDS),
+                "data_placement": DEFAULT_DATA_PLACEMENT,
+                "index_mode": DEFAULT_INDEX_MODE,
             }
             print("[repository]")
             for key in [
@@ -71,6 +95,10 @@ def list_config(config):
                 "storage_quota",
                 "additional_free_space",
                 "append_only",
+                "parity_shards",
+                "parity_data_shards",
+                "data_placement",
+                "index_mode",
                 "id",
             ]:
                 value = config.get("repository", key, fallback=False)
@@ -79,12 +107,21 @@ def list_config(config):
                     if value is None:
                         raise Error("The repository config is missing the %s key which has no default value" % key)
                 print(f"{key} = {value}")
-            for key in ["last_segment_checked"]:
+            for key in ["data_roots", "draining_data_roots", "last_segment_checked"]:
                 value = config.get("repository", key, fallback=None)
                 if value is None:
                     continue
                 print(f"{key} = {value}")
 
+        if args.add_data_root or args.drain_data_root:
+            if args.cache or args.name is not None:
+                raise CommandError("--add-data-root and --drain-data-root do not take a config key name.")
+            if args.add_data_root:
+                repository.add_data_root(args.add_data_root)
+            else:
+                repository.drain_data_root(args.drain_data_root)
+            return
+
         if not args.list:
             if args.name is None:
                 raise CommandError("No config key name was provided.")
@@ -151,6 +188,34 @@ def build_parser_config(self, subparsers, common_parser, mid_common_parser):
 
         By default, borg config manipulates the repository config file. Using ``--cache``
         edits the repository cache's config file instead.
+
+        Data roots: usually, all segment files of a repository are in its ``data/`` directory.
+        To grow a repository beyond its filesystem, add more data roots (e.g. directories on
+        other disks) using ``--add-data-root PATH``. New segment files are then placed into the
+        data root with the most free space or, if the ``data_placement`` key is set to
+        ``round-robin``, into one data root after the other. Existing segment files are not
+        moved. ``--drain-data-root PATH`` moves the segment files of a data root to the
+        other data roots and removes it from the repository (if it gets interrupted, just
+        run it again). While a data root is not available (e.g. not mounted), the repository
+        can not be used.
+
+        Examples::
+
+            borg config --add-data-root /mnt/disk2/borg-data
+            borg config data_placement round-robin
+            borg config --drain-data-root /mnt/disk2/borg-data
+
+        Index mode: by default, the repository index and the chunks cache are read into memory
+        completely. For huge repositories, set the ``index_mode`` key to ``mapped`` (in the
+        repository config and/or, using ``--cache``, in the cache config). The index files are
+        then used through a memory mapping and updated in place, so only the parts of them that
+        are actually used need to be in memory. Mapped index files are not protected by a
+        checksum. In the repository config, ``mapped`` needs the repository to be on local storage.
+
+        Examples::
+
+            borg config index_mode mapped
+            borg config --cache index_mode mapped
         """
         )
         subparser = subparsers.add_parser(
@@ -172,6 +237,18 @@ def build_parser_config(self, subparsers, common_parser, mid_common_parser):
             "-d", "--delete", dest="delete", action="store_true", help="delete the key from the config file"
         )
         group.add_argument("-l", "--list", dest="list", action="store_true", help="list the configuration of the repo")
+        group.add_argument(
+            "--add-data-root",
+            metavar="PATH",
+            dest="add_data_root",
+            help="add PATH (a local directory, empty or not existing) as data root of the repo",
+        )
+        group.add_argument(
+            "--drain-data-root",
+            metavar="PATH",
+            dest="drain_data_root",
+            help="move the segment files in data root PATH to the other data roots and remove it",
+        )
 
         subparser.add_argument("name", metavar="NAME", nargs="?", help="name of config key")
         subparser.add_argument("value", metavar="VALUE", nargs="?", help="new value for key")
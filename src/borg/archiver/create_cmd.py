@@ -6,6 +6,7 @@
 import stat
 import subprocess
 import time
+from datetime import timedelta
 from io import TextIOWrapper
 
 from ._common import with_repository, Highlander
@@ -13,13 +14,13 @@
 from ..archive import Archive, is_special
 from ..archive import BackupError, BackupOSError, backup_io, OsOpen, stat_update_check
 from ..archive import FilesystemObjectProcessors, MetadataCollector, ChunksProcessor
-from ..cache import Cache
+from ..cache import Cache, files_cache_scope
 from ..constants import *  # NOQA
 from ..compress import CompressionSpec
 from ..helpers import comment_validator, ChunkerParams, PathSpec
 from ..helpers import archivename_validator, FilesCacheMode
 from ..helpers import eval_escapes
-from ..helpers import timestamp, archive_ts_now
+from ..helpers import timestamp, archive_ts_now, interval
 from ..helpers import get_cache_dir, os_stat, get_strip_prefix
 from ..helpers import dir_is_tagged
 from ..helpers import log_multi
@@ -201,6 +202,8 @@ def create_inner(archive, cache, fso):
                     raise Error("Got Ctrl-C / SIGINT.")
                 else:
                     archive.save(comment=args.comment, timestamp=args.timestamp)
+                    if args.retention_lock:
+                        archive.retention_lock(archive_ts_now() + timedelta(hours=args.retention_lock))
                     args.stats |= args.json
                     if args.stats:
                         if args.json:
@@ -228,6 +231,7 @@ def create_inner(archive, cache, fso):
                 no_cache_sync_forced=args.no_cache_sync_forced,
                 prefer_adhoc_cache=args.prefer_adhoc_cache,
                 cache_mode=args.files_cache_mode,
+                files_cache_scope=files_cache_scope(args.name, args.paths),
                 iec=args.iec,
             ) as cache:
                 archive = Archive(
@@ -630,6 +634,13 @@ def build_parser_create(self, subparsers, common_parser, mid_common_parser):
           it had before a content change happened. This can be used maliciously as well as
           well-meant, but in both cases mtime based cache modes can be problematic.
 
+        Archives of the same series (archive names only differing in numbers, e.g. created
+        from the same name with a {now} placeholder) created from the same paths share a files
+        cache, which is separate from the files caches of other series, so backups of different
+        data sets do not evict each other's files cache entries. Files caches not used for
+        BORG_FILES_CACHE_MAX_AGE days (default: 60) are removed. If BORG_FILES_CACHE_SUFFIX
+        is set, the files cache named by it is used instead.
+
         The mount points of filesystems or filesystem snapshots should be the same for every
         creation of a new archive to ensure fast operation. This is because the file cache that
         is used to determine changed files quickly uses absolute filenames.
@@ -718,6 +729,37 @@ def build_parser_create(self, subparsers, common_parser, mid_common_parser):
         - 'i' = backup data was read from standard input (stdin)
         - '?' = missing status code (if you see this, please file a bug report!)
 
+        Concurrent backups
+        ++++++++++++++++++
+
+        Usually, ``borg create`` locks the repository exclusively, so backups of multiple
+        clients into the same repository run one after the other. With ``--concurrent``,
+        the repository is only locked shared and other ``borg create --concurrent`` can
+        write to it at the same time. Every writer keeps its new data in separate staging
+        segments until it commits. At commit time, a short-lived commit lock serializes the
+        writers: the staged segments are added to the repository index and the new archive
+        is added to the most recent manifest (containing the archives committed by the
+        others meanwhile).
+
+        Deduplication between the concurrent writers is limited: chunks another writer
+        stored after this one started may be stored twice (``borg compact`` frees the
+        space later). Operations needing an exclusive lock (like ``borg compact``, ``borg check``
+        or ``borg delete``) wait until all concurrent writers are finished.
+
+        Retention locks
+        +++++++++++++++
+
+        ``--retention-lock INTERVAL`` (e.g. ``90d``) locks the new archive: until the interval
+        is over, the repository refuses to delete the archive and the chunks it references,
+        so neither ``borg delete`` / ``borg prune`` nor ``borg compact`` can remove it. The locks
+        are enforced by the repository code (also by ``borg serve`` for remote repositories,
+        no matter what the client does). They can only be extended, never shortened, and at
+        most up to 100 years into the future.
+
+        Locked archives are skipped by ``borg delete`` and kept by ``borg prune``. Even if the
+        manifest gets manipulated, the locked archives stay in the repository, a manifest
+        rebuild (``borg check --repair`` without a manifest) finds them again.
+
         Reading backup data from stdin
         ++++++++++++++++++++++++++++++
 
@@ -817,6 +859,12 @@ def build_parser_create(self, subparsers, common_parser, mid_common_parser):
             action="store_true",
             help="experimental: prefer AdHocCache (w/o files cache) over AdHocWithFilesCache (with files cache).",
         )
+        subparser.add_argument(
+            "--concurrent",
+            dest="concurrent",
+            action="store_true",
+            help="allow other clients to create archives in the repository at the same time (see description).",
+        )
         subparser.add_argument(
             "--stdin-name",
             metavar="NAME",
@@ -956,6 +1004,14 @@ def build_parser_create(self, subparsers, common_parser, mid_common_parser):
             help="manually specify the archive creation date/time (yyyy-mm-ddThh:mm:ss[(+|-)HH:MM] format, "
             "(+|-)HH:MM is the UTC offset, default: local time zone). Alternatively, give a reference file/directory.",
         )
+        archive_group.add_argument(
+            "--retention-lock",
+            metavar="INTERVAL",
+            dest="retention_lock",
+            type=interval,
+            action=Highlander,
+            help="lock the archive against deletion for INTERVAL (e.g. 90d), enforced by the repository",
+        )
         archive_group.add_argument(
             "-c",
             "--checkpoint-interval",
@@ -2,10 +2,10 @@
 import logging
 
 from ._common import with_repository, Highlander
-from ..archive import Archive, Statistics
+from ..archive import Archive, Statistics, retention_locked
 from ..cache import Cache
 from ..constants import *  # NOQA
-from ..helpers import log_multi, format_archive, sig_int, CommandError, Error
+from ..helpers import log_multi, format_archive, format_time, sig_int, CommandError, Error
 from ..manifest import Manifest
 
 from ..logger import create_logger
@@ -20,7 +20,8 @@ def do_delete(self, args, repository):
         self.output_list = args.output_list
         dry_run = args.dry_run
         manifest = Manifest.load(repository, (Manifest.Operation.DELETE,))
-        archive_names = tuple(x.name for x in manifest.archives.list_considering(args))
+        archive_infos = manifest.archives.list_considering(args)
+        archive_names = tuple(x.name for x in archive_infos)
         if not archive_names:
             return
         if args.match_archives is None and args.first == 0 and args.last == 0:
@@ -28,11 +29,18 @@ def do_delete(self, args, repository):
                 "Aborting: if you really want to delete all archives, please use -a 'sh:*' "
                 "or just delete the whole repository (might be much faster)."
             )
+        locked = retention_locked(repository, archive_infos)
+        locked = {x.name: locked[x.id] for x in archive_infos if x.id in locked}
+        msg_locked = "Archive {} is retention locked until {}, skipping it ({}/{})."
 
         if args.forced == 2:
             deleted = False
             logger_list = logging.getLogger("borg.output.list")
             for i, archive_name in enumerate(archive_names, 1):
+                if archive_name in locked:
+                    until = format_time(locked[archive_name])
+                    self.print_warning(msg_locked.format(archive_name, until, i, len(archive_names)))
+                    continue
                 try:
                     current_archive = manifest.archives.pop(archive_name)
                 except KeyError:
@@ -73,6 +81,10 @@ def checkpoint_func():
                 except KeyError:
                     self.print_warning(msg_not_found.format(archive_name, i, len(archive_names)))
                 else:
+                    if archive_name in locked:
+                        until = format_time(locked[archive_name])
+                        self.print_warning(msg_locked.format(archive_name, until, i, len(archive_names)))
+                        continue
                     if self.output_list:
                         logger_list.info(msg_delete.format(format_archive(archive_info), i, len(archive_names)))
 
@@ -101,6 +113,9 @@ def build_parser_delete(self, subparsers, common_parser, mid_common_parser):
         Important: When deleting archives, repository disk space is **not** freed until
         you run ``borg compact``.
 
+        Archives which are retention locked (see ``borg create --retention-lock``) can not
+        be deleted until the lock expires, they are skipped (with a warning).
+
         When in doubt, use ``--dry-run --list`` to see what would be deleted.
 
         When using ``--stats``, you will get some statistics about how much data was
@@ -7,7 +7,7 @@
 import re
 
 from ._common import with_repository, Highlander
-from ..archive import Archive, Statistics
+from ..archive import Archive, Statistics, retention_locked
 from ..cache import Cache
 from ..constants import *  # NOQA
 from ..helpers import ArchiveFormatter, interval, sig_int, log_multi, ProgressIndicatorPercent, CommandError, Error
@@ -127,6 +127,14 @@ def do_prune(self, args, repository, manifest):
                 keep += prune_split(archives, rule, num, kept_because)
 
         to_delete = (set(archives) | checkpoints) - (set(keep) | set(keep_checkpoints))
+        # the repository refuses to delete retention locked archives (see borg create --retention-lock)
+        locked = retention_locked(repository, to_delete)
+        kept_locked = 0
+        for archive in archives_checkpoints:
+            if archive.id in locked:
+                to_delete.remove(archive)
+                kept_locked += 1
+                kept_because[archive.id] = ("retention-lock", kept_locked)
         stats = Statistics(iec=args.iec)
         with Cache(repository, manifest, lock_wait=self.lock_wait, iec=args.iec) as cache:
 
@@ -235,6 +243,10 @@ def build_parser_prune(self, subparsers, common_parser, mid_common_parser):
         keep the last N archives under the assumption that you do not create more than one
         backup archive in the same second).
 
+        Archives which are retention locked (see ``borg create --retention-lock``) are
+        always kept (rule: retention-lock), the repository would refuse to delete them.
+        They do not count towards the totals specified by the other options.
+
         When using ``--stats``, you will get some statistics about how much data was
         deleted - the "Deleted data" deduplicated size there is most interesting as
         that is how much your repository will shrink.
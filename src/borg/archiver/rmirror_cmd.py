@@ -0,0 +1,91 @@
+import argparse
+
+from ._common import with_repository, with_other_repository, Highlander
+from ..constants import *  # NOQA
+from ..helpers import CommandError
+from ..helpers import location_validator, Location
+from ..mirror import mirror_repository
+
+from ..logger import create_logger
+
+logger = create_logger()
+
+
+class RMirrorMixIn:
+    @with_other_repository()
+    @with_repository(create_option=True, exclusive=True, manifest=False)
+    def do_rmirror(self, args, repository, other_repository=None):
+        """Mirror another repository incrementally"""
+        if other_repository is None:
+            raise CommandError("You need to give the repository to mirror, using --other-repo.")
+        mirror_repository(other_repository, repository, dry_run=args.dry_run, progress=args.progress)
+
+    def build_parser_rmirror(self, subparsers, common_parser, mid_common_parser):
+        from ._common import process_epilog
+
+        rmirror_epilog = process_epilog(
+            """
+        This command updates a repository to be an exact copy (a mirror) of the latest
+        transaction of another repository, without decrypting anything (no passphrase
+        or key needed). Both repositories can be local or remote.
+
+        Only what is new since the last run is copied: segment files are never modified
+        after they were committed, so just the segment files the mirror does not have yet
+        are copied, plus the repository index of the latest transaction. Segment files
+        the other repository does not have any more (e.g. due to ``borg compact``) are
+        deleted from the mirror.
+
+        The mirror stays consistent: the copied data is staged and verified first, the
+        mirror only switches to the new transaction when everything was received. If the
+        command gets interrupted, the mirror is still at its previous transaction and the
+        next run starts over. Uncommitted data of the other repository is never copied.
+
+        Use ``--create`` to create the mirror repository. An existing, empty repository
+        can also become a mirror, it gets the repository id and the repokey (if any) of
+        the other repository. For key file mode, the key file of the other repository
+        works for the mirror, too.
+
+        If the mirror is a remote repository, its ``borg serve`` must allow this using
+        ``--allow-mirror`` (it does if borg is invoked by ssh without a forced command).
+        A ``--storage-quota`` of the mirror applies, in ``--append-only`` mode a mirror can
+        only be updated if no segment files need to be deleted.
+
+        Examples::
+
+            # initially create the mirror, then update it (e.g. daily)
+            borg --repo=MIRROR_REPO rmirror --other-repo=SRC_REPO --create
+            borg --repo=MIRROR_REPO rmirror --other-repo=SRC_REPO
+
+        As the mirror has the same repository id as the other repository, borg treats
+        using it like using the other repository after it was moved. If the mirror is
+        behind the other repository, borg will also detect a repository "replay" when
+        using it with the cache of the other repository. The mirror is meant for restore
+        (and for updating it by this command), not for creating new archives in it, as
+        new transactions in the mirror would make it diverge from the other repository.
+        """
+        )
+        subparser = subparsers.add_parser(
+            "rmirror",
+            parents=[common_parser],
+            add_help=False,
+            description=self.do_rmirror.__doc__,
+            epilog=rmirror_epilog,
+            formatter_class=argparse.RawDescriptionHelpFormatter,
+            help="mirror another repository",
+        )
+        subparser.set_defaults(func=self.do_rmirror, allow_mirror=True)  # see borg serve --allow-mirror
+        subparser.add_argument(
+            "-n", "--dry-run", dest="dry_run", action="store_true", help="do not change repository, just check"
+        )
+        subparser.add_argument(
+            "--other-repo",
+            metavar="SRC_REPOSITORY",
+            dest="other_location",
+            type=location_validator(other=True),
+            default=Location(other=True),
+            action=Highlander,
+            help="mirror the other repository",
+        )
+        subparser.add_argument(
+            "--create", dest="create", action="store_true", help="create the mirror repository (must not exist)"
+        )
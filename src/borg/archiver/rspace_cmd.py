@@ -0,0 +1,255 @@
+import argparse
+from collections import defaultdict
+
+from ._common import with_repository
+from ..archive import Archive
+from ..constants import *  # NOQA
+from ..compress import COMPRESSOR_LIST
+from ..hashindex import ChunkIndex, CacheSynchronizer
+from ..helpers import bin_to_hex, json_print, basic_json_data, format_file_size, remove_surrogates
+from ..helpers import ProgressIndicatorPercent, OutputTimestamp
+from ..manifest import Manifest
+
+from ..logger import create_logger
+
+logger = create_logger()
+
+# how the repo object types are reported
+OBJECT_TYPES = {
+    ROBJ_FILE_STREAM: "file_data",
+    ROBJ_ARCHIVE_STREAM: "item_metadata",
+    ROBJ_ARCHIVE_CHUNKIDS: "archive_metadata",
+    ROBJ_ARCHIVE_META: "archive_metadata",
+    ROBJ_MANIFEST: "manifest",
+    ROBJ_CHUNK_INDEX: "chunk_index",
+}
+
+
+def compressor_name(ctype):
+    for cls in COMPRESSOR_LIST:
+        if cls.ID == ctype:
+            return cls.name
+    return str(ctype)
+
+
+def space_stats():
+    return dict(count=0, size=0, stored_size=0)
+
+
+def scan_objects(repository, repo_objs, cache):
+    """
+    scan all objects in the repository, sum up their sizes by object type and by compression algorithm.
+
+    return the stats and a ChunkIndex of all referenced objects: id -> (refcount, stored size).
+    """
+    stored = ChunkIndex(usable=len(cache.chunks))
+    for id, entry in cache.chunks.iteritems():
+        stored[id] = entry.refcount, 0
+    types = defaultdict(space_stats)
+    compression = defaultdict(space_stats)
+    unreferenced = space_stats()
+    state = None
+    chunks_count = len(repository)
+    chunks_limit = min(1000, max(100, chunks_count // 1000))
+    pi = ProgressIndicatorPercent(
+        total=chunks_count, msg="Scanning repository objects %3.1f%%", step=0.1, msgid="rspace.scan_objects"
+    )
+    while True:
+        chunk_ids, state = repository.scan(limit=chunks_limit, state=state)
+        if not chunk_ids:
+            break
+        for id, chunk_no_data in zip(chunk_ids, repository.get_many(chunk_ids, read_data=False)):
+            meta = repo_objs.parse_meta(id, chunk_no_data, ro_type=ROBJ_DONTCARE)
+            size, stored_size = meta["size"], meta["csize"]
+            stats = [types[OBJECT_TYPES.get(meta["type"], "other")], compression[compressor_name(meta["ctype"])]]
+            entry = stored.get(id)
+            if entry is not None:
+                stored[id] = entry.refcount, stored_size
+            elif meta["type"] not in (ROBJ_MANIFEST, ROBJ_CHUNK_INDEX):
+                stats.append(unreferenced)
+            for s in stats:
+                s["count"] += 1
+                s["size"] += size
+                s["stored_size"] += stored_size
+            pi.show(increase=1)
+    pi.finish()
+    for s in compression.values():
+        s["ratio"] = s["size"] / s["stored_size"] if s["stored_size"] else 1.0
+    return stored, dict(types), dict(compression), unreferenced
+
+
+def archive_chunk_index(archive):
+    """return a ChunkIndex with the reference counts of all objects used by *archive*"""
+    archive_index = ChunkIndex()
+    archive_index.add(archive.id, 1, 0)
+    for id in archive.metadata.get("item_ptrs", []):
+        archive_index.add(id, 1, 0)
+    sync = CacheSynchronizer(archive_index)
+    for id, chunk in zip(archive.metadata.items, archive.repository.get_many(archive.metadata.items)):
+        archive_index.add(id, 1, 0)
+        _, data = archive.repo_objs.parse(id, chunk, ro_type=ROBJ_ARCHIVE_STREAM)
+        sync.feed(data)
+    return archive_index
+
+
+def group_stats(group_indexes, stored):
+    """
+    compute the space used by groups of archives: referenced (each object counted once), unique (only used
+    by this group) and shared with each other group.
+    """
+    groups = {}
+    for name, group_index in group_indexes.items():
+        stats = dict(referenced_size=0, unique_size=0, shared_size=0)
+        shared_with = {other: 0 for other in group_indexes if other != name}
+        for id, entry in group_index.iteritems():
+            master = stored[id]
+            stats["referenced_size"] += master.size
+            if entry.refcount == master.refcount:
+                stats["unique_size"] += master.size
+                continue
+            stats["shared_size"] += master.size
+            for other in shared_with:
+                if id in group_indexes[other]:
+                    shared_with[other] += master.size
+        stats["shared_with"] = shared_with
+        groups[name] = stats
+    return groups
+
+
+class RSpaceMixIn:
+    @with_repository(cache=True, compatibility=(Manifest.Operation.READ,))
+    def do_rspace(self, args, repository, manifest, cache):
+        """Show what is using the space in the repository"""
+        repo_objs = manifest.repo_objs
+        stored, types, compression, unreferenced = scan_objects(repository, repo_objs, cache)
+
+        args.consider_checkpoints = True
+        archive_infos = manifest.archives.list_considering(args)
+        reported = {info.id for info in archive_infos}
+        groups = {pattern: {info.id for info in manifest.archives.list(match=pattern)} for pattern in args.groups or []}
+        grouped = set().union(*groups.values())
+        # archives only needed for the groups are processed, but not reported
+        archive_infos += [info for info in manifest.archives.list() if info.id in grouped - reported]
+        group_indexes = {pattern: ChunkIndex() for pattern in groups}
+
+        archives = []
+        pi = ProgressIndicatorPercent(
+            total=len(archive_infos), msg="%3.0f%% Processing archive %s.", step=1, msgid="rspace.archives"
+        )
+        for info in archive_infos:
+            pi.show(info=[remove_surrogates(info.name)])
+            archive = Archive(manifest, info.name, cache=cache)
+            archive_index = archive_chunk_index(archive)
+            if info.id in reported:
+                _, unique_size, unique_chunks, _ = archive_index.stats_against(stored)
+                archives.append(
+                    {
+                        "name": info.name,
+                        "id": bin_to_hex(info.id),
+                        "start": OutputTimestamp(info.ts),
+                        "unique_chunks": unique_chunks,
+                        "unique_size": unique_size,
+                    }
+                )
+            for pattern, ids in groups.items():
+                if info.id in ids:
+                    group_indexes[pattern].merge(archive_index)
+        pi.finish()
+
+        response = repository.info()
+        space = {
+            "repository_size": response["storage_quota_use"],
+            "types": types,
+            "compression": compression,
+            "unreferenced": unreferenced,
+            "archives": archives,
+            "groups": group_stats(group_indexes, stored),
+            "segments": {
+                "sparse_segments": response.get("sparse_segments"),
+                "reclaimable_size": response.get("reclaimable_space"),
+            },
+        }
+        if args.json:
+            json_print(basic_json_data(manifest, extra={"space": space}))
+            return
+
+        def fmt(size):
+            return format_file_size(size, iec=args.iec)
+
+        print(f"Repository size: {fmt(space['repository_size'])}")
+        print()
+        print("Stored by object type:")
+        for name, s in sorted(types.items()):
+            print(f"  {name:<18} {s['count']:>10} objects {fmt(s['size']):>10} {fmt(s['stored_size']):>10} stored")
+        print()
+        print("Compression:")
+        for name, s in sorted(compression.items()):
+            line = f"  {name:<18} {s['count']:>10} objects {fmt(s['size']):>10} {fmt(s['stored_size']):>10} stored"
+            print(f"{line}, ratio {s['ratio']:.2f}")
+        if unreferenced["count"]:
+            print()
+            print(f"Unreferenced objects: {unreferenced['count']}, {fmt(unreferenced['stored_size'])} stored")
+        if archives:
+            print()
+            print("Unique size of archives:")
+            for a in archives:
+                print(f"  {a['name']:<36} {a['start']} {fmt(a['unique_size']):>10}")
+        for pattern, g in space["groups"].items():
+            print()
+            print(f"Archive group {pattern}:")
+            print(f"  referenced: {fmt(g['referenced_size'])}")
+            print(f"  unique: {fmt(g['unique_size'])}")
+            print(f"  shared: {fmt(g['shared_size'])}")
+            for other, size in g["shared_with"].items():
+                print(f"  shared with {other}: {fmt(size)}")
+        segments = space["segments"]
+        if segments["reclaimable_size"] is not None:
+            print()
+            print(
+                f"Reclaimable by compaction: {fmt(segments['reclaimable_size'])} "
+                f"in {segments['sparse_segments']} segments"
+            )
+
+    def build_parser_rspace(self, subparsers, common_parser, mid_common_parser):
+        from ._common import process_epilog, define_archive_filters_group
+
+        rspace_epilog = process_epilog(
+            """
+        This command reports what is using the space in the repository:
+
+        - the stored size of the objects by type (file data, item metadata, archive metadata)
+        - the original and stored size by compression algorithm and the compression ratio
+        - the unique size of each archive, i.e. the space that would be freed if only
+          this archive was deleted (use the archive filter options to select archives)
+        - for groups of archives given by ``--group PATTERN`` (use it multiple times), the
+          size unique to the group and the size shared with the other groups
+        - the size of unreferenced objects and the space a compaction could free in sparse
+          segments.
+
+        Stored sizes are the sizes of the compressed (and maybe obfuscated) data, the
+        overhead of encryption and of the repository is not included.
+
+        This needs to scan all objects of the repository and to read the metadata of all
+        archives (of the archives in groups and of the reported ones), so it may take a while.
+        """
+        )
+        subparser = subparsers.add_parser(
+            "rspace",
+            parents=[common_parser],
+            add_help=False,
+            description=self.do_rspace.__doc__,
+            epilog=rspace_epilog,
+            formatter_class=argparse.RawDescriptionHelpFormatter,
+            help="show repository space usage",
+        )
+        subparser.set_defaults(func=self.do_rspace)
+        subparser.add_argument("--json", action="store_true", help="format output as JSON")
+        subparser.add_argument(
+            "--group",
+            metavar="PATTERN",
+            dest="groups",
+            action="append",
+            help="report the space shared between groups of archives, each given by a PATTERN "
+            'like for --match-archives (use multiple times), see "borg help match-archives"',
+        )
+        define_archive_filters_group(subparser)
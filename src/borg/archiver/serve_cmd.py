@@ -2,7 +2,7 @@
 
 from ._common import Highlander
 from ..constants import *  # NOQA
-from ..helpers import parse_storage_quota
+from ..helpers import parse_storage_quota, parse_listen_address, parse_serve_hook
 from ..remote import RepositoryServer
 
 from ..logger import create_logger
@@ -19,6 +19,17 @@ def do_serve(self, args):
             append_only=args.append_only,
             storage_quota=args.storage_quota,
             use_socket=args.use_socket,
+            auto_compact=None if args.auto_compact is None else args.auto_compact / 100,
+            audit_log=args.audit_log,
+            policy=args.policy,
+            client_id=args.client_id,
+            http=args.http,
+            http_tokens=args.http_tokens,
+            tls_cert=args.tls_cert,
+            tls_key=args.tls_key,
+            tls_client_ca=args.tls_client_ca,
+            hooks=args.hooks,
+            allow_mirror=args.allow_mirror,
         ).serve()
 
     def build_parser_serve(self, subparsers, common_parser, mid_common_parser):
@@ -37,6 +48,119 @@ def build_parser_serve(self, subparsers, common_parser, mid_common_parser):
         - Getting started by some other means (not by the borg client) as a long-running socket
           server to be used for borg clients using a socket://... repository (see the `--socket`
           option if you do not want to use the default path for the socket and pid file).
+
+        - Getting started as a long-running HTTP server (``--http [HOST:]PORT``) to be used for
+          borg clients using a http://... or https://... repository, e.g. behind a reverse proxy
+          or load balancer where ssh is not possible.
+
+        With ``--auto-compact PERCENT``, borg serve compacts the repositories a client committed
+        to after the client disconnected, if there are segments with more than PERCENT freeable
+        space (see ``borg compact --threshold``). This is done by a detached background process
+        doing online compaction (see ``borg compact --online``), so neither the client nor other
+        borg commands using the repository need to wait for it.
+
+        With ``--audit-log FILE``, borg serve appends JSON lines to FILE for every client session
+        (i.e. every ssh connection or socket connection). Every entry has an ``event``, a ``time``
+        and a ``session`` value shared by all entries of the session. The entries are written as
+        things happen, so the sessions of a killed borg serve are still logged:
+
+        - ``start``: the client identity: the ssh environment variables (``SSH_CONNECTION``,
+          ``SSH_CLIENT``, ``SSH_USER_AUTH`` if ``ExposeAuthInfo`` is enabled in sshd, ``USER``,
+          ``LOGNAME``) or, for socket connections, the pid, uid and gid of the connected process,
+        - ``open``: a repository path the client opened,
+        - ``commit``: a commit to a repository,
+        - ``denied``: a denied operation (e.g. a path not allowed by ``--restrict-to-path``),
+        - ``end``: the client's borg version, per RPC method the number of calls, objects (e.g.
+          chunks put, fetched or deleted), bytes and errors and whether the connection dropped
+          while a repository was open.
+
+        To tell different clients using the same ssh account apart, use a different FILE per
+        key in ``authorized_keys``, e.g. ``command="borg serve --audit-log /var/log/borg/alice.log"``.
+
+        With ``--policy FILE``, borg serve checks every client against an access control policy.
+        FILE is an INI file with a section per client identity, values in the ``[DEFAULT]``
+        section apply to all clients::
+
+            [alice]
+            repositories = /srv/borg/alice
+                /srv/borg/shared/*
+            role = append-only
+            storage_quota = 100G
+            ssh_keys = SHA256:bcEbJ1z8w5RpzFrdnkU5Lb6F0vdZ1xV6Lt5pdGZ9B1s
+
+        - ``repositories``: shell-style patterns (one per line), the client may only open
+          repositories whose resolved path matches one of them.
+        - ``role``: ``read-only`` (list, extract, check, ...; this is the default),
+          ``append-only`` (like ``--append-only``, but only for this client) or ``full``.
+          ``check --repair`` needs the ``full`` role. Read-only clients may not lock the
+          repository (a lock would block the writers), so they need to use ``--bypass-lock``.
+        - ``storage_quota``: overrides ``--storage-quota`` for this client.
+        - ``ssh_keys``: the fingerprints (one per line, as shown by ``ssh-keygen -l -f KEY.pub``)
+          of the keys the client uses for ssh connections.
+
+        For ssh connections, the client identity is the one having the key the client authenticated
+        with in ``ssh_keys``. sshd tells it to borg serve if ``ExposeAuthInfo yes`` is set in
+        ``sshd_config`` (clients with an unknown key are denied). Alternatively, ``--client-id``
+        gives the identity (and overrides the key), use a different one per key in
+        ``authorized_keys``, e.g. ``command="borg serve --policy /etc/borg/policy.ini --client-id alice"``.
+        All ssh clients share the user borg serve runs as, so for them, ``--policy`` needs one of both.
+        For socket connections, the client identity is the user name of the connected process and
+        for ``--http`` connections the authenticated client.
+        Clients without a section are denied. Operations not allowed by the policy fail with
+        a ``PolicyDenied`` error (rc 88) and are recorded in the audit log.
+        The socket server re-reads FILE for every connection, so it does not need to be restarted
+        after changing the policy.
+
+        With ``--http [HOST:]PORT``, borg serve listens on PORT (on HOST, default: localhost) and
+        serves one client at a time, like for ``--socket`` (the handshakes of other connections run
+        meanwhile, a handshake must complete within 30 seconds). A client connects with a HTTP/1.1
+        request to upgrade the connection to the borg RPC protocol (``Upgrade: borg-rpc``, like
+        WebSockets do), so a reverse proxy needs to pass the ``Upgrade`` and ``Connection`` headers
+        (HTTP/2 is not supported). Clients must authenticate:
+
+        - with a bearer token: ``--http-tokens FILE`` has a line ``ID TOKEN`` per client, the client
+          sends its token from the ``BORG_HTTP_TOKEN`` environment variable.
+        - with a client certificate (mutual TLS): ``--tls-client-ca FILE`` has the CA certificate(s)
+          client certificates must be signed by, the client uses ``BORG_HTTP_CLIENT_CERT`` (and
+          ``BORG_HTTP_CLIENT_KEY``). The certificate's common name is the client identity.
+
+        If both are given, clients need a token and a certificate of the same identity.
+        The client identity is used for ``--policy`` and recorded in the audit log.
+        ``--tls-cert FILE`` (and ``--tls-key FILE``) enable TLS (https://). Without TLS, the tokens
+        and the repository data are sent in plain text, so use plain HTTP only on localhost or
+        behind a reverse proxy terminating TLS (borg serve refuses to use tokens without TLS
+        if HOST is not a loopback address). Example::
+
+            borg serve --http 0.0.0.0:8443 --tls-cert server.pem --tls-key server.key \\
+                       --http-tokens /etc/borg/tokens --policy /etc/borg/policy.ini
+            BORG_HTTP_TOKEN=... borg -r https://backup.example.org:8443/srv/borg/alice list
+
+        With ``--hook EVENT=COMMAND``, borg serve runs COMMAND (via the shell) when EVENT happens,
+        e.g. to trigger a replication, to update metrics or to notify someone:
+
+        - ``commit``: after a client committed a transaction.
+        - ``rollback``: after a transaction with changes was rolled back (the commit failed, the
+          client rolled back, or it closed the repository or the connection dropped without
+          committing; a transaction kept for a resuming client is not rolled back).
+        - ``quota-exceeded``: when a client exceeded the storage quota (once per transaction).
+
+        ``--hook`` can be given multiple times, also for the same EVENT. borg serve does not wait
+        for the commands (they might take longer than the client is connected), they get no input
+        and their output is discarded. They get the details in the environment:
+
+        - ``BORG_HOOK_EVENT``, ``BORG_HOOK_REPOSITORY`` (the path), ``BORG_HOOK_CLIENT`` (the
+          client identity, see ``--policy``),
+        - ``BORG_HOOK_TRANSACTION_ID``: the id of the last committed transaction,
+        - ``BORG_HOOK_OBJECTS_WRITTEN``, ``BORG_HOOK_BYTES_WRITTEN``, ``BORG_HOOK_OBJECTS_DELETED``:
+          the changes of the transaction,
+        - ``BORG_HOOK_QUOTA_USED``, ``BORG_HOOK_QUOTA``: the storage quota use and the quota
+          (0: no quota) of the repository in bytes,
+        - ``BORG_HOOK_ERROR``: why the transaction was rolled back or the quota error.
+
+        Example::
+
+            borg serve --hook 'commit=logger -t borg "$BORG_HOOK_CLIENT committed to $BORG_HOOK_REPOSITORY"' \\
+                       --hook 'quota-exceeded=mail -s "borg quota exceeded" admin <<< "$BORG_HOOK_REPOSITORY"'
         """
         )
         subparser = subparsers.add_parser(
@@ -91,3 +215,95 @@ def build_parser_serve(self, subparsers, common_parser, mid_common_parser):
             "When a new repository is initialized, sets the storage quota on the new "
             "repository as well. Default: no quota.",
         )
+        subparser.add_argument(
+            "--allow-mirror",
+            dest="allow_mirror",
+            action="store_true",
+            help="allow clients to replace the repository by a mirror of another repository (``borg rmirror``). "
+            "This deletes segment files and the repository index, so it is not allowed by default.",
+        )
+        subparser.add_argument(
+            "--auto-compact",
+            metavar="PERCENT",
+            dest="auto_compact",
+            type=int,
+            default=None,
+            action=Highlander,
+            help="compact repositories clients committed to, if segments have more than PERCENT freeable space. "
+            "Default: do not compact automatically.",
+        )
+        subparser.add_argument(
+            "--audit-log",
+            metavar="FILE",
+            dest="audit_log",
+            default=None,
+            action=Highlander,
+            help="append entries (JSON lines) about every client session to FILE. Default: no audit log.",
+        )
+        subparser.add_argument(
+            "--policy",
+            metavar="FILE",
+            dest="policy",
+            default=None,
+            action=Highlander,
+            help="check clients against the access control policy in FILE. Default: no policy.",
+        )
+        subparser.add_argument(
+            "--client-id",
+            metavar="ID",
+            dest="client_id",
+            default=None,
+            action=Highlander,
+            help="identity of the client for ``--policy``. "
+            "Default: the client with the ssh key used to authenticate (see ``ssh_keys``), else the user name.",
+        )
+        subparser.add_argument(
+            "--http",
+            metavar="[HOST:]PORT",
+            dest="http",
+            type=parse_listen_address,
+            default=None,
+            action=Highlander,
+            help="listen for HTTP connections on PORT (on HOST, default: localhost).",
+        )
+        subparser.add_argument(
+            "--http-tokens",
+            metavar="FILE",
+            dest="http_tokens",
+            default=None,
+            action=Highlander,
+            help="authenticate HTTP clients with the bearer tokens in FILE (a line ``ID TOKEN`` per client).",
+        )
+        subparser.add_argument(
+            "--tls-cert",
+            metavar="FILE",
+            dest="tls_cert",
+            default=None,
+            action=Highlander,
+            help="use TLS for HTTP connections with the server certificate (chain) in FILE.",
+        )
+        subparser.add_argument(
+            "--tls-key",
+            metavar="FILE",
+            dest="tls_key",
+            default=None,
+            action=Highlander,
+            help="the private key for ``--tls-cert``. Default: the key is in the certificate FILE.",
+        )
+        subparser.add_argument(
+            "--tls-client-ca",
+            metavar="FILE",
+            dest="tls_client_ca",
+            default=None,
+            action=Highlander,
+            help="require client certificates signed by the CA certificate(s) in FILE (mutual TLS).",
+        )
+        subparser.add_argument(
+            "--hook",
+            metavar="EVENT=COMMAND",
+            dest="hooks",
+            type=parse_serve_hook,
+            action="append",
+            help="run COMMAND after EVENT (commit, rollback, quota-exceeded). "
+            "Can be specified multiple times.",
+        )
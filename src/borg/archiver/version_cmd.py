@@ -15,7 +15,7 @@ def do_version(self, args):
         from borg.version import parse_version, format_version
 
         client_version = parse_version(__version__)
-        if args.location.proto in ("ssh", "socket"):
+        if args.location.proto in ("ssh", "socket", "http", "https"):
             with RemoteRepository(args.location, lock=False, args=args) as repository:
                 server_version = repository.server_version
         else:
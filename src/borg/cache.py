@@ -1,7 +1,13 @@
 import configparser
+import hashlib
+import hmac
+import io
 import os
+import re
 import shutil
+import sqlite3
 import stat
+import time
 from collections import namedtuple
 from time import perf_counter
 
@@ -11,7 +17,8 @@
 
 files_cache_logger = create_logger("borg.debug.files_cache")
 
-from .constants import CACHE_README, FILES_CACHE_MODE_DISABLED, ROBJ_FILE_STREAM
+from .chunkindex import RepositoryChunkIndex, archives_digest
+from .constants import CACHE_README, FILES_CACHE_MODE_DISABLED, ROBJ_FILE_STREAM, DEFAULT_INDEX_MODE
 from .hashindex import ChunkIndex, ChunkIndexEntry, CacheSynchronizer
 from .helpers import Error
 from .helpers import get_cache_dir, get_security_dir
@@ -24,15 +31,16 @@
 from .helpers import set_ec, EXIT_WARNING
 from .helpers import safe_unlink
 from .helpers import msgpack
-from .helpers.msgpack import int_to_timestamp, timestamp_to_int
+from .helpers.msgpack import Timestamp, int_to_timestamp, timestamp_to_int
 from .item import ArchiveItem, ChunkListEntry
 from .crypto.key import PlaintextKey
-from .crypto.file_integrity import IntegrityCheckedFile, DetachedIntegrityCheckedFile, FileIntegrityError
+from .crypto.file_integrity import IntegrityCheckedFile, DetachedIntegrityCheckedFile, EncryptedFile
+from .crypto.file_integrity import FileIntegrityError
 from .locking import Lock
 from .manifest import Manifest
 from .platform import SaveFile
 from .remote import cache_if_remote
-from .repository import LIST_SCAN_LIMIT
+from .repository import Repository, LIST_SCAN_LIMIT
 
 # note: cmtime might be either a ctime or a mtime timestamp, chunks is a list of ChunkListEntry
 FileCacheEntry = namedtuple("FileCacheEntry", "age inode size cmtime chunks")
@@ -65,6 +73,7 @@ def __init__(self, repository):
         self.key_type_file = os.path.join(self.dir, "key-type")
         self.location_file = os.path.join(self.dir, "location")
         self.manifest_ts_file = os.path.join(self.dir, "manifest-timestamp")
+        self.cache_encryption_file = os.path.join(self.dir, "cache-encryption")
 
     @staticmethod
     def destroy(repository, path=None):
@@ -99,6 +108,30 @@ def save(self, manifest, key):
         with SaveFile(self.manifest_ts_file) as fd:
             fd.write(manifest.timestamp)
 
+    def cache_encryption_mac(self, encryption_key):
+        return hmac.new(encryption_key, b"borg-cache-encryption" + self.repository.id, hashlib.sha256).hexdigest()
+
+    def cache_encrypted(self, encryption_key):
+        """Return whether the cache was encrypted with *encryption_key* (see CacheConfig.migrate_encryption)."""
+        try:
+            with open(self.cache_encryption_file) as fd:
+                mac = fd.read()
+        except FileNotFoundError:
+            return False
+        except OSError as exc:
+            # do not accept unencrypted cache files just because the file can not be read.
+            logger.warning("Could not read cache encryption file: %s", exc)
+            return True
+        if not hmac.compare_digest(mac, self.cache_encryption_mac(encryption_key)):
+            logger.warning("The cache encryption file %s is not valid for this key.", self.cache_encryption_file)
+            return False
+        return True
+
+    def save_cache_encrypted(self, encryption_key):
+        logger.debug("security: remembering that the cache is encrypted")
+        with SaveFile(self.cache_encryption_file) as fd:
+            fd.write(self.cache_encryption_mac(encryption_key))
+
     def assert_location_matches(self):
         # Warn user before sending data to a relocated repository
         try:
@@ -207,13 +240,20 @@ def cache_dir(repository, path=None):
 
 
 class CacheConfig:
-    def __init__(self, repository, path=None, lock_wait=None):
+    def __init__(self, repository, path=None, lock_wait=None, encryption_key=None):
         self.repository = repository
         self.path = cache_dir(repository, path)
         logger.debug("Using %s as cache", self.path)
         self.config_path = os.path.join(self.path, "config")
         self.lock = None
         self.lock_wait = lock_wait
+        self.encryption_key = encryption_key
+        # unencrypted cache files are only accepted until they were encrypted once, see migrate_encryption.
+        self.allow_plaintext = encryption_key is not None and not SecurityManager(repository).cache_encrypted(
+            encryption_key
+        )
+        # whether the config could not be read (or was not encrypted) and a new one was used instead
+        self.discarded = False
 
     def __enter__(self):
         self.open()
@@ -225,8 +265,7 @@ def __exit__(self, exc_type, exc_val, exc_tb):
     def exists(self):
         return os.path.exists(self.config_path)
 
-    def create(self):
-        assert not self.exists()
+    def new_config(self):
         config = configparser.ConfigParser(interpolation=None)
         config.add_section("cache")
         config.set("cache", "version", "1")
@@ -234,8 +273,12 @@ def create(self):
         config.set("cache", "manifest", "")
         config.add_section("integrity")
         config.set("integrity", "manifest", "")
-        with SaveFile(self.config_path) as fd:
-            config.write(fd)
+        return config
+
+    def create(self):
+        assert not self.exists()
+        self._config = self.new_config()
+        self.save()
 
     def open(self):
         self.lock = Lock(os.path.join(self.path, "lock"), exclusive=True, timeout=self.lock_wait).acquire()
@@ -243,8 +286,20 @@ def open(self):
 
     def load(self):
         self._config = configparser.ConfigParser(interpolation=None)
-        with open(self.config_path) as fd:
-            self._config.read_file(fd)
+        try:
+            with IntegrityCheckedFile(
+                path=self.config_path,
+                write=False,
+                encryption_key=self.encryption_key,
+                allow_plaintext=self.allow_plaintext,
+            ) as fd:
+                data = fd.read()
+        except FileIntegrityError as fie:
+            logger.warning("Discarding the cache config, it is corrupted or not encrypted. [%s]", fie)
+            self.discarded = True
+            self._config = self.new_config()
+        else:
+            self._config.read_string(data.decode(), source=self.config_path)
         self._check_upgrade(self.config_path)
         self.id = self._config.get("cache", "repository")
         self.manifest_id = hex_to_bin(self._config.get("cache", "manifest"))
@@ -252,6 +307,11 @@ def load(self):
         self.mandatory_features = set(
             parse_stringified_list(self._config.get("cache", "mandatory_features", fallback=""))
         )
+        self.index_mode = self._config.get("cache", "index_mode", fallback=DEFAULT_INDEX_MODE)
+        # position (epoch, seq) of the repository chunk index the chunks index corresponds to, see chunkindex
+        self.chunk_index_position = self.parse_chunk_index_position(
+            self._config.get("cache", "chunk_index", fallback="")
+        )
         try:
             self.integrity = dict(self._config.items("integrity"))
             if self._config.get("cache", "manifest") != self.integrity.pop("manifest"):
@@ -269,22 +329,97 @@ def load(self):
 
     def save(self, manifest=None):
         if manifest:
-            self._config.set("cache", "manifest", manifest.id_str)
+            # if other clients wrote to the repository at the same time, the chunks index does not know about
+            # the chunks of their archives, so we must not claim to be in sync with the (merged) manifest.
+            manifest_id = "" if manifest.foreign_changes else manifest.id_str
+            self._config.set("cache", "manifest", manifest_id)
+            position = manifest.chunk_index.position if manifest.chunk_index is not None else None
+            self._config.set("cache", "chunk_index", self.format_chunk_index_position(position))
             self._config.set("cache", "ignored_features", ",".join(self.ignored_features))
             self._config.set("cache", "mandatory_features", ",".join(self.mandatory_features))
             if not self._config.has_section("integrity"):
                 self._config.add_section("integrity")
             for file, integrity_data in self.integrity.items():
                 self._config.set("integrity", file, integrity_data)
-            self._config.set("integrity", "manifest", manifest.id_str)
-        with SaveFile(self.config_path) as fd:
-            self._config.write(fd)
+            self._config.set("integrity", "manifest", manifest_id)
+        text = io.StringIO()
+        self._config.write(text)
+        data = text.getvalue().encode()
+        if self.encryption_key is not None:
+            data = EncryptedFile.encrypt(self.encryption_key, data, self.config_path)
+        with SaveFile(self.config_path, binary=True) as fd:
+            fd.write(data)
+
+    def migrate_encryption(self):
+        """
+        Encrypt the cache files written before BORG_CACHE_ENCRYPTION was enabled (once).
+
+        Afterwards, this is remembered in the security dir (authenticated by the key, see
+        SecurityManager.cache_encrypted) and unencrypted cache files are rejected, as anybody could have written them.
+        """
+        logger.debug("Encrypting the cache files written before BORG_CACHE_ENCRYPTION was enabled.")
+
+        def copy_chunk_index(src, dst):
+            # the chunks index hashes its parts separately (see IntegrityCheckedFile.hash_part), so it can not be
+            # copied byte by byte.
+            ChunkIndex.read(src, permit_compact=src.path.endswith(".compact")).write(dst)
+
+        for name, integrity_data in list(self.integrity.items()):
+            path = os.path.join(self.path, name)
+            if os.path.exists(path):
+                copy = copy_chunk_index if name == "chunks" else shutil.copyfileobj  # files caches
+                fd = self._encrypt_file(IntegrityCheckedFile, path, copy, integrity_data=integrity_data)
+                if fd is not None:
+                    self.integrity[name] = fd.integrity_data
+                    self._config.set("integrity", name, fd.integrity_data)
+        archive_path = os.path.join(self.path, "chunks.archive.d")
+        if os.path.isdir(archive_path):
+            for fn in os.listdir(archive_path):
+                if len(fn) == 64 or len(fn) == 72 and fn.endswith(".compact"):
+                    self._encrypt_file(DetachedIntegrityCheckedFile, os.path.join(archive_path, fn), copy_chunk_index)
+        self.allow_plaintext = False
+        self.save()
+        SecurityManager(self.repository).save_cache_encrypted(self.encryption_key)
+
+    def _encrypt_file(self, file_cls, path, copy, **kwargs):
+        """
+        Encrypt the unencrypted cache file *path* with *copy(src, dst)*, return the file object it was written with.
+
+        If the file can not be read, it is left alone (and gets rejected and rebuilt when the cache uses it).
+        """
+        with open(path, "rb") as fd:
+            if EncryptedFile.is_encrypted(fd):
+                return None
+        tmp_path = path + ".tmp"
+        try:
+            with file_cls(
+                path=path, write=False, encryption_key=self.encryption_key, allow_plaintext=True, **kwargs
+            ) as src:
+                with file_cls(
+                    path=tmp_path, write=True, filename=os.path.basename(path), encryption_key=self.encryption_key
+                ) as dst:
+                    copy(src, dst)
+        except (OSError, ValueError, FileIntegrityError) as exc:
+            logger.warning("Could not encrypt the cache file %s: %s", path, exc)
+            safe_unlink(tmp_path)
+            return None
+        os.replace(tmp_path, path)
+        return dst
 
     def close(self):
         if self.lock is not None:
             self.lock.release()
             self.lock = None
 
+    @staticmethod
+    def parse_chunk_index_position(value):
+        epoch, _, seq = value.partition(":")
+        return (hex_to_bin(epoch), int(seq)) if seq else None
+
+    @staticmethod
+    def format_chunk_index_position(position):
+        return "%s:%d" % (bin_to_hex(position[0]), position[1]) if position else ""
+
     def _check_upgrade(self, config_path):
         try:
             cache_version = self._config.getint("cache", "version")
@@ -303,6 +438,19 @@ def get_cache_impl():
     return os.environ.get("BORG_CACHE_IMPL", "adhocwithfiles")
 
 
+def cache_encryption_key(key):
+    """
+    Return the key to encrypt the cache files with (derived from the repository *key*),
+    None if BORG_CACHE_ENCRYPTION is not enabled or the repository is not encrypted.
+    """
+    if os.environ.get("BORG_CACHE_ENCRYPTION", "no").lower() not in ["yes", "1", "true"]:
+        return None
+    encryption_key = key.cache_encryption_key()
+    if encryption_key is None:
+        logger.warning("BORG_CACHE_ENCRYPTION: the repository is not encrypted, not encrypting the cache either.")
+    return encryption_key
+
+
 class Cache:
     """Client Side cache"""
 
@@ -331,6 +479,11 @@ class RepositoryReplay(Error):
 
         exit_mcode = 64
 
+    class EncryptionNotSupported(Error):
+        """The cache can not be encrypted (BORG_CACHE_ENCRYPTION) with {}."""
+
+        exit_mcode = 65
+
     @staticmethod
     def break_lock(repository, path=None):
         path = cache_dir(repository, path)
@@ -358,6 +511,7 @@ def __new__(
         no_cache_sync_forced=False,
         prefer_adhoc_cache=False,
         cache_mode=FILES_CACHE_MODE_DISABLED,
+        files_cache_scope=None,
         iec=False,
     ):
         def local():
@@ -370,6 +524,20 @@ def local():
                 iec=iec,
                 lock_wait=lock_wait,
                 cache_mode=cache_mode,
+                files_cache_scope=files_cache_scope,
+            )
+
+        def sqlite():
+            return SQLiteCache(
+                manifest=manifest,
+                path=path,
+                sync=sync,
+                warn_if_unencrypted=warn_if_unencrypted,
+                progress=progress,
+                iec=iec,
+                lock_wait=lock_wait,
+                cache_mode=cache_mode,
+                files_cache_scope=files_cache_scope,
             )
 
         def adhocwithfiles():
@@ -381,6 +549,7 @@ def adhocwithfiles():
                 iec=iec,
                 lock_wait=lock_wait,
                 cache_mode=cache_mode,
+                files_cache_scope=files_cache_scope,
             )
 
         def adhoc():
@@ -388,7 +557,7 @@ def adhoc():
 
         impl = get_cache_impl()
         if impl != "cli":
-            methods = dict(local=local, adhocwithfiles=adhocwithfiles, adhoc=adhoc)
+            methods = dict(local=local, sqlite=sqlite, adhocwithfiles=adhocwithfiles, adhoc=adhoc)
             try:
                 method = methods[impl]
             except KeyError:
@@ -405,7 +574,7 @@ def adhoc():
         # it by needlessly using the AdHocCache or the AdHocWithFilesCache.
         # Check if the local cache exists and is in sync.
 
-        cache_config = CacheConfig(repository, path, lock_wait)
+        cache_config = CacheConfig(repository, path, lock_wait, cache_encryption_key(manifest.key))
         if cache_config.exists():
             with cache_config:
                 cache_in_sync = cache_config.manifest_id == manifest.id
@@ -463,6 +632,48 @@ def format_tuple(self):
         return self.Summary(**stats)
 
 
+def files_cache_scope(archive_name, paths):
+    """
+    return the files cache scope for creating archive *archive_name* from *paths*.
+
+    Archives of the same series (same name, except for numbers and the separators around them, e.g. from
+    a {now} placeholder) created from the same backup roots share a files cache.
+    """
+    series = re.sub(r"[-_.:+]*[0-9]+[-_.:+TZ]*", "", archive_name)
+    roots = sorted(os.path.abspath(path) for path in paths)
+    return hashlib.sha256("\0".join([series] + roots).encode("utf-8", "surrogateescape")).hexdigest()[:16]
+
+
+def fetch_archive_chunk_index(key, decrypted_repository, archive_id, chunk_idx):
+    """
+    Add the chunks referenced by the archive *archive_id* to *chunk_idx*.
+
+    Returns the size and number of the item metadata chunks processed.
+    """
+    processed_bytes = processed_chunks = 0
+    csize, data = decrypted_repository.get(archive_id)
+    chunk_idx.add(archive_id, 1, len(data))
+    archive = key.unpack_archive(data)
+    archive = ArchiveItem(internal_dict=archive)
+    if archive.version not in (1, 2):  # legacy
+        raise Exception("Unknown archive metadata version")
+    if archive.version == 1:
+        items = archive.items
+    elif archive.version == 2:
+        items = []
+        for chunk_id, (csize, data) in zip(archive.item_ptrs, decrypted_repository.get_many(archive.item_ptrs)):
+            chunk_idx.add(chunk_id, 1, len(data))
+            ids = msgpack.unpackb(data)
+            items.extend(ids)
+    sync = CacheSynchronizer(chunk_idx)
+    for item_id, (csize, data) in zip(items, decrypted_repository.get_many(items)):
+        chunk_idx.add(item_id, 1, len(data))
+        processed_bytes += len(data)
+        processed_chunks += 1
+        sync.feed(data)
+    return processed_bytes, processed_chunks
+
+
 class FilesCacheMixin:
     """
     Massively accelerate processing of unchanged files by caching their chunks list.
@@ -470,15 +681,21 @@ class FilesCacheMixin:
     """
 
     FILES_CACHE_NAME = "files"
+    FILES_CACHE_SCOPED_PREFIX = FILES_CACHE_NAME + ".scope-"
 
-    def __init__(self, cache_mode):
+    def __init__(self, cache_mode, scope=None):
         self.cache_mode = cache_mode
+        self.files_cache_scope = scope
         self.files = None
         self._newest_cmtime = None
 
     def files_cache_name(self):
         suffix = os.environ.get("BORG_FILES_CACHE_SUFFIX", "")
-        return self.FILES_CACHE_NAME + "." + suffix if suffix else self.FILES_CACHE_NAME
+        if suffix:
+            return self.FILES_CACHE_NAME + "." + suffix
+        if self.files_cache_scope:
+            return self.FILES_CACHE_SCOPED_PREFIX + self.files_cache_scope
+        return self.FILES_CACHE_NAME
 
     def discover_files_cache_name(self, path):
         return [
@@ -486,7 +703,9 @@ def discover_files_cache_name(self, path):
         ][0]
 
     def _create_empty_files_cache(self, path):
-        with IntegrityCheckedFile(path=os.path.join(path, self.files_cache_name()), write=True) as fd:
+        with IntegrityCheckedFile(
+            path=os.path.join(path, self.files_cache_name()), write=True, encryption_key=self.encryption_key
+        ) as fd:
             pass  # empty file
         return fd.integrity_data
 
@@ -498,11 +717,18 @@ def _read_files_cache(self):
         logger.debug("Reading files cache ...")
         files_cache_logger.debug("FILES-CACHE-LOAD: starting...")
         msg = None
+        name = self.files_cache_name()
+        if self.files_cache_scope and not os.path.exists(os.path.join(self.path, name)):
+            # start a new scoped files cache from the unscoped one (e.g. after upgrading borg), if there is one.
+            if os.path.exists(os.path.join(self.path, self.FILES_CACHE_NAME)):
+                name = self.FILES_CACHE_NAME
         try:
             with IntegrityCheckedFile(
-                path=os.path.join(self.path, self.files_cache_name()),
+                path=os.path.join(self.path, name),
                 write=False,
-                integrity_data=self.cache_config.integrity.get(self.files_cache_name()),
+                integrity_data=self.cache_config.integrity.get(name),
+                encryption_key=self.encryption_key,
+                allow_plaintext=self.cache_config.allow_plaintext,
             ) as fd:
                 u = msgpack.Unpacker(use_list=True)
                 while True:
@@ -534,7 +760,9 @@ def _write_files_cache(self):
             self._newest_cmtime = 2**63 - 1  # nanoseconds, good until y2262
         ttl = int(os.environ.get("BORG_FILES_CACHE_TTL", 20))
         files_cache_logger.debug("FILES-CACHE-SAVE: starting...")
-        with IntegrityCheckedFile(path=os.path.join(self.path, self.files_cache_name()), write=True) as fd:
+        with IntegrityCheckedFile(
+            path=os.path.join(self.path, self.files_cache_name()), write=True, encryption_key=self.encryption_key
+        ) as fd:
             entry_count = 0
             for path_hash, item in self.files.items():
                 # Only keep files seen in this backup that are older than newest cmtime seen in this backup -
@@ -554,8 +782,31 @@ def _write_files_cache(self):
             "FILES-CACHE-KILL: removed all current entries with newest cmtime %d", self._newest_cmtime
         )
         files_cache_logger.debug("FILES-CACHE-SAVE: finished, %d remaining entries saved.", entry_count)
+        if self.files_cache_scope:
+            self._remove_stale_files_caches()
         return fd.integrity_data
 
+    def _remove_stale_files_caches(self):
+        """remove scoped (and the unscoped) files caches which were not used for BORG_FILES_CACHE_MAX_AGE days"""
+        max_age = float(os.environ.get("BORG_FILES_CACHE_MAX_AGE", 60)) * 24 * 3600
+        now = time.time()
+        for name in os.listdir(self.path):
+            if name == self.files_cache_name():
+                continue
+            if name != self.FILES_CACHE_NAME and not name.startswith(self.FILES_CACHE_SCOPED_PREFIX):
+                continue  # other files in the cache directory or files caches named by BORG_FILES_CACHE_SUFFIX
+            path = os.path.join(self.path, name)
+            try:
+                if now - os.stat(path).st_mtime <= max_age:
+                    continue
+                os.unlink(path)
+            except OSError:
+                continue
+            files_cache_logger.debug("FILES-CACHE-KILL: removed stale files cache %s", name)
+            self.cache_config.integrity.pop(name, None)
+            if self.cache_config._config.has_section("integrity"):
+                self.cache_config._config.remove_option("integrity", name)
+
     def file_known_and_unchanged(self, hashed_path, path_hash, st):
         """
         Check if we know the file that has this path_hash (know == it is in our files cache) and
@@ -649,6 +900,7 @@ def chunk_incref(self, id, size, stats):
         if not self._txn_active:
             self.begin_txn()
         count, _size = self.chunks.incref(id)
+        self.chunk_index.record(id, 1, size)
         stats.update(size, False)
         return ChunkListEntry(id, size)
 
@@ -657,6 +909,7 @@ def chunk_decref(self, id, size, stats, wait=True):
         if not self._txn_active:
             self.begin_txn()
         count, _size = self.chunks.decref(id)
+        self.chunk_index.record(id, -1, size)
         if count == 0:
             del self.chunks[id]
             self.repository.delete(id, wait=wait)
@@ -714,6 +967,7 @@ def add_chunk(
         )
         self.repository.put(id, cdata, wait=wait)
         self.chunks.add(id, 1, size)
+        self.chunk_index.record(id, 1, size)
         stats.update(size, not refcount)
         return ChunkListEntry(id, size)
 
@@ -773,6 +1027,7 @@ def __init__(
         progress=False,
         lock_wait=None,
         cache_mode=FILES_CACHE_MODE_DISABLED,
+        files_cache_scope=None,
         iec=False,
     ):
         """
@@ -780,21 +1035,24 @@ def __init__(
         :param lock_wait: timeout for lock acquisition (int [s] or None [wait forever])
         :param sync: do :meth:`.sync`
         :param cache_mode: what shall be compared in the file stat infos vs. cached stat infos comparison
+        :param files_cache_scope: use a separate files cache for this scope, see files_cache_scope()
         """
         CacheStatsMixin.__init__(self, iec=iec)
-        FilesCacheMixin.__init__(self, cache_mode)
+        FilesCacheMixin.__init__(self, cache_mode, files_cache_scope)
         assert isinstance(manifest, Manifest)
         self.manifest = manifest
         self.repository = manifest.repository
         self.key = manifest.key
+        self.encryption_key = cache_encryption_key(self.key)
         self.repo_objs = manifest.repo_objs
         self.progress = progress
         self._txn_active = False
         self.do_cache = os.environ.get("BORG_USE_CHUNKS_ARCHIVE", "yes").lower() in ["yes", "1", "true"]
+        manifest.chunk_index = self.chunk_index = RepositoryChunkIndex(manifest, cache=self)
 
         self.path = cache_dir(self.repository, path)
         self.security_manager = SecurityManager(self.repository)
-        self.cache_config = CacheConfig(self.repository, self.path, lock_wait)
+        self.cache_config = CacheConfig(self.repository, self.path, lock_wait, self.encryption_key)
 
         # Warn user before sending data to a never seen before unencrypted repository
         if not os.path.exists(self.path):
@@ -808,6 +1066,9 @@ def __init__(
             self.open()
 
         try:
+            self.check_encryption_support()
+            if self.cache_config.allow_plaintext:
+                self.cache_config.migrate_encryption()
             self.security_manager.assert_secure(manifest, self.key)
 
             if not self.check_cache_compatibility():
@@ -815,9 +1076,18 @@ def __init__(
 
             self.update_compatibility()
 
-            if sync and self.manifest.id != self.cache_config.manifest_id:
-                self.sync()
+            in_sync = self.manifest.id == self.cache_config.manifest_id
+            if sync and not in_sync:
+                if not self.update_from_chunk_index():
+                    self.sync()
+                    head = self.chunk_index.head()
+                    self.chunk_index.position = head and self.chunk_index.position_of(head)
                 self.commit()
+                in_sync = True
+            elif in_sync:
+                self.chunk_index.position = self.cache_config.chunk_index_position
+            if in_sync:
+                self.chunk_index.digest = archives_digest(manifest, manifest.base_archives)
         except:  # noqa
             self.close()
             raise
@@ -840,14 +1110,41 @@ def create(self):
 
     def _do_open(self):
         self.cache_config.load()
-        with IntegrityCheckedFile(
-            path=os.path.join(self.path, "chunks"),
-            write=False,
-            integrity_data=self.cache_config.integrity.get("chunks"),
-        ) as fd:
-            self.chunks = ChunkIndex.read(fd)
+        if self.cache_config.index_mode == "mapped":
+            # the chunks index is updated in place, so there is no checksum of it.
+            self._forget_chunks_integrity()
+            self.chunks = ChunkIndex.map(os.path.join(self.path, "chunks"))
+        else:
+            with IntegrityCheckedFile(
+                path=os.path.join(self.path, "chunks"),
+                write=False,
+                integrity_data=self.cache_config.integrity.get("chunks"),
+                encryption_key=self.encryption_key,
+                allow_plaintext=self.cache_config.allow_plaintext,
+            ) as fd:
+                self.chunks = ChunkIndex.read(fd)
         self._read_files_cache()
 
+    def _forget_chunks_integrity(self):
+        self.cache_config.integrity.pop("chunks", None)
+        if self.cache_config._config.has_section("integrity"):
+            self.cache_config._config.remove_option("integrity", "chunks")
+
+    def _new_chunk_index(self, usable=None):
+        """return a new, empty chunks index, mapped from the chunks file if the cache uses index_mode = mapped"""
+        if self.cache_config.index_mode == "mapped":
+            return ChunkIndex.create_mapped(os.path.join(self.path, "chunks"), usable=usable)
+        return ChunkIndex(usable=usable)
+
+    def _clear_chunks(self):
+        """empty the chunks index before it gets rebuilt"""
+        self.chunks.clear()
+
+    def check_encryption_support(self):
+        """refuse to use a cache with unencrypted parts if BORG_CACHE_ENCRYPTION is enabled"""
+        if self.encryption_key is not None and self.cache_config.index_mode == "mapped":
+            raise Cache.EncryptionNotSupported("index_mode = mapped")
+
     def open(self):
         if not os.path.isdir(self.path):
             raise Exception("%s Does not look like a Borg cache" % self.path)
@@ -888,9 +1185,15 @@ def commit(self):
             integrity_data = self._write_files_cache()
             self.cache_config.integrity[self.files_cache_name()] = integrity_data
         pi.output("Saving chunks cache")
-        with IntegrityCheckedFile(path=os.path.join(self.path, "chunks"), write=True) as fd:
-            self.chunks.write(fd)
-        self.cache_config.integrity["chunks"] = fd.integrity_data
+        if self.chunks.mapped:
+            self.chunks.flush()
+            self._forget_chunks_integrity()
+        else:
+            with IntegrityCheckedFile(
+                path=os.path.join(self.path, "chunks"), write=True, encryption_key=self.encryption_key
+            ) as fd:
+                self.chunks.write(fd)
+            self.cache_config.integrity["chunks"] = fd.integrity_data
         pi.output("Saving cache config")
         self.cache_config.save(self.manifest)
         os.replace(os.path.join(self.path, "txn.active"), os.path.join(self.path, "txn.tmp"))
@@ -906,6 +1209,7 @@ def rollback(self):
         # Roll back active transaction
         txn_dir = os.path.join(self.path, "txn.active")
         if os.path.exists(txn_dir):
+            self.chunks = None  # a mapped chunks index must not see the file being overwritten
             shutil.copy(os.path.join(txn_dir, "config"), self.path)
             shutil.copy(os.path.join(txn_dir, "chunks"), self.path)
             shutil.copy(os.path.join(txn_dir, self.discover_files_cache_name(txn_dir)), self.path)
@@ -916,6 +1220,26 @@ def rollback(self):
         self._txn_active = False
         self._do_open()
 
+    def update_from_chunk_index(self):
+        """
+        Update the chunks cache from the repository chunk index, return False if it is stale.
+
+        Usually only the deltas since the cache was last in sync need to be fetched.
+        """
+        head = self.chunk_index.head()
+        if head is None:
+            logger.debug("Repository chunk index is stale, synchronizing from the archives.")
+            return False
+        self.begin_txn()
+        if self.chunk_index.update(self.chunks, self.cache_config.chunk_index_position, head):
+            logger.debug("Updated chunks cache from the repository chunk index deltas.")
+        else:
+            logger.info("Fetching the repository chunk index.")
+            self._clear_chunks()
+            self.chunks = self.chunk_index.load(head, self._new_chunk_index())
+        self.chunk_index.position = self.chunk_index.position_of(head)
+        return True
+
     def sync(self):
         """Re-synchronize chunks cache with repository.
 
@@ -972,26 +1296,9 @@ def cleanup_cached_archive(id, cleanup_compact=True):
         def fetch_and_build_idx(archive_id, decrypted_repository, chunk_idx):
             nonlocal processed_item_metadata_bytes
             nonlocal processed_item_metadata_chunks
-            csize, data = decrypted_repository.get(archive_id)
-            chunk_idx.add(archive_id, 1, len(data))
-            archive = self.key.unpack_archive(data)
-            archive = ArchiveItem(internal_dict=archive)
-            if archive.version not in (1, 2):  # legacy
-                raise Exception("Unknown archive metadata version")
-            if archive.version == 1:
-                items = archive.items
-            elif archive.version == 2:
-                items = []
-                for chunk_id, (csize, data) in zip(archive.item_ptrs, decrypted_repository.get_many(archive.item_ptrs)):
-                    chunk_idx.add(chunk_id, 1, len(data))
-                    ids = msgpack.unpackb(data)
-                    items.extend(ids)
-            sync = CacheSynchronizer(chunk_idx)
-            for item_id, (csize, data) in zip(items, decrypted_repository.get_many(items)):
-                chunk_idx.add(item_id, 1, len(data))
-                processed_item_metadata_bytes += len(data)
-                processed_item_metadata_chunks += 1
-                sync.feed(data)
+            nbytes, nchunks = fetch_archive_chunk_index(self.key, decrypted_repository, archive_id, chunk_idx)
+            processed_item_metadata_bytes += nbytes
+            processed_item_metadata_chunks += nchunks
             if self.do_cache:
                 write_archive_index(archive_id, chunk_idx)
 
@@ -1002,7 +1309,10 @@ def write_archive_index(archive_id, chunk_idx):
             fn_tmp = mkpath(archive_id, suffix=".tmp")
             try:
                 with DetachedIntegrityCheckedFile(
-                    path=fn_tmp, write=True, filename=bin_to_hex(archive_id) + ".compact"
+                    path=fn_tmp,
+                    write=True,
+                    filename=bin_to_hex(archive_id) + ".compact",
+                    encryption_key=self.encryption_key,
                 ) as fd:
                     chunk_idx.write(fd)
             except Exception:
@@ -1016,7 +1326,12 @@ def read_archive_index(archive_id, archive_name):
             try:
                 try:
                     # Attempt to load compact index first
-                    with DetachedIntegrityCheckedFile(path=archive_chunk_idx_path + ".compact", write=False) as fd:
+                    with DetachedIntegrityCheckedFile(
+                        path=archive_chunk_idx_path + ".compact",
+                        write=False,
+                        encryption_key=self.encryption_key,
+                        allow_plaintext=self.cache_config.allow_plaintext,
+                    ) as fd:
                         archive_chunk_idx = ChunkIndex.read(fd, permit_compact=True)
                     # In case a non-compact index exists, delete it.
                     cleanup_cached_archive(archive_id, cleanup_compact=False)
@@ -1024,7 +1339,12 @@ def read_archive_index(archive_id, archive_name):
                     return archive_chunk_idx
                 except FileNotFoundError:
                     # No compact index found, load non-compact index, and convert below.
-                    with DetachedIntegrityCheckedFile(path=archive_chunk_idx_path, write=False) as fd:
+                    with DetachedIntegrityCheckedFile(
+                        path=archive_chunk_idx_path,
+                        write=False,
+                        encryption_key=self.encryption_key,
+                        allow_plaintext=self.cache_config.allow_plaintext,
+                    ) as fd:
                         archive_chunk_idx = ChunkIndex.read(fd)
             except FileIntegrityError as fie:
                 logger.error("Cached archive chunk index of %s is corrupted: %s", archive_name, fie)
@@ -1062,13 +1382,13 @@ def create_master_idx(chunk_idx):
                 len(archive_ids - cached_ids),
             )
             # deallocates old hashindex, creates empty hashindex:
-            chunk_idx.clear()
+            self._clear_chunks()
             cleanup_outdated(cached_ids - archive_ids)
             # Explicitly set the usable initial hash table capacity to avoid performance issues
             # due to hash table "resonance".
             master_index_capacity = len(self.repository)
             if archive_ids:
-                chunk_idx = None if not self.do_cache else ChunkIndex(usable=master_index_capacity)
+                chunk_idx = None if not self.do_cache else self._new_chunk_index(usable=master_index_capacity)
                 pi = ProgressIndicatorPercent(
                     total=len(archive_ids),
                     step=0.1,
@@ -1092,7 +1412,7 @@ def create_master_idx(chunk_idx):
                         logger.debug("Merging into master chunks index.")
                         chunk_idx.merge(archive_chunk_idx)
                     else:
-                        chunk_idx = chunk_idx or ChunkIndex(usable=master_index_capacity)
+                        chunk_idx = chunk_idx or self._new_chunk_index(usable=master_index_capacity)
                         logger.info("Fetching archive index for %s.", archive_name)
                         fetch_and_build_idx(archive_id, decrypted_repository, chunk_idx)
                 pi.finish()
@@ -1135,10 +1455,16 @@ def wipe_cache(self):
         if os.path.isdir(archive_path):
             shutil.rmtree(os.path.join(self.path, "chunks.archive.d"))
             os.makedirs(os.path.join(self.path, "chunks.archive.d"))
-        self.chunks = ChunkIndex()
-        with IntegrityCheckedFile(path=os.path.join(self.path, "chunks"), write=True) as fd:
-            self.chunks.write(fd)
-        self.cache_config.integrity["chunks"] = fd.integrity_data
+        if self.cache_config.index_mode == "mapped":
+            self.chunks = self._new_chunk_index()
+            self._forget_chunks_integrity()
+        else:
+            self.chunks = ChunkIndex()
+            with IntegrityCheckedFile(
+                path=os.path.join(self.path, "chunks"), write=True, encryption_key=self.encryption_key
+            ) as fd:
+                self.chunks.write(fd)
+            self.cache_config.integrity["chunks"] = fd.integrity_data
         integrity_data = self._create_empty_files_cache(self.path)
         self.cache_config.integrity[self.files_cache_name()] = integrity_data
         self.cache_config.manifest_id = ""
@@ -1152,8 +1478,7 @@ def wipe_cache(self):
 
         self.cache_config.ignored_features = set()
         self.cache_config.mandatory_features = set()
-        with SaveFile(self.cache_config.config_path) as fd:
-            self.cache_config._config.write(fd)
+        self.cache_config.save()
 
     def update_compatibility(self):
         operation_to_features_map = self.manifest.get_all_mandatory_features()
@@ -1166,6 +1491,262 @@ def update_compatibility(self):
         self.cache_config.mandatory_features.update(repo_features & my_features)
 
 
+class SQLiteCache(LocalCache):
+    """
+    Persistent, local (client-side) cache, stored in a SQLite database.
+
+    Unlike LocalCache, this does not copy and rewrite the whole chunks index and files cache for every
+    transaction: commit() only writes the changed chunks index entries and the files cache entries of the
+    files seen by the backup, together with the cache state, in one database transaction.
+    """
+
+    DB_NAME = "cache.db"
+
+    SCHEMA = """
+        CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+        CREATE TABLE IF NOT EXISTS chunks (
+            id BLOB PRIMARY KEY, refcount INTEGER NOT NULL, size INTEGER NOT NULL
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS files_caches (
+            name TEXT PRIMARY KEY, generation INTEGER NOT NULL, used REAL NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS files (
+            name TEXT NOT NULL, path_hash BLOB NOT NULL, generation INTEGER NOT NULL, entry BLOB NOT NULL,
+            PRIMARY KEY (name, path_hash)
+        ) WITHOUT ROWID;
+    """
+
+    def __init__(self, *args, **kwargs):
+        self.db = None
+        # whether the chunks index was rebuilt in this transaction and must be written completely, otherwise
+        # the chunks index journals the changed keys.
+        self._rewrite_chunks = False
+        super().__init__(*args, **kwargs)
+
+    def create(self):
+        """Create a new empty cache at `self.path`"""
+        os.makedirs(self.path)
+        with open(os.path.join(self.path, "README"), "w") as fd:
+            fd.write(CACHE_README)
+        self.cache_config.create()
+        os.makedirs(os.path.join(self.path, "chunks.archive.d"))
+
+    def _connect(self):
+        # autocommit mode, we manage the transactions ourselves.
+        self.db = sqlite3.connect(os.path.join(self.path, self.DB_NAME), isolation_level=None)
+        self.db.execute("PRAGMA journal_mode = WAL")
+        self.db.executescript(self.SCHEMA)
+
+    @classmethod
+    def remove_database(cls, path):
+        """remove the database (and its WAL files) from the cache directory *path*"""
+        for suffix in "", "-wal", "-shm":
+            try:
+                os.unlink(os.path.join(path, cls.DB_NAME + suffix))
+            except FileNotFoundError:
+                pass
+
+    def open(self):
+        if not os.path.isdir(self.path):
+            raise Exception("%s Does not look like a Borg cache" % self.path)
+        self.cache_config.open()
+        try:
+            self._connect()
+            self.rollback()
+        except sqlite3.DatabaseError as exc:
+            logger.warning("The cache database is corrupted, discarding it. [%s]", exc)
+            if self.db is not None:
+                self.db.close()
+                self.db = None
+            self.remove_database(self.path)
+            self._connect()
+            self.rollback()
+
+    def close(self):
+        if self.db is not None:
+            self.db.close()
+            self.db = None
+        super().close()
+
+    def _do_open(self):
+        self.cache_config.load()
+        # the cache state is in the database, so it is always consistent with the chunks index and files cache.
+        meta = dict(self.db.execute("SELECT key, value FROM meta"))
+        self.cache_config.manifest_id = hex_to_bin(meta.get("manifest", ""))
+        self.cache_config.chunk_index_position = CacheConfig.parse_chunk_index_position(meta.get("chunk_index", ""))
+        (count,) = self.db.execute("SELECT count(*) FROM chunks").fetchone()
+        self.chunks = ChunkIndex(usable=count)
+        for id, refcount, size in self.db.execute("SELECT id, refcount, size FROM chunks"):
+            self.chunks[id] = ChunkIndexEntry(refcount, size)
+        self._rewrite_chunks = False
+        self._read_files_cache()
+
+    def _new_chunk_index(self, usable=None):
+        return ChunkIndex(usable=usable)
+
+    def check_encryption_support(self):
+        if self.encryption_key is not None:
+            raise Cache.EncryptionNotSupported("BORG_CACHE_IMPL=sqlite")
+
+    def _clear_chunks(self):
+        if self._txn_active and not self._rewrite_chunks:
+            self.chunks.stop_journal()
+        self._rewrite_chunks = True
+        self.chunks.clear()
+
+    def begin_txn(self):
+        if not self._rewrite_chunks:
+            self.chunks.start_journal()
+        self._txn_active = True
+
+    def commit(self):
+        """Commit transaction"""
+        if not self._txn_active:
+            return
+        self.security_manager.save(self.manifest, self.key)
+        pi = ProgressIndicatorMessage(msgid="cache.commit")
+        manifest_id = "" if self.manifest.foreign_changes else self.manifest.id_str
+        position = self.chunk_index.position
+        self.db.execute("BEGIN IMMEDIATE")
+        try:
+            if self.files is not None:
+                pi.output("Saving files cache")
+                self._write_files_cache()
+            pi.output("Saving chunks cache")
+            self._write_chunks()
+            meta = dict(manifest=manifest_id, chunk_index=CacheConfig.format_chunk_index_position(position))
+            self.db.executemany("INSERT OR REPLACE INTO meta VALUES (?, ?)", meta.items())
+        except BaseException:
+            self.db.execute("ROLLBACK")
+            raise
+        self.db.execute("COMMIT")
+        if not self._rewrite_chunks:
+            self.chunks.stop_journal()
+        self._rewrite_chunks = False
+        self.cache_config.manifest_id = hex_to_bin(manifest_id)
+        self.cache_config.chunk_index_position = position
+        pi.output("Saving cache config")
+        self._save_config()
+        self._txn_active = False
+        pi.finish()
+
+    def _save_config(self):
+        # manifest and chunk_index in the config file stay untouched, they belong to the files of LocalCache.
+        self.cache_config._config.set("cache", "ignored_features", ",".join(self.cache_config.ignored_features))
+        self.cache_config._config.set("cache", "mandatory_features", ",".join(self.cache_config.mandatory_features))
+        self.cache_config.save()
+
+    def _write_chunks(self):
+        if self._rewrite_chunks:
+            self.db.execute("DELETE FROM chunks")
+            self.db.executemany(
+                "INSERT INTO chunks VALUES (?, ?, ?)",
+                ((id, entry.refcount, entry.size) for id, entry in self.chunks.iteritems()),
+            )
+            return
+
+        def changed():
+            # the journal has a key for every change of it, writing the same row again does not hurt.
+            return ((id, self.chunks.get(id)) for id in self.chunks.journal_keys())
+
+        self.db.executemany(
+            "INSERT OR REPLACE INTO chunks VALUES (?, ?, ?)",
+            ((id, entry.refcount, entry.size) for id, entry in changed() if entry is not None),
+        )
+        self.db.executemany("DELETE FROM chunks WHERE id = ?", ((id,) for id, entry in changed() if entry is None))
+
+    def rollback(self):
+        """Roll back partial and aborted transactions"""
+        # nothing was written yet, just load the committed state again.
+        self._txn_active = False
+        self._do_open()
+
+    def _read_files_cache(self):
+        if "d" in self.cache_mode:  # d(isabled)
+            return
+
+        self.files = {}
+        logger.debug("Reading files cache ...")
+        files_cache_logger.debug("FILES-CACHE-LOAD: starting...")
+        self._files_cache_source = name = self.files_cache_name()
+        row = self.db.execute("SELECT generation FROM files_caches WHERE name = ?", (name,)).fetchone()
+        if row is None and self.files_cache_scope:
+            # start a new scoped files cache from the unscoped one, if there is one.
+            source = self.FILES_CACHE_NAME
+            row = self.db.execute("SELECT generation FROM files_caches WHERE name = ?", (source,)).fetchone()
+            if row is not None:
+                self._files_cache_source = source
+        # the generation counts the backups using this files cache, the age of an entry is derived from it.
+        self._files_cache_generation = (row[0] if row else 0) + 1
+        for path_hash, generation, item in self.db.execute(
+            "SELECT path_hash, generation, entry FROM files WHERE name = ?", (self._files_cache_source,)
+        ):
+            entry = FileCacheEntry(*msgpack.unpackb(item))
+            self.files[path_hash] = msgpack.packb(entry._replace(age=self._files_cache_generation - generation))
+        files_cache_logger.debug("FILES-CACHE-LOAD: finished, %d entries loaded.", len(self.files))
+
+    def _write_files_cache(self):
+        if self._newest_cmtime is None:
+            # was never set because no files were modified/added
+            self._newest_cmtime = 2**63 - 1  # nanoseconds, good until y2262
+        ttl = int(os.environ.get("BORG_FILES_CACHE_TTL", 20))
+        name, generation = self.files_cache_name(), self._files_cache_generation
+        # entries of files not seen in this backup are already stored, unless we started from another files cache.
+        copy_unseen = self._files_cache_source != name
+        files_cache_logger.debug("FILES-CACHE-SAVE: starting...")
+        updated, removed = [], []
+        for path_hash, item in self.files.items():
+            # Only keep files seen in this backup that are older than newest cmtime seen in this backup -
+            # this is to avoid issues with filesystem snapshots and cmtime granularity.
+            entry = FileCacheEntry(*msgpack.unpackb(item))
+            if entry.age == 0 and timestamp_to_int(entry.cmtime) >= self._newest_cmtime:
+                removed.append((name, path_hash))
+            elif entry.age == 0 or (copy_unseen and entry.age < ttl):
+                updated.append((name, path_hash, generation - entry.age, msgpack.packb(entry._replace(age=0))))
+        self.db.executemany("INSERT OR REPLACE INTO files VALUES (?, ?, ?, ?)", updated)
+        self.db.executemany("DELETE FROM files WHERE name = ? AND path_hash = ?", removed)
+        self.db.execute("DELETE FROM files WHERE name = ? AND generation <= ?", (name, generation - ttl))
+        self.db.execute("INSERT OR REPLACE INTO files_caches VALUES (?, ?, ?)", (name, generation, time.time()))
+        files_cache_logger.debug("FILES-CACHE-KILL: removed all old entries with age >= TTL [%d]", ttl)
+        files_cache_logger.debug(
+            "FILES-CACHE-KILL: removed all current entries with newest cmtime %d", self._newest_cmtime
+        )
+        files_cache_logger.debug("FILES-CACHE-SAVE: finished, %d entries of seen files saved.", len(updated))
+        self._files_cache_source = name
+        if self.files_cache_scope:
+            self._remove_stale_files_caches()
+
+    def _remove_stale_files_caches(self):
+        max_age = float(os.environ.get("BORG_FILES_CACHE_MAX_AGE", 60)) * 24 * 3600
+        rows = self.db.execute(
+            "SELECT name FROM files_caches WHERE used < ? AND name != ?",
+            (time.time() - max_age, self.files_cache_name()),
+        ).fetchall()
+        for (name,) in rows:
+            if name != self.FILES_CACHE_NAME and not name.startswith(self.FILES_CACHE_SCOPED_PREFIX):
+                continue  # files caches named by BORG_FILES_CACHE_SUFFIX
+            self.db.execute("DELETE FROM files WHERE name = ?", (name,))
+            self.db.execute("DELETE FROM files_caches WHERE name = ?", (name,))
+            files_cache_logger.debug("FILES-CACHE-KILL: removed stale files cache %s", name)
+
+    def wipe_cache(self):
+        logger.warning("Discarding incompatible or corrupted cache and forcing a cache rebuild")
+        archive_path = os.path.join(self.path, "chunks.archive.d")
+        if os.path.isdir(archive_path):
+            shutil.rmtree(os.path.join(self.path, "chunks.archive.d"))
+            os.makedirs(os.path.join(self.path, "chunks.archive.d"))
+        self.db.execute("BEGIN IMMEDIATE")
+        for table in "meta", "chunks", "files_caches", "files":
+            self.db.execute("DELETE FROM %s" % table)
+        self.db.execute("COMMIT")
+        self.cache_config.manifest_id = b""
+        self.cache_config.chunk_index_position = None
+        self.cache_config.ignored_features = set()
+        self.cache_config.mandatory_features = set()
+        self._save_config()
+        self._do_open()
+
+
 class AdHocWithFilesCache(CacheStatsMixin, FilesCacheMixin, ChunksMixin):
     """
     Like AdHocCache, but with a files cache.
@@ -1179,26 +1760,30 @@ def __init__(
         progress=False,
         lock_wait=None,
         cache_mode=FILES_CACHE_MODE_DISABLED,
+        files_cache_scope=None,
         iec=False,
     ):
         """
         :param warn_if_unencrypted: print warning if accessing unknown unencrypted repository
         :param lock_wait: timeout for lock acquisition (int [s] or None [wait forever])
         :param cache_mode: what shall be compared in the file stat infos vs. cached stat infos comparison
+        :param files_cache_scope: use a separate files cache for this scope, see files_cache_scope()
         """
         CacheStatsMixin.__init__(self, iec=iec)
-        FilesCacheMixin.__init__(self, cache_mode)
+        FilesCacheMixin.__init__(self, cache_mode, files_cache_scope)
         assert isinstance(manifest, Manifest)
         self.manifest = manifest
         self.repository = manifest.repository
         self.key = manifest.key
+        self.encryption_key = cache_encryption_key(self.key)
         self.repo_objs = manifest.repo_objs
         self.progress = progress
         self._txn_active = False
+        manifest.chunk_index = self.chunk_index = RepositoryChunkIndex(manifest)
 
         self.path = cache_dir(self.repository, path)
         self.security_manager = SecurityManager(self.repository)
-        self.cache_config = CacheConfig(self.repository, self.path, lock_wait)
+        self.cache_config = CacheConfig(self.repository, self.path, lock_wait, self.encryption_key)
 
         # Warn user before sending data to a never seen before unencrypted repository
         if not os.path.exists(self.path):
@@ -1207,6 +1792,8 @@ def __init__(
 
         self.open()
         try:
+            if self.cache_config.allow_plaintext:
+                self.cache_config.migrate_encryption()
             self.security_manager.assert_secure(manifest, self.key)
 
             if not self.check_cache_compatibility():
@@ -1352,6 +1939,7 @@ def __init__(self, manifest, warn_if_unencrypted=True, lock_wait=None, iec=False
         self.key = manifest.key
         self.repo_objs = manifest.repo_objs
         self._txn_active = False
+        manifest.chunk_index = self.chunk_index = RepositoryChunkIndex(manifest)
 
         self.security_manager = SecurityManager(self.repository)
         self.security_manager.assert_secure(manifest, self.key, lock_wait=lock_wait)
@@ -1387,3 +1975,382 @@ def rollback(self):
     def begin_txn(self):
         self._txn_active = True
         self.chunks = self._load_chunks_from_repo()
+
+
+def valid_files_cache_entry(path_hash, item, chunks):
+    """return whether a files cache entry is well-formed and only references chunks present in *chunks*"""
+    if not isinstance(path_hash, bytes) or len(path_hash) != 32:
+        return False
+    try:
+        entry = FileCacheEntry(*item)
+    except TypeError:
+        return False
+    if not all(isinstance(value, int) and value >= 0 for value in (entry.age, entry.inode, entry.size)):
+        return False
+    if not isinstance(entry.cmtime, Timestamp) or not isinstance(entry.chunks, list):
+        return False
+    for chunk in entry.chunks:
+        if not isinstance(chunk, list) or len(chunk) != 2:
+            return False
+        id, size = chunk
+        if not isinstance(id, bytes) or len(id) != 32 or not isinstance(size, int) or id not in chunks:
+            return False
+    return True
+
+
+class CacheChecker:
+    """
+    Check the local cache against the repository and repair what is wrong, see ``borg cache check``.
+
+    The chunks index (the chunks file or the chunks table of the SQLite cache database) is compared to the
+    reference counts computed from the archives (like a cache sync would build it), the archive indexes in
+    chunks.archive.d to the ones of the archives and the files cache entries must only reference chunks
+    present in the repository.
+    """
+
+    def __init__(self, manifest, path=None, lock_wait=None):
+        self.manifest = manifest
+        self.repository = manifest.repository
+        self.key = manifest.key
+        self.encryption_key = cache_encryption_key(self.key)
+        self.repo_objs = manifest.repo_objs
+        self.path = cache_dir(self.repository, path)
+        self.cache_config = CacheConfig(self.repository, self.path, lock_wait, self.encryption_key)
+        self.error_found = False
+        self.unrepairable_found = False  # errors --repair can not fix (e.g. damaged archives)
+        self.repair = False
+
+    def error(self, msg, *args, repairable=True):
+        self.error_found = True
+        if not repairable:
+            self.unrepairable_found = True
+        logger.error(msg, *args)
+
+    def check(self, repair=False):
+        """Check the local cache, return True if no problems were found (or all were repaired)."""
+        logger.info("Starting cache check")
+        if not self.cache_config.exists():
+            logger.info("There is no local cache for this repository.")
+            return True
+        self.repair = repair
+        with self.cache_config:
+            if self.cache_config.discarded:
+                self.error("The cache config is damaged or not encrypted.")
+            self.check_transaction()
+            self.check_integrity_data()
+            present = self.repository_chunks()
+            expected = self.check_archive_indexes()
+            self.check_chunks(expected)
+            for name in sorted(os.listdir(self.path)):
+                if name == FilesCacheMixin.FILES_CACHE_NAME or name.startswith(FilesCacheMixin.FILES_CACHE_NAME + "."):
+                    self.check_files_cache(name, present)
+            self.check_database(expected)
+            if self.repair:
+                self.save_config()
+        if not self.error_found:
+            logger.info("Cache check complete, no problems found.")
+        elif self.repair and self.unrepairable_found:
+            logger.warning("Not all problems could be repaired.")
+        return not self.error_found or self.repair and not self.unrepairable_found
+
+    def check_transaction(self):
+        txn_dir = os.path.join(self.path, "txn.active")
+        if not os.path.exists(txn_dir):
+            return
+        self.error("The cache has an unfinished transaction.")
+        if self.repair:
+            # roll back like LocalCache.rollback does
+            for name in os.listdir(txn_dir):
+                shutil.copy(os.path.join(txn_dir, name), self.path)
+            shutil.rmtree(txn_dir)
+            self.cache_config.load()
+
+    def check_integrity_data(self):
+        config = self.cache_config._config
+        if not config.has_section("integrity"):
+            self.error("The cache config has no integrity data.")
+        elif config.get("integrity", "manifest", fallback=None) != config.get("cache", "manifest"):
+            self.error("The integrity data in the cache config is invalid, an old borg version modified the cache.")
+        for name in list(self.cache_config.integrity):
+            if not os.path.exists(os.path.join(self.path, name)):
+                self.error("The cache config has integrity data of the missing file %s.", name)
+                if self.repair:
+                    del self.cache_config.integrity[name]
+
+    def repository_chunks(self):
+        chunks = ChunkIndex(usable=len(self.repository))
+        entry = ChunkIndexEntry(refcount=ChunkIndex.MAX_VALUE, size=0)
+        marker = None
+        while True:
+            result = self.repository.list(limit=LIST_SCAN_LIMIT, marker=marker)
+            if not result:
+                break
+            marker = result[-1]
+            for id in result:
+                chunks[id] = entry
+        return chunks
+
+    def check_archive_indexes(self):
+        """check chunks.archive.d and return the chunks index built from the archives, None if that failed"""
+        archive_path = os.path.join(self.path, "chunks.archive.d")
+        cached = {}  # archive id -> names of its archive index files
+        if os.path.isdir(archive_path):
+            for fn in os.listdir(archive_path):
+                if len(fn) == 64 or len(fn) == 72 and fn.endswith(".compact"):
+                    cached.setdefault(hex_to_bin(fn[:64]), []).append(fn)
+        archives = {info.id: info.name for info in self.manifest.archives.list()}
+        for archive_id in cached.keys() - archives.keys():
+            self.error("Stale archive index %s in chunks.archive.d.", bin_to_hex(archive_id))
+            if self.repair:
+                self.remove_archive_index(archive_path, cached[archive_id])
+        expected = ChunkIndex(usable=len(self.repository))
+        pi = ProgressIndicatorPercent(
+            total=len(archives),
+            step=0.1,
+            msg="%3.0f%% Checking chunks index. Processing archive %s.",
+            msgid="cache.check",
+        )
+        with cache_if_remote(self.repository, decrypted_cache=self.repo_objs) as decrypted_repository:
+            for archive_id, archive_name in archives.items():
+                pi.show(info=[remove_surrogates(archive_name)])
+                archive_chunk_idx = ChunkIndex()
+                try:
+                    fetch_archive_chunk_index(self.key, decrypted_repository, archive_id, archive_chunk_idx)
+                except Repository.ObjectNotFound:
+                    self.error("Archive %s is damaged, run borg check.", archive_name, repairable=False)
+                    expected = None
+                    continue
+                for fn in cached.get(archive_id, []):
+                    if not self.archive_index_matches(os.path.join(archive_path, fn), archive_chunk_idx):
+                        self.error("Archive index %s of archive %s is wrong or damaged.", fn, archive_name)
+                        if self.repair:
+                            self.remove_archive_index(archive_path, [fn])
+                if expected is not None:
+                    expected.merge(archive_chunk_idx)
+        pi.finish()
+        return expected
+
+    def archive_index_matches(self, path, archive_chunk_idx):
+        try:
+            with DetachedIntegrityCheckedFile(
+                path=path,
+                write=False,
+                encryption_key=self.encryption_key,
+                allow_plaintext=self.cache_config.allow_plaintext,
+            ) as fd:
+                chunk_idx = ChunkIndex.read(fd, permit_compact=path.endswith(".compact"))
+        except (OSError, ValueError, FileIntegrityError):
+            return False
+        return not any(self.compare_chunks(chunk_idx, archive_chunk_idx))
+
+    @staticmethod
+    def remove_archive_index(archive_path, names):
+        for fn in names:
+            for name in fn, fn + ".integrity":
+                try:
+                    os.unlink(os.path.join(archive_path, name))
+                except FileNotFoundError:
+                    pass
+
+    @staticmethod
+    def compare_chunks(chunks, expected):
+        """return the ids of the (missing, superfluous, wrong) entries of *chunks* compared to *expected*"""
+        missing, superfluous, wrong = [], [], []
+        for id, entry in expected.iteritems():
+            present = chunks.get(id)
+            if present is None:
+                missing.append(id)
+            elif present != entry:
+                wrong.append(id)
+        for id, _ in chunks.iteritems():
+            if id not in expected:
+                superfluous.append(id)
+        return missing, superfluous, wrong
+
+    def check_chunks(self, expected):
+        path = os.path.join(self.path, "chunks")
+        if not os.path.exists(path):
+            return  # the cache implementation has no persistent chunks index
+        mapped = self.cache_config.index_mode == "mapped"
+        integrity_data = self.cache_config.integrity.get("chunks")
+        reseal = not mapped and integrity_data is None
+        if reseal:
+            self.error("The chunks index has no integrity data.")
+        try:
+            if mapped:
+                chunks = ChunkIndex.map(path)
+            else:
+                with IntegrityCheckedFile(
+                    path=path,
+                    write=False,
+                    integrity_data=integrity_data,
+                    encryption_key=self.encryption_key,
+                    allow_plaintext=self.cache_config.allow_plaintext,
+                ) as fd:
+                    chunks = ChunkIndex.read(fd)
+        except (OSError, ValueError, FileIntegrityError) as exc:
+            self.error("The chunks index is damaged: %s", exc)
+            chunks = None
+        if expected is None:
+            logger.warning("Skipping the chunks index check, the chunks referenced by the archives are not known.")
+            if chunks is None or reseal:
+                self.unrepairable_found = True  # it can not be rebuilt (or resealed) without knowing them
+            return
+        if chunks is None:
+            if self.repair:
+                logger.info("Rebuilding the chunks index.")
+                if mapped:
+                    safe_unlink(path)
+                    chunks = ChunkIndex.create_mapped(path, usable=len(expected))
+                    chunks.merge(expected)
+                else:
+                    chunks = expected
+                self.write_chunks(chunks)
+                head = RepositoryChunkIndex(self.manifest).head()
+                position = RepositoryChunkIndex.position_of(head) if head is not None else None
+                self.cache_config._config.set("cache", "manifest", self.manifest.id_str)
+                self.cache_config._config.set(
+                    "cache", "chunk_index", self.cache_config.format_chunk_index_position(position)
+                )
+            return
+        if self.cache_config.manifest_id != self.manifest.id:
+            logger.info("The chunks index is not in sync with the repository, it gets updated when the cache is used.")
+        else:
+            missing, superfluous, wrong = self.check_chunk_entries("chunks index", chunks, expected)
+            if self.repair and (missing or superfluous or wrong):
+                for id in missing + wrong:
+                    chunks[id] = expected[id]
+                for id in superfluous:
+                    del chunks[id]
+                reseal = True
+        if self.repair and reseal:
+            self.write_chunks(chunks)
+
+    def check_chunk_entries(self, what, chunks, expected):
+        """compare *chunks* to *expected* and report the differences, return them like compare_chunks"""
+        missing, superfluous, wrong = self.compare_chunks(chunks, expected)
+        if missing or superfluous or wrong:
+            self.error(
+                "The %s has %d missing and %d superfluous chunks and %d wrong reference counts or sizes.",
+                what,
+                len(missing),
+                len(superfluous),
+                len(wrong),
+            )
+            for id in missing + wrong:
+                logger.debug("Chunk %s: expected %r, found %r.", bin_to_hex(id), expected[id], chunks.get(id))
+            for id in superfluous:
+                logger.debug("Chunk %s: not referenced by any archive.", bin_to_hex(id))
+        return missing, superfluous, wrong
+
+    def write_chunks(self, chunks):
+        if chunks.mapped:
+            chunks.flush()
+            self.cache_config.integrity.pop("chunks", None)
+        else:
+            with IntegrityCheckedFile(
+                path=os.path.join(self.path, "chunks"), write=True, encryption_key=self.encryption_key
+            ) as fd:
+                chunks.write(fd)
+            self.cache_config.integrity["chunks"] = fd.integrity_data
+
+    def check_files_cache(self, name, present):
+        integrity_data = self.cache_config.integrity.get(name)
+        rewrite = integrity_data is None
+        if rewrite:
+            self.error("The files cache %s has no integrity data.", name)
+        entries, invalid = [], 0
+        try:
+            with IntegrityCheckedFile(
+                path=os.path.join(self.path, name),
+                write=False,
+                integrity_data=integrity_data,
+                encryption_key=self.encryption_key,
+                allow_plaintext=self.cache_config.allow_plaintext,
+            ) as fd:
+                u = msgpack.Unpacker(use_list=True)
+                while True:
+                    data = fd.read(64 * 1024)
+                    if not data:
+                        break
+                    u.feed(data)
+                    for path_hash, item in u:
+                        if valid_files_cache_entry(path_hash, item, present):
+                            entries.append((path_hash, item))
+                        else:
+                            invalid += 1
+        except (OSError, ValueError, TypeError, FileIntegrityError) as exc:
+            self.error("The files cache %s is damaged: %s", name, exc)
+            entries, rewrite = [], True
+        if invalid:
+            self.error("The files cache %s has %d invalid entries.", name, invalid)
+            rewrite = True
+        if self.repair and rewrite:
+            with IntegrityCheckedFile(
+                path=os.path.join(self.path, name), write=True, encryption_key=self.encryption_key
+            ) as fd:
+                for path_hash, item in entries:
+                    msgpack.pack((path_hash, item), fd)
+            self.cache_config.integrity[name] = fd.integrity_data
+
+    def check_database(self, expected):
+        path = os.path.join(self.path, SQLiteCache.DB_NAME)
+        if not os.path.exists(path):
+            return
+        try:
+            db = sqlite3.connect(path)
+            try:
+                result = "\n".join(row[0] for row in db.execute("PRAGMA integrity_check"))
+            finally:
+                db.close()
+        except sqlite3.DatabaseError as exc:
+            result = str(exc)
+        if result != "ok":
+            self.error("The cache database is damaged: %s", result)
+            if self.repair:
+                # the sqlite cache implementation starts with a new database and updates it from the repository.
+                SQLiteCache.remove_database(self.path)
+        elif expected is None:
+            logger.warning("Skipping the database check, the chunks referenced by the archives are not known.")
+        else:
+            self.check_database_chunks(path, expected)
+
+    def check_database_chunks(self, path, expected):
+        db = sqlite3.connect(path, isolation_level=None)
+        try:
+            meta = dict(db.execute("SELECT key, value FROM meta"))
+            if hex_to_bin(meta.get("manifest", "")) != self.manifest.id:
+                logger.info(
+                    "The cache database is not in sync with the repository, it gets updated when the cache is used."
+                )
+                return
+            (count,) = db.execute("SELECT count(*) FROM chunks").fetchone()
+            chunks = ChunkIndex(usable=count)
+            for id, refcount, size in db.execute("SELECT id, refcount, size FROM chunks"):
+                chunks[id] = ChunkIndexEntry(refcount, size)
+            what = "chunks index in the cache database"
+            missing, superfluous, wrong = self.check_chunk_entries(what, chunks, expected)
+            if self.repair and (missing or superfluous or wrong):
+                db.execute("BEGIN IMMEDIATE")
+                db.executemany(
+                    "INSERT OR REPLACE INTO chunks VALUES (?, ?, ?)",
+                    ((id, expected[id].refcount, expected[id].size) for id in missing + wrong),
+                )
+                db.executemany("DELETE FROM chunks WHERE id = ?", ((id,) for id in superfluous))
+                db.execute("COMMIT")
+        except sqlite3.DatabaseError as exc:
+            self.error("The cache database is damaged: %s", exc, repairable=False)
+        finally:
+            db.close()
+
+    def save_config(self):
+        config = self.cache_config._config
+        if not config.has_section("integrity"):
+            config.add_section("integrity")
+        for name, _ in config.items("integrity"):
+            if name != "manifest" and name not in self.cache_config.integrity:
+                config.remove_option("integrity", name)
+        for name, integrity_data in self.cache_config.integrity.items():
+            config.set("integrity", name, integrity_data)
+        config.set("integrity", "manifest", config.get("cache", "manifest"))
+        self.cache_config.save()
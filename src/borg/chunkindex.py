@@ -0,0 +1,207 @@
+"""
+Repository chunk index: the chunk refcount / size index, stored (encrypted) in the repository.
+
+Rebuilding the chunks index of the local cache from all archives (LocalCache.sync) is expensive, so clients
+maintaining the repository's archives also maintain this index in the repository. Other clients then only
+need to download what changed since they last saw the repository.
+
+The index is referenced from ``manifest.config["chunk_index"]`` (the "head") and thus always updated in the
+same repository transaction as the archives list it describes::
+
+    {
+        "version": 1,
+        "epoch": <16 random bytes, new for every snapshot>,
+        "seq": <sequence number of the last delta>,
+        "archives": <digest of the archive ids the index belongs to>,
+        "snapshot": [<ids of the snapshot part objects>],
+        "deltas": [<ids of the delta objects written after the snapshot>],
+    }
+
+Snapshot parts and deltas are normal repository objects of type ROBJ_CHUNK_INDEX, each containing a list of
+at most CHUNK_INDEX_PART_ENTRIES (chunk id, refcount, size) entries - for deltas the refcount is the change done
+by a transaction. A transaction writes one or more deltas and advances seq by their number, so a position
+(epoch, seq) tells which deltas a client still needs to apply. The index is obtained by applying the snapshot
+parts and then the deltas to an empty index.
+
+If a client that does not maintain the index (e.g. an older borg) changes the archives, the digest does not
+match anymore and the index is stale. It stays so until a client having a complete chunks index (LocalCache)
+writes a new snapshot.
+"""
+
+import os
+
+from .constants import ROBJ_CHUNK_INDEX, CHUNK_INDEX_PART_ENTRIES, CHUNK_INDEX_MAX_DELTAS
+from .hashindex import ChunkIndex, ChunkIndexEntry
+from .helpers import msgpack
+from .logger import create_logger
+from .repository import Repository
+
+logger = create_logger()
+
+CHUNK_INDEX_VERSION = 1
+
+
+def archives_digest(manifest, archives):
+    """return the digest of the archive ids in *archives* (a raw archives dict, name -> {"id": ..., "time": ...})"""
+    # the chunk refcounts only depend on the set of archives, not on their names.
+    ids = sorted(info["id"] for info in archives.values())
+    return manifest.repo_objs.id_hash(msgpack.packb(ids))
+
+
+def apply_entries(chunks, entries):
+    """apply chunk index *entries* (id, refcount delta, size) to the ChunkIndex *chunks*"""
+    for id, delta, size in entries:
+        entry = chunks.get(id)
+        if entry is None:
+            refcount = delta
+        elif entry.refcount == ChunkIndex.MAX_VALUE:
+            continue  # sticky, see hashindex
+        else:
+            refcount = entry.refcount + delta
+            size = size or entry.size
+        if refcount <= 0:
+            chunks.pop(id, None)
+        else:
+            chunks[id] = ChunkIndexEntry(min(refcount, ChunkIndex.MAX_VALUE), size)
+
+
+class RepositoryChunkIndex:
+    """
+    Reads the repository chunk index and records the chunk refcount changes of a transaction to update it.
+
+    The cache reports all refcount changes via record() and sets it as the manifest's chunk_index, so
+    write() gets called when the manifest is written and the index is updated in the same transaction.
+    """
+
+    def __init__(self, manifest, cache=None):
+        self.manifest = manifest
+        self.repository = manifest.repository
+        self.repo_objs = manifest.repo_objs
+        # a cache with a complete chunks index (LocalCache), which enables writing snapshots.
+        self.cache = cache
+        # position (epoch, seq) of the index the cache's chunks index corresponds to, None if unknown.
+        self.position = None
+        # digest of the archives the cache's chunks index corresponds to, None if not in sync.
+        self.digest = None
+        self.changes = {}  # chunk id -> [refcount delta, size]
+
+    def record(self, id, delta, size):
+        change = self.changes.setdefault(id, [0, size])
+        change[0] += delta
+        change[1] = size or change[1]
+
+    def head(self, archives=None):
+        """
+        return the index head if it is fresh for *archives* (default: the manifest's base archives), else None.
+
+        Without archives no chunks are referenced, so the (empty) index is known even if there is no head.
+        """
+        archives = self.manifest.base_archives if archives is None else archives
+        digest = archives_digest(self.manifest, archives)
+        head = self.manifest.config.get("chunk_index")
+        if head is not None and head.get("version") == CHUNK_INDEX_VERSION and head.get("archives") == digest:
+            return head
+        if not archives:
+            return dict(
+                version=CHUNK_INDEX_VERSION, epoch=os.urandom(16), seq=0, archives=digest, snapshot=[], deltas=[]
+            )
+        return None
+
+    @staticmethod
+    def position_of(head):
+        return head["epoch"], head["seq"]
+
+    def load(self, head, chunks=None):
+        """return *chunks* (default: a new ChunkIndex) filled with the contents of the index *head*"""
+        chunks = ChunkIndex() if chunks is None else chunks
+        for id in head["snapshot"] + head["deltas"]:
+            apply_entries(chunks, self.get_entries(id))
+        return chunks
+
+    def update(self, chunks, position, head):
+        """
+        apply the deltas after *position* to *chunks*, return whether that was possible.
+
+        It is not if *position* is unknown or older than the snapshot of *head*, then use load().
+        """
+        if position is None or position[0] != head["epoch"]:
+            return False
+        seq, first = position[1], head["seq"] - len(head["deltas"])
+        if not first <= seq <= head["seq"]:
+            return False
+        for id in head["deltas"][seq - first :]:
+            apply_entries(chunks, self.get_entries(id))
+        return True
+
+    def get_entries(self, id):
+        _, data = self.repo_objs.parse(id, self.repository.get(id), ro_type=ROBJ_CHUNK_INDEX)
+        return msgpack.unpackb(data)["entries"]
+
+    def put_entries(self, entries):
+        # the random salt makes sure that an index object never is identical to another object, so we can
+        # delete it again without any refcounting.
+        data = msgpack.packb({"salt": os.urandom(16), "entries": entries})
+        id = self.repo_objs.id_hash(data)
+        self.repository.put(id, self.repo_objs.format(id, {}, data, ro_type=ROBJ_CHUNK_INDEX))
+        return id
+
+    def put_deltas(self, changes):
+        """store the *changes* of a transaction in one or more delta objects, return their ids"""
+        step = CHUNK_INDEX_PART_ENTRIES
+        return [self.put_entries(changes[i : i + step]) for i in range(0, max(len(changes), 1), step)]
+
+    def write_snapshot(self, chunks, seq):
+        parts, entries = [], []
+        for id, entry in chunks.iteritems():
+            entries.append((id, entry.refcount, entry.size))
+            if len(entries) >= CHUNK_INDEX_PART_ENTRIES:
+                parts.append(self.put_entries(entries))
+                entries = []
+        if entries:
+            parts.append(self.put_entries(entries))
+        logger.debug("Repository chunk index: wrote snapshot of %d chunks in %d parts.", len(chunks), len(parts))
+        return dict(version=CHUNK_INDEX_VERSION, epoch=os.urandom(16), seq=seq, snapshot=parts, deltas=[])
+
+    def write(self):
+        """update the index head in the manifest config, called by Manifest.write before writing the manifest"""
+        manifest = self.manifest
+        current = manifest.config.get("chunk_index")
+        head = self.head()
+        chunks = self.cache.chunks if self.cache is not None else None
+        if chunks is not None and head is not None and self.digest != head["archives"]:
+            # other clients committed since our chunks index was in sync, catch up with their changes.
+            if self.update(chunks, self.position, head):
+                self.digest = head["archives"]
+        complete = chunks is not None and self.digest == archives_digest(manifest, manifest.base_archives)
+        digest = archives_digest(manifest, manifest.archives.get_raw_dict())
+        changes = [(id, delta, size) for id, (delta, size) in self.changes.items() if delta]
+        self.changes = {}
+        if head is None and not complete:
+            # we do not know the state of the index, it stays stale.
+            self.position = self.digest = None
+            return
+        seq = max(head["seq"] if head else 0, current.get("seq", 0) if current else 0)
+        if head is not None and not changes and head["archives"] == digest and head is current:
+            new = head  # nothing changed
+        elif complete and (head is None or len(head["deltas"]) >= CHUNK_INDEX_MAX_DELTAS):
+            new = self.write_snapshot(chunks, seq + 1)  # the cache's chunks index already includes our changes
+        elif len(head["deltas"]) >= CHUNK_INDEX_MAX_DELTAS:
+            index = self.load(head)
+            apply_entries(index, changes)
+            new = self.write_snapshot(index, seq + 1)
+        else:
+            deltas = self.put_deltas(changes)
+            new = dict(head, seq=seq + len(deltas), deltas=head["deltas"] + deltas)
+        new = dict(new, archives=digest)
+        if current is not None and current.get("epoch") != new["epoch"]:
+            # the objects of the previous snapshot and its deltas are not needed anymore.
+            for id in current.get("snapshot", []) + current.get("deltas", []):
+                try:
+                    self.repository.delete(id)
+                except Repository.ObjectNotFound:
+                    pass
+        manifest.config["chunk_index"] = new
+        if complete:
+            self.position, self.digest = self.position_of(new), digest
+        else:
+            self.position = self.digest = None
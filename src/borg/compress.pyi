@@ -1,4 +1,4 @@
-from typing import Any, Type, Dict, Tuple
+from typing import Any, Type, Dict, Optional, Tuple
 
 API_VERSION: str
 
@@ -57,6 +57,7 @@ class LZMA(DecidingCompressor):
 
 class ZSTD(DecidingCompressor):
     def __init__(self, level: int = ..., **kwargs) -> None: ...
+    def decompress(self, meta: Dict, data: bytes, max_size: Optional[int] = ...) -> Tuple[Dict, bytes]: ...
     level: int
 
 LZ4_COMPRESSOR: Type[LZ4]
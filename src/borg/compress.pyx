@@ -342,7 +342,8 @@ class ZSTD(DecidingCompressor):
         else:
             return NONE_COMPRESSOR, (meta, None)
 
-    def decompress(self, meta, data):
+    def decompress(self, meta, data, max_size=None):
+        """see CompressorBase.decompress, *max_size* limits the size of the decompressed data (if given)"""
         meta, idata = super().decompress(meta, data)
         if not isinstance(idata, bytes):
             idata = bytes(idata)  # code below does not work with memoryview
@@ -356,6 +357,9 @@ class ZSTD(DecidingCompressor):
             raise DecompressionError('zstd get size failed: data was not compressed by zstd')
         if osize == ZSTD_CONTENTSIZE_UNKNOWN:
             raise DecompressionError('zstd get size failed: original size unknown')
+        if max_size is not None and osize > max_size:
+            # the output buffer has the size from the frame header, ZSTD_decompress never writes more into it.
+            raise DecompressionError('zstd decompress failed: decompressed data too large')
         try:
             buf = buffer.get(osize)
         except MemoryError:
@@ -39,6 +39,7 @@
 ROBJ_ARCHIVE_CHUNKIDS = "C"  # objects with a list of archive metadata stream chunkids
 ROBJ_ARCHIVE_STREAM = "S"  # archive metadata stream chunk (containing items)
 ROBJ_FILE_STREAM = "F"  # file content stream chunk (containing user data)
+ROBJ_CHUNK_INDEX = "I"  # repository chunk index snapshot part or delta (see borg.chunkindex)
 ROBJ_DONTCARE = "*"  # used to parse without type assertion (= accept any type)
 
 # in borg < 1.3, this has been defined like this:
@@ -63,6 +64,26 @@
 # repo config max_segment_size value must be below this limit to stay within uint32 offsets:
 MAX_SEGMENT_SIZE_LIMIT = 2**32 - MAX_OBJECT_SIZE
 
+# Reed-Solomon parity of segment files (see borg.parity), disabled by default (parity_shards = 0).
+# With parity_shards = 2, any 2 of 8 consecutive 4 KiB blocks of a segment can be rebuilt, for 25% overhead.
+DEFAULT_PARITY_DATA_SHARDS = 8
+DEFAULT_PARITY_SHARDS = 0
+
+# where new segments go if the repository has additional data roots (repo config data_placement):
+# into the data root with the most free space or into one data root after the other.
+DATA_PLACEMENTS = ("free-space", "round-robin")
+DEFAULT_DATA_PLACEMENT = "free-space"
+
+# how the repository index (repo config index_mode) and the chunks index of the local cache (cache config
+# index_mode) are used: read into memory completely or through a memory mapping of the index file.
+INDEX_MODES = ("memory", "mapped")
+DEFAULT_INDEX_MODE = "memory"
+
+# repository chunk index (see borg.chunkindex): max. entries per snapshot part or delta object and how
+# many deltas may follow a snapshot before a client writes a new snapshot.
+CHUNK_INDEX_PART_ENTRIES = 100000
+CHUNK_INDEX_MAX_DELTAS = 64
+
 # how many metadata stream chunk ids do we store into a "pointer chunk" of the ArchiveItem.item_ptrs list?
 IDS_PER_CHUNK = 3  # MAX_DATA_SIZE // 40
 
@@ -81,6 +102,23 @@
 # repo.list() / .scan() result count limit the borg client uses
 LIST_SCAN_LIMIT = 100000
 
+# object ids per repo.retention_lock() call
+RETENTION_LOCK_BATCH = 10000
+
+# borg serve --auto-compact: seconds per compaction slice (and pause between slices), seconds to wait for the lock
+AUTO_COMPACT_SLICE = 10
+AUTO_COMPACT_LOCK_WAIT = 60
+
+# borg serve --hook EVENT=COMMAND: the events
+SERVE_HOOK_EVENTS = ("commit", "rollback", "quota-exceeded")
+
+# seconds a client tries to reconnect to a remote repository after the connection dropped
+RECONNECT_TIMEOUT = 300
+
+# resuming interrupted sessions: seconds a suspended transaction is kept / to wait for it being suspended
+SESSION_RESUME_TIMEOUT = 24 * 3600
+SESSION_RESUME_WAIT = 10
+
 FD_MAX_AGE = 4 * 60  # 4 minutes
 
 # Some bounds on segment / segment_dir indexes
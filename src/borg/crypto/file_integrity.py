@@ -2,12 +2,14 @@
 import io
 import json
 import os
+import struct
 from hmac import compare_digest
 from typing import Callable
 
 from ..helpers import IntegrityError
 from ..logger import create_logger
 from ..checksums import StreamingXXH64
+from .low_level import AES256_OCB, hmac_sha256, IntegrityError as CryptoIntegrityError
 
 logger = create_logger()
 
@@ -127,8 +129,155 @@ class FileIntegrityError(IntegrityError):
     exit_mcode = 91
 
 
+class EncryptedFile(FileLikeWrapper):
+    """
+    Wrapper for file-like objects that encrypts the data written to the backing file and
+    decrypts the data read from it, using AES256-OCB with a key derived from *key*.
+
+    Layout: MAGIC + salt + blocks, every block is: auth tag (16 bytes) + encrypted data (BLOCK_SIZE bytes,
+    the last block may be shorter or empty).
+
+    Every file gets its own key, derived from *key* and a random salt, the block index is used as the nonce.
+    The authenticated data of a block are its index, whether it is the last block and the name of the file
+    (like IntegrityCheckedFile, only the basename), so reordering, truncating or extending the blocks and
+    renaming the file are detected.
+
+    While reading, seeking is supported (offsets refer to the decrypted data), while writing, it is only
+    possible to seek to the end, e.g. to query the size of the file.
+    """
+
+    MAGIC = b"BORG_ENC"
+    SALT_SIZE = 32
+    HEADER_SIZE = len(MAGIC) + SALT_SIZE
+    MAC_SIZE = 16
+    BLOCK_SIZE = 1024 * 1024
+
+    def __init__(self, backing_fd, key, write, path, filename=None):
+        super().__init__(backing_fd)
+        self.path = path
+        self.writing = write
+        self.filename = os.path.basename(filename or path).encode()
+        self.pos = 0
+        if write:
+            salt = os.urandom(self.SALT_SIZE)
+            self.fd.write(self.MAGIC + salt)
+            self.buffer = bytearray()
+            self.index = 0
+        else:
+            header = self.fd.read(self.HEADER_SIZE)
+            if len(header) != self.HEADER_SIZE or not header.startswith(self.MAGIC):
+                raise FileIntegrityError(path)
+            salt = header[len(self.MAGIC) :]
+            length = self.fd.seek(0, io.SEEK_END) - self.HEADER_SIZE
+            self.blocks = -(-length // (self.MAC_SIZE + self.BLOCK_SIZE))
+            if self.blocks == 0 or length - (self.blocks - 1) * (self.MAC_SIZE + self.BLOCK_SIZE) < self.MAC_SIZE:
+                raise FileIntegrityError(path)
+            self.size = length - self.blocks * self.MAC_SIZE
+            self.index = None  # index of the decrypted block in self.buffer
+        self.cipher = AES256_OCB(key=hmac_sha256(key, salt), header_len=0)
+        if not write:
+            # authenticates the key and the length of the file, even if nothing gets read.
+            self.read_block(self.blocks - 1)
+
+    @classmethod
+    def encrypt(cls, key, data, path, filename=None):
+        """Return *data* encrypted like EncryptedFile writes it to the file *path*."""
+        fd = cls(io.BytesIO(), key, True, path, filename)
+        fd.write(data)
+        fd.write_block(fd.buffer, final=True)
+        return fd.fd.getvalue()
+
+    @classmethod
+    def is_encrypted(cls, fd):
+        """Return whether the file *fd* (positioned at the start) is encrypted, the position is kept."""
+        magic = fd.read(len(cls.MAGIC))
+        fd.seek(-len(magic), io.SEEK_CUR)
+        return magic == cls.MAGIC
+
+    def aad(self, index, final):
+        return struct.pack(">QB", index, final) + self.filename
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        if exc_type is None and self.writing:
+            self.write_block(self.buffer, final=True)
+        super().__exit__(exc_type, exc_val, exc_tb)
+
+    def close(self):
+        self.fd.close()
+
+    def write_block(self, data, final):
+        self.fd.write(self.cipher.encrypt(bytes(data), iv=self.index, aad=self.aad(self.index, final)))
+        self.index += 1
+
+    def write(self, data):
+        self.buffer += data
+        self.pos += len(data)
+        # the last block is written when the file is closed, it is marked as such.
+        while len(self.buffer) > self.BLOCK_SIZE:
+            self.write_block(self.buffer[: self.BLOCK_SIZE], final=False)
+            del self.buffer[: self.BLOCK_SIZE]
+        return len(data)
+
+    def read_block(self, index):
+        if index != self.index:
+            self.fd.seek(self.HEADER_SIZE + index * (self.MAC_SIZE + self.BLOCK_SIZE))
+            envelope = self.fd.read(self.MAC_SIZE + self.BLOCK_SIZE)
+            self.cipher.set_iv(index)
+            try:
+                self.buffer = self.cipher.decrypt(envelope, aad=self.aad(index, index == self.blocks - 1))
+            except CryptoIntegrityError:
+                raise FileIntegrityError(self.path) from None
+            self.index = index
+        return self.buffer
+
+    def read(self, n=None):
+        if n is None or n < 0:
+            n = self.size - self.pos
+        data = bytearray()
+        while len(data) < n and self.pos < self.size:
+            index, offset = divmod(self.pos, self.BLOCK_SIZE)
+            part = self.read_block(index)[offset : offset + n - len(data)]
+            data += part
+            self.pos += len(part)
+        return bytes(data)
+
+    def seek(self, offset, whence=io.SEEK_SET):
+        if self.writing:
+            if offset != 0 or whence == io.SEEK_SET:
+                raise io.UnsupportedOperation("can only seek to the end of an encrypted file while writing")
+            return self.pos
+        if whence == io.SEEK_CUR:
+            offset += self.pos
+        elif whence == io.SEEK_END:
+            offset += self.size
+        if offset < 0:
+            raise ValueError("negative seek position %d" % offset)
+        self.pos = offset
+        return self.pos
+
+    def tell(self):
+        return self.pos
+
+
 class IntegrityCheckedFile(FileLikeWrapper):
-    def __init__(self, path, write, filename=None, override_fd=None, integrity_data=None):
+    """
+    Wrapper for files that checks the integrity of the file, see FileHashingWrapper.
+
+    If *encryption_key* is given, the file is also encrypted, see EncryptedFile. While reading, an unencrypted
+    file is rejected then (as anybody could have written it), unless *allow_plaintext* is set to migrate files
+    written before encryption got enabled. An encrypted file is rejected if no *encryption_key* is given.
+    """
+
+    def __init__(
+        self,
+        path,
+        write,
+        filename=None,
+        override_fd=None,
+        integrity_data=None,
+        encryption_key=None,
+        allow_plaintext=False,
+    ):
         self.path = path
         self.writing = write
         mode = "wb" if write else "rb"
@@ -136,6 +285,21 @@ def __init__(self, path, write, filename=None, override_fd=None, integrity_data=
         self.file_opened = override_fd is None
         self.digests = {}
 
+        try:
+            self.encrypted = encryption_key is not None if write else EncryptedFile.is_encrypted(self.file_fd)
+            if self.encrypted:
+                if encryption_key is None:
+                    logger.warning("%s is encrypted, but no key to decrypt it was given.", path)
+                    raise FileIntegrityError(path)
+                self.file_fd = EncryptedFile(self.file_fd, encryption_key, write, path, filename)
+            elif encryption_key is not None and not allow_plaintext:
+                logger.warning("%s is not encrypted.", path)
+                raise FileIntegrityError(path)
+        except Exception:
+            if self.file_opened:
+                self.file_fd.close()
+            raise
+
         hash_cls = XXH64FileHashingWrapper
 
         if not write:
@@ -217,8 +381,10 @@ def store_integrity_data(self, data: str):
 
 
 class DetachedIntegrityCheckedFile(IntegrityCheckedFile):
-    def __init__(self, path, write, filename=None, override_fd=None):
-        super().__init__(path, write, filename, override_fd)
+    def __init__(self, path, write, filename=None, override_fd=None, encryption_key=None, allow_plaintext=False):
+        super().__init__(
+            path, write, filename, override_fd, encryption_key=encryption_key, allow_plaintext=allow_plaintext
+        )
         filename = filename or os.path.basename(path)
         output_dir = os.path.dirname(path)
         self.output_integrity_file = self.integrity_file_path(os.path.join(output_dir, filename))
@@ -190,6 +190,12 @@ def assert_id(self, id, data):
             if not hmac.compare_digest(id_computed, id):
                 raise IntegrityError("Chunk %s: id verification failed" % bin_to_hex(id))
 
+    def cache_encryption_key(self):
+        """Return the key for encrypting the local cache, None if this key does not encrypt."""
+        # crypt_key is a PRK, so PRF security is good enough here. This is domain separated from the
+        # session keys of AEADKeyBase, see _get_session_key.
+        return sha256(self.crypt_key + b"borg-cache-key").digest()
+
     def assert_type(self, type_byte, id=None):
         if type_byte not in self.TYPES_ACCEPTABLE:
             id_str = bin_to_hex(id) if id is not None else "(unknown)"
@@ -249,6 +255,9 @@ def decrypt(self, id, data):
         self.assert_type(data[0], id)
         return memoryview(data)[1:]
 
+    def cache_encryption_key(self):
+        return None  # there is no key
+
 
 def random_blake2b_256_key():
     # This might look a bit curious, but is the same construction used in the keyed mode of BLAKE2b.
@@ -769,6 +778,9 @@ def init_ciphers(self, manifest_data=None):
         if manifest_data is not None:
             self.assert_type(manifest_data[0])
 
+    def cache_encryption_key(self):
+        return None  # the repository is not encrypted either
+
     def encrypt(self, id, data):
         return b"".join([self.TYPE_STR, data])
 
@@ -164,7 +164,9 @@ def iter_archive_items(self, archive_item_ids, filter=None):
         meta = self.meta
         pack_indirect_into = self.indirect_entry_struct.pack_into
 
-        for key, (csize, data) in zip(archive_item_ids, self.decrypted_repository.get_many(archive_item_ids)):
+        for key, (csize, data) in zip(
+            archive_item_ids, self.decrypted_repository.get_many(archive_item_ids, metadata=True)
+        ):
             # Store the chunk ID in the meta-array
             if write_offset + 32 >= len(meta):
                 self.meta = meta = meta + bytes(self.GROW_META_BY)
@@ -4,7 +4,7 @@ API_VERSION: str
 
 PATH_OR_FILE = Union[str, IO]
 
-def hashindex_variant(fn: str) -> str: ...
+def hashindex_variant(fn: PATH_OR_FILE) -> int: ...
 
 class IndexBase:
     value_size: int
@@ -78,6 +78,12 @@ class FuseVersionsIndex(IndexBase):
     def __getitem__(self, key: bytes) -> Any: ...
     def __setitem__(self, key: bytes, value: Any) -> None: ...
 
+class RetentionIndex(IndexBase):
+    def iteritems(self) -> Iterator[Tuple[bytes, int]]: ...
+    def __contains__(self, key: bytes) -> bool: ...
+    def __getitem__(self, key: bytes) -> int: ...
+    def __setitem__(self, key: bytes, value: int) -> None: ...
+
 class CacheSynchronizer:
     size_totals: int
     num_files_totals: int
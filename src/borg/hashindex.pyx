@@ -1,4 +1,8 @@
 from collections import namedtuple
+from functools import partial
+import mmap
+import os
+import tempfile
 
 cimport cython
 from libc.stdint cimport uint32_t, UINT32_MAX, uint64_t
@@ -18,6 +22,10 @@ cdef extern from "_hashindex.c":
         char hash[16]
 
     HashIndex *hashindex_read(object file_py, int permit_compact, int legacy) except *
+    HashIndex *hashindex_map(object map_py, object resize_cb, int permit_compact) except *
+    HashIndex *hashindex_map_init(object resize_cb, int capacity, int key_size, int value_size) except *
+    object hashindex_mapping(HashIndex *index)
+    void hashindex_sync_header(HashIndex *index)
     HashIndex *hashindex_init(int capacity, int key_size, int value_size)
     void hashindex_free(HashIndex *index)
     int hashindex_len(HashIndex *index)
@@ -71,9 +79,13 @@ assert _MAX_VALUE % 2 == 1
 
 
 def hashindex_variant(fn):
-    """peek into an index file and find out what it is"""
-    with open(fn, 'rb') as f:
-        magic = f.read(8)  # MAGIC_LEN
+    """peek into an index file (given by path or as seekable file object) and find out what it is"""
+    if isinstance(fn, (str, bytes, os.PathLike)):
+        with open(fn, 'rb') as f:
+            magic = f.read(8)  # MAGIC_LEN
+    else:
+        magic = fn.read(8)  # MAGIC_LEN
+        fn.seek(0)
     if magic == b'BORG_IDX':
         return 1  # legacy
     if magic == b'BORG2IDX':
@@ -83,10 +95,42 @@ def hashindex_variant(fn):
     raise ValueError(f'unknown hashindex magic: {magic!r}')
 
 
+def _new_mapping(path, shared, pending, size):
+    """return a writable mapping of a new, zero-filled file of *size* bytes for a mapped index at *path*"""
+    if shared:
+        # the new file replaces the index file when flushing (see IndexBase.flush), until then the index file
+        # stays as it was before the resize (an existing mapping of it stays valid).
+        fd_num, tmp_path = tempfile.mkstemp(
+            prefix=os.path.basename(path) + '.', suffix='.tmp', dir=os.path.dirname(path) or '.'
+        )
+        with open(fd_num, 'w+b') as fd:
+            fd.truncate(size)
+            mapping = mmap.mmap(fd.fileno(), size)
+        if pending:
+            # resized again before flushing, the previous new file is obsolete.
+            os.unlink(pending.pop())
+        pending.append(tmp_path)
+    else:
+        # changes must not go to the index file, so use an anonymous file next to it.
+        with tempfile.TemporaryFile(dir=os.path.dirname(path) or '.') as fd:
+            fd.truncate(size)
+            mapping = mmap.mmap(fd.fileno(), size)
+    return mapping
+
+
+# records per read of the journal of an index, see IndexBase.start_journal
+JOURNAL_BLOCK_RECORDS = 65536
+
+
 @cython.internal
 cdef class IndexBase:
     cdef HashIndex *index
     cdef int key_size
+    cdef object resize_cb  # not None for a mapped index, see map()
+    cdef object mapped_path  # the index file of a mapped index
+    cdef object pending  # new file of a resized, shared mapped index (at most one), replacing mapped_path on flush()
+    cdef object journal  # file of (key, present, previous value) records, see start_journal()
+    cdef object journal_dir  # where to create a temporary journal file (None: the temporary directory)
     legacy = 0
 
     _key_size = 32
@@ -94,9 +138,26 @@ cdef class IndexBase:
     MAX_LOAD_FACTOR = HASH_MAX_LOAD
     MAX_VALUE = _MAX_VALUE
 
-    def __cinit__(self, capacity=0, path=None, permit_compact=False, usable=None):
+    def __cinit__(self, capacity=0, path=None, permit_compact=False, usable=None, mapped=False, shared=True):
         self.key_size = self._key_size
-        if path:
+        if mapped:
+            if self.legacy:
+                raise ValueError('legacy indexes can not be mapped')
+            self.mapped_path = os.fsdecode(path)
+            self.pending = []
+            self.resize_cb = partial(_new_mapping, self.mapped_path, shared, self.pending)
+            self.journal_dir = os.path.dirname(self.mapped_path) or '.'
+            if capacity is None:
+                # map the existing index file
+                with open(path, 'r+b' if shared else 'rb') as fd:
+                    mapping = mmap.mmap(fd.fileno(), 0, access=mmap.ACCESS_WRITE if shared else mmap.ACCESS_COPY)
+                self.index = hashindex_map(mapping, self.resize_cb, permit_compact)
+            else:
+                if usable is not None:
+                    capacity = int(usable / self.MAX_LOAD_FACTOR)
+                self.index = hashindex_map_init(self.resize_cb, capacity, self.key_size, self.value_size)
+            assert self.index, 'hashindex_map() returned NULL with no exception set'
+        elif path:
             if isinstance(path, (str, bytes)):
                 with open(path, 'rb') as fd:
                     self.index = hashindex_read(fd, permit_compact, self.legacy)
@@ -113,11 +174,48 @@ cdef class IndexBase:
     def __dealloc__(self):
         if self.index:
             hashindex_free(self.index)
+        if self.pending:
+            # changes after the last resize were not flushed
+            try:
+                os.unlink(self.pending.pop())
+            except OSError:
+                pass
 
     @classmethod
     def read(cls, path, permit_compact=False):
         return cls(path=path, permit_compact=permit_compact)
 
+    @classmethod
+    def map(cls, path, shared=True, permit_compact=False):
+        """
+        Use the index file at *path* through a memory mapping instead of reading it.
+
+        Only the parts of the table in use are resident. With *shared*, changes go to the file (call flush()
+        to make them durable), otherwise they are private to this index. Resizing moves the table to a new file,
+        which replaces the index file when flushing, so the index file is never in the middle of a resize.
+        """
+        return cls(capacity=None, path=path, permit_compact=permit_compact, mapped=True, shared=shared)
+
+    @classmethod
+    def create_mapped(cls, path, capacity=0, usable=None):
+        """Create a new, empty index file at *path* (replacing an existing one) and map() it."""
+        index = cls(capacity=capacity, path=path, usable=usable, mapped=True)
+        index.flush()
+        return index
+
+    @property
+    def mapped(self):
+        return self.resize_cb is not None
+
+    def flush(self):
+        """Update the header of a mapped index file and write all changes to disk."""
+        if self.resize_cb is None:
+            raise ValueError('index is not mapped')
+        hashindex_sync_header(self.index)
+        hashindex_mapping(self.index).flush()
+        if self.pending:
+            os.replace(self.pending.pop(), self.mapped_path)
+
     def write(self, path):
         if isinstance(path, (str, bytes)):
             with open(path, 'wb') as fd:
@@ -126,11 +224,85 @@ cdef class IndexBase:
             hashindex_write(self.index, path, self.legacy)
 
     def clear(self):
+        if self.journal is not None:
+            raise ValueError('can not clear an index with an active journal')
         hashindex_free(self.index)
-        self.index = hashindex_init(0, self.key_size, self.value_size)
+        if self.resize_cb is not None:
+            self.index = NULL  # in case hashindex_map_init raises
+            self.index = hashindex_map_init(self.resize_cb, 0, self.key_size, self.value_size)
+        else:
+            self.index = hashindex_init(0, self.key_size, self.value_size)
         if not self.index:
             raise Exception('hashindex_init failed')
 
+    def start_journal(self, path=None):
+        """
+        Remember the previous values of all keys changed from now on, so undo_journal() can revert them.
+
+        Every change appends a record to the journal file, so it does not take memory, however many keys are
+        changed. By default, the journal is a temporary file (next to the file of a mapped index). With *path*,
+        the journal is that file and records are written to it right away: after a crash, the index file and the
+        journal can be opened again (an existing journal at *path* is continued) and undone. stop_journal() and
+        undo_journal() do not remove the file at *path*.
+        """
+        self.stop_journal()
+        if path is None:
+            # appending mode: reading the journal (see journal_keys) does not move where the next record goes.
+            self.journal = tempfile.TemporaryFile(mode='a+b', dir=self.journal_dir)
+        else:
+            # unbuffered: a record is in the file before the change it journals is made.
+            self.journal = open(path, 'a+b', buffering=0)
+
+    def stop_journal(self):
+        """Keep the changes made since start_journal()."""
+        journal, self.journal = self.journal, None
+        if journal is not None:
+            journal.close()
+
+    def _journal_blocks(self, reverse=False):
+        """yield the blocks of whole journal records, in the order they were written or in reverse order"""
+        record_size = self.key_size + 1 + self.value_size
+        block_size = JOURNAL_BLOCK_RECORDS * record_size
+        end = self.journal.seek(0, os.SEEK_END)
+        # a partial record at the end was written by a crashed writer, the change it journals was not made.
+        end -= end % record_size
+        starts = range(0, end, block_size)
+        for start in reversed(starts) if reverse else starts:
+            self.journal.seek(start)
+            yield self.journal.read(min(block_size, end - start))
+
+    def journal_keys(self):
+        """Yield the keys changed since start_journal(), a key is yielded for every change of it."""
+        record_size = self.key_size + 1 + self.value_size
+        for block in self._journal_blocks():
+            for offset in range(0, len(block), record_size):
+                yield block[offset:offset + self.key_size]
+
+    def undo_journal(self):
+        """Revert all changes made since start_journal()."""
+        record_size = self.key_size + 1 + self.value_size
+        # going backwards, the oldest record of a key comes last, it has the value from before start_journal().
+        for block in self._journal_blocks(reverse=True):
+            for offset in range(len(block) - record_size, -1, -record_size):
+                key = block[offset:offset + self.key_size]
+                if block[offset + self.key_size]:
+                    value = block[offset + self.key_size + 1:offset + record_size]
+                    if not hashindex_set(self.index, <unsigned char *>key, <unsigned char *>value):
+                        raise Exception('hashindex_set failed')
+                elif hashindex_get(self.index, <unsigned char *>key) != NULL:
+                    if hashindex_delete(self.index, <unsigned char *>key) == 0:
+                        raise Exception('hashindex_delete failed')
+        self.stop_journal()
+
+    cdef _journal(self, key):
+        cdef const unsigned char *value
+        key = bytes(key)
+        value = hashindex_get(self.index, <unsigned char *>key)
+        if value == NULL:
+            self.journal.write(key + bytes(1 + self.value_size))
+        else:
+            self.journal.write(key + b'\x01' + PyBytes_FromStringAndSize(<char *>value, self.value_size))
+
     def setdefault(self, key, value):
         if not key in self:
             self[key] = value
@@ -138,6 +310,8 @@ cdef class IndexBase:
 
     def __delitem__(self, key):
         assert len(key) == self.key_size
+        if self.journal is not None:
+            self._journal(key)
         rc = hashindex_delete(self.index, <unsigned char *>key)
         if rc == 1:
             return  # success
@@ -170,6 +344,8 @@ cdef class IndexBase:
         return hashindex_size(self.index)
 
     def compact(self):
+        if self.resize_cb is not None:
+            raise ValueError('a mapped index can not be compacted')
         return hashindex_compact(self.index)
 
 
@@ -195,6 +371,8 @@ cdef class FuseVersionsIndex(IndexBase):
             raise TypeError("Expected bytes of length 16 for second value")
         memcpy(data.hash, PyBytes_AS_STRING(value[1]), 16)
         data.version = _htole32(data.version)
+        if self.journal is not None:
+            self._journal(key)
         if not hashindex_set(self.index, <unsigned char *>key, <void *> &data):
             raise Exception('hashindex_set failed')
 
@@ -228,6 +406,8 @@ cdef class NSIndex(IndexBase):
         data[1] = _htole32(value[1])
         data[2] = _htole32(value[2])
         data[3] = 0  # init flags to all cleared
+        if self.journal is not None:
+            self._journal(key)
         if not hashindex_set(self.index, <unsigned char *>key, data):
             raise Exception('hashindex_set failed')
 
@@ -264,6 +444,8 @@ cdef class NSIndex(IndexBase):
             raise KeyError(key)
         flags = _le32toh(data[3])
         if isinstance(value, int):
+            if self.journal is not None:
+                self._journal(key)
             new_flags = flags & ~mask  # clear masked bits
             new_flags |= value & mask  # set value bits
             data[3] = _htole32(new_flags)
@@ -331,6 +513,8 @@ cdef class NSIndex1(IndexBase):  # legacy borg 1.x
         assert segment <= _MAX_VALUE, "maximum number of segments reached"
         data[0] = _htole32(segment)
         data[1] = _htole32(value[1])
+        if self.journal is not None:
+            self._journal(key)
         if not hashindex_set(self.index, <unsigned char *>key, data):
             raise Exception('hashindex_set failed')
 
@@ -426,6 +610,8 @@ cdef class ChunkIndex(IndexBase):
         assert refcount <= _MAX_VALUE, "invalid reference count"
         data[0] = _htole32(refcount)
         data[1] = _htole32(value[1])
+        if self.journal is not None:
+            self._journal(key)
         if not hashindex_set(self.index, <unsigned char *>key, data):
             raise Exception('hashindex_set failed')
 
@@ -439,6 +625,8 @@ cdef class ChunkIndex(IndexBase):
     def incref(self, key):
         """Increase refcount for 'key', return (refcount, size)"""
         assert len(key) == self.key_size
+        if self.journal is not None:
+            self._journal(key)
         data = <uint32_t *>hashindex_get(self.index, <unsigned char *>key)
         if not data:
             raise KeyError(key)
@@ -452,6 +640,8 @@ cdef class ChunkIndex(IndexBase):
     def decref(self, key):
         """Decrease refcount for 'key', return (refcount, size)"""
         assert len(key) == self.key_size
+        if self.journal is not None:
+            self._journal(key)
         data = <uint32_t *>hashindex_get(self.index, <unsigned char *>key)
         if not data:
             raise KeyError(key)
@@ -543,6 +733,8 @@ cdef class ChunkIndex(IndexBase):
 
     cdef _add(self, unsigned char *key, uint32_t *data):
         cdef uint64_t refcount1, refcount2, result64
+        if self.journal is not None:
+            self._journal(PyBytes_FromStringAndSize(<char *>key, self.key_size))
         values = <uint32_t*> hashindex_get(self.index, key)
         if values:
             refcount1 = _le32toh(values[0])
@@ -594,6 +786,72 @@ cdef class ChunkKeyIterator:
         return (<char *>self.key)[:self.key_size], ChunkIndexEntry(refcount, _le32toh(value[1]))
 
 
+cdef class RetentionIndex(IndexBase):
+    """
+    Mapping of 32 byte object ids to until when they are retention locked (unix timestamp, 64-bit unsigned).
+
+    The timestamp is stored as (high, low) 32-bit halves, so the first half is far from the values marking
+    empty and deleted buckets.
+    """
+
+    value_size = 8
+
+    def __getitem__(self, key):
+        assert len(key) == self.key_size
+        data = <uint32_t *>hashindex_get(self.index, <unsigned char *>key)
+        if not data:
+            raise KeyError(key)
+        return (<uint64_t>_le32toh(data[0]) << 32) | _le32toh(data[1])
+
+    def __setitem__(self, key, value):
+        assert len(key) == self.key_size
+        cdef uint32_t[2] data
+        cdef uint64_t until = value
+        assert until >> 32 <= _MAX_VALUE, "invalid retention timestamp"
+        data[0] = _htole32(until >> 32)
+        data[1] = _htole32(until & UINT32_MAX)
+        if self.journal is not None:
+            self._journal(key)
+        if not hashindex_set(self.index, <unsigned char *>key, data):
+            raise Exception('hashindex_set failed')
+
+    def __contains__(self, key):
+        assert len(key) == self.key_size
+        return hashindex_get(self.index, <unsigned char *>key) != NULL
+
+    def iteritems(self):
+        iter = RetentionKeyIterator(self.key_size)
+        iter.idx = self
+        iter.index = self.index
+        return iter
+
+
+cdef class RetentionKeyIterator:
+    cdef RetentionIndex idx
+    cdef HashIndex *index
+    cdef const unsigned char *key
+    cdef int key_size
+    cdef int exhausted
+
+    def __cinit__(self, key_size):
+        self.key = NULL
+        self.key_size = key_size
+        self.exhausted = 0
+
+    def __iter__(self):
+        return self
+
+    def __next__(self):
+        if self.exhausted:
+            raise StopIteration
+        self.key = hashindex_next_key(self.index, <unsigned char *>self.key)
+        if not self.key:
+            self.exhausted = 1
+            raise StopIteration
+        cdef uint32_t *value = <uint32_t *>(self.key + self.key_size)
+        return (<char *>self.key)[:self.key_size], (<uint64_t>_le32toh(value[0]) << 32) | _le32toh(value[1])
+
+
 cdef Py_buffer ro_buffer(object data) except *:
     cdef Py_buffer view
     PyObject_GetBuffer(data, &view, PyBUF_SIMPLE)
@@ -606,6 +864,8 @@ cdef class CacheSynchronizer:
 
     def __cinit__(self, chunks):
         self.chunks = chunks
+        if self.chunks.journal is not None:
+            raise ValueError('changes of the cache synchronizer can not be journaled')
         self.sync = cache_sync_init(self.chunks.index)
         if not self.sync:
             raise Exception('cache_sync_init failed')
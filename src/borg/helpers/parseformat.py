@@ -351,6 +351,72 @@ def parse_storage_quota(storage_quota):
     return parsed
 
 
+def parse_rpc_compression(s):
+    """argparse type for the compression of the RPC stream: none or zstd[,L], return None or ("zstd", L)"""
+    name, _, level = s.partition(",")
+    if name == "none" and not level:
+        return None
+    if name == "zstd":
+        try:
+            level = int(level) if level else 3
+        except ValueError:
+            level = 0
+        if 1 <= level <= 22:
+            return name, level
+    raise argparse.ArgumentTypeError(f"invalid RPC compression {s!r}, use none or zstd[,L] (L = 1..22)")
+
+
+def parse_serve_hook(s):
+    """argparse type for borg serve --hook EVENT=COMMAND, return (event, command)"""
+    event, sep, command = s.partition("=")
+    if not sep or event not in SERVE_HOOK_EVENTS or not command.strip():
+        events = ", ".join(SERVE_HOOK_EVENTS)
+        raise argparse.ArgumentTypeError(f"invalid hook {s!r}, use EVENT=COMMAND (EVENT: {events})")
+    return event, command
+
+
+def parse_listen_address(s):
+    """argparse type for a TCP listen address: [HOST:]PORT (default HOST: localhost), return (host, port)"""
+    host, sep, port = s.rpartition(":")
+    host = host.strip("[]") if sep else "localhost"  # [::1]:PORT for ipv6 addrs
+    try:
+        port = int(port)
+    except ValueError:
+        port = -1
+    if not 0 <= port <= 65535:
+        raise argparse.ArgumentTypeError(f"invalid listen address {s!r}, use [HOST:]PORT")
+    return host, port
+
+
+def parse_ratelimit(s):
+    """argparse type for network rate limits: RATE[,HH:MM-HH:MM=RATE,...] in kiByte/s (0=unlimited)
+
+    The optional periods (local time, may wrap around midnight) override the default RATE.
+    Return (rate, ((start, end, rate), ...)) with start and end in minutes after midnight.
+    """
+
+    def minutes(hhmm):
+        hours, colon, mins = hhmm.partition(":")
+        hours, mins = int(hours), int(mins)
+        if not colon or not 0 <= hours <= 24 or not 0 <= mins < 60 or hours * 60 + mins > 24 * 60:
+            raise ValueError
+        return hours * 60 + mins
+
+    rate, *periods = s.split(",")
+    try:
+        rate = int(rate)
+        schedule = []
+        for period in periods:
+            times, _, period_rate = period.partition("=")
+            start, _, end = times.partition("-")
+            schedule.append((minutes(start), minutes(end), int(period_rate)))
+    except ValueError:
+        raise argparse.ArgumentTypeError(f"invalid rate limit {s!r}, expected RATE[,HH:MM-HH:MM=RATE,...]") from None
+    if rate < 0 or any(start == end or period_rate < 0 for start, end, period_rate in schedule):
+        raise argparse.ArgumentTypeError(f"invalid rate limit {s!r}: negative rate or empty period")
+    return rate, tuple(schedule)
+
+
 def sizeof_fmt(num, suffix="B", units=None, power=None, sep="", precision=2, sign=False):
     sign = "+" if sign and num > 0 else ""
     fmt = "{0:{1}.{2}f}{3}{4}{5}"
@@ -415,7 +481,7 @@ class Location:
     # path must not contain :: (it ends at :: or string end), but may contain single colons.
     # to avoid ambiguities with other regexes, it must also not start with ":" nor with "//" nor with "ssh://".
     local_path_re = r"""
-        (?!(:|//|ssh://|socket://))                         # not starting with ":" or // or ssh:// or socket://
+        (?!(:|//|ssh://|sftp://|socket://|s3://|s3\+http://|https?://))  # not starting with ":" or // or a url scheme
         (?P<path>([^:]|(:(?!:)))+)                          # any chars, but no "::"
         """
 
@@ -443,7 +509,7 @@ class Location:
     # regexes for misc. kinds of supported location specifiers:
     ssh_re = re.compile(
         r"""
-        (?P<proto>ssh)://                                       # ssh://
+        (?P<proto>ssh|sftp)://                                  # ssh:// or sftp://
         """
         + optional_user_re
         + host_re
@@ -454,6 +520,33 @@ class Location:
         re.VERBOSE,
     )  # path
 
+    # s3://[profile@]host[:port]/bucket[/prefix], s3+http:// for endpoints without TLS
+    s3_re = re.compile(
+        r"""
+        (?P<proto>s3(\+http)?)://                              # s3:// or s3+http://
+        """
+        + optional_user_re
+        + host_re
+        + r"""                 # profile@  (optional), endpoint host name or address
+        (?::(?P<port>\d+))?                                     # :port (optional)
+        """
+        + abs_path_re,
+        re.VERBOSE,
+    )  # /bucket/prefix
+
+    # http://host[:port]/path or https://..., borg serve --http on the server
+    http_re = re.compile(
+        r"""
+        (?P<proto>https?)://                                    # http:// or https://
+        """
+        + host_re
+        + r"""                 # host name or address
+        (?::(?P<port>\d+))?                                     # :port (optional)
+        """
+        + abs_path_re,
+        re.VERBOSE,
+    )  # path
+
     socket_re = re.compile(
         r"""
         (?P<proto>socket)://                                    # socket://
@@ -525,6 +618,21 @@ def normpath_special(p):
             self.port = m.group("port") and int(m.group("port")) or None
             self.path = normpath_special(m.group("path"))
             return True
+        m = self.s3_re.match(text)
+        if m:
+            self.proto = m.group("proto")
+            self.user = m.group("user")
+            self._host = m.group("host")
+            self.port = m.group("port") and int(m.group("port")) or None
+            self.path = os.path.normpath(m.group("path"))
+            return True
+        m = self.http_re.match(text)
+        if m:
+            self.proto = m.group("proto")
+            self._host = m.group("host")
+            self.port = m.group("port") and int(m.group("port")) or None
+            self.path = normpath_special(m.group("path"))
+            return True
         m = self.file_re.match(text)
         if m:
             self.proto = m.group("proto")
@@ -582,7 +690,8 @@ def canonical_path(self):
                 path = "/./" + self.path  # /./x = path x relative to cwd
             else:
                 path = self.path
-            return "ssh://{}{}{}{}".format(
+            return "{}://{}{}{}{}".format(
+                self.proto,
                 f"{self.user}@" if self.user else "",
                 self._host,  # needed for ipv6 addrs
                 f":{self.port}" if self.port else "",
@@ -46,7 +46,7 @@ def output(self, msg):
 class ProgressIndicatorPercent(ProgressIndicatorBase):
     JSON_TYPE = "progress_percent"
 
-    def __init__(self, total=0, step=5, start=0, msg="%3.0f%%", msgid=None):
+    def __init__(self, total=0, step=5, start=0, msg="%3.0f%%", msgid=None, interval=None):
         """
         Percentage-based progress indicator
 
@@ -54,12 +54,15 @@ def __init__(self, total=0, step=5, start=0, msg="%3.0f%%", msgid=None):
         :param step: step size in percent
         :param start: at which percent value to start
         :param msg: output message, must contain one %f placeholder for the percentage
+        :param interval: also output if this many seconds passed since the last output (for slow progress)
         """
         self.counter = 0  # 0 .. (total-1)
         self.total = total
         self.trigger_at = start  # output next percentage value when reaching (at least) this
         self.step = step
         self.msg = msg
+        self.interval = interval
+        self.last_output = time.monotonic()
 
         super().__init__(msgid=msgid)
 
@@ -70,7 +73,11 @@ def progress(self, current=None, increase=1):
         self.counter += increase
         if pct >= self.trigger_at:
             self.trigger_at += self.step
-            return pct
+        elif self.interval is None or time.monotonic() < self.last_output + self.interval:
+            return None
+        if self.interval is not None:
+            self.last_output = time.monotonic()
+        return pct
 
     def show(self, current=None, increase=1, info=None):
         """
@@ -233,6 +233,12 @@ def __init__(self, key, repository, item_keys=None, ro_cls=RepoObj):
         self.repository = repository
         self.item_keys = frozenset(item_keys) if item_keys is not None else ITEM_KEYS
         self.timestamp = None
+        # the archives as loaded from / written to the repository, to find our changes when merging.
+        self.base_archives = {}
+        # True if merging brought in changes made by other clients (see merge)
+        self.foreign_changes = False
+        # the repository chunk index, updated when writing the manifest (see chunkindex)
+        self.chunk_index = None
 
     @property
     def id_str(self):
@@ -262,6 +268,7 @@ def load(cls, repository, operations, key=None, *, ro_cls=RepoObj):
         if m.get("version") not in (1, 2):
             raise ValueError("Invalid manifest version")
         manifest.archives.set_raw_dict(m.archives)
+        manifest.base_archives = dict(m.archives)
         manifest.timestamp = m.get("timestamp")
         manifest.config = m.config
         # valid item keys are whatever is known in the repo or every key we know
@@ -296,9 +303,43 @@ def get_all_mandatory_features(self):
                 result[operation] = set(requirements["mandatory"])
         return result
 
+    def merge(self):
+        """
+        Merge our changes of the archives list into the manifest most recently committed to the repository.
+
+        Needed if other clients may write to the repository at the same time (see Repository concurrent writers),
+        the caller must hold the repository's commit lock.
+        """
+        from .archive import Archive
+
+        latest = Manifest.load(self.repository, self.NO_OPERATION_CHECK, key=self.key, ro_cls=type(self.repo_objs))
+        if latest.id == getattr(self, "id", None):
+            return  # nobody else committed a manifest
+        archives = dict(latest.archives.get_raw_dict())
+        self.foreign_changes = self.foreign_changes or archives != self.base_archives
+        ours = self.archives.get_raw_dict()
+        for name in self.base_archives.keys() - ours.keys():
+            archives.pop(name, None)  # we deleted it
+        for name, info in ours.items():
+            if self.base_archives.get(name) != info:  # we added or replaced it
+                if name in archives and archives[name] != self.base_archives.get(name):
+                    raise Archive.AlreadyExists(name)  # another client did the same
+                archives[name] = info
+        self.archives = Archives()
+        self.archives.set_raw_dict(archives)
+        self.base_archives = dict(latest.archives.get_raw_dict())
+        self.config = latest.config
+        self.item_keys |= latest.item_keys
+        if self.timestamp is None or latest.timestamp and latest.last_timestamp > self.last_timestamp:
+            self.timestamp = latest.timestamp
+
     def write(self):
         from .item import ManifestItem
 
+        if getattr(self.repository, "concurrent", False):
+            # other clients may have committed since we loaded the manifest.
+            self.repository.begin_commit()
+            self.merge()
         # self.timestamp needs to be strictly monotonically increasing. Clocks often are not set correctly
         if self.timestamp is None:
             self.timestamp = datetime.now(tz=timezone.utc).isoformat(timespec="microseconds")
@@ -312,6 +353,8 @@ def write(self):
         assert all(len(name) <= 255 for name in self.archives)
         assert len(self.item_keys) <= 100
         self.config["item_keys"] = tuple(sorted(self.item_keys))
+        if self.chunk_index is not None:
+            self.chunk_index.write()
         manifest = ManifestItem(
             version=2,
             archives=StableDict(self.archives.get_raw_dict()),
@@ -321,3 +364,4 @@ def write(self):
         data = self.key.pack_metadata(manifest.as_dict())
         self.id = self.repo_objs.id_hash(data)
         self.repository.put(self.MANIFEST_ID, self.repo_objs.format(self.MANIFEST_ID, {}, data, ro_type=ROBJ_MANIFEST))
+        self.base_archives = dict(self.archives.get_raw_dict())
@@ -0,0 +1,99 @@
+"""
+Incremental mirroring of a repository, see borg rmirror.
+
+Committed segments are never modified (only deleted by compaction), so a mirror of a repository can be
+updated by copying the segments it does not have yet (compared by number and size), the index, hints and
+integrity files of the latest transaction and by deleting the segments the source does not have any more.
+
+Everything is written to a staging area in the target first. Only after the received segments were
+verified, the target switches to the mirrored transaction (see Repository.mirror_commit).
+"""
+
+from .helpers import Error, ProgressIndicatorPercent, format_file_size
+from .logger import create_logger
+
+logger = create_logger(__name__)
+
+# how much to transfer per RPC call
+MIRROR_BLOCK_SIZE = 8 * 1024 * 1024
+
+
+class NotAMirror(Error):
+    """Repository {} is not a mirror of repository {}."""
+
+    exit_mcode = 23
+
+
+def repository_name(repository):
+    location = getattr(repository, "location", None)  # RemoteRepository
+    return location.canonical_path() if location is not None else repository.path
+
+
+def copy_file(source, target, kind, number, size=None, pi=None):
+    """copy a file (*size* bytes, if known, else until EOF) from source to target, return the copied size"""
+    offset = 0
+    while True:
+        data = source.mirror_read(kind, number, offset, MIRROR_BLOCK_SIZE)
+        if data or offset == 0:
+            target.mirror_write(kind, number, offset, data)
+        offset += len(data)
+        if pi is not None:
+            pi.show(increase=len(data))
+        if not data or offset == size:
+            return offset
+
+
+def mirror_repository(source, target, *, dry_run=False, progress=False):
+    """
+    Update *target* to be a mirror of the current transaction of *source*.
+
+    *target* may also be an empty repository, it gets the id of *source* then.
+    Returns a dict with statistics.
+    """
+    source_name, target_name = repository_name(source), repository_name(target)
+    src = source.mirror_info()
+    dst = target.mirror_info()
+    if src["version"] != dst["version"]:
+        raise Error(f"Can not mirror a version {src['version']} repository to a version {dst['version']} repository.")
+    if src["id"] != dst["id"] and (dst["transaction_id"] is not None or dst["segments"]):
+        raise NotAMirror(target_name, source_name)
+    transaction_id = src["transaction_id"]
+    if transaction_id is None:
+        raise Error(f"Repository {source_name} is empty, there is nothing to mirror.")
+    if dst["transaction_id"] is not None and dst["transaction_id"] > transaction_id:
+        raise NotAMirror(target_name, source_name)  # it is ahead of the source
+    present = {segment: size for segment, size in dst["segments"]}
+    wanted = {segment for segment, _ in src["segments"]}
+    missing = [(segment, size) for segment, size in src["segments"] if present.get(segment) != size]
+    stats = dict(
+        transaction_id=transaction_id,
+        copied_segments=len(missing),
+        copied_size=sum(size for _, size in missing),
+        deleted_segments=len(set(present) - wanted),
+    )
+    up_to_date = dst["transaction_id"] == transaction_id and not missing and not stats["deleted_segments"]
+    if not dry_run and not up_to_date:
+        pi = None
+        if progress:
+            pi = ProgressIndicatorPercent(total=stats["copied_size"], msg="Copying segments %3.0f%%", msgid="rmirror")
+        for segment, size in missing:
+            copy_file(source, target, "segment", segment, size, pi=pi)
+        if pi is not None:
+            pi.finish()
+        for kind in ("index", "hints", "integrity"):
+            copy_file(source, target, kind, transaction_id)
+        target.mirror_commit(src["id"], transaction_id, src["segments"])
+    if not dry_run:
+        # the repokey (if any) might have changed, e.g. by borg key change-passphrase
+        keydata = source.load_key()
+        if keydata != target.load_key():
+            target.save_key(keydata)
+    logger.info(
+        "%s transaction %d: %d segments (%s) copied, %d segments deleted.",
+        "Would mirror" if dry_run else "Mirrored",
+        transaction_id,
+        stats["copied_segments"],
+        format_file_size(stats["copied_size"]),
+        stats["deleted_segments"],
+    )
+    return stats
@@ -0,0 +1,237 @@
+"""
+Reed-Solomon parity data for segment files.
+
+A segment file is split into stripes of k data shards of SHARD_SIZE bytes each (the last stripe is
+padded with zeros). For each stripe, m parity shards are computed, so that any m damaged shards of
+the stripe can be rebuilt from the others.
+
+The parity file of a segment (data/<X // SEGMENTS_PER_DIR>/<X>.parity) looks like this:
+
+- a header: magic, k, m, shard size, size of the segment file, crc32 of all this
+- for each stripe: the crc32 of each of the k + m shards, the crc32 of these crc32s, the m parity shards
+
+The shard crc32s tell which shards of a stripe are damaged, so the erasure decoding knows where the
+errors are. Damage to the parity file itself is detected the same way and just makes the affected
+stripes unrecoverable, the segment file is not touched then.
+"""
+
+import struct
+from collections import namedtuple
+from functools import lru_cache
+
+from .checksums import crc32
+
+PARITY_MAGIC = b"BORG_PAR"
+
+# small enough that some bit rot usually only damages a single shard of a stripe, large enough to keep
+# the overhead of the per-shard crc32s and the Python code low.
+SHARD_SIZE = 4096
+
+header_fmt = struct.Struct("<8sBBIQ")
+crc_fmt = struct.Struct("<I")
+
+# arithmetic in GF(2**8), with the primitive polynomial x**8 + x**4 + x**3 + x**2 + 1
+GF_EXP = bytearray(512)
+GF_LOG = bytearray(256)
+_x = 1
+for _i in range(255):
+    GF_EXP[_i] = _x
+    GF_LOG[_x] = _i
+    _x <<= 1
+    if _x & 0x100:
+        _x ^= 0x11D
+GF_EXP[255:510] = GF_EXP[:255]
+
+
+def gf_mul(a, b):
+    if a == 0 or b == 0:
+        return 0
+    return GF_EXP[GF_LOG[a] + GF_LOG[b]]
+
+
+def gf_inv(a):
+    if a == 0:
+        raise ZeroDivisionError("0 has no inverse")
+    return GF_EXP[255 - GF_LOG[a]]
+
+
+@lru_cache(maxsize=256)
+def gf_mul_table(c):
+    """return a bytes.translate() table which multiplies each byte with *c*"""
+    return bytes(gf_mul(c, x) for x in range(256))
+
+
+def gf_dot(coefficients, shards, size):
+    """return the sum of the products of the coefficients and the shards (bytes objects of *size* bytes)"""
+    # multiplying is a byte translation, adding is xor - both run at C speed on whole shards this way.
+    result = 0
+    for c, shard in zip(coefficients, shards):
+        if c:
+            result ^= int.from_bytes(shard if c == 1 else shard.translate(gf_mul_table(c)), "little")
+    return result.to_bytes(size, "little")
+
+
+def gf_invert_matrix(matrix):
+    """invert a square matrix over GF(2**8) (Gauss-Jordan elimination)"""
+    n = len(matrix)
+    rows = [list(row) + [int(i == j) for j in range(n)] for i, row in enumerate(matrix)]
+    for col in range(n):
+        pivot = next((r for r in range(col, n) if rows[r][col]), None)
+        if pivot is None:
+            raise ValueError("matrix is singular")
+        rows[col], rows[pivot] = rows[pivot], rows[col]
+        inv = gf_inv(rows[col][col])
+        rows[col] = [gf_mul(inv, x) for x in rows[col]]
+        for r in range(n):
+            factor = rows[r][col]
+            if r != col and factor:
+                rows[r] = [x ^ gf_mul(factor, y) for x, y in zip(rows[r], rows[col])]
+    return [row[n:] for row in rows]
+
+
+class ReedSolomon:
+    """A systematic Reed-Solomon erasure code with *data_shards* data shards and *parity_shards* parity shards"""
+
+    def __init__(self, data_shards, parity_shards):
+        if data_shards < 1 or parity_shards < 1 or data_shards + parity_shards > 256:
+            raise ValueError(f"unsupported number of shards: {data_shards} data + {parity_shards} parity (max. 256)")
+        self.data_shards = data_shards
+        self.parity_shards = parity_shards
+        # a Cauchy matrix: any square submatrix of the identity matrix stacked onto it is invertible,
+        # thus any data_shards of the data_shards + parity_shards shards are enough to rebuild the data.
+        self.matrix = [[gf_inv((data_shards + j) ^ i) for i in range(data_shards)] for j in range(parity_shards)]
+
+    def encode(self, shards):
+        """return the parity shards for the data shards"""
+        size = len(shards[0])
+        return [gf_dot(row, shards, size) for row in self.matrix]
+
+    def reconstruct(self, shards):
+        """return the data shards, rebuilt from the data and parity shards (None for damaged shards)"""
+        k = self.data_shards
+        available = [i for i, shard in enumerate(shards) if shard is not None]
+        if len(available) < k:
+            raise ValueError(f"too many damaged shards: only {len(available)} of the required {k} shards available")
+        missing = [i for i in range(k) if shards[i] is None]
+        if not missing:
+            return list(shards[:k])
+        rows = available[:k]
+        identity = [[int(i == j) for j in range(k)] for i in range(k)]
+        decode_matrix = gf_invert_matrix([identity[i] if i < k else self.matrix[i - k] for i in rows])
+        sources = [shards[i] for i in rows]
+        size = len(sources[0])
+        data = list(shards[:k])
+        for i in missing:
+            data[i] = gf_dot(decode_matrix[i], sources, size)
+        return data
+
+
+class ParityError(Exception):
+    """Invalid parity file."""
+
+
+# damaged: number of stripes with damaged data shards (a wrong segment size counts as one)
+# unrecoverable: number of damaged stripes that can not be rebuilt
+# parity_damaged: number of stripes with damaged parity data
+ParityReport = namedtuple("ParityReport", "damaged unrecoverable parity_damaged")
+
+
+def read_stripe(fd, data_shards, size):
+    """read up to *size* bytes of a stripe from *fd*, return the (zero padded) data shards"""
+    data = fd.read(min(size, data_shards * SHARD_SIZE)).ljust(data_shards * SHARD_SIZE, b"\0")
+    return [data[i * SHARD_SIZE : (i + 1) * SHARD_SIZE] for i in range(data_shards)]
+
+
+def pack_crcs(shards):
+    crcs = struct.pack("<%dI" % len(shards), *(crc32(shard) for shard in shards))
+    return crcs + crc_fmt.pack(crc32(crcs))
+
+
+def write_parity(rs, segment_fd, size, parity_fd):
+    """compute the parity data of the segment file (*size* bytes, read from *segment_fd*), write it to *parity_fd*"""
+    header = header_fmt.pack(PARITY_MAGIC, rs.data_shards, rs.parity_shards, SHARD_SIZE, size)
+    parity_fd.write(header + crc_fmt.pack(crc32(header)))
+    remaining = size
+    while remaining > 0:
+        shards = read_stripe(segment_fd, rs.data_shards, remaining)
+        remaining -= rs.data_shards * SHARD_SIZE
+        parity = rs.encode(shards)
+        parity_fd.write(pack_crcs(shards + parity) + b"".join(parity))
+
+
+class SegmentParity:
+    """The parity file of a segment, read from *fd*"""
+
+    def __init__(self, fd):
+        self.fd = fd
+        data = fd.read(header_fmt.size + crc_fmt.size)
+        if len(data) != header_fmt.size + crc_fmt.size:
+            raise ParityError("parity file too short")
+        header, (crc,) = data[: header_fmt.size], crc_fmt.unpack(data[header_fmt.size :])
+        if crc32(header) != crc:
+            raise ParityError("parity header checksum mismatch")
+        magic, self.data_shards, self.parity_shards, self.shard_size, self.size = header_fmt.unpack(header)
+        if magic != PARITY_MAGIC or self.shard_size != SHARD_SIZE:
+            raise ParityError("unsupported parity file format")
+        self.rs = ReedSolomon(self.data_shards, self.parity_shards)
+
+    def stripes(self, segment_fd):
+        """
+        Yield (data shards, shards) for each stripe of the segment file read from *segment_fd*.
+
+        shards are the data and parity shards with None for damaged ones, or None if the parity data of the stripe is
+        damaged (we can not tell whether the data shards are fine then).
+        """
+        k, m = self.data_shards, self.parity_shards
+        crcs_fmt = struct.Struct("<%dI" % (k + m))
+        record_size = crcs_fmt.size + crc_fmt.size + m * SHARD_SIZE
+        self.fd.seek(header_fmt.size + crc_fmt.size)
+        remaining = self.size
+        while remaining > 0:
+            data = read_stripe(segment_fd, k, remaining)
+            remaining -= k * SHARD_SIZE
+            record = self.fd.read(record_size)
+            crcs, (crc,) = record[: crcs_fmt.size], crc_fmt.unpack_from(record.ljust(record_size, b"\0"), crcs_fmt.size)
+            if len(record) != record_size or crc32(crcs) != crc:
+                yield data, None
+                continue
+            parity_offset = crcs_fmt.size + crc_fmt.size
+            parity = [record[parity_offset + j * SHARD_SIZE : parity_offset + (j + 1) * SHARD_SIZE] for j in range(m)]
+            shards = [shard if crc32(shard) == c else None for shard, c in zip(data + parity, crcs_fmt.unpack(crcs))]
+            yield data, shards
+
+    def verify(self, segment_fd, segment_size):
+        """verify the segment file read from *segment_fd*, return a ParityReport"""
+        k = self.data_shards
+        damaged = unrecoverable = parity_damaged = 0
+        for data, shards in self.stripes(segment_fd):
+            if shards is None:
+                parity_damaged += 1
+                continue
+            if any(shard is None for shard in shards[k:]):
+                parity_damaged += 1
+            if any(shard is None for shard in shards[:k]):
+                damaged += 1
+                if sum(shard is not None for shard in shards) < k:
+                    unrecoverable += 1
+        if segment_size != self.size and not damaged:
+            damaged = 1
+        return ParityReport(damaged, unrecoverable, parity_damaged)
+
+    def rebuild(self, segment_fd, dst_fd):
+        """
+        Write the segment file read from *segment_fd* to *dst_fd*, with damaged stripes rebuilt.
+
+        Stripes that can not be rebuilt are written as they are. Returns the number of such stripes.
+        """
+        unrecoverable = 0
+        remaining = self.size
+        for data, shards in self.stripes(segment_fd):
+            if shards is not None:
+                try:
+                    data = self.rs.reconstruct(shards)
+                except ValueError:
+                    unrecoverable += 1
+            dst_fd.write(b"".join(data)[:remaining])
+            remaining -= self.data_shards * SHARD_SIZE
+        return unrecoverable
@@ -1,32 +1,48 @@
 import atexit
+import base64
+import binascii
+import configparser
 import errno
+import fnmatch
 import functools
+import getpass
+import hashlib
+import hmac
 import inspect
+import ipaddress
+import json
 import logging
 import os
+import pwd
 import queue
 import select
 import shlex
 import shutil
 import socket
+import ssl
 import struct
 import sys
 import tempfile
 import textwrap
+import threading
 import time
 import traceback
-from subprocess import Popen, PIPE
+import urllib.parse
+from collections import deque
+from datetime import datetime, timezone
+from subprocess import Popen, PIPE, DEVNULL, TimeoutExpired
 
 import borg.logger
 from . import __version__
-from .compress import Compressor
+from .compress import Compressor, ZSTD, CNONE
 from .constants import *  # NOQA
 from .helpers import Error, ErrorWithTraceback, IntegrityError
 from .helpers import bin_to_hex
+from .helpers import get_cache_dir
 from .helpers import get_limited_unpacker
 from .helpers import replace_placeholders
 from .helpers import sysinfo
-from .helpers import format_file_size
+from .helpers import format_file_size, parse_file_size
 from .helpers import safe_unlink
 from .helpers import prepare_subprocess_env, ignore_sigint
 from .helpers import get_socket_filename
@@ -42,10 +58,35 @@
 
 BORG_VERSION = parse_version(__version__)
 MSGID, MSG, ARGS, RESULT, LOG = "i", "m", "a", "r", "l"
+CHANNEL, WINDOW = "c", "w"  # protocol 2
 
 MAX_INFLIGHT = 100
 
+# RPC protocol 2 adds flow controlled streaming of gets on separate channels and a write window for puts.
+RPC_PROTOCOL = 2
+CHANNEL_CONTROL, CHANNEL_GET, CHANNEL_PRELOAD = 0, 1, 2
+RPC_WINDOW = 32 * 1024 * 1024  # response bytes (per stream channel) / put bytes in flight, at least a link's BDP
+STREAM_MSG_OVERHEAD = 64  # accounted per message in addition to the data
+MAX_INFLIGHT_STREAMING = 10000  # with protocol 2, the windows limit how much data is in flight
+MAX_QUEUED_REQUESTS = 2 * MAX_INFLIGHT_STREAMING  # borg serve does not read more requests while it has queued that many
+
+# requests which can be sent again after reconnecting, although the server might have processed them already
+REPLAYABLE_RPCS = {"get", "put", "list", "scan", "flags", "flags_many", "info", "__len__", "load_key", "get_retention"}
+
+# zstd compression of the RPC stream: (compressor, level) the client offers, see RPCCompression
+RPC_COMPRESSION = ("zstd", 3)
+RPC_FRAME_SIZE = 4 * 1024 * 1024  # compress at most this much (plus one message) into a frame
+MAX_RPC_FRAME_SIZE = RPC_FRAME_SIZE + 2 * MAX_OBJECT_SIZE
+
 RATELIMIT_PERIOD = 0.1
+RATELIMIT_SCHEDULE_INTERVAL = 10  # seconds between checks of the rate limit schedule
+
+# http:// and https:// locations (borg serve --http) upgrade an HTTP/1.1 connection to the RPC stream
+HTTP_UPGRADE = "borg-rpc"
+HTTP_TIMEOUT = 30  # seconds for connecting and for the whole upgrade handshake
+HTTP_MAX_HEAD_SIZE = 16 * 1024
+HTTP_MAX_HANDSHAKES = 64  # connections borg serve --http does the handshake with at the same time
+TLS_RELAY_BUFSIZE = 1024 * 1024
 
 
 def os_write(fd, data):
@@ -67,6 +108,51 @@ def os_write(fd, data):
     return amount
 
 
+class RPCCompression:
+    """
+    zstd compression of the RPC stream.
+
+    If client and server negotiated it, both send everything after the negotiate request / response in frames:
+    data size (32 bit), compression type and level, compressed data. A frame contains complete messages.
+    The compression type is zstd or none (the zstd compressor stores incompressible data as is), a frame
+    must not decompress to more than MAX_RPC_FRAME_SIZE.
+    """
+
+    header = struct.Struct("<IBB")
+
+    def __init__(self, level):
+        self.compressor = ZSTD(level=level)
+        self.received = bytearray()  # begin of a frame not received completely yet
+
+    def compress(self, data):
+        meta, compressed = self.compressor.compress({}, data)
+        return self.header.pack(len(compressed), meta["ctype"], meta["clevel"]) + compressed
+
+    def decompress(self, data):
+        """return the data of all frames *data* completes, raise ValueError for invalid frames"""
+        self.received += data
+        result = []
+        while len(self.received) >= self.header.size:
+            size, ctype, clevel = self.header.unpack_from(self.received)
+            if size > MAX_RPC_FRAME_SIZE:
+                raise ValueError("RPC frame too large")
+            end = self.header.size + size
+            if len(self.received) < end:
+                break
+            data = bytes(self.received[self.header.size : end])
+            if ctype == ZSTD.ID:
+                meta = dict(ctype=ctype, clevel=clevel, csize=size)
+                try:
+                    _, data = self.compressor.decompress(meta, data, max_size=MAX_RPC_FRAME_SIZE)
+                except Exception as e:
+                    raise ValueError(f"RPC frame can not be decompressed: {e}") from None
+            elif ctype != CNONE.ID:
+                raise ValueError(f"RPC frame has an unexpected compression type {ctype}")
+            result.append(data)
+            del self.received[:end]
+        return b"".join(result)
+
+
 class ConnectionClosed(Error):
     """Connection closed by remote host"""
 
@@ -91,6 +177,12 @@ class InvalidRPCMethod(Error):
     exit_mcode = 82
 
 
+class PolicyDenied(Error):
+    """Not allowed by the borg serve policy: {}"""
+
+    exit_mcode = 88
+
+
 class UnexpectedRPCDataFormatFromClient(Error):
     """Borg {}: Got unexpected RPC data format from client."""
 
@@ -118,6 +210,451 @@ class ConnectionBrokenWithHint(Error):
     exit_mcode = 87
 
 
+class AuditLog:
+    """
+    The audit log of borg serve (--audit-log FILE): JSON lines recording the client sessions.
+
+    A session is one ssh connection (or one connection to the socket server). A "start" entry is
+    written when it begins (the client identity: the ssh environment variables, the peer of a
+    socket connection), followed by an entry for every repository it opens, every commit and every
+    denied operation as they happen. The "end" entry has the RPC calls with their object and byte
+    counts. All entries of a session have the same "session" value, so a session without an "end"
+    entry is still visible if borg serve gets killed.
+    """
+
+    # ssh sets these, SSH_USER_AUTH only with ExposeAuthInfo yes (sshd_config).
+    IDENTITY_ENV_VARS = ("SSH_CONNECTION", "SSH_CLIENT", "SSH_USER_AUTH", "USER", "LOGNAME")
+    DENIED = (
+        PathNotAllowed,
+        PolicyDenied,
+        InvalidRPCMethod,
+        Repository.PathPermissionDenied,
+        Repository.StorageQuotaExceeded,
+        Repository.RetentionLocked,
+    )
+
+    def __init__(self, path):
+        self.path = path
+        self.session = None
+        open(path, "a").close()  # fail early if we can not write to it
+
+    def write(self, event, **entry):
+        entry.update(event=event, session=self.session["id"], time=datetime.now(timezone.utc).isoformat())
+        # a single write of a line in append mode does not mix with the entries of other borg serve processes.
+        with open(self.path, "a") as fd:
+            fd.write(json.dumps(entry, sort_keys=True) + "\n")
+
+    def start(self, peer=None, client_id=None):
+        self.session = dict(id=bin_to_hex(os.urandom(8)), paths=[], methods={}, disconnected=False)
+        client = {name: os.environ[name] for name in self.IDENTITY_ENV_VARS if name in os.environ}
+        if peer is not None:
+            client["peer"] = peer
+        if client_id is not None:
+            client["id"] = client_id
+        self.write("start", pid=os.getpid(), client=client)
+
+    def call(self, method, args, result=None, exception=None):
+        """record a RPC call (and its result or exception)"""
+        stats = self.session["methods"].setdefault(method, dict(calls=0, objects=0, bytes=0, errors=0))
+        stats["calls"] += 1
+        if isinstance(args, dict):
+            if "ids" in args and isinstance(args["ids"], (list, tuple)):
+                stats["objects"] += len(args["ids"])
+            elif "id" in args:
+                stats["objects"] += 1
+            if isinstance(args.get("data"), bytes):
+                stats["bytes"] += len(args["data"])
+        if isinstance(result, bytes):
+            stats["bytes"] += len(result)
+        if exception is not None:
+            stats["errors"] += 1
+            if isinstance(exception, self.DENIED):
+                path = args.get("path") if isinstance(args, dict) else None
+                self.write(
+                    "denied",
+                    method=method,
+                    path=os.fsdecode(path) if isinstance(path, (str, bytes)) else None,
+                    reason=exception.__class__.__name__,
+                    message=exception.get_message(),
+                )
+
+    def opened(self, path):
+        if path not in self.session["paths"]:
+            self.session["paths"].append(path)
+            self.write("open", path=path)
+
+    def committed(self, path):
+        self.write("commit", path=path)
+
+    def dropped(self):
+        """the connection dropped while the client had a repository open"""
+        self.session["disconnected"] = True
+
+    def end(self, client_version=None):
+        """write the end entry of the session"""
+        if self.session is None:
+            return
+        self.write(
+            "end",
+            client_version=format_version(client_version) if client_version else None,
+            paths=self.session["paths"],
+            methods=self.session["methods"],
+            disconnected=self.session["disconnected"],
+        )
+        self.session = None
+
+
+def ssh_key_fingerprints(path):
+    """return the fingerprints (SHA256:..., like ssh-keygen -l) of the keys a ssh client authenticated with"""
+    # *path* is the SSH_USER_AUTH file of sshd (ExposeAuthInfo yes), a line per authentication method.
+    fingerprints = []
+    with open(path) as fd:
+        for line in fd:
+            fields = line.split()
+            if len(fields) < 3 or fields[0] != "publickey":
+                continue
+            try:
+                blob = base64.b64decode(fields[2], validate=True)
+            except (ValueError, binascii.Error):
+                continue
+            fingerprints.append("SHA256:" + base64.b64encode(hashlib.sha256(blob).digest()).decode().rstrip("="))
+    return fingerprints
+
+
+def is_loopback(host):
+    """return whether *host* (a name or an address) only refers to loopback addresses"""
+    try:
+        return ipaddress.ip_address(host).is_loopback
+    except ValueError:
+        pass
+    try:
+        addresses = {info[4][0] for info in socket.getaddrinfo(host, None)}
+    except OSError:
+        return False
+    return bool(addresses) and all(ipaddress.ip_address(address.partition("%")[0]).is_loopback for address in addresses)
+
+
+class ServePolicy:
+    """
+    The access control policy of borg serve (--policy FILE).
+
+    FILE has a section per client identity (see RepositoryServer.client_identity), values in the
+    [DEFAULT] section apply to all clients::
+
+        [alice]
+        repositories = /srv/borg/alice
+            /srv/borg/shared/*
+        role = append-only
+        storage_quota = 100G
+        ssh_keys = SHA256:bcEbJ1z8w5RpzFrdnkU5Lb6F0vdZ1xV6Lt5pdGZ9B1s
+
+    *repositories* are shell-style patterns (one per line) the resolved repository path must match,
+    *role* is read-only, append-only or full (default: read-only), *storage_quota* overrides the quota
+    of the repositories, *ssh_keys* are the fingerprints (one per line) of the keys identifying the client
+    for ssh connections. Clients without a section are not allowed to open any repository.
+    Read-only clients may not lock the repository, they need to use --bypass-lock.
+    """
+
+    ROLES = ("read-only", "append-only", "full")
+    READ_ONLY_METHODS = {
+        "__len__",
+        "check",
+        "check_summary",
+        "close",
+        "flags",
+        "flags_many",
+        "get",
+        "get_retention",
+        "info",
+        "list",
+        "load_key",
+        "mirror_info",
+        "mirror_read",
+        "negotiate",
+        "open",
+        "rollback",
+        "scan",
+    }
+    APPEND_ONLY_METHODS = READ_ONLY_METHODS | {"begin_commit", "commit", "delete", "put", "retention_lock"}
+
+    class Client:
+        def __init__(self, identity, repositories, role, storage_quota):
+            self.identity = identity
+            self.repositories = repositories
+            self.role = role
+            self.storage_quota = storage_quota
+
+        def allows_repository(self, path):
+            return any(fnmatch.fnmatchcase(path, pattern) for pattern in self.repositories)
+
+        def check_call(self, method, args):
+            """raise PolicyDenied if the role of the client does not allow the call"""
+            if self.role == "full":
+                return
+            args = args if isinstance(args, dict) else {}
+            if self.role == "read-only":
+                allowed = method in ServePolicy.READ_ONLY_METHODS
+                # locks would block the writers: read-only clients need to open without a lock (--bypass-lock).
+                if method == "open" and (args.get("create") or args.get("exclusive") or args.get("lock", True)):
+                    allowed = False
+                if method in ("flags", "flags_many") and args.get("value") is not None:
+                    allowed = False
+            else:
+                allowed = method in ServePolicy.APPEND_ONLY_METHODS
+            if method == "check" and args.get("repair"):
+                allowed = False
+            if not allowed:
+                raise PolicyDenied(f"{method} for client {self.identity} with role {self.role}")
+
+    def __init__(self, path):
+        self.path = path
+        self.load()  # fail early if the policy is invalid
+
+    def load(self):
+        config = configparser.ConfigParser(interpolation=None)
+        try:
+            with open(self.path) as fd:
+                config.read_file(fd)
+        except (OSError, configparser.Error) as e:
+            raise Error(f"Invalid borg serve policy file {self.path}: {e}") from None
+        clients = {}
+        ssh_keys = {}  # fingerprint -> identity
+        for identity in config.sections():
+            section = config[identity]
+            role = section.get("role", "read-only")
+            if role not in self.ROLES:
+                raise Error(f"Invalid role {role!r} for client {identity} in borg serve policy file {self.path}.")
+            repositories = [line.strip() for line in section.get("repositories", "").splitlines() if line.strip()]
+            try:
+                quota = parse_file_size(section["storage_quota"]) if section.get("storage_quota") else None
+            except ValueError:
+                raise Error(
+                    f"Invalid storage_quota for client {identity} in borg serve policy file {self.path}."
+                ) from None
+            clients[identity] = self.Client(identity, repositories, role, quota)
+            for fingerprint in section.get("ssh_keys", "").split():
+                if ssh_keys.setdefault(fingerprint, identity) != identity:
+                    raise Error(
+                        f"The ssh key {fingerprint} of client {identity} in borg serve policy file {self.path} "
+                        f"is also the key of client {ssh_keys[fingerprint]}."
+                    )
+        self.clients = clients
+        self.ssh_keys = ssh_keys
+
+    def client(self, identity):
+        """return the Client policy for *identity*, None if it is unknown"""
+        return self.clients.get(identity)
+
+    def ssh_key_identity(self, fingerprints):
+        """return the identity of the client having one of the ssh keys *fingerprints*, None if it is unknown"""
+        for fingerprint in fingerprints:
+            if fingerprint in self.ssh_keys:
+                return self.ssh_keys[fingerprint]
+        return None
+
+
+class ServeHooks:
+    """
+    The hook commands of borg serve (--hook EVENT=COMMAND).
+
+    They run via the shell after a commit, after the rollback of a transaction with changes (a failed commit,
+    an explicit rollback or the client closing or dropping the connection) and when the storage quota is
+    exceeded. borg serve does not wait for them, they get the details in the environment and no input or output.
+    """
+
+    def __init__(self, hooks):
+        self.commands = {}
+        for event, command in hooks:
+            self.commands.setdefault(event, []).append(command)
+        self.reset()
+
+    def reset(self):
+        """start a new transaction"""
+        self.objects_written = self.bytes_written = self.objects_deleted = 0
+        self.quota_exceeded = False
+
+    @property
+    def changed(self):
+        return bool(self.objects_written or self.objects_deleted or self.quota_exceeded)
+
+    def call(self, method, args):
+        """account a successful RPC call to the transaction"""
+        if method == "put":
+            self.objects_written += 1
+            self.bytes_written += len(args.get("data", b""))
+        elif method == "delete":
+            self.objects_deleted += 1
+
+    def run(self, event, repository, client, error=None):
+        """run the commands for *event* in the background"""
+        try:
+            transaction_id = repository.get_index_transaction_id()  # the last commit
+        except Exception:
+            transaction_id = None
+        env = prepare_subprocess_env(system=True)
+        env.update(
+            BORG_HOOK_EVENT=event,
+            BORG_HOOK_REPOSITORY=repository.path,
+            BORG_HOOK_CLIENT=client,
+            BORG_HOOK_TRANSACTION_ID="" if transaction_id is None else str(transaction_id),
+            BORG_HOOK_OBJECTS_WRITTEN=str(self.objects_written),
+            BORG_HOOK_BYTES_WRITTEN=str(self.bytes_written),
+            BORG_HOOK_OBJECTS_DELETED=str(self.objects_deleted),
+            BORG_HOOK_QUOTA_USED=str(repository.storage_quota_use),
+            BORG_HOOK_QUOTA=str(repository.storage_quota or 0),
+        )
+        if error is not None:
+            env["BORG_HOOK_ERROR"] = error
+        for command in self.commands.get(event, []):
+            try:
+                # stdin and stdout are the RPC stream of a ssh connection, stderr would keep it open.
+                Popen(
+                    command, shell=True, env=env, stdin=DEVNULL, stdout=DEVNULL, stderr=DEVNULL, start_new_session=True
+                )
+            except OSError as e:
+                logging.warning("Running the %s hook %r failed: %s", event, command, e)
+
+
+class HTTPTokens:
+    """
+    The bearer tokens of the clients of borg serve --http (--http-tokens FILE).
+
+    Each line of FILE is a client identity (see borg serve --policy) and its token, separated by whitespace.
+    Empty lines and lines starting with # are ignored.
+    """
+
+    def __init__(self, path):
+        self.path = path
+        self.load()  # fail early if the file is invalid
+
+    def load(self):
+        tokens = []
+        try:
+            with open(self.path) as fd:
+                for lineno, line in enumerate(fd, 1):
+                    fields = line.split()
+                    if not fields or fields[0].startswith("#"):
+                        continue
+                    if len(fields) != 2:
+                        raise Error(f"Invalid line {lineno} in borg serve token file {self.path}, use: ID TOKEN")
+                    tokens.append(tuple(fields))
+        except OSError as e:
+            raise Error(f"Invalid borg serve token file {self.path}: {e}") from None
+        self.tokens = tokens
+
+    def identify(self, token):
+        """return the identity of the client with *token*, None if it is unknown"""
+        identity = None
+        for client, client_token in self.tokens:
+            if hmac.compare_digest(client_token.encode(), token.encode()):
+                identity = client
+        return identity
+
+
+class HTTPRejected(Exception):
+    """the HTTP request of a client was rejected (the error response was sent already)"""
+
+
+def read_http_head(sock, deadline, exact=True):
+    """
+    read the start line and the header fields of an HTTP/1.1 message from *sock* until *deadline* (time.monotonic).
+
+    With exact=True, this reads byte by byte, so nothing after the head (i.e. the RPC stream) gets consumed.
+    Otherwise, it reads what is available and data after the head is an error (borg clients only start the
+    RPC stream after the upgrade response).
+    Return the start line and a dict of the header fields (with lower case names).
+    """
+    head = bytearray()
+    while b"\r\n\r\n" not in head:
+        timeout = deadline - time.monotonic()
+        if timeout <= 0:
+            raise TimeoutError("HTTP handshake timed out")
+        sock.settimeout(timeout)
+        data = sock.recv(1 if exact else HTTP_MAX_HEAD_SIZE + 1 - len(head))
+        if not data:
+            raise ConnectionClosed()
+        head += data
+        if len(head) > HTTP_MAX_HEAD_SIZE:
+            raise ValueError("HTTP message head too large")
+    head, _, rest = head.partition(b"\r\n\r\n")
+    if rest:
+        raise ValueError("unexpected data after the HTTP message head")
+    start, *lines = head.decode("latin-1").split("\r\n")
+    headers = {}
+    for line in lines:
+        name, _, value = line.partition(":")
+        headers[name.strip().lower()] = value.strip()
+    return start, headers
+
+
+def client_cert_identity(cert):
+    """return the common name of the subject of a (verified) client certificate, see SSLSocket.getpeercert"""
+    for rdn in (cert or {}).get("subject", ()):
+        for key, value in rdn:
+            if key == "commonName":
+                return value
+
+
+class TLSRelay:
+    """
+    Relay between a TLS connection and one end of a socket pair.
+
+    The RPC code reads, writes and selects on plain file descriptors, which does not work for a TLS socket
+    (it buffers decrypted data select does not know about), so it uses the other end of the socket pair (sock).
+    Closing either connection closes the other one, after sending the data received before.
+    """
+
+    def __init__(self, tls_sock):
+        self.tls_sock = tls_sock
+        self.sock, self.relay_sock = socket.socketpair()
+        self.thread = threading.Thread(target=self.run, name="tls-relay", daemon=True)
+        self.thread.start()
+
+    def run(self):
+        tls_sock, relay_sock = self.tls_sock, self.relay_sock
+        tls_sock.setblocking(False)
+        relay_sock.setblocking(False)
+        to_relay = to_tls = b""  # data received from one side, not sent to the other side yet
+        try:
+            while True:
+                readers = [sock for sock, data in ((tls_sock, to_relay), (relay_sock, to_tls)) if len(data) < BUFSIZE]
+                writers = [sock for sock, data in ((relay_sock, to_relay), (tls_sock, to_tls)) if data]
+                pending = tls_sock in readers and tls_sock.pending()
+                r, w, _ = select.select(readers, writers, [], 0 if pending else None)
+                if pending or tls_sock in r:
+                    try:
+                        data = tls_sock.recv(TLS_RELAY_BUFSIZE)
+                    except (ssl.SSLWantReadError, ssl.SSLWantWriteError):
+                        data = None
+                    if data == b"":
+                        relay_sock.setblocking(True)
+                        relay_sock.sendall(to_relay)
+                        break
+                    to_relay += data or b""
+                if relay_sock in r:
+                    data = relay_sock.recv(TLS_RELAY_BUFSIZE)
+                    if not data:
+                        tls_sock.setblocking(True)
+                        tls_sock.sendall(to_tls)
+                        break
+                    to_tls += data
+                if relay_sock in w:
+                    to_relay = to_relay[relay_sock.send(to_relay) :]
+                if tls_sock in w:
+                    try:
+                        to_tls = to_tls[tls_sock.send(to_tls) :]
+                    except (ssl.SSLWantReadError, ssl.SSLWantWriteError):
+                        pass
+        except OSError:
+            pass  # the connection dropped, closing the sockets tells the other side
+        finally:
+            tls_sock.close()
+            relay_sock.close()
+
+    def join(self):
+        self.thread.join()
+
+
 # Protocol compatibility:
 # In general the server is responsible for rejecting too old clients and the client it responsible for rejecting
 # too old servers. This ensures that the knowledge what is compatible is always held by the newer component.
@@ -137,7 +674,10 @@ class ConnectionBrokenWithHint(Error):
 class RepositoryServer:  # pragma: no cover
     rpc_methods = (
         "__len__",
+        "begin_commit",
         "check",
+        "check_summary",
+        "scrub",
         "commit",
         "delete",
         "destroy",
@@ -145,6 +685,13 @@ class RepositoryServer:  # pragma: no cover
         "flags_many",
         "get",
         "list",
+        "mirror_info",
+        "mirror_read",
+        "mirror_write",
+        "mirror_commit",
+        "retention_lock",
+        "get_retention",
+        "compact_slice",
         "scan",
         "negotiate",
         "open",
@@ -158,7 +705,25 @@ class RepositoryServer:  # pragma: no cover
         "inject_exception",
     )
 
-    def __init__(self, restrict_to_paths, restrict_to_repositories, append_only, storage_quota, use_socket):
+    def __init__(
+        self,
+        restrict_to_paths,
+        restrict_to_repositories,
+        append_only,
+        storage_quota,
+        use_socket,
+        auto_compact=None,
+        audit_log=None,
+        policy=None,
+        client_id=None,
+        http=None,
+        http_tokens=None,
+        tls_cert=None,
+        tls_key=None,
+        tls_client_ca=None,
+        hooks=None,
+        allow_mirror=False,
+    ):
         self.repository = None
         self.restrict_to_paths = restrict_to_paths
         self.restrict_to_repositories = restrict_to_repositories
@@ -168,13 +733,65 @@ def __init__(self, restrict_to_paths, restrict_to_repositories, append_only, sto
         # (see RepositoryServer.open below).
         self.append_only = append_only
         self.storage_quota = storage_quota
+        self.allow_mirror = allow_mirror  # mirror_write and mirror_commit replace the repository, see borg rmirror
         self.client_version = None  # we update this after client sends version information
+        self.protocol = 1  # we update this after client sends the protocols it supports
+        self.client_window = RPC_WINDOW
+        self.compression = None  # RPCCompression, if the client negotiated it
+        self.negotiated_compression = None  # RPCCompression to use after sending the negotiate response
+        self.output_held = False  # do not send frames before the client switched to compression
+        self.outgoing = []  # messages for the next frame
+        self.outgoing_size = 0
+        # compaction threshold for the repositories clients committed to, see auto_compact.
+        self.auto_compact_threshold = auto_compact
+        self.auto_compact_paths = set()
+        self.audit = AuditLog(audit_log) if audit_log else None
+        self.policy = ServePolicy(policy) if policy else None
+        self.client_id = client_id  # client identity for the policy, see client_identity
+        self.client_policy = None  # ServePolicy.Client of the current session
+        self.hooks = ServeHooks(hooks) if hooks else None
+        self.peer = None  # the peer of the current session, see client_identity
         if use_socket is False:
             self.socket_path = None
         elif use_socket is True:  # --socket
             self.socket_path = get_socket_filename()
         else:  # --socket=/some/path
             self.socket_path = use_socket
+        self.http_address = http  # (host, port) to listen on for http:// and https:// connections
+        self.http_tokens = HTTPTokens(http_tokens) if http_tokens else None
+        self.ssh_key_fingerprints = None  # of the key a ssh client authenticated with, see client_identity
+        if policy and not client_id and not self.socket_path and http is None:
+            # for ssh connections, the user borg serve runs as is shared by all clients, but sshd tells the key.
+            user_auth = os.environ.get("SSH_USER_AUTH")
+            if not user_auth:
+                raise Error(
+                    "borg serve --policy needs --client-id, --socket, --http or sshd with ExposeAuthInfo yes "
+                    "to identify the clients."
+                )
+            try:
+                self.ssh_key_fingerprints = ssh_key_fingerprints(user_auth)
+            except OSError as e:
+                raise Error(f"Can not read SSH_USER_AUTH file {user_auth}: {e}") from None
+        self.tls = None
+        if http is not None:
+            if self.socket_path:
+                raise Error("borg serve can not listen on a socket and for HTTP connections at the same time.")
+            if http_tokens is None and tls_client_ca is None:
+                raise Error("borg serve --http needs client authentication (--http-tokens or --tls-client-ca).")
+            if tls_client_ca and not tls_cert:
+                raise Error("borg serve --tls-client-ca needs TLS (--tls-cert).")
+            if http_tokens and not tls_cert and not is_loopback(http[0]):
+                # a reverse proxy terminating TLS needs to connect via localhost then.
+                raise Error("borg serve --http-tokens needs TLS (--tls-cert) to listen on other than localhost.")
+            if tls_cert:
+                self.tls = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+                try:
+                    self.tls.load_cert_chain(tls_cert, tls_key)
+                    if tls_client_ca:
+                        self.tls.load_verify_locations(tls_client_ca)
+                        self.tls.verify_mode = ssl.CERT_REQUIRED
+                except OSError as e:  # includes ssl.SSLError
+                    raise Error(f"Invalid TLS certificate, key or CA for borg serve: {e}") from None
 
     def filter_args(self, f, kwargs):
         """Remove unknown named parameters from call, because client did (implicitly) say it's ok."""
@@ -189,8 +806,133 @@ def send_queued_log(self):
             except queue.Empty:
                 break
             else:
-                msg = msgpack.packb({LOG: lr_dict})
-                os_write(self.stdout_fd, msg)
+                self.send(msgpack.packb({LOG: lr_dict}))
+        self.flush()
+
+    def send(self, msg):
+        if self.compression is None:
+            os_write(self.stdout_fd, msg)
+            if self.negotiated_compression is not None:
+                # this was the negotiate response, from now on we send frames (after the client sent its first).
+                self.compression, self.negotiated_compression = self.negotiated_compression, None
+                self.output_held = True
+            return
+        self.outgoing.append(msg)
+        self.outgoing_size += len(msg)
+        if self.outgoing_size >= RPC_FRAME_SIZE:
+            self.flush()
+
+    def flush(self):
+        """send the buffered messages in a frame, see RPCCompression"""
+        if self.outgoing and not self.output_held:
+            os_write(self.stdout_fd, self.compression.compress(b"".join(self.outgoing)))
+            self.outgoing.clear()
+            self.outgoing_size = 0
+
+    def process_request(self, msgid, method, args):
+        """
+        process a request, return the packed response and its size as accounted for the flow control.
+        """
+        try:
+            if method not in self.rpc_methods:
+                raise InvalidRPCMethod(method)
+            if method in ("mirror_write", "mirror_commit") and not self.allow_mirror:
+                raise PolicyDenied(f"{method} needs borg serve --allow-mirror")
+            if self.policy is not None:
+                if self.client_policy is not None:
+                    self.client_policy.check_call(method, args)
+                elif method not in ("negotiate", "close"):
+                    raise PolicyDenied(f"unknown client {self.client_identity()}")
+            try:
+                f = getattr(self, method)
+            except AttributeError:
+                f = getattr(self.repository, method)
+            args = self.filter_args(f, args)
+            res = f(**args)
+        except BaseException as e:
+            if self.audit is not None:
+                self.audit.call(method, args, exception=e)
+            if self.hooks is not None and method == "put" and isinstance(e, Repository.StorageQuotaExceeded):
+                self.quota_exceeded(e)  # commit does this itself, before running the rollback hooks
+            ex_short = traceback.format_exception_only(e.__class__, e)
+            ex_full = traceback.format_exception(*sys.exc_info())
+            ex_trace = True
+            if isinstance(e, Error):
+                ex_short = [e.get_message()]
+                ex_trace = e.traceback
+            if isinstance(e, (Repository.DoesNotExist, Repository.AlreadyExists, PathNotAllowed, PolicyDenied)):
+                # These exceptions are reconstructed on the client end in RemoteRepository.call_many(),
+                # and will be handled just like locally raised exceptions. Suppress the remote traceback
+                # for these, except ErrorWithTraceback, which should always display a traceback.
+                pass
+            else:
+                logging.debug("\n".join(ex_full))
+
+            sys_info = sysinfo()
+            try:
+                msg = msgpack.packb(
+                    {
+                        MSGID: msgid,
+                        "exception_class": e.__class__.__name__,
+                        "exception_args": e.args,
+                        "exception_full": ex_full,
+                        "exception_short": ex_short,
+                        "exception_trace": ex_trace,
+                        "sysinfo": sys_info,
+                    }
+                )
+            except TypeError:
+                msg = msgpack.packb(
+                    {
+                        MSGID: msgid,
+                        "exception_class": e.__class__.__name__,
+                        "exception_args": [x if isinstance(x, (str, bytes, int)) else None for x in e.args],
+                        "exception_full": ex_full,
+                        "exception_short": ex_short,
+                        "exception_trace": ex_trace,
+                        "sysinfo": sys_info,
+                    }
+                )
+            return msg, STREAM_MSG_OVERHEAD
+        else:
+            if self.audit is not None:
+                self.audit.call(method, args, result=res)
+            if self.hooks is not None:
+                self.hooks.call(method, args)
+            size = STREAM_MSG_OVERHEAD + (len(res) if isinstance(res, bytes) else 0)
+            return msgpack.packb({MSGID: msgid, RESULT: res}), size
+
+    def process_pending(self, pending, streams):
+        """
+        protocol 2: process the pending requests in order, queue gets on stream channels.
+
+        gets on the stream channels are answered as the client's credit allows (see send_streams),
+        all other requests must see the effects of the requests before them, so they wait until the
+        stream queues are empty.
+        """
+        while pending:
+            msgid, method, args, channel = pending[0]
+            if method == "get" and channel != CHANNEL_CONTROL:
+                streams.setdefault(channel, deque()).append((msgid, args))
+            elif any(streams.values()):
+                return
+            else:
+                self.send(self.process_request(msgid, method, args)[0])
+            pending.popleft()
+
+    def send_streams(self, streams, credit):
+        """
+        protocol 2: send one response on each stream channel the client has credit for.
+
+        return whether there is more to send right away.
+        """
+        for channel, queued in streams.items():
+            if queued and credit.setdefault(channel, self.client_window) > 0:
+                msgid, args = queued.popleft()
+                msg, size = self.process_request(msgid, "get", args)
+                self.send(msg)
+                credit[channel] -= size
+        return any(queued and credit[channel] > 0 for channel, queued in streams.items())
 
     def serve(self):
         def inner_serve():
@@ -201,6 +943,13 @@ def inner_serve():
 
             unpacker = get_limited_unpacker("server")
             shutdown_serve = False
+            self.protocol = 1  # until the client negotiates a newer one
+            self.compression = self.negotiated_compression = None  # until the client negotiates it
+            self.outgoing.clear()
+            self.outgoing_size = 0
+            pending = deque()  # protocol 2: requests not processed yet, in order of arrival
+            streams = {}  # protocol 2: stream channel -> deque of queued get requests
+            credit = {}  # protocol 2: stream channel -> response bytes the client is willing to receive
             while True:
                 # before processing any new RPCs, send out all pending log output
                 self.send_queued_log()
@@ -210,81 +959,78 @@ def inner_serve():
                     assert self.repository is None
                     return
 
-                # process new RPCs
-                r, w, es = select.select([self.stdin_fd], [], [], 10)
+                sending = False
+                reading = True
+                if self.protocol >= 2:
+                    self.process_pending(pending, streams)
+                    sending = self.send_streams(streams, credit)
+                    # bound the memory for queued requests: no more requests until we have caught up.
+                    reading = len(pending) + sum(len(queued) for queued in streams.values()) < MAX_QUEUED_REQUESTS
+                    if not (reading or sending):
+                        # the client has more requests in flight than it may and does not give us credit.
+                        if self.repository is not None:
+                            self.repository.close()
+                        raise UnexpectedRPCDataFormatFromClient(__version__)
+                self.flush()
+
+                # process new RPCs (do not wait for them if there are responses the client is waiting for)
+                r, w, es = select.select([self.stdin_fd] if reading else [], [], [], 0 if sending else 10)
                 if r:
                     data = os.read(self.stdin_fd, BUFSIZE)
                     if not data:
+                        # the client is gone, maybe without closing the repository (the connection dropped).
+                        self.disconnected()
                         shutdown_serve = True
                         continue
+                    if self.compression is not None:
+                        # the client switched to compression after the negotiate response, so can we.
+                        self.output_held = False
+                        try:
+                            data = self.compression.decompress(data)
+                        except ValueError:
+                            if self.repository is not None:
+                                self.repository.close()
+                            raise UnexpectedRPCDataFormatFromClient(__version__) from None
                     unpacker.feed(data)
                     for unpacked in unpacker:
                         if isinstance(unpacked, dict):
-                            msgid = unpacked[MSGID]
-                            method = unpacked[MSG]
-                            args = unpacked[ARGS]
+                            msgid = unpacked.get(MSGID)
+                            method = unpacked.get(MSG)
+                            args = unpacked.get(ARGS)
                         else:
                             if self.repository is not None:
                                 self.repository.close()
                             raise UnexpectedRPCDataFormatFromClient(__version__)
-                        try:
-                            if method not in self.rpc_methods:
-                                raise InvalidRPCMethod(method)
-                            try:
-                                f = getattr(self, method)
-                            except AttributeError:
-                                f = getattr(self.repository, method)
-                            args = self.filter_args(f, args)
-                            res = f(**args)
-                        except BaseException as e:
-                            ex_short = traceback.format_exception_only(e.__class__, e)
-                            ex_full = traceback.format_exception(*sys.exc_info())
-                            ex_trace = True
-                            if isinstance(e, Error):
-                                ex_short = [e.get_message()]
-                                ex_trace = e.traceback
-                            if isinstance(e, (Repository.DoesNotExist, Repository.AlreadyExists, PathNotAllowed)):
-                                # These exceptions are reconstructed on the client end in RemoteRepository.call_many(),
-                                # and will be handled just like locally raised exceptions. Suppress the remote traceback
-                                # for these, except ErrorWithTraceback, which should always display a traceback.
-                                pass
-                            else:
-                                logging.debug("\n".join(ex_full))
-
-                            sys_info = sysinfo()
-                            try:
-                                msg = msgpack.packb(
-                                    {
-                                        MSGID: msgid,
-                                        "exception_class": e.__class__.__name__,
-                                        "exception_args": e.args,
-                                        "exception_full": ex_full,
-                                        "exception_short": ex_short,
-                                        "exception_trace": ex_trace,
-                                        "sysinfo": sys_info,
-                                    }
-                                )
-                            except TypeError:
-                                msg = msgpack.packb(
-                                    {
-                                        MSGID: msgid,
-                                        "exception_class": e.__class__.__name__,
-                                        "exception_args": [
-                                            x if isinstance(x, (str, bytes, int)) else None for x in e.args
-                                        ],
-                                        "exception_full": ex_full,
-                                        "exception_short": ex_short,
-                                        "exception_trace": ex_trace,
-                                        "sysinfo": sys_info,
-                                    }
-                                )
-                            os_write(self.stdout_fd, msg)
+                        if WINDOW in unpacked:  # protocol 2: the client consumed responses of a stream channel
+                            channel = unpacked[CHANNEL]
+                            credit[channel] = credit.get(channel, self.client_window) + unpacked[WINDOW]
+                        elif self.protocol >= 2:
+                            pending.append((msgid, method, args, unpacked.get(CHANNEL, CHANNEL_CONTROL)))
                         else:
-                            os_write(self.stdout_fd, msgpack.packb({MSGID: msgid, RESULT: res}))
+                            self.send(self.process_request(msgid, method, args)[0])
                 if es:
                     shutdown_serve = True
                     continue
 
+        def serve_connection(peer=None):
+            self.peer = peer
+            if self.policy is not None:
+                try:
+                    self.policy.load()  # pick up changes without restarting the socket server
+                except Error as e:
+                    logging.error("%s Using the previous policy.", e.get_message())
+                self.client_policy = self.policy.client(self.client_identity(peer))
+            if self.audit is not None:
+                self.audit.start(peer, client_id=self.client_identity(peer))
+            try:
+                inner_serve()
+            except (BrokenPipeError, ConnectionResetError):
+                # the connection dropped while we were sending a response.
+                self.disconnected()
+            finally:
+                if self.audit is not None:
+                    self.audit.end(self.client_version)
+
         if self.socket_path:  # server for socket:// connections
             try:
                 # remove any left-over socket file
@@ -314,21 +1060,212 @@ def inner_serve():
                 print(f"Accepted a connection on socket {self.socket_path} ...", file=sys.stderr)
                 self.stdin_fd = connection.makefile("rb").fileno()
                 self.stdout_fd = connection.makefile("wb").fileno()
-                inner_serve()
+                peer = None
+                if (self.audit is not None or self.policy is not None) and hasattr(socket, "SO_PEERCRED"):
+                    creds = connection.getsockopt(socket.SOL_SOCKET, socket.SO_PEERCRED, struct.calcsize("3i"))
+                    peer = dict(zip(("pid", "uid", "gid"), struct.unpack("3i", creds)))
+                serve_connection(peer)
                 print(f"Finished with connection on socket {self.socket_path} .", file=sys.stderr)
+                self.auto_compact_detached()
+        elif self.http_address:  # server for http:// and https:// connections
+            host, port = self.http_address
+            family = socket.AF_INET6 if ":" in host else socket.AF_INET
+            sock = socket.create_server((host, port), family=family)
+            scheme = "https" if self.tls is not None else "http"
+            host, port = sock.getsockname()[:2]  # the actual port for port 0
+            print(f"borg serve: PID {os.getpid()}, listening on {scheme}://{host}:{port} ...", file=sys.stderr)
+
+            # the handshakes run in threads, so slow (or malicious) clients do not block the others.
+            ready = queue.Queue()  # connections that completed the handshake: (stream, relay, peer)
+            threading.Thread(target=self.http_accept_loop, args=(sock, ready), name="http-accept", daemon=True).start()
+            while True:
+                stream, relay, peer = ready.get()
+                self.stdin_fd = self.stdout_fd = stream.fileno()
+                serve_connection(peer)
+                stream.close()
+                if relay is not None:
+                    relay.join()
+                print(f"Finished with the connection from {peer['address']} .", file=sys.stderr)
+                self.auto_compact_detached()
         else:  # server for one ssh:// connection
             self.stdin_fd = sys.stdin.fileno()
             self.stdout_fd = sys.stdout.fileno()
-            inner_serve()
+            serve_connection()
+            self.auto_compact_detached()
+
+    def http_accept_loop(self, sock, ready):
+        """accept connections on *sock*, put the ones that completed the handshake into the queue *ready*"""
+        handshakes = threading.BoundedSemaphore(HTTP_MAX_HANDSHAKES)
+        while True:
+            connection, address = sock.accept()
+            if not handshakes.acquire(blocking=False):
+                print(f"Rejected the connection from {address[0]}: too many handshakes", file=sys.stderr)
+                connection.close()
+                continue
+            args = (connection, address, ready, handshakes)
+            threading.Thread(target=self.http_handshake, args=args, name="http-handshake", daemon=True).start()
+
+    def http_handshake(self, connection, address, ready, handshakes):
+        """do the TLS and the HTTP handshake with a client within HTTP_TIMEOUT"""
+        print(f"Accepted a connection from {address[0]} ...", file=sys.stderr)
+        deadline = time.monotonic() + HTTP_TIMEOUT
+
+        def expired():
+            # the socket timeout limits each recv, this limits the whole handshake (e.g. a client sending slowly).
+            try:
+                connection.shutdown(socket.SHUT_RDWR)
+            except OSError:
+                pass
+
+        timer = threading.Timer(HTTP_TIMEOUT, expired)
+        timer.start()
+        try:
+            connection.settimeout(HTTP_TIMEOUT)
+            if self.tls is not None:
+                connection = self.tls.wrap_socket(connection, server_side=True, do_handshake_on_connect=False)
+                connection.do_handshake()
+            peer = dict(address=address[0], port=address[1], id=self.http_accept(connection, deadline))
+            connection.settimeout(None)
+        except (OSError, ValueError, ConnectionClosed, HTTPRejected) as e:
+            print(f"Rejected the connection from {address[0]}: {e}", file=sys.stderr)
+            connection.close()
+            return
+        finally:
+            timer.cancel()
+            handshakes.release()
+        relay = TLSRelay(connection) if self.tls is not None else None
+        stream = relay.sock if relay is not None else connection
+        ready.put((stream, relay, peer))
+
+    def http_accept(self, connection, deadline):
+        """
+        do the server side of the HTTP upgrade to the RPC stream, return the authenticated client identity.
+
+        Clients authenticate with a bearer token (--http-tokens) and/or a client certificate (--tls-client-ca,
+        verified by the TLS handshake already), the identity is the one of the token or the certificate's CN.
+        If both are required, the identities must match.
+        """
+
+        def reject(status, *fields):
+            response = [f"HTTP/1.1 {status}", *fields, "Content-Length: 0", "Connection: close", "", ""]
+            connection.sendall("\r\n".join(response).encode("latin-1"))
+            raise HTTPRejected(status)
+
+        start, headers = read_http_head(connection, deadline, exact=False)
+        identity = None
+        if self.tls is not None and self.tls.verify_mode == ssl.CERT_REQUIRED:
+            identity = client_cert_identity(connection.getpeercert())
+        if self.http_tokens is not None:
+            try:
+                self.http_tokens.load()  # pick up changes without restarting the server
+            except Error as e:
+                logging.error("%s Using the previous tokens.", e.get_message())
+            scheme, _, token = headers.get("authorization", "").partition(" ")
+            token_identity = self.http_tokens.identify(token.strip()) if scheme.lower() == "bearer" else None
+            if identity is not None and token_identity != identity:
+                # the token of another client must not replace the identity of the certificate.
+                token_identity = None
+            identity = token_identity
+        if identity is None:
+            reject("401 Unauthorized", 'WWW-Authenticate: Bearer realm="borg"')
+        if not start.startswith("GET ") or headers.get("upgrade", "").lower() != HTTP_UPGRADE:
+            reject("426 Upgrade Required", f"Upgrade: {HTTP_UPGRADE}", "Connection: Upgrade")
+        response = ["HTTP/1.1 101 Switching Protocols", f"Upgrade: {HTTP_UPGRADE}", "Connection: Upgrade", "", ""]
+        connection.sendall("\r\n".join(response).encode("latin-1"))
+        return identity
+
+    def client_identity(self, peer=None):
+        """
+        the identity of the client: the one authenticated by borg serve --http, --client-id,
+        the one whose ssh key (see ServePolicy) the ssh client authenticated with, the user of the
+        socket peer or the user we run as.
+        """
+        if peer is not None and peer.get("id"):
+            return peer["id"]
+        if self.client_id:
+            return self.client_id
+        if self.ssh_key_fingerprints is not None:
+            # an unknown key must not fall back to the user all ssh clients share.
+            identity = self.policy.ssh_key_identity(self.ssh_key_fingerprints)
+            if identity is None:
+                identity = self.ssh_key_fingerprints[0] if self.ssh_key_fingerprints else "<no ssh key>"
+            return identity
+        if peer is not None and "uid" in peer:
+            try:
+                return pwd.getpwuid(peer["uid"]).pw_name
+            except KeyError:
+                return str(peer["uid"])
+        return os.environ.get("USER") or getpass.getuser()
+
+    def disconnected(self):
+        """close the repository after the client is gone, keep the transaction for a resuming client"""
+        if self.repository is None:
+            return
+        if self.audit is not None:
+            self.audit.dropped()
+        if self.repository.suspend():
+            logging.debug("Suspended the transaction of session %s.", self.repository.session)
+        else:
+            self.rolled_back("The connection to the client dropped.")
+        self.repository.close()
+        self.repository = None
+
+    def auto_compact_detached(self):
+        """run auto_compact in a detached process, so the client (or the next one) does not need to wait"""
+        if not self.auto_compact_paths:
+            return
+        if not hasattr(os, "fork"):
+            self.auto_compact()
+            return
+        pid = os.fork()
+        if pid:
+            self.auto_compact_paths.clear()
+            os.waitpid(pid, 0)
+            return
+        # double fork, so the compacting process gets reparented and does not become a zombie.
+        try:
+            os.setsid()
+            if os.fork() == 0:
+                devnull = os.open(os.devnull, os.O_RDWR)
+                for fd in (0, 1, 2):
+                    os.dup2(devnull, fd)  # the ssh connection ends when nobody uses it any more
+                self.auto_compact()
+        finally:
+            os._exit(0)
+
+    def auto_compact(self):
+        """compact the repositories clients committed to in bounded slices, see borg serve --auto-compact"""
+        while self.auto_compact_paths:
+            path = self.auto_compact_paths.pop()
+            try:
+                with Repository(path, exclusive=True, concurrent=True, lock_wait=AUTO_COMPACT_LOCK_WAIT) as repository:
+                    while repository.compact_slice(self.auto_compact_threshold, max_duration=AUTO_COMPACT_SLICE):
+                        # give the writers a chance to commit (they need the commit lock)
+                        time.sleep(AUTO_COMPACT_SLICE)
+            except Error as e:
+                logging.warning("Auto compaction of %s failed: %s", path, e.get_message())
+            while not borg_serve_log_queue.empty():
+                borg_serve_log_queue.get_nowait()  # nobody is listening any more
 
     def negotiate(self, client_data):
         if isinstance(client_data, dict):
             self.client_version = client_data["client_version"]
+            # older clients do not send these and talk protocol 1.
+            self.protocol = min(client_data.get("rpc_protocol", 1), RPC_PROTOCOL)
+            self.client_window = client_data.get("window", RPC_WINDOW)
         else:
             self.client_version = BORG_VERSION  # seems to be newer than current version (no known old format)
 
         # not a known old format, send newest negotiate this version knows
-        return {"server_version": BORG_VERSION}
+        response = {"server_version": BORG_VERSION}
+        if self.protocol >= 2:
+            response.update(rpc_protocol=self.protocol, write_window=RPC_WINDOW)
+        compression = client_data.get("compression") if isinstance(client_data, dict) else None
+        if compression and compression[0] == "zstd" and isinstance(compression[1], int) and 1 <= compression[1] <= 22:
+            # the response is sent uncompressed, the client sends frames after it got it, see send.
+            self.negotiated_compression = RPCCompression(compression[1])
+            response["compression"] = compression
+        return response
 
     def _resolve_path(self, path):
         if isinstance(path, bytes):
@@ -341,7 +1278,17 @@ def _resolve_path(self, path):
         return os.path.realpath(path)
 
     def open(
-        self, path, create=False, lock_wait=None, lock=True, exclusive=None, append_only=False, make_parent_dirs=False
+        self,
+        path,
+        create=False,
+        lock_wait=None,
+        lock=True,
+        exclusive=None,
+        append_only=False,
+        make_parent_dirs=False,
+        concurrent=False,
+        session=None,
+        resume=False,
     ):
         logging.debug("Resolving repository path %r", path)
         path = self._resolve_path(path)
@@ -367,23 +1314,79 @@ def open(
         # "borg init" on "borg serve --append-only" (=self.append_only) does not create an append only repo,
         # while "borg init --append-only" (=append_only) does, regardless of the --append-only (self.append_only)
         # flag for serve.
-        append_only = (not create and self.append_only) or append_only
+        enforce_append_only = self.append_only
+        storage_quota = self.storage_quota
+        if self.client_policy is not None:
+            if not self.client_policy.allows_repository(path):
+                raise PathNotAllowed(path)
+            enforce_append_only = enforce_append_only or self.client_policy.role == "append-only"
+            storage_quota = self.client_policy.storage_quota or storage_quota
+        append_only = (not create and enforce_append_only) or append_only
+        if session is not None and not (len(session) == 16 and set(session) <= set("0123456789abcdef")):
+            raise Error(f"Invalid session name {session!r}.")
         self.repository = Repository(
             path,
             create,
             lock_wait=lock_wait,
             lock=lock,
             append_only=append_only,
-            storage_quota=self.storage_quota,
+            storage_quota=storage_quota,
             exclusive=exclusive,
             make_parent_dirs=make_parent_dirs,
             send_log_cb=self.send_queued_log,
+            concurrent=concurrent,
+            session=session if concurrent else None,
         )
         self.repository.__enter__()  # clean exit handled by serve() method
+        if resume:
+            try:
+                self.repository.resume()
+            except Exception:
+                self.close()
+                raise
+        if self.audit is not None:
+            self.audit.opened(path)
+        if self.hooks is not None:
+            self.hooks.reset()
         return self.repository.id
 
+    def commit(self, compact=True, threshold=0.1):
+        try:
+            self.repository.commit(compact=compact, threshold=threshold)
+        except Exception as e:
+            if isinstance(e, Repository.StorageQuotaExceeded):
+                self.quota_exceeded(e)
+            self.rolled_back(e.get_message() if isinstance(e, Error) else str(e))
+            raise
+        if self.audit is not None:
+            self.audit.committed(self.repository.path)
+        if self.hooks is not None:
+            self.hooks.run("commit", self.repository, self.client_identity(self.peer))
+            self.hooks.reset()
+        if self.auto_compact_threshold is not None and not compact and not self.repository.append_only:
+            self.auto_compact_paths.add(self.repository.path)
+
+    def rollback(self):
+        self.repository.rollback()
+        self.rolled_back("The client rolled back the transaction.")
+
+    def rolled_back(self, error):
+        """run the rollback hooks if the transaction had changes"""
+        if self.hooks is not None and self.hooks.changed:
+            self.hooks.run("rollback", self.repository, self.client_identity(self.peer), error=error)
+        if self.hooks is not None:
+            self.hooks.reset()
+
+    def quota_exceeded(self, error):
+        """run the quota-exceeded hooks (once per transaction)"""
+        if not self.hooks.quota_exceeded:
+            self.hooks.quota_exceeded = True
+            client = self.client_identity(self.peer)
+            self.hooks.run("quota-exceeded", self.repository, client, error=error.get_message())
+
     def close(self):
         if self.repository is not None:
+            self.rolled_back("The client closed the repository without committing.")
             self.repository.__exit__(None, None, None)
             self.repository = None
         borg.logger.flush_logging()
@@ -411,15 +1414,50 @@ def inject_exception(self, kind):
 
 
 class SleepingBandwidthLimiter:
-    def __init__(self, limit):
-        if limit:
-            self.ratelimit = int(limit * RATELIMIT_PERIOD)
-            self.ratelimit_last = time.monotonic()
-            self.ratelimit_quota = self.ratelimit
-        else:
-            self.ratelimit = None
+    def __init__(self, limit, schedule=()):
+        """limit the bandwidth to *limit* bytes/s (0 = unlimited)
+
+        *schedule* is a sequence of (start, end, limit) tuples, overriding *limit* while the local time is
+        between start and end (minutes after midnight, the period may wrap around midnight).
+        """
+        self.limit = limit
+        self.schedule = schedule
+        self.schedule_checked = time.monotonic()
+        self.ratelimit = self.current_ratelimit()
+        self.ratelimit_last = time.monotonic()
+        self.ratelimit_quota = self.ratelimit
+
+    @classmethod
+    def from_ratelimit(cls, ratelimit):
+        """create a limiter from a parse_ratelimit value (kiByte/s) or None"""
+        if not ratelimit:
+            return cls(0)
+        limit, schedule = ratelimit
+        return cls(limit * 1024, tuple((start, end, limit * 1024) for start, end, limit in schedule))
+
+    def current_ratelimit(self):
+        """return the quota per RATELIMIT_PERIOD that applies now (None = unlimited)"""
+        limit = self.limit
+        if self.schedule:
+            now = time.localtime()
+            minute = now.tm_hour * 60 + now.tm_min
+            for start, end, period_limit in self.schedule:
+                if (start <= minute < end) if start < end else (minute >= start or minute < end):
+                    limit = period_limit
+                    break
+        return int(limit * RATELIMIT_PERIOD) or None
 
-    def write(self, fd, to_send):
+    def quota(self, size):
+        """return how many of *size* bytes may be transferred now, sleep until some quota is available"""
+        if self.schedule:
+            now = time.monotonic()
+            if self.schedule_checked + RATELIMIT_SCHEDULE_INTERVAL <= now:
+                self.schedule_checked = now
+                ratelimit = self.current_ratelimit()
+                if ratelimit != self.ratelimit:
+                    self.ratelimit = ratelimit
+                    self.ratelimit_last = now
+                    self.ratelimit_quota = ratelimit
         if self.ratelimit:
             now = time.monotonic()
             if self.ratelimit_last + RATELIMIT_PERIOD <= now:
@@ -432,16 +1470,30 @@ def write(self, fd, to_send):
                 time.sleep(tosleep)
                 self.ratelimit_quota += self.ratelimit
                 self.ratelimit_last = time.monotonic()
-            if len(to_send) > self.ratelimit_quota:
-                to_send = to_send[: self.ratelimit_quota]
+            if size > self.ratelimit_quota:
+                size = self.ratelimit_quota
+        return size
+
+    def consume(self, size):
+        if self.ratelimit:
+            self.ratelimit_quota -= size
+
+    def write(self, fd, to_send):
+        size = self.quota(len(to_send))
+        if size < len(to_send):
+            to_send = to_send[:size]
         try:
             written = os.write(fd, to_send)
         except BrokenPipeError:
             raise ConnectionBrokenWithHint("Broken Pipe") from None
-        if self.ratelimit:
-            self.ratelimit_quota -= written
+        self.consume(written)
         return written
 
+    def read(self, fd, size):
+        data = os.read(fd, self.quota(size))
+        self.consume(len(data))
+        return data
+
 
 def api(*, since, **kwargs_decorator):
     """Check version requirements and use self.call to do the remote method call.
@@ -505,6 +1557,30 @@ def do_rpc(self, *args, **kwargs):
     return decorator
 
 
+class StreamChannel:
+    """client side flow control of a protocol 2 stream channel"""
+
+    def __init__(self, window):
+        self.window = window
+        self.granted = window  # response bytes the server may send in total
+        self.received = 0
+        self.consumed = 0
+        self.outstanding = 0  # requests sent, but not answered yet
+
+    def consume(self, size):
+        """account consumed response bytes, return the credit to grant to the server (or 0)"""
+        self.consumed += size
+        grant = self.consumed + self.window - self.granted
+        if grant < self.window // 4:
+            return 0  # avoid lots of tiny window updates
+        self.granted += grant
+        return grant
+
+    def stalled(self):
+        """is the server unable to answer the outstanding requests?"""
+        return self.outstanding and self.received >= self.granted
+
+
 class RemoteRepository:
     extra_test_args = []  # type: ignore
 
@@ -555,13 +1631,18 @@ def __init__(
         append_only=False,
         make_parent_dirs=False,
         args=None,
+        concurrent=False,
     ):
         self.location = self._location = location
+        self.concurrent = concurrent
         self.preload_ids = []
         self.msgid = 0
         self.rx_bytes = 0
         self.tx_bytes = 0
         self.to_send = EfficientCollectionQueue(1024 * 1024, bytes)
+        self.rpc_compression = None  # RPCCompression, if negotiated
+        self.to_compress = []  # messages for the next frame, see push_send
+        self.to_compress_size = 0
         self.stdin_fd = self.stdout_fd = self.stderr_fd = None
         self.stderr_received = b""  # incomplete stderr line bytes received (no \n yet)
         self.chunkid_to_msgids = {}
@@ -569,12 +1650,56 @@ def __init__(
         self.responses = {}
         self.async_responses = {}
         self.shutdown_time = None
-        self.ratelimit = SleepingBandwidthLimiter(args.upload_ratelimit * 1024 if args and args.upload_ratelimit else 0)
+        self.upload_ratelimit = SleepingBandwidthLimiter.from_ratelimit(args.upload_ratelimit if args else None)
+        self.download_ratelimit = SleepingBandwidthLimiter.from_ratelimit(args.download_ratelimit if args else None)
         self.upload_buffer_size_limit = args.upload_buffer * 1024 * 1024 if args and args.upload_buffer else 0
         self.unpacker = get_limited_unpacker("client")
+        self.metadata_cache = None
         self.server_version = None  # we update this after server sends its version
+        self.protocol = 1  # we update this after server sends the protocol it chose
+        self.session = None  # see Repository.resume
+        self.write_window = 0  # protocol 2: put data bytes the server takes without acknowledging them
+        self.put_sizes = {}  # protocol 2: msgid -> data size of puts not acknowledged yet
+        self.put_unacked = 0
+        self.streams = {}  # protocol 2: stream channel -> StreamChannel
+        self.stream_msgids = {}  # protocol 2: msgid -> stream channel of gets not consumed yet
         self.p = self.sock = None
+        self.tls_relay = None  # TLSRelay of a https:// connection
         self._args = args
+        self.reconnect_timeout = getattr(args, "reconnect_timeout", RECONNECT_TIMEOUT)
+        self.rpc_compression_spec = getattr(args, "rpc_compression", RPC_COMPRESSION)
+        self.reconnecting = False
+        self.sent = {}  # msgid -> (method, packed request) of requests not answered yet, see reconnect
+        self.txn_state = None  # "staged" (resumable, see Repository.resume) or "unsafe" if there are changes
+        self._connect()
+        try:
+            self._negotiate()
+            # the transaction of a concurrent writer can be resumed after reconnecting.
+            resumable = concurrent and self.server_version >= parse_version("2.0.0b10")
+            self.session = bin_to_hex(os.urandom(8)) if resumable else None
+            self.open_args = dict(
+                path=self.location.path,
+                lock_wait=lock_wait,
+                lock=lock,
+                exclusive=exclusive,
+                append_only=append_only,
+                make_parent_dirs=make_parent_dirs,
+                concurrent=concurrent,
+                session=self.session,
+            )
+            self.id = self.open(create=create, **self.open_args)
+            if args and args.metadata_cache:
+                self.metadata_cache = MetadataCache(self.id, args.metadata_cache)
+            info = self.info()
+            self.version = info["version"]
+            self.append_only = info["append_only"]
+
+        except Exception:
+            self.close()
+            raise
+
+    def _connect(self):
+        args, location = self._args, self.location
         if self.location.proto == "ssh":
             testing = location.host == "__testsuite__"
             # when testing, we invoke and talk to a borg process directly (no ssh).
@@ -611,6 +1736,13 @@ def __init__(
             self.stderr_fd = None
             self.r_fds = [self.stdout_fd]
             self.x_fds = [self.stdin_fd, self.stdout_fd]
+        elif self.location.proto in ("http", "https"):
+            self.sock = self.http_connect(location)
+            self.stdin_fd = self.sock.makefile("wb").fileno()
+            self.stdout_fd = self.sock.makefile("rb").fileno()
+            self.stderr_fd = None
+            self.r_fds = [self.stdout_fd]
+            self.x_fds = [self.stdin_fd, self.stdout_fd]
         else:
             raise Error(f"Unsupported protocol {location.proto}")
 
@@ -622,32 +1754,134 @@ def __init__(
             os.set_blocking(self.stderr_fd, False)
             assert not os.get_blocking(self.stderr_fd)
 
+    def http_connect(self, location):
+        """connect to borg serve --http, upgrade the HTTP connection to the RPC stream and return its socket"""
+        url = location.canonical_path()
+        https = location.proto == "https"
         try:
-            try:
-                version = self.call("negotiate", {"client_data": {"client_version": BORG_VERSION}})
-            except ConnectionClosed:
-                raise ConnectionClosedWithHint("Is borg working on the server?") from None
-            if isinstance(version, dict):
-                self.server_version = version["server_version"]
-            else:
-                raise Exception("Server insisted on using unsupported protocol version %s" % version)
-
-            self.id = self.open(
-                path=self.location.path,
-                create=create,
-                lock_wait=lock_wait,
-                lock=lock,
-                exclusive=exclusive,
-                append_only=append_only,
-                make_parent_dirs=make_parent_dirs,
-            )
-            info = self.info()
-            self.version = info["version"]
-            self.append_only = info["append_only"]
+            sock = socket.create_connection((location.host, location.port or (443 if https else 80)), HTTP_TIMEOUT)
+        except OSError as e:
+            raise Error(f"Connecting to {url} failed: {e}") from None
+        try:
+            if https:
+                context = ssl.create_default_context(cafile=os.environ.get("BORG_HTTP_CA_CERT"))
+                if os.environ.get("BORG_HTTP_CLIENT_CERT"):
+                    context.load_cert_chain(os.environ["BORG_HTTP_CLIENT_CERT"], os.environ.get("BORG_HTTP_CLIENT_KEY"))
+                sock = context.wrap_socket(sock, server_hostname=location.host)
+            request = [
+                f"GET {urllib.parse.quote(location.path)} HTTP/1.1",
+                f"Host: {location._host}" + (f":{location.port}" if location.port else ""),
+                f"User-Agent: borg/{__version__}",
+                f"Upgrade: {HTTP_UPGRADE}",
+                "Connection: Upgrade",
+            ]
+            if os.environ.get("BORG_HTTP_TOKEN"):
+                request.append(f"Authorization: Bearer {os.environ['BORG_HTTP_TOKEN']}")
+            sock.sendall("\r\n".join(request + ["", ""]).encode("latin-1"))
+            status, headers = read_http_head(sock, time.monotonic() + HTTP_TIMEOUT)
+            sock.settimeout(None)
+        except (OSError, ValueError, ConnectionClosed) as e:
+            sock.close()
+            raise Error(f"Connecting to {url} failed: {e}") from None
+        if status.split(" ")[1:2] != ["101"]:
+            sock.close()
+            hint = " Check BORG_HTTP_TOKEN or the client certificate." if " 401 " in status + " " else ""
+            raise Error(f"Connecting to {url} failed: the server responded {status!r}.{hint}")
+        if https:
+            self.tls_relay = TLSRelay(sock)
+            return self.tls_relay.sock
+        return sock
+
+    def _negotiate(self):
+        try:
+            client_data = {"client_version": BORG_VERSION, "rpc_protocol": RPC_PROTOCOL, "window": RPC_WINDOW}
+            if self.rpc_compression_spec is not None:
+                client_data["compression"] = self.rpc_compression_spec
+            version = self.call("negotiate", {"client_data": client_data})
+        except ConnectionClosed:
+            raise ConnectionClosedWithHint("Is borg working on the server?") from None
+        if isinstance(version, dict):
+            self.server_version = version["server_version"]
+            # older servers do not send these and talk protocol 1.
+            self.protocol = version.get("rpc_protocol", 1)
+            self.write_window = version.get("write_window", 0)
+            if self.protocol >= 2:
+                self.streams = {channel: StreamChannel(RPC_WINDOW) for channel in (CHANNEL_GET, CHANNEL_PRELOAD)}
+            if version.get("compression"):
+                # older servers do not compress, newer ones wait for our first frame to start compressing.
+                self.rpc_compression = RPCCompression(version["compression"][1])
+        else:
+            raise Exception("Server insisted on using unsupported protocol version %s" % version)
 
-        except Exception:
-            self.close()
-            raise
+    def _disconnect(self):
+        """forget a dropped connection"""
+        if self.p:
+            for f in (self.p.stdin, self.p.stdout, self.p.stderr):
+                try:
+                    f.close()
+                except OSError:
+                    pass
+            try:
+                self.p.wait(timeout=10)
+            except TimeoutExpired:
+                self.p.kill()
+                self.p.wait()
+            self.p = None
+        if self.sock:
+            self.sock.close()
+            self.sock = None
+        self.tls_relay = None  # it closes the TLS connection after the socket
+
+    def reconnect(self):
+        """
+        Reconnect after the connection dropped and send the requests not answered yet again.
+
+        This is only done if it is safe: the server might have processed these requests already, so they must
+        be idempotent, and changes not committed yet must be resumable (see Repository.resume).
+        Returns whether we are connected again.
+        """
+        if self.reconnecting or not self.reconnect_timeout or self.txn_state == "unsafe":
+            return False
+        if any(method not in REPLAYABLE_RPCS for method, _ in self.sent.values()):
+            return False
+        logger.warning("Connection to %s lost, reconnecting...", self.location.processed)
+        deadline = time.monotonic() + self.reconnect_timeout
+        delay = 1
+        requests = sorted(self.sent.items())
+        self.reconnecting = True
+        try:
+            while True:
+                self._disconnect()
+                self.sent.clear()
+                self.to_send = EfficientCollectionQueue(1024 * 1024, bytes)
+                self.rpc_compression = None
+                self.to_compress.clear()
+                self.to_compress_size = 0
+                self.unpacker = get_limited_unpacker("client")
+                self.stderr_received = b""
+                try:
+                    self._connect()
+                    self._negotiate()
+                    self.open(**self.open_args, resume=self.txn_state == "staged")
+                    break
+                except (ConnectionClosed, OSError) as e:
+                    if time.monotonic() + delay > deadline:
+                        raise ConnectionClosed() from e
+                    logger.debug("Reconnecting failed: %s, trying again in %d seconds.", e, delay)
+                    time.sleep(delay)
+                    delay = min(delay * 2, 30)
+        finally:
+            self.reconnecting = False
+        # the new connection has new flow control windows (see _negotiate), forget responses received before.
+        replayed = {msgid for msgid, _ in requests}
+        self.stream_msgids = {msgid: channel for msgid, channel in self.stream_msgids.items() if msgid in replayed}
+        for channel in self.stream_msgids.values():
+            self.streams[channel].outstanding += 1
+        for msgid, (method, request) in requests:
+            self.sent[msgid] = method, request
+            self.push_send(request)
+        logger.warning("Reconnected to %s.", self.location.processed)
+        return True
 
     def __del__(self):
         if len(self.responses):
@@ -721,6 +1955,8 @@ def borg_cmd(self, args, testing):
 
             if "storage_quota" in args and args.storage_quota:
                 opts.append("--storage-quota=%s" % args.storage_quota)
+            if "allow_mirror" in args and args.allow_mirror:
+                opts.append("--allow-mirror")
         env_vars = []
         if testing:
             return env_vars + [sys.executable, "-m", "borg", "serve"] + opts + self.extra_test_args
@@ -741,6 +1977,25 @@ def ssh_cmd(self, location):
             args.append("%s" % location.host)
         return args
 
+    def push_send(self, msg):
+        """queue a packed message for sending"""
+        if self.rpc_compression is None:
+            self.to_send.push_back(msg)
+            return
+        self.to_compress.append(msg)
+        self.to_compress_size += len(msg)
+        if self.to_compress_size >= RPC_FRAME_SIZE:
+            self.compress_frame()
+
+    def compress_frame(self):
+        """move the queued messages into a frame, see RPCCompression"""
+        self.to_send.push_back(self.rpc_compression.compress(b"".join(self.to_compress)))
+        self.to_compress.clear()
+        self.to_compress_size = 0
+
+    def unsent(self):
+        return len(self.to_send) + self.to_compress_size
+
     def call(self, cmd, args, **kw):
         for resp in self.call_many(cmd, [args], **kw):
             return resp
@@ -750,18 +2005,95 @@ def call_many(self, cmd, calls, wait=True, is_preloaded=False, async_wait=True):
             return
 
         def send_buffer():
+            if self.to_compress and not self.to_send:
+                self.compress_frame()
             if self.to_send:
                 try:
-                    written = self.ratelimit.write(self.stdin_fd, self.to_send.peek_front())
+                    written = self.upload_ratelimit.write(self.stdin_fd, self.to_send.peek_front())
                     self.tx_bytes += written
                     self.to_send.pop_front(written)
                 except OSError as e:
                     # io.write might raise EAGAIN even though select indicates
                     # that the fd should be writable.
                     # EWOULDBLOCK is added for defensive programming sake.
-                    if e.errno not in [errno.EAGAIN, errno.EWOULDBLOCK]:
+                    # EPIPE: the connection dropped, we will notice when reading, see connection_lost.
+                    if e.errno not in [errno.EAGAIN, errno.EWOULDBLOCK, errno.EPIPE]:
                         raise
 
+        def connection_lost():
+            if not self.reconnect():
+                raise ConnectionClosed()
+
+        def can_send():
+            if not (calls or self.preload_ids) or len(waiting_for) >= max_inflight:
+                return False
+            if cmd == "put" and calls and self.put_unacked:
+                # protocol 2: the server takes only so much put data before it acknowledges it
+                return self.put_unacked + len(calls[0]["data"]) <= self.write_window
+            return True
+
+        def send_request(method, args, channel):
+            self.msgid += 1
+            request = {MSGID: self.msgid, MSG: method, ARGS: args}
+            if method in ("put", "delete"):
+                # a concurrent writer's changes are resumable after reconnecting, see reconnect.
+                self.txn_state = self.txn_state or ("staged" if self.session else "unsafe")
+            elif method == "begin_commit":
+                self.txn_state = "unsafe"  # the commit lock would be lost
+            if self.protocol >= 2:
+                if channel != CHANNEL_CONTROL:
+                    request[CHANNEL] = channel
+                    self.stream_msgids[self.msgid] = channel
+                    self.streams[channel].outstanding += 1
+                elif method == "put":
+                    self.put_sizes[self.msgid] = len(args["data"])
+                    self.put_unacked += len(args["data"])
+            packed = msgpack.packb(request)
+            self.sent[self.msgid] = method, packed
+            self.push_send(packed)
+            return self.msgid
+
+        def response_size(unpacked):
+            result = unpacked.get(RESULT)
+            return STREAM_MSG_OVERHEAD + (len(result) if isinstance(result, bytes) else 0)
+
+        def received(msgid, unpacked):
+            """bookkeeping for a received response, see reconnect, protocol 2: flow control accounting"""
+            method, _ = self.sent.pop(msgid, (None, None))
+            if method in ("commit", "rollback"):
+                self.txn_state = None
+            self.put_unacked -= self.put_sizes.pop(msgid, 0)
+            channel = self.stream_msgids.get(msgid)
+            if channel is not None:
+                self.streams[channel].received += response_size(unpacked)
+                self.streams[channel].outstanding -= 1
+                if msgid in self.ignore_responses:
+                    consumed(msgid, unpacked)  # nobody is going to consume it
+
+        def consumed(msgid, unpacked):
+            """protocol 2: give credit for a consumed response back to the server"""
+            channel = self.stream_msgids.pop(msgid, None)
+            if channel is not None:
+                grant_window(channel, self.streams[channel].consume(response_size(unpacked)))
+
+        def grant_window(channel, grant):
+            if grant:
+                self.push_send(msgpack.packb({CHANNEL: channel, WINDOW: grant}))
+
+        def grant_stalled():
+            """
+            protocol 2: give extra credit if we wait for responses the server can not send.
+
+            this happens if the responses received before were not consumed yet, e.g. preloaded chunks
+            or the responses of an outer get_many while an inner one is iterated.
+            """
+            channel = self.stream_msgids.get(waiting_for[0]) if waiting_for else None
+            for ch, stream in self.streams.items():
+                if (channel is None or ch == channel) and stream.stalled():
+                    grant = stream.window // 4
+                    stream.granted += grant
+                    grant_window(ch, grant)
+
         def pop_preload_msgid(chunkid):
             msgid = self.chunkid_to_msgids[chunkid].pop(0)
             if not self.chunkid_to_msgids[chunkid]:
@@ -789,12 +2121,18 @@ def handle_error(unpacked):
                 raise IntegrityError(args[0])
             elif error == "PathNotAllowed":
                 raise PathNotAllowed(args[0])
+            elif error == "PolicyDenied":
+                raise PolicyDenied(args[0])
             elif error == "PathPermissionDenied":
                 raise Repository.PathPermissionDenied(args[0])
             elif error == "ParentPathDoesNotExist":
                 raise Repository.ParentPathDoesNotExist(args[0])
             elif error == "ObjectNotFound":
                 raise Repository.ObjectNotFound(args[0], self.location.processed)
+            elif error == "RetentionLocked":
+                raise Repository.RetentionLocked(args[0], args[1])
+            elif error == "SessionNotResumable":
+                raise Repository.SessionNotResumable(args[0], args[1])
             elif error == "InvalidRPCMethod":
                 raise InvalidRPCMethod(args[0])
             elif error == "LockTimeout":
@@ -811,6 +2149,7 @@ def handle_error(unpacked):
         calls = list(calls)
         waiting_for = []
         maximum_to_send = 0 if wait else self.upload_buffer_size_limit
+        max_inflight = MAX_INFLIGHT_STREAMING if self.protocol >= 2 and cmd in ("get", "put") else MAX_INFLIGHT
         send_buffer()  # Try to send data, as some cases (async_response) will never try to send data otherwise.
         while wait or calls:
             if self.shutdown_time and time.monotonic() > self.shutdown_time:
@@ -825,7 +2164,7 @@ def handle_error(unpacked):
             while waiting_for:
                 try:
                     unpacked = self.responses.pop(waiting_for[0])
-                    waiting_for.pop(0)
+                    consumed(waiting_for.pop(0), unpacked)
                     handle_error(unpacked)
                     yield unpacked[RESULT]
                     if not waiting_for and not calls:
@@ -846,7 +2185,9 @@ def handle_error(unpacked):
                     else:
                         handle_error(unpacked)
                         yield unpacked[RESULT]
-            if self.to_send or ((calls or self.preload_ids) and len(waiting_for) < MAX_INFLIGHT):
+            if self.streams:
+                grant_stalled()
+            if self.unsent() or can_send():
                 w_fds = [self.stdin_fd]
             else:
                 w_fds = []
@@ -855,10 +2196,16 @@ def handle_error(unpacked):
                 raise Exception("FD exception occurred")
             for fd in r:
                 if fd is self.stdout_fd:
-                    data = os.read(fd, BUFSIZE)
+                    data = self.download_ratelimit.read(fd, BUFSIZE)
                     if not data:
-                        raise ConnectionClosed()
+                        connection_lost()
+                        break
                     self.rx_bytes += len(data)
+                    if self.rpc_compression is not None:
+                        try:
+                            data = self.rpc_compression.decompress(data)
+                        except ValueError:
+                            raise UnexpectedRPCDataFormatFromServer(data) from None
                     self.unpacker.feed(data)
                     for unpacked in self.unpacker:
                         if not isinstance(unpacked, dict):
@@ -873,6 +2220,7 @@ def handle_error(unpacked):
                             continue
 
                         msgid = unpacked[MSGID]
+                        received(msgid, unpacked)
                         if msgid in self.ignore_responses:
                             self.ignore_responses.remove(msgid)
                             # async methods never return values, but may raise exceptions.
@@ -888,7 +2236,8 @@ def handle_error(unpacked):
                 elif fd is self.stderr_fd:
                     data = os.read(fd, 32768)
                     if not data:
-                        raise ConnectionClosed()
+                        connection_lost()
+                        break
                     self.rx_bytes += len(data)
                     # deal with incomplete lines (may appear due to block buffering)
                     if self.stderr_received:
@@ -907,11 +2256,7 @@ def handle_error(unpacked):
                         # decode late, avoid partial utf-8 sequences.
                         _logger.warning("stderr: " + line.decode().strip())
             if w:
-                while (
-                    (len(self.to_send) <= maximum_to_send)
-                    and (calls or self.preload_ids)
-                    and len(waiting_for) < MAX_INFLIGHT
-                ):
+                while self.unsent() <= maximum_to_send and can_send():
                     if calls:
                         if is_preloaded:
                             assert cmd == "get", "is_preload is only supported for 'get'"
@@ -922,15 +2267,12 @@ def handle_error(unpacked):
                             if cmd == "get" and args["id"] in self.chunkid_to_msgids:
                                 waiting_for.append(pop_preload_msgid(args["id"]))
                             else:
-                                self.msgid += 1
-                                waiting_for.append(self.msgid)
-                                self.to_send.push_back(msgpack.packb({MSGID: self.msgid, MSG: cmd, ARGS: args}))
-                    if not self.to_send and self.preload_ids:
+                                channel = CHANNEL_GET if cmd == "get" else CHANNEL_CONTROL
+                                waiting_for.append(send_request(cmd, args, channel))
+                    if not self.unsent() and self.preload_ids:
                         chunk_id = self.preload_ids.pop(0)
-                        args = {"id": chunk_id}
-                        self.msgid += 1
-                        self.chunkid_to_msgids.setdefault(chunk_id, []).append(self.msgid)
-                        self.to_send.push_back(msgpack.packb({MSGID: self.msgid, MSG: "get", ARGS: args}))
+                        msgid = send_request("get", {"id": chunk_id}, CHANNEL_PRELOAD)
+                        self.chunkid_to_msgids.setdefault(chunk_id, []).append(msgid)
 
                 send_buffer()
         self.ignore_responses |= set(waiting_for)  # we lose order here
@@ -939,9 +2281,22 @@ def handle_error(unpacked):
         since=parse_version("1.0.0"),
         append_only={"since": parse_version("1.0.7"), "previously": False},
         make_parent_dirs={"since": parse_version("1.1.9"), "previously": False},
+        concurrent={"since": parse_version("2.0.0b10"), "previously": False},
+        session={"since": parse_version("2.0.0b10"), "previously": None},
+        resume={"since": parse_version("2.0.0b10"), "previously": False},
     )
     def open(
-        self, path, create=False, lock_wait=None, lock=True, exclusive=False, append_only=False, make_parent_dirs=False
+        self,
+        path,
+        create=False,
+        lock_wait=None,
+        lock=True,
+        exclusive=False,
+        append_only=False,
+        make_parent_dirs=False,
+        concurrent=False,
+        session=None,
+        resume=False,
     ):
         """actual remoting is done via self.call in the @api decorator"""
 
@@ -953,6 +2308,42 @@ def info(self):
     def check(self, repair=False, max_duration=0):
         """actual remoting is done via self.call in the @api decorator"""
 
+    @api(since=parse_version("2.0.0b10"))
+    def check_summary(self):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def scrub(self):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def mirror_info(self):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def mirror_read(self, kind, number, offset, size):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def mirror_write(self, kind, number, offset, data):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def mirror_commit(self, id, transaction_id, segments):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def retention_lock(self, ids, until, flush=True):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def get_retention(self, ids):
+        """actual remoting is done via self.call in the @api decorator"""
+
+    @api(since=parse_version("2.0.0b10"))
+    def compact_slice(self, threshold=0.1, max_duration=None):
+        """actual remoting is done via self.call in the @api decorator"""
+
     @api(
         since=parse_version("1.0.0"),
         compact={"since": parse_version("1.2.0a0"), "previously": True, "dontcare": True},
@@ -961,6 +2352,10 @@ def check(self, repair=False, max_duration=0):
     def commit(self, compact=True, threshold=0.1):
         """actual remoting is done via self.call in the @api decorator"""
 
+    @api(since=parse_version("2.0.0b10"))
+    def begin_commit(self):
+        """actual remoting is done via self.call in the @api decorator"""
+
     @api(since=parse_version("1.0.0"))
     def rollback(self):
         """actual remoting is done via self.call in the @api decorator"""
@@ -993,12 +2388,20 @@ def flags(self, id, mask=0xFFFFFFFF, value=None):
     def flags_many(self, ids, mask=0xFFFFFFFF, value=None):
         """actual remoting is done via self.call in the @api decorator"""
 
-    def get(self, id, read_data=True):
-        for resp in self.get_many([id], read_data=read_data):
+    def get(self, id, read_data=True, metadata=False):
+        for resp in self.get_many([id], read_data=read_data, metadata=metadata):
             return resp
 
-    def get_many(self, ids, read_data=True, is_preloaded=False):
-        yield from self.call_many("get", [{"id": id, "read_data": read_data} for id in ids], is_preloaded=is_preloaded)
+    def get_many(self, ids, read_data=True, is_preloaded=False, metadata=False):
+        """*metadata* tells that *ids* are archive metadata objects, these are served from the MetadataCache"""
+
+        def fetch_many(ids):
+            return self.call_many("get", [{"id": id, "read_data": read_data} for id in ids], is_preloaded=is_preloaded)
+
+        if metadata and read_data and not is_preloaded and self.metadata_cache is not None:
+            yield from self.metadata_cache.get_many(ids, fetch_many)
+        else:
+            yield from fetch_many(ids)
 
     @api(since=parse_version("1.0.0"))
     def put(self, id, data, wait=True):
@@ -1021,8 +2424,14 @@ def break_lock(self):
         """actual remoting is done via self.call in the @api decorator"""
 
     def close(self):
+        if self.metadata_cache is not None:
+            self.metadata_cache.close()
+            self.metadata_cache = None
         if self.p or self.sock:
-            self.call("close", {}, wait=True)
+            try:
+                self.call("close", {}, wait=True)
+            except ConnectionClosed:
+                pass  # the connection dropped and we could not reconnect, the server is gone.
         if self.p:
             self.p.stdin.close()
             self.p.stdout.close()
@@ -1068,11 +2477,11 @@ def __enter__(self):
     def __exit__(self, exc_type, exc_val, exc_tb):
         self.close()
 
-    def get(self, key, read_data=True):
-        return next(self.get_many([key], read_data=read_data, cache=False))
+    def get(self, key, read_data=True, metadata=False):
+        return next(self.get_many([key], read_data=read_data, cache=False, metadata=metadata))
 
-    def get_many(self, keys, read_data=True, cache=True):
-        for key, data in zip(keys, self.repository.get_many(keys, read_data=read_data)):
+    def get_many(self, keys, read_data=True, cache=True, metadata=False):
+        for key, data in zip(keys, self.repository.get_many(keys, read_data=read_data, metadata=metadata)):
             yield self.transform(key, data)
 
     def log_instrumentation(self):
@@ -1177,10 +2586,12 @@ def close(self):
         self.cache.clear()
         shutil.rmtree(self.basedir)
 
-    def get_many(self, keys, read_data=True, cache=True):
+    def get_many(self, keys, read_data=True, cache=True, metadata=False):
         # It could use different cache keys depending on read_data and cache full vs. meta-only chunks.
         unknown_keys = [key for key in keys if self.prefixed_key(key, complete=read_data) not in self.cache]
-        repository_iterator = zip(unknown_keys, self.repository.get_many(unknown_keys, read_data=read_data))
+        repository_iterator = zip(
+            unknown_keys, self.repository.get_many(unknown_keys, read_data=read_data, metadata=metadata)
+        )
         for key in keys:
             pkey = self.prefixed_key(key, complete=read_data)
             if pkey in self.cache:
@@ -1198,7 +2609,7 @@ def get_many(self, keys, read_data=True, cache=True):
                 else:
                     # slow path: eviction during this get_many removed this key from the cache
                     t0 = time.perf_counter()
-                    data = self.repository.get(key, read_data=read_data)
+                    data = self.repository.get(key, read_data=read_data, metadata=metadata)
                     self.slow_lat += time.perf_counter() - t0
                     transformed = self.add_entry(key, data, cache, complete=read_data)
                     self.slow_misses += 1
@@ -1208,6 +2619,150 @@ def get_many(self, keys, read_data=True, cache=True):
             pass
 
 
+class MetadataCache:
+    """
+    A persistent, size-bounded local cache of archive metadata objects of a remote repository.
+
+    The objects are stored as they come from the repository (encrypted and authenticated, callers
+    verify them when parsing, as usual), prefixed with a xxh64 checksum to detect local corruption.
+    Corrupted objects are discarded and fetched again. If the cache grows bigger than *size_limit*,
+    the least recently used objects are evicted.
+
+    The cache lives in <cache dir>/metadata/<repository id> and is reused by all later borg
+    invocations using that repository. Only content-addressed, never modified objects (archive
+    metadata, item metadata streams) must be put into it.
+    """
+
+    def __init__(self, repository_id, size_limit, path=None):
+        self.path = path or os.path.join(get_cache_dir(), "metadata", bin_to_hex(repository_id))
+        self.size_limit = size_limit
+        self.size = None  # we only scan the cache (see scan) when storing the first object
+        # Instrumentation
+        self.hits = 0
+        self.misses = 0
+        self.corrupted = 0
+        self.evictions = 0
+
+    def object_path(self, id):
+        hex_id = bin_to_hex(id)
+        return os.path.join(self.path, hex_id[:2], hex_id)
+
+    def scan(self):
+        """return a list of (mtime, size, path) of all cached objects"""
+        objects = []
+        try:
+            subdirs = [entry.path for entry in os.scandir(self.path) if entry.is_dir()]
+        except FileNotFoundError:
+            return objects
+        for subdir in subdirs:
+            for entry in os.scandir(subdir):
+                try:
+                    st = entry.stat()
+                except FileNotFoundError:
+                    continue  # another borg process evicted it
+                objects.append((st.st_mtime, st.st_size, entry.path))
+        return objects
+
+    def load(self, id):
+        """return the cached object *id* or None"""
+        path = self.object_path(id)
+        try:
+            with open(path, "rb") as fd:
+                data = fd.read()
+        except FileNotFoundError:
+            return None
+        checksum, data = data[:8], data[8:]
+        if checksum != xxh64(data):
+            logger.warning("Discarding corrupted object %s from the metadata cache.", bin_to_hex(id))
+            self.corrupted += 1
+            self.discard(path)
+            return None
+        try:
+            os.utime(path)  # mtime is used for evicting the least recently used objects
+        except FileNotFoundError:
+            pass
+        return data
+
+    def store(self, id, data):
+        path = self.object_path(id)
+        dirname = os.path.dirname(path)
+        # temporary files start with a "." and stay invisible until they are complete
+        tmp_path = os.path.join(dirname, f".{os.path.basename(path)}.{os.getpid()}")
+        try:
+            os.makedirs(dirname, exist_ok=True)
+            with open(tmp_path, "wb") as fd:
+                fd.write(xxh64(data))
+                fd.write(data)
+            os.replace(tmp_path, path)
+        except OSError as os_error:
+            self.discard(tmp_path)
+            if os_error.errno == errno.ENOSPC:
+                self.evict(0)
+                return
+            raise
+        if self.size is None:
+            self.size = sum(size for _, size, _ in self.scan())
+        else:
+            self.size += 8 + len(data)
+        if self.size > self.size_limit:
+            self.evict(int(0.9 * self.size_limit))
+
+    def discard(self, path):
+        try:
+            safe_unlink(path)
+        except FileNotFoundError:
+            pass
+
+    def evict(self, target_size):
+        """evict the least recently used objects until the cache is not bigger than *target_size*"""
+        objects = sorted(self.scan())
+        self.size = sum(size for _, size, _ in objects)
+        for _, size, path in objects:
+            if self.size <= target_size:
+                break
+            self.discard(path)
+            self.size -= size
+            self.evictions += 1
+
+    def get_many(self, ids, fetch_many):
+        """yield the objects *ids*, use *fetch_many* to get the objects not in the cache from the repository"""
+        ids = list(ids)
+        # fetch each missing object once, later occurrences of its id find it in the cache.
+        missing = list(dict.fromkeys(id for id in ids if not os.path.exists(self.object_path(id))))
+        pending = set(missing)
+        fetched = zip(missing, fetch_many(missing))
+        for id in ids:
+            if id in pending:
+                pending.remove(id)
+                id_, data = next(fetched)
+                assert id_ == id
+                data_is_new = True
+            else:
+                data = self.load(id)
+                data_is_new = data is None
+                if data_is_new:
+                    # slow path: evicted or corrupted since we checked
+                    data = next(fetch_many([id]))
+            if data_is_new:
+                self.misses += 1
+                self.store(id, data)
+            else:
+                self.hits += 1
+            yield data
+        # consume any pending requests
+        for _ in fetched:
+            pass
+
+    def close(self):
+        logger.debug(
+            "MetadataCache: %d hits, %d misses, %d corrupted, %d evictions",
+            self.hits,
+            self.misses,
+            self.corrupted,
+            self.evictions,
+        )
+
+
 def cache_if_remote(repository, *, decrypted_cache=False, pack=None, unpack=None, transform=None, force_cache=False):
     """
     Return a Repository(No)Cache for *repository*.
@@ -1,7 +1,7 @@
 import errno
-import mmap
+import io
+import json
 import os
-import shutil
 import stat
 import struct
 import time
@@ -13,19 +13,20 @@
 from typing import Callable, DefaultDict
 
 from .constants import *  # NOQA
-from .hashindex import NSIndexEntry, NSIndex, NSIndex1, hashindex_variant
+from .hashindex import NSIndexEntry, NSIndex, NSIndex1, RetentionIndex, hashindex_variant
 from .helpers import Error, ErrorWithTraceback, IntegrityError, format_file_size, parse_file_size
-from .helpers import Location
 from .helpers import ProgressIndicatorPercent
 from .helpers import bin_to_hex, hex_to_bin
-from .helpers import secure_erase, safe_unlink
+from .helpers import secure_erase
 from .helpers import msgpack
 from .helpers.lrucache import LRUCache
-from .locking import Lock, LockError, LockErrorT
+from .locking import LockError, LockErrorT, SHARED
 from .logger import create_logger
 from .manifest import Manifest
-from .platform import SaveFile, SyncFile, sync_dir, safe_fadvise
+from .parity import ReedSolomon, SegmentParity, ParityError, write_parity
+from .platform import SaveFile, safe_fadvise
 from .repoobj import RepoObj
+from .storage import PosixStore
 from .checksums import crc32, StreamingXXH64
 from .crypto.file_integrity import IntegrityCheckedFile, FileIntegrityError
 
@@ -50,6 +51,27 @@
 
 FreeSpace: Callable[[], DefaultDict] = partial(defaultdict, int)
 
+# where mirror_write stages the files of a mirrored transaction
+MIRROR_STAGING = "staging/mirror"
+
+# the retention locks, see Repository.retention_lock
+RETENTION_FILE = "retention"
+
+# how far into the future objects can be retention locked [s]
+MAX_RETENTION = 100 * 366 * 24 * 3600
+
+# the index of the current transaction, see Repository "Mapped index"
+MAPPED_INDEX = "index.mapped"
+# the undo journal of the mapped index, followed by the transaction the index was at before the current one
+MAPPED_INDEX_JOURNAL = MAPPED_INDEX + ".journal."
+
+# in each additional data root, see Repository "Data roots"
+DATA_ROOT_ID_FILE = "repository-id"
+
+
+def format_retention(until):
+    return datetime.fromtimestamp(until, timezone.utc).isoformat(timespec="seconds")
+
 
 def header_size(tag):
     if tag == TAG_PUT2:
@@ -100,13 +122,99 @@ class Repository:
     Sparse segments can be compacted and thereby disk space freed. This destroys the transaction for which the
     superseded entries where current.
 
+    The files are kept in a Store (see storage.base.Store), by default a PosixStore, i.e. a local directory.
+
     On disk layout:
 
     dir/README
     dir/config
     dir/data/<X // SEGMENTS_PER_DIR>/<X>
+    dir/data/<X // SEGMENTS_PER_DIR>/<X>.parity
     dir/index.X
+    dir/index.mapped
     dir/hints.X
+    dir/retention
+    dir/staging/<WRITER>/<X // SEGMENTS_PER_DIR>/<X>
+    dir/staging/<WRITER>/owner
+    dir/staging/mirror/...
+    <DATA_ROOT>/repository-id
+    <DATA_ROOT>/<X // SEGMENTS_PER_DIR>/<X>
+
+    Parity files
+    ------------
+
+    If the repository config has parity_shards > 0, a parity file with Reed-Solomon parity data (see borg.parity)
+    is written for each segment when it is closed. It does not take part in the transactions, segments are not
+    modified after being closed. check --repair and scrub use it to rebuild damaged segments.
+
+    Data roots
+    ----------
+
+    Segments are usually all in dir/data/. The repository config can list additional data roots (local
+    directories, e.g. on other disks) in data_roots, new segments are then placed into one of the data roots by
+    free space or round-robin (data_placement). A segment keeps its number, whatever root it is in, so the index
+    does not change when adding a data root or moving segments between them. LoggedIO finds the data root of a
+    segment by looking for it. Each data root has a repository-id file, opening the repository fails if a data
+    root is missing (e.g. not mounted), so we do not take its segments as lost. Draining a data root first moves
+    it to draining_data_roots (still read, but no new segments), then moves its segments to the other roots.
+
+    Mapped index
+    ------------
+
+    By default, the index is read into memory completely and written completely at each commit. With
+    index_mode = mapped in the repository config (local repositories only), the index file is used through a
+    memory mapping (see NSIndex.map), so only the parts in use are resident. A writer holding the exclusive lock
+    updates it in place: for the transaction, index.X is renamed to index.mapped, changes are journaled in
+    index.mapped.journal.X (rollback reverts them, then renames it back) and the commit only flushes the changed
+    pages and renames it to index.Y. The journal is created before the rename and removed after the commit (or
+    rollback), its records are written before the changes they journal. So after a crash, the journal of
+    index.mapped is undone and it is renamed back to index.X, then only the segments after X are replayed. A new
+    index (e.g. while replaying all segments) is built in index.mapped directly, without a journal; after a crash,
+    it is garbage and, as there is no index.X, the index is rebuilt by replaying all segments again.
+    Everybody else maps index.X privately and writes a new index file, like in memory mode. As hashing a mapped
+    index would mean reading all of it, the integrity file has no checksum for it (borg check compares the index
+    with the segments, though).
+
+    Online compaction
+    -----------------
+
+    compact_slice() compacts without an exclusive lock: it only needs a shared lock (concurrent=True) and holds
+    the commit lock while compacting some segments for a bounded time. It commits like compact_segments does
+    after commit() and writes the index of the new transaction, so the segment / index state changes atomically
+    for everybody taking the commit lock to load the index. Readers which loaded the index before still work
+    with it, until they can not read an object because its segment was compacted away. Then they load the
+    current index and try again (see get). While concurrent writers (see below) of still running processes
+    have a transaction in progress, compact_slice() does not compact: it does not know which objects they
+    deleted, so moving objects would make their commit take these as re-added by another writer.
+
+    Retention locks
+    ---------------
+
+    retention_lock() locks objects until some point in time (WORM, write once read many): until then, they can
+    not be deleted and a PUT does not replace them, so compaction can not remove them either. The locks are kept
+    in dir/retention (a RetentionIndex id -> until [unix timestamp]), they can only be extended, never shortened.
+    They are evaluated here, so "borg serve" enforces them without needing the key. A mirror_commit() must keep
+    the locked objects, too.
+
+    Concurrent writers
+    ------------------
+
+    Usually, a writer needs an exclusive lock. Opened with concurrent=True, a writer only needs a shared lock,
+    so multiple writers can work at the same time. Each writer logs its PUTs and DELETEs to its own staging
+    segments (dir/staging/<WRITER>/...), which are not visible to others. At commit time, while holding the
+    commit lock, the staged segments get the next free segment numbers, are moved to dir/data/ and replayed
+    into the most recent index, followed by a COMMIT (like replay_segments does after a crash).
+    Writers must not overwrite each others' changes to the manifest (see begin_commit and Manifest.merge).
+
+    Resuming sessions
+    -----------------
+
+    A concurrent writer opened with a session (a name chosen by the client) uses dir/staging/<SESSION>. If the
+    connection to the client drops, "borg serve" suspends the transaction (see suspend): it keeps the staging
+    segments and marks them as suspended. A new "borg serve" process the client reconnected to adopts them
+    (see resume): it rebuilds the in-memory state of the transaction from the staging segments and continues.
+    Suspended transactions count as in progress for online compaction, unless they are older than
+    SESSION_RESUME_TIMEOUT. A writer with the exclusive lock removes them like other staging leftovers.
 
     File system interaction
     -----------------------
@@ -131,6 +239,12 @@ class Repository:
     LoggedIO gracefully handles truncate/unlink splits as long as the truncate resulted in
     a zero length file. Zero length segments are considered not to exist, while LoggedIO.cleanup()
     will still get rid of them.
+
+    Object stores can not rename, replacing is copying (which replaces the destination atomically)
+    and deleting the source (see Store.replace), so a crash can leave the source behind. Only
+    temporary files are replaced: leftover index/hints/integrity .tmp files are never read and
+    deleted by the next write_index(), leftover segment .tmp files are never read either, staged
+    segments are deleted with the staging directory. The config is stored as a whole (Store.store).
     """
 
     class AlreadyExists(Error):
@@ -193,6 +307,21 @@ class PathPermissionDenied(Error):
 
         exit_mcode = 21
 
+    class RetentionLocked(Error):
+        """Retention lock: {} can not be deleted until {}."""
+
+        exit_mcode = 24
+
+    class DataRootUnavailable(Error):
+        """Data root {} of repository {} is not available: {}."""
+
+        exit_mcode = 28
+
+    class SessionNotResumable(Error):
+        """The interrupted session {} can not be resumed in repository {}."""
+
+        exit_mcode = 29
+
     def __init__(
         self,
         path,
@@ -204,9 +333,13 @@ def __init__(
         storage_quota=None,
         make_parent_dirs=False,
         send_log_cb=None,
+        store=None,
+        concurrent=False,
+        session=None,
     ):
-        self.path = os.path.abspath(path)
-        self._location = Location("file://%s" % self.path)
+        self.store = store or PosixStore(path)
+        self.path = self.store.path
+        self._location = self.store.location
         self.version = None
         # long-running repository methods which emit log or progress output are responsible for calling
         # the ._send_log method periodically to get log and progress output transferred to the borg client
@@ -214,8 +347,13 @@ def __init__(
         # for local repositories ._send_log can be called also (it will just do nothing in that case).
         self._send_log = send_log_cb or (lambda: None)
         self.io = None  # type: LoggedIO
+        self.data_roots = []  # additional data roots, see "Data roots" above
+        self.draining_data_roots = []
         self.lock = None
         self.index = None
+        self.index_mode = DEFAULT_INDEX_MODE
+        self.index_in_place = False  # self.index is the mapped index.mapped, see "Mapped index" above
+        self.mapped_base = None  # transaction id of the index renamed to index.mapped
         # This is an index of shadowed log entries during this transaction. Consider the following sequence:
         # segment_n PUT A, segment_x DELETE A
         # After the "DELETE A" in segment_x the shadow index will contain "A -> [n]".
@@ -229,7 +367,22 @@ def __init__(
         self.do_lock = lock
         self.do_create = create
         self.created = False
-        self.exclusive = exclusive
+        # a concurrent writer only needs a shared lock, see "Concurrent writers" above.
+        self.concurrent = concurrent
+        self.exclusive = False if concurrent else exclusive
+        self.commit_lock = None
+        self.staging = None  # LoggedIO of the staging segments
+        self.last_check_summary = None  # see check_summary
+        self.staged = {}  # id -> NSIndexEntry (in staging segments) of objects put in this transaction
+        self.staged_deletes = set()  # ids of committed objects deleted in this transaction
+        self.index_transaction_id = None  # transaction id of self.index (concurrent writers)
+        self.txn_base = None  # transaction id of the index this transaction started from
+        self.session = session  # name of the staging directory, see "Resuming sessions" above
+        self.mirror_staging = None  # LoggedIO of the segments received by mirror_write
+        self.mirror_staged = set()  # numbers of the segments received by mirror_write
+        self.mirror_file = None  # (name, fd, position) of the file currently written by mirror_write
+        self.retention = None  # RetentionIndex, loaded lazily, see _retention_until
+        self.retention_pending = RetentionIndex()  # collected by retention_lock(..., flush=False)
         self.append_only = append_only
         self.storage_quota = storage_quota
         self.storage_quota_use = 0
@@ -300,6 +453,18 @@ def check_can_create_repository(self, path):
             repository, user's can only use the quota'd repository, when their --restrict-to-path points
             at the user's repository.
         """
+        if not self.store.is_local:
+            # there are no parent directories in remote stores, just check whether the store is unused.
+            if self.store.has("README"):
+                raise self.AlreadyExists(path)
+            try:
+                if self.store.list():
+                    raise self.PathAlreadyExists(path)
+            except FileNotFoundError:
+                pass  # nothing there!
+            except PermissionError:
+                raise self.PathPermissionDenied(path) from None
+            return
         try:
             st = os.stat(path)
         except FileNotFoundError:
@@ -336,17 +501,12 @@ def check_can_create_repository(self, path):
     def create(self, path):
         """Create a new empty repository at `path`"""
         self.check_can_create_repository(path)
-        if self.make_parent_dirs:
-            parent_path = os.path.join(path, os.pardir)
-            os.makedirs(parent_path, exist_ok=True)
-        if not os.path.exists(path):
-            try:
-                os.mkdir(path)
-            except FileNotFoundError as err:
-                raise self.ParentPathDoesNotExist(path) from err
-        with open(os.path.join(path, "README"), "w") as fd:
-            fd.write(REPOSITORY_README)
-        os.mkdir(os.path.join(path, "data"))
+        try:
+            self.store.create(make_parent_dirs=self.make_parent_dirs)
+        except FileNotFoundError as err:
+            raise self.ParentPathDoesNotExist(path) from err
+        self.store.store("README", REPOSITORY_README.encode())
+        self.store.makedirs("data")
         config = ConfigParser(interpolation=None)
         config.add_section("repository")
         self.version = 2
@@ -359,10 +519,20 @@ def create(self, path):
         else:
             config.set("repository", "storage_quota", "0")
         config.set("repository", "additional_free_space", "0")
+        config.set("repository", "parity_shards", str(DEFAULT_PARITY_SHARDS))
+        config.set("repository", "parity_data_shards", str(DEFAULT_PARITY_DATA_SHARDS))
+        config.set("repository", "data_placement", DEFAULT_DATA_PLACEMENT)
+        config.set("repository", "index_mode", DEFAULT_INDEX_MODE)
         config.set("repository", "id", bin_to_hex(os.urandom(32)))
         self.save_config(path, config)
 
     def save_config(self, path, config):
+        if not self.store.is_local:
+            # remote stores replace the config object as a whole, there is no old file we could erase securely.
+            buf = io.StringIO()
+            config.write(buf)
+            self.store.store("config", buf.getvalue().encode())
+            return
         config_path = os.path.join(path, "config")
         old_config_path = os.path.join(path, "config.old")
 
@@ -417,22 +587,72 @@ def destroy(self):
         """Destroy the repository at `self.path`"""
         if self.append_only:
             raise ValueError(self.path + " is in append-only mode")
+        until = max((until for id, until in self._load_retention().iteritems()), default=0)
+        if until > time.time():
+            raise self.RetentionLocked(self.path, format_retention(until))
+        data_roots = self.data_roots + self.draining_data_roots
         self.close()
-        os.remove(os.path.join(self.path, "config"))  # kill config first
-        shutil.rmtree(self.path)
+        self.store.delete("config")  # kill config first
+        for root in data_roots:
+            self._remove_tree(root)
+        self.store.destroy()
 
     def get_index_transaction_id(self):
+        if self.index_in_place:
+            # index.<mapped_base> is renamed to index.mapped while the transaction updates it in place
+            return self.mapped_base
         indices = sorted(
-            int(fn[6:])
-            for fn in os.listdir(self.path)
-            if fn.startswith("index.") and fn[6:].isdigit() and os.stat(os.path.join(self.path, fn)).st_size != 0
+            int(entry.name[6:])
+            for entry in self.store.list()
+            if entry.name.startswith("index.") and entry.name[6:].isdigit() and self.store.size(entry.name) != 0
         )
         if indices:
             return indices[-1]
         else:
             return None
 
+    def _recover_mapped_index(self):
+        """undo the changes of a crashed writer to the mapped index, so it is index.X again, see "Mapped index" """
+        if self.index_in_place:
+            return  # the journal of our own transaction
+        names = {entry.name for entry in self.store.list()}
+        prefix = MAPPED_INDEX_JOURNAL
+        journals = [name for name in names if name.startswith(prefix) and name[len(prefix) :].isdigit()]
+        if not journals:
+            return
+        if self.do_lock and not self.lock.got_exclusive_lock() and self.commit_lock is None:
+            self.lock.upgrade()
+        for journal_name in journals:
+            base = int(journal_name[len(prefix) :])
+            index_name = "index.%d" % base
+            # without index.mapped, the writer crashed before renaming index.X or after the commit renamed it.
+            if MAPPED_INDEX in names and index_name not in names:
+                journal_path = self.store.filename(journal_name)
+                try:
+                    index = NSIndex.map(self.store.filename(MAPPED_INDEX))
+                    index.start_journal(journal_path)
+                    index.undo_journal()
+                    # index.X only refers to segments up to X, a newer one means the undo did not work out.
+                    index.start_journal(journal_path)
+                    for key in index.journal_keys():
+                        entry = index.get(key)
+                        if entry is not None and entry.segment > base:
+                            raise ValueError("index refers to segment %d, after transaction %d" % (entry.segment, base))
+                    index.stop_journal()
+                    index.flush()
+                except (ValueError, OSError) as exc:
+                    logger.warning("Index of a crashed transaction is damaged, rebuilding it: %s", exc)
+                    index = None
+                    self.store.delete(MAPPED_INDEX)
+                else:
+                    del index
+                    self.store.replace(MAPPED_INDEX, index_name)
+                    self.store.sync_dir()
+                    logger.info("Reverted the index of a crashed transaction to transaction %d.", base)
+            self.store.delete(journal_name)
+
     def check_transaction(self):
+        self._recover_mapped_index()
         index_transaction_id = self.get_index_transaction_id()
         segments_transaction_id = self.io.get_segments_transaction_id()
         if index_transaction_id is not None and segments_transaction_id is None:
@@ -457,7 +677,8 @@ def get_transaction_id(self):
         return self.get_index_transaction_id()
 
     def break_lock(self):
-        Lock(os.path.join(self.path, "lock")).break_lock()
+        self.store.get_lock().break_lock()
+        self.store.get_exclusive_lock("lock.commit").break_lock()
 
     def migrate_lock(self, old_id, new_id):
         # note: only needed for local repos
@@ -466,20 +687,17 @@ def migrate_lock(self, old_id, new_id):
 
     def open(self, path, exclusive, lock_wait=None, lock=True):
         self.path = path
-        try:
-            st = os.stat(path)
-        except FileNotFoundError:
+        if not self.store.exists():
             raise self.DoesNotExist(path)
-        if not stat.S_ISDIR(st.st_mode):
+        if not self.store.isdir():
             raise self.InvalidRepository(path)
         if lock:
-            self.lock = Lock(os.path.join(path, "lock"), exclusive, timeout=lock_wait).acquire()
+            self.lock = self.store.get_lock(exclusive, timeout=lock_wait).acquire()
         else:
             self.lock = None
         self.config = ConfigParser(interpolation=None)
         try:
-            with open(os.path.join(self.path, "config")) as fd:
-                self.config.read_file(fd)
+            self.config.read_string(self.store.load("config").decode())
         except FileNotFoundError:
             self.close()
             raise self.InvalidRepository(self.path)
@@ -505,27 +723,155 @@ def open(self, path, exclusive, lock_wait=None, lock=True):
             # self.storage_quota is None => no explicit storage_quota was specified, use repository setting.
             self.storage_quota = parse_file_size(self.config.get("repository", "storage_quota", fallback=0))
         self.id = hex_to_bin(self.config.get("repository", "id").strip(), length=32)
-        self.io = LoggedIO(self.path, self.max_segment_size, self.segments_per_dir)
+        parity_shards = self.config.getint("repository", "parity_shards", fallback=DEFAULT_PARITY_SHARDS)
+        parity_data_shards = self.config.getint("repository", "parity_data_shards", fallback=DEFAULT_PARITY_DATA_SHARDS)
+        try:
+            parity = ReedSolomon(parity_data_shards, parity_shards) if parity_shards else None
+        except ValueError as err:
+            self.close()
+            raise self.InvalidRepositoryConfig(path, str(err))
+        self.data_roots = self._config_list("data_roots")
+        self.draining_data_roots = self._config_list("draining_data_roots")
+        self.data_placement = self.config.get("repository", "data_placement", fallback=DEFAULT_DATA_PLACEMENT)
+        if self.data_placement not in DATA_PLACEMENTS:
+            self.close()
+            raise self.InvalidRepositoryConfig(path, "data_placement must be one of %s" % ", ".join(DATA_PLACEMENTS))
+        if (self.data_roots or self.draining_data_roots) and not self.store.is_local:
+            self.close()
+            raise self.InvalidRepositoryConfig(path, "data roots are only supported for local repositories")
+        self.index_mode = self.config.get("repository", "index_mode", fallback=DEFAULT_INDEX_MODE)
+        if self.index_mode not in INDEX_MODES:
+            self.close()
+            raise self.InvalidRepositoryConfig(path, "index_mode must be one of %s" % ", ".join(INDEX_MODES))
+        if self.index_mode == "mapped" and not self.store.is_local:
+            self.close()
+            raise self.InvalidRepositoryConfig(path, "index_mode mapped is only supported for local repositories")
+        for root in self.data_roots + self.draining_data_roots:
+            self._check_data_root(root)
+        self.io = LoggedIO(
+            self.store,
+            self.max_segment_size,
+            self.segments_per_dir,
+            parity=parity,
+            data_roots=self.data_roots,
+            draining_data_roots=self.draining_data_roots,
+            placement=self.data_placement,
+        )
+
+    def _config_list(self, name):
+        value = self.config.get("repository", name, fallback="")
+        return [item.strip() for item in value.split(",") if item.strip()]
+
+    def _set_config_list(self, name, items):
+        if items:
+            self.config.set("repository", name, ",".join(items))
+        else:
+            self.config.remove_option("repository", name)
+
+    def _check_data_root(self, root):
+        try:
+            repository_id = self.store.load(root + "/" + DATA_ROOT_ID_FILE).decode().strip()
+        except FileNotFoundError:
+            self.close()
+            raise self.DataRootUnavailable(root, self.path, "not found (not mounted?)") from None
+        if repository_id != bin_to_hex(self.id):
+            self.close()
+            raise self.DataRootUnavailable(root, self.path, "it belongs to another repository")
+
+    def _update_data_roots(self, data_roots, draining_data_roots):
+        """save the data roots in the repo config and use them"""
+        self._set_config_list("data_roots", data_roots)
+        self._set_config_list("draining_data_roots", draining_data_roots)
+        self.save_config(self.path, self.config)
+        self.data_roots, self.draining_data_roots = list(data_roots), list(draining_data_roots)
+        self.io.close()
+        self.io = LoggedIO(
+            self.store,
+            self.max_segment_size,
+            self.segments_per_dir,
+            parity=self.io.parity,
+            data_roots=self.data_roots,
+            draining_data_roots=self.draining_data_roots,
+            placement=self.data_placement,
+        )
+
+    def add_data_root(self, path):
+        """add a data root (a local directory, which must be empty or not exist yet), see "Data roots" above"""
+        if not self.store.is_local:
+            raise Error("Data roots are only supported for local repositories.")
+        assert self.lock.got_exclusive_lock()
+        root = os.path.abspath(path).replace(os.sep, "/")
+        if "," in root:
+            raise Error(f"The data root path {root} must not contain a comma.")
+        if root in self.data_roots + self.draining_data_roots:
+            raise Error(f"{root} already is a data root of repository {self.path}.")
+        if (root + "/").startswith(self.store.local_path().rstrip("/") + "/"):
+            raise Error(f"The data root {root} must be outside of the repository directory.")
+        try:
+            if self.store.list(root):
+                raise Error(f"The data root {root} must be empty.")
+        except FileNotFoundError:
+            self.store.makedirs(root)
+        self.store.store(root + "/" + DATA_ROOT_ID_FILE, bin_to_hex(self.id).encode())
+        self._update_data_roots(self.data_roots + [root], self.draining_data_roots)
+        logger.info("Added data root %s.", root)
+
+    def drain_data_root(self, path):
+        """move the segments of a data root to the other data roots and remove it, see "Data roots" above"""
+        assert self.lock.got_exclusive_lock()
+        root = os.path.abspath(path).replace(os.sep, "/")
+        if root not in self.data_roots + self.draining_data_roots:
+            raise Error(f"{root} is not a data root of repository {self.path}.")
+        if root not in self.draining_data_roots:
+            # from now on, no new segments go there.
+            data_roots = [data_root for data_root in self.data_roots if data_root != root]
+            self._update_data_roots(data_roots, self.draining_data_roots + [root])
+        segments = [segment for segment, _ in self.io.segment_iterator() if self.io.segment_root(segment) == root]
+        pi = ProgressIndicatorPercent(
+            total=len(segments), msg="Moving segments %3.0f%%", step=1, msgid="repository.drain_data_root"
+        )
+        for segment in segments:
+            pi.show()
+            self.io.move_segment(segment, self.io.place_segment(segment))
+        pi.finish()
+        self.io.clear_empty_dirs()
+        self.store.delete(root + "/" + DATA_ROOT_ID_FILE)
+        draining_data_roots = [data_root for data_root in self.draining_data_roots if data_root != root]
+        self._update_data_roots(self.data_roots, draining_data_roots)
+        try:
+            self.store.rmdir(root)
+        except OSError:
+            pass  # not empty, but it is not a data root any more
+        logger.info("Drained data root %s, moved %d segments.", root, len(segments))
 
     def _load_hints(self):
         if (transaction_id := self.get_transaction_id()) is None:
             # self is a fresh repo, so transaction_id is None and there is no hints file
-            return
+            return {}
         hints = self._unpack_hints(transaction_id)
         self.version = hints["version"]
         self.storage_quota_use = hints["storage_quota_use"]
         self.shadow_index = hints["shadow_index"]
+        return hints
 
     def info(self):
         """return some infos about the repo (must be opened first)"""
         info = dict(id=self.id, version=self.version, append_only=self.append_only)
-        self._load_hints()
+        hints = self._load_hints()
         info["storage_quota"] = self.storage_quota
         info["storage_quota_use"] = self.storage_quota_use
+        # space compaction could free (with threshold 0), see compact_segments()
+        compact = hints.get("compact", {})
+        info["sparse_segments"] = len(compact)
+        info["reclaimable_space"] = sum(compact.values())
         return info
 
     def close(self):
+        self._discard_staging()
+        self._close_mirror_file()
         if self.lock:
+            if self.index_in_place:
+                self._release_index()
             if self.io:
                 self.io.close()
             self.io = None
@@ -538,6 +884,10 @@ def commit(self, compact=True, threshold=0.1):
             exception = self.transaction_doomed
             self.rollback()
             raise exception
+        if self.concurrent:
+            # compaction needs an exclusive lock (borg compact), readers might still use the segments.
+            self.commit_concurrent()
+            return
         self.check_free_space()
         segment = self.io.write_commit()
         self.segments.setdefault(segment, 0)
@@ -547,12 +897,237 @@ def commit(self, compact=True, threshold=0.1):
         self.write_index()
         self.rollback()
 
+    def begin_commit(self):
+        """
+        Acquire the commit lock (only for concurrent writers, a no-op otherwise).
+
+        Until commit() or rollback(), no other writer can commit and get() returns the most recently committed
+        objects (if not put or deleted in this transaction), so the caller can merge its changes to shared
+        objects (like the manifest) with the changes other writers committed meanwhile.
+        """
+        if not self.concurrent or self.commit_lock is not None:
+            return
+        self.commit_lock = self.store.get_exclusive_lock("lock.commit", timeout=self.lock_wait).acquire()
+        self._load_index()
+
+    def commit_concurrent(self):
+        """Commit the transaction of a concurrent writer, see "Concurrent writers" above."""
+        committed = False
+        transaction_id = None
+        try:
+            self.begin_commit()
+            if self.staging is None:
+                return  # nothing to commit
+            self.staging.close_segment()
+            transaction_id = self.index_transaction_id
+            # objects we deleted, but another writer has put again since our transaction started, must survive.
+            base = -1 if self.txn_base is None else self.txn_base
+            readded = {}
+            for id in self.staged_deletes:
+                in_index = self.index.get(id)
+                if in_index is not None and in_index.segment > base:
+                    readded[id] = self.io.read(in_index.segment, in_index.offset, id, expected_size=in_index.size)
+            # this also cleans up segments left behind by an aborted commit of another writer:
+            self.prepare_txn(transaction_id)
+            # objects might have been locked since we deleted them.
+            self._check_retention(self.staged_deletes - set(readded))
+            latest_segment = self.io.get_latest_segment()
+            self.io.segment = 0 if latest_segment is None else latest_segment + 1
+            for staged_segment, _ in list(self.staging.segment_iterator()):
+                segment = self.io.segment
+                self.io.segment += 1
+                name = self.io.new_segment_name(segment)
+                self.store.makedirs(name.rpartition("/")[0])
+                self.io.move_file(self.staging.segment_name(staged_segment), name)
+                try:
+                    self.io.move_file(self.staging.parity_name(staged_segment), self.io.parity_name(segment))
+                except FileNotFoundError:
+                    pass
+                self._update_index(segment, self.io.iter_objects(segment))
+            for id, data in readded.items():
+                # put it again after our DELETE, so that it also survives replaying the segments.
+                segment, offset = self.io.write_put(id, data)
+                self.storage_quota_use += header_size(TAG_PUT2) + len(data)
+                self.segments.setdefault(segment, 0)
+                self.segments[segment] += 1
+                self.index[id] = NSIndexEntry(segment, offset, len(data))
+            if self.storage_quota and self.storage_quota_use > self.storage_quota:
+                raise self.StorageQuotaExceeded(
+                    format_file_size(self.storage_quota), format_file_size(self.storage_quota_use)
+                )
+            self.check_free_space()
+            segment = self.io.write_commit()
+            committed = True
+            self.segments.setdefault(segment, 0)
+            self.compact[segment] += LoggedIO.header_fmt.size
+            self.write_index()
+        finally:
+            if self.staging is not None and self.commit_lock is not None and not committed:
+                # remove what we already moved to data/ (we still have the commit lock).
+                self.io.cleanup(-1 if transaction_id is None else transaction_id)
+            self.rollback()
+
+    def _load_index(self):
+        """load the index of the most recent transaction"""
+        self._release_index()
+        if self.commit_lock is None and self.lock is not None and not self.lock.got_exclusive_lock():
+            # a concurrent writer or an online compaction might commit (and remove the old index) while we read it.
+            if self.concurrent:
+                self.begin_commit()
+                self.commit_lock.release()
+                self.commit_lock = None
+            else:
+                with self.store.get_exclusive_lock("lock.commit", timeout=self.lock_wait):
+                    self.index_transaction_id = self.get_transaction_id()
+                    self.index = self.open_index(self.index_transaction_id)
+            return
+        self.index_transaction_id = self.get_transaction_id()
+        self.index = self.open_index(self.index_transaction_id)
+
+    def _index_moved(self, id):
+        """reload the index after reading *id* failed, return whether the object was moved meanwhile"""
+        if self.lock is None or self.lock.got_exclusive_lock() or self._active_txn:
+            return False  # nobody else could have compacted the repository
+        old = self.index.get(id)
+        self.index = None
+        self._load_index()
+        return self.index.get(id) != old
+
+    def _prepare_staging(self):
+        """start a transaction of a concurrent writer"""
+        # the staging directory must exist before we load the index, see _other_writers.
+        data_dir = "staging/" + (self.session or bin_to_hex(os.urandom(8)))
+        self.store.makedirs(data_dir)
+        if self.lock is not None:
+            self.store.store(data_dir + "/owner", json.dumps(self.lock.id).encode())
+        if self.index is None:
+            self._load_index()
+        self.txn_base = self.index_transaction_id
+        if self.storage_quota:
+            # our puts are accounted on top of the committed quota use, commit_concurrent() recomputes it.
+            try:
+                self._load_hints()
+            except FileNotFoundError:
+                pass  # another writer committed meanwhile, then we only account for our puts.
+        self.staging = LoggedIO(
+            self.store, self.max_segment_size, self.segments_per_dir, data_dir=data_dir, parity=self.io.parity
+        )
+
+    def _discard_staging(self):
+        """forget the transaction of a concurrent writer, release the commit lock"""
+        if self.staging is not None:
+            self.staging.close()
+            self._remove_tree(self.staging.data_dir)
+            self.staging = None
+        self.staged.clear()
+        self.staged_deletes.clear()
+        self.txn_base = None
+        if self.commit_lock is not None:
+            self.commit_lock.release()
+            self.commit_lock = None
+
+    def _other_writers(self):
+        """return whether concurrent writers of other processes have a transaction in progress"""
+        if self.lock is None:
+            return False
+        try:
+            entries = self.store.list("staging")
+        except FileNotFoundError:
+            return False
+        lockers = self.lock._roster.get(SHARED)
+        for entry in entries:
+            if not entry.is_dir:
+                continue
+            try:
+                owner = tuple(json.loads(self.store.load(f"staging/{entry.name}/owner")))
+            except (FileNotFoundError, ValueError):
+                # not set up yet: it will load the index after we committed (or it is garbage).
+                continue
+            if owner in lockers or self._suspended(f"staging/{entry.name}") is not None:
+                return True
+        return False
+
+    def _suspended(self, data_dir):
+        """return the state of the transaction suspended in *data_dir* (None if there is none or it expired)"""
+        try:
+            suspended = json.loads(self.store.load(data_dir + "/suspended"))
+        except (FileNotFoundError, ValueError):
+            return None
+        if time.time() - suspended["time"] > SESSION_RESUME_TIMEOUT:
+            return None
+        return suspended
+
+    def suspend(self):
+        """
+        Keep the transaction of a concurrent writer for resume(), see "Resuming sessions" above.
+
+        Returns whether there was a transaction to keep, the repository should be closed afterwards.
+        """
+        if self.session is None or self.staging is None or self.transaction_doomed:
+            return False
+        self.staging.close_segment()
+        suspended = dict(txn_base=self.txn_base, time=time.time())
+        self.store.store(self.staging.data_dir + "/suspended", json.dumps(suspended).encode())
+        self.staging.close()
+        self.staging = None  # so _discard_staging keeps the segments
+        self._discard_staging()
+        return True
+
+    def resume(self):
+        """
+        Adopt the transaction of our session suspended by another process, see "Resuming sessions" above.
+
+        The other process might not have noticed yet that its client is gone, so wait a bit for it.
+        """
+        if self.session is None:
+            raise self.SessionNotResumable(None, self.path)
+        data_dir = "staging/" + self.session
+        deadline = time.monotonic() + max(self.lock_wait or 0, SESSION_RESUME_WAIT)
+        while (suspended := self._suspended(data_dir)) is None:
+            try:
+                self.store.list(data_dir)
+            except FileNotFoundError:
+                raise self.SessionNotResumable(self.session, self.path) from None
+            if time.monotonic() > deadline:
+                raise self.SessionNotResumable(self.session, self.path)
+            time.sleep(0.2)
+        if self.lock is not None:
+            self.store.store(data_dir + "/owner", json.dumps(self.lock.id).encode())
+        self.store.delete(data_dir + "/suspended")
+        if self.index is None:
+            self._load_index()
+        self.txn_base = suspended["txn_base"]
+        self.staging = LoggedIO(
+            self.store, self.max_segment_size, self.segments_per_dir, data_dir=data_dir, parity=self.io.parity
+        )
+        # replay the staging segments like put() and delete() did it.
+        for segment, _ in list(self.staging.segment_iterator()):
+            for tag, id, offset, size, _ in self.staging.iter_objects(segment, read_data=False):
+                if tag == TAG_DELETE:
+                    self.staged.pop(id, None)
+                    if id in self.index:
+                        self.staged_deletes.add(id)
+                elif tag in (TAG_PUT2, TAG_PUT):
+                    self.staged[id] = NSIndexEntry(segment, offset, size)
+                    self.staged_deletes.discard(id)
+            self.staging.segment = segment + 1
+
+    def _remove_tree(self, name):
+        try:
+            entries = self.store.list(name)
+        except FileNotFoundError:
+            return
+        for entry in entries:
+            if entry.is_dir:
+                self._remove_tree(f"{name}/{entry.name}")
+            else:
+                self.store.delete(f"{name}/{entry.name}")
+        self.store.rmdir(name)
+
     def _read_integrity(self, transaction_id, key):
         integrity_file = "integrity.%d" % transaction_id
-        integrity_path = os.path.join(self.path, integrity_file)
         try:
-            with open(integrity_path, "rb") as fd:
-                integrity = msgpack.unpack(fd)
+            integrity = msgpack.unpackb(self.store.load(integrity_file))
         except FileNotFoundError:
             return
         if integrity.get("version") != 2:
@@ -563,18 +1138,24 @@ def _read_integrity(self, transaction_id, key):
     def open_index(self, transaction_id, auto_recover=True):
         if transaction_id is None:
             return NSIndex()
-        index_path = os.path.join(self.path, "index.%d" % transaction_id)
-        variant = hashindex_variant(index_path)
+        index_name = "index.%d" % transaction_id
+        index_path = self.store.filename(index_name)
+        with self.store.open_read(index_name) as fd:
+            variant = hashindex_variant(fd)
         integrity_data = self._read_integrity(transaction_id, "index")
         try:
-            with IntegrityCheckedFile(index_path, write=False, integrity_data=integrity_data) as fd:
+            if variant == 2 and self.index_mode == "mapped":
+                return NSIndex.map(index_path, shared=False)
+            with IntegrityCheckedFile(
+                index_path, write=False, override_fd=self.store.open_read(index_name), integrity_data=integrity_data
+            ) as fd:
                 if variant == 2:
                     return NSIndex.read(fd)
                 if variant == 1:  # legacy
                     return NSIndex1.read(fd)
         except (ValueError, OSError, FileIntegrityError) as exc:
             logger.warning("Repository index missing or corrupted, trying to recover from: %s", exc)
-            os.unlink(index_path)
+            self.store.delete(index_name)
             if not auto_recover:
                 raise
             self.prepare_txn(self.get_transaction_id())
@@ -582,15 +1163,59 @@ def open_index(self, transaction_id, auto_recover=True):
             self.commit(compact=False)
             return self.open_index(self.get_transaction_id())
 
+    def _map_index(self, transaction_id):
+        """map the index of *transaction_id* for updating it in place, see "Mapped index" above"""
+        mapped_path = self.store.filename(MAPPED_INDEX)
+        if transaction_id is None:
+            index = NSIndex.create_mapped(mapped_path)
+        else:
+            index_name = "index.%d" % transaction_id
+            with self.store.open_read(index_name) as fd:
+                if hashindex_variant(fd) != 2:
+                    # a legacy index is converted when writing it
+                    return self.open_index(transaction_id, auto_recover=False)
+            # the journal must exist before index.X is gone, so a crash leaves either index.X or both.
+            journal_name = MAPPED_INDEX_JOURNAL + str(transaction_id)
+            with self.store.open_write(journal_name):
+                pass
+            self.store.replace(index_name, MAPPED_INDEX)
+            index = NSIndex.map(mapped_path)
+            index.start_journal(self.store.filename(journal_name))
+        self.index_in_place = True
+        self.mapped_base = transaction_id
+        return index
+
+    def _release_index(self):
+        """forget self.index, reverting the uncommitted changes of a mapped index updated in place"""
+        index, self.index = self.index, None
+        if not self.index_in_place:
+            return
+        self.index_in_place = False
+        if self.mapped_base is None:
+            del index
+            self.store.delete(MAPPED_INDEX)
+            return
+        index.undo_journal()
+        index.flush()
+        del index
+        self.store.replace(MAPPED_INDEX, "index.%d" % self.mapped_base)
+        self.store.delete(MAPPED_INDEX_JOURNAL + str(self.mapped_base))
+
     def _unpack_hints(self, transaction_id):
-        hints_path = os.path.join(self.path, "hints.%d" % transaction_id)
+        hints_name = "hints.%d" % transaction_id
         integrity_data = self._read_integrity(transaction_id, "hints")
-        with IntegrityCheckedFile(hints_path, write=False, integrity_data=integrity_data) as fd:
+        with IntegrityCheckedFile(
+            self.store.filename(hints_name),
+            write=False,
+            override_fd=self.store.open_read(hints_name),
+            integrity_data=integrity_data,
+        ) as fd:
             return msgpack.unpack(fd)
 
     def prepare_txn(self, transaction_id, do_cleanup=True):
         self._active_txn = True
-        if self.do_lock and not self.lock.got_exclusive_lock():
+        # concurrent writers do not have an exclusive lock, but they hold the commit lock when getting here.
+        if self.do_lock and not self.lock.got_exclusive_lock() and self.commit_lock is None:
             if self.exclusive is not None:
                 # self.exclusive is either True or False, thus a new client is active here.
                 # if it is False and we get here, the caller did not use exclusive=True although
@@ -607,13 +1232,23 @@ def prepare_txn(self, transaction_id, do_cleanup=True):
                 # the repository instance lives on - even if exceptions happened.
                 self._active_txn = False
                 raise
+        # another client might have added retention locks while we did not hold the exclusive (or commit) lock.
+        self.retention = None
+        if do_cleanup and not self.concurrent:
+            # as we have the exclusive lock, staged segments are leftovers of crashed concurrent writers.
+            self._remove_tree("staging")
         if not self.index or transaction_id is None:
+            self._release_index()
+            if self.index_mode == "mapped" and self.lock is not None and self.lock.got_exclusive_lock():
+                load_index = self._map_index
+            else:
+                load_index = partial(self.open_index, auto_recover=False)
             try:
-                self.index = self.open_index(transaction_id, auto_recover=False)
+                self.index = load_index(transaction_id)
             except (ValueError, OSError, FileIntegrityError) as exc:
                 logger.warning("Checking repository transaction due to previous error: %s", exc)
                 self.check_transaction()
-                self.index = self.open_index(transaction_id, auto_recover=False)
+                self.index = load_index(transaction_id)
         if transaction_id is None:
             self.segments = {}  # XXX bad name: usage_count_of_segment_x = self.segments[x]
             self.compact = FreeSpace()  # XXX bad name: freeable_space_of_segment_x = self.compact[x]
@@ -622,16 +1257,15 @@ def prepare_txn(self, transaction_id, do_cleanup=True):
         else:
             if do_cleanup:
                 self.io.cleanup(transaction_id)
-            hints_path = os.path.join(self.path, "hints.%d" % transaction_id)
-            index_path = os.path.join(self.path, "index.%d" % transaction_id)
             try:
                 hints = self._unpack_hints(transaction_id)
             except (msgpack.UnpackException, FileNotFoundError, FileIntegrityError) as e:
                 logger.warning("Repository hints file missing or corrupted, trying to recover: %s", e)
                 if not isinstance(e, FileNotFoundError):
-                    os.unlink(hints_path)
+                    self.store.delete("hints.%d" % transaction_id)
                 # index must exist at this point
-                os.unlink(index_path)
+                self._release_index()
+                self.store.delete("index.%d" % transaction_id)
                 self.check_transaction()
                 self.prepare_txn(transaction_id)
                 return
@@ -659,12 +1293,8 @@ def prepare_txn(self, transaction_id, do_cleanup=True):
                         shadowed_segments.remove(segment)
 
     def write_index(self):
-        def flush_and_sync(fd):
-            fd.flush()
-            os.fsync(fd.fileno())
-
-        def rename_tmp(file):
-            os.replace(file + ".tmp", file)
+        def rename_tmp(name):
+            self.store.replace(name + ".tmp", name)
 
         hints = {
             "version": 2,
@@ -683,55 +1313,82 @@ def rename_tmp(file):
 
         # Log transaction in append-only mode
         if self.append_only:
-            with open(os.path.join(self.path, "transactions"), "a") as log:
-                print(
-                    "transaction %d, UTC time %s"
-                    % (transaction_id, datetime.now(tz=timezone.utc).isoformat(timespec="microseconds")),
-                    file=log,
-                )
+            log = "transaction %d, UTC time %s\n" % (
+                transaction_id,
+                datetime.now(tz=timezone.utc).isoformat(timespec="microseconds"),
+            )
+            self.store.append("transactions", log.encode())
 
-        # Write hints file
+        # Write hints file (the store's writer makes the contents durable when closing it)
         hints_name = "hints.%d" % transaction_id
-        hints_file = os.path.join(self.path, hints_name)
-        with IntegrityCheckedFile(hints_file + ".tmp", filename=hints_name, write=True) as fd:
+        with IntegrityCheckedFile(
+            self.store.filename(hints_name + ".tmp"),
+            filename=hints_name,
+            write=True,
+            override_fd=self.store.open_write(hints_name + ".tmp"),
+        ) as fd:
             msgpack.pack(hints, fd)
-            flush_and_sync(fd)
         integrity["hints"] = fd.integrity_data
 
         # Write repository index
         index_name = "index.%d" % transaction_id
-        index_file = os.path.join(self.path, index_name)
-        with IntegrityCheckedFile(index_file + ".tmp", filename=index_name, write=True) as fd:
-            # XXX: Consider using SyncFile for index write-outs.
-            self.index.write(fd)
-            flush_and_sync(fd)
-        integrity["index"] = fd.integrity_data
+        if self.index_in_place:
+            # only the changed pages are written, see "Mapped index" above. the journal is removed with the
+            # other files of the previous transaction below.
+            self.index.stop_journal()
+            self.index.flush()
+            integrity["index"] = None
+        else:
+            with IntegrityCheckedFile(
+                self.store.filename(index_name + ".tmp"),
+                filename=index_name,
+                write=True,
+                override_fd=self.store.open_write(index_name + ".tmp"),
+            ) as fd:
+                self.index.write(fd)
+            integrity["index"] = fd.integrity_data
 
         # Write integrity file, containing checksums of the hints and index files
         integrity_name = "integrity.%d" % transaction_id
-        integrity_file = os.path.join(self.path, integrity_name)
-        with open(integrity_file + ".tmp", "wb") as fd:
+        with self.store.open_write(integrity_name + ".tmp") as fd:
             msgpack.pack(integrity, fd)
-            flush_and_sync(fd)
 
         # Rename the integrity file first
-        rename_tmp(integrity_file)
-        sync_dir(self.path)
+        rename_tmp(integrity_name)
+        self.store.sync_dir()
         # Rename the others after the integrity file is hypothetically on disk
-        rename_tmp(hints_file)
-        rename_tmp(index_file)
-        sync_dir(self.path)
+        rename_tmp(hints_name)
+        if self.index_in_place:
+            self.index_in_place = False
+            self.store.replace(MAPPED_INDEX, index_name)
+        else:
+            rename_tmp(index_name)
+        self.store.sync_dir()
 
         # Remove old auxiliary files
         current = ".%d" % transaction_id
-        for name in os.listdir(self.path):
+        for entry in self.store.list():
+            name = entry.name
             if not name.startswith(("index.", "hints.", "integrity.")):
                 continue
             if name.endswith(current):
                 continue
-            os.unlink(os.path.join(self.path, name))
+            self.store.delete(name)
         self.index = None
 
+    def _free_space(self):
+        """return the free space for new segments, counting each file system only once (see "Data roots" above)"""
+        free_space = self.store.free_space()
+        if free_space is None or not self.data_roots:
+            return free_space
+        devices = {os.stat(self.store.local_path()).st_dev}
+        for root in self.data_roots:
+            device = os.stat(self.store.local_path(root)).st_dev
+            if device not in devices:
+                devices.add(device)
+                free_space += self.store.free_space(root)
+        return free_space
+
     def check_free_space(self):
         """Pre-commit check for sufficient free space necessary to perform the commit."""
         # As a baseline we take four times the current (on-disk) index size.
@@ -776,10 +1433,13 @@ def check_free_space(self):
                 required_free_space += full_segment_size
 
         try:
-            free_space = shutil.disk_usage(self.path).free
+            free_space = self._free_space()
         except OSError as os_error:
             logger.warning("Failed to check free space before committing: " + str(os_error))
             return
+        if free_space is None:
+            logger.debug("check_free_space: Store has no free space limit")
+            return
         logger.debug(f"check_free_space: Required bytes {required_free_space}, free bytes {free_space}")
         if free_space < required_free_space:
             if self.created:
@@ -791,11 +1451,18 @@ def check_free_space(self):
             formatted_free = format_file_size(free_space)
             raise self.InsufficientFreeSpaceError(formatted_required, formatted_free)
 
-    def compact_segments(self, threshold):
-        """Compact sparse segments by copying data into new segments"""
+    def compact_segments(self, threshold, deadline=None):
+        """
+        Compact sparse segments by copying data into new segments
+
+        If a *deadline* (time.monotonic() value) is given, stop compacting more segments when it is reached and
+        return True (there is more to do).
+        """
         if not self.compact:
             logger.debug("Nothing to do: compact empty")
-            return
+            return False
+        more = False
+        compacted = 0
         quota_use_before = self.storage_quota_use
         index_transaction_id = self.get_index_transaction_id()
         segments = self.segments
@@ -845,6 +1512,11 @@ def complete_xfer(intermediate=True):
                 pi.show()
                 self._send_log()
                 continue
+            if freeable_space < segment_size - MAGIC_LEN:  # there are objects to move, this takes time
+                if deadline is not None and compacted and time.monotonic() > deadline:
+                    more = True
+                    break
+                compacted += 1
             segments.setdefault(segment, 0)
             logger.debug(
                 "Compacting segment %d with usage count %d (maybe freeable: %2.2f%% [%d bytes])",
@@ -960,7 +1632,50 @@ def complete_xfer(intermediate=True):
         self.io.clear_empty_dirs()
         quota_use_after = self.storage_quota_use
         logger.info("Compaction freed about %s repository space.", format_file_size(quota_use_before - quota_use_after))
-        logger.debug("Compaction completed.")
+        logger.debug("Compaction completed." if not more else "Compaction stopped at the deadline.")
+        return more
+
+    def compactable(self, threshold):
+        """Return whether compact_segments(threshold) would compact segments which are not just a COMMIT."""
+        # compacting these alone is pointless: compaction writes a new one. compact_segments removes them on the way.
+        commit_only_size = MAGIC_LEN + LoggedIO.header_fmt.size
+        for segment, freeable_space in self.compact.items():
+            if not self.io.segment_exists(segment):
+                continue
+            segment_size = self.io.segment_size(segment)
+            if segment_size > commit_only_size and freeable_space / segment_size > threshold:
+                return True
+        return False
+
+    def compact_slice(self, threshold=0.1, max_duration=None):
+        """
+        Online compaction: compact sparse segments for about *max_duration* seconds (no limit if None).
+
+        The repository must be opened with concurrent=True, see "Online compaction" above.
+        Returns whether there is more to compact (or compaction has to wait for concurrent writers).
+        """
+        if not self.concurrent:
+            raise ValueError("online compaction needs a concurrent repository")
+        if self.append_only:
+            return False
+        deadline = None if max_duration is None else time.monotonic() + max_duration
+        try:
+            self.begin_commit()
+            transaction_id = self.index_transaction_id
+            if transaction_id is None:
+                return False
+            if self._other_writers():
+                # their commits would take objects we move as added by another writer, see commit_concurrent.
+                logger.info("Online compaction waits for concurrent writers to finish their transactions.")
+                return True
+            self.prepare_txn(transaction_id)
+            if not self.compactable(threshold):
+                return False
+            more = self.compact_segments(threshold, deadline=deadline)
+            self.write_index()
+            return more and self.compactable(threshold)
+        finally:
+            self.rollback()
 
     def replay_segments(self, index_transaction_id, segments_transaction_id):
         # fake an old client, so that in case we do not have an exclusive lock yet, prepare_txn will upgrade the lock:
@@ -986,7 +1701,7 @@ def replay_segments(self, index_transaction_id, segments_transaction_id):
             self.write_index()
         finally:
             self.exclusive = remember_exclusive
-            self.rollback()
+            self._rollback(cleanup=False)
 
     def _update_index(self, segment, objects, report=None):
         """some code shared between replay_segments and check"""
@@ -1062,12 +1777,30 @@ def check(self, repair=False, max_duration=0):
         if self.append_only and repair:
             raise ValueError(self.path + " is in append-only mode")
         error_found = False
+        check_start = time.monotonic()
+        summary = self.last_check_summary = dict(
+            mode="partial" if max_duration else "full",
+            repair=repair,
+            segments=0,
+            segments_checked=0,
+            damaged_segments=[],
+            errors=0,
+            last_segment_checked=None,
+            duration=0.0,
+            ok=False,
+        )
 
         def report_error(msg, *args):
             nonlocal error_found
             error_found = True
+            summary["errors"] += 1
             logger.error(msg, *args)
 
+        def finished(ok):
+            summary["ok"] = ok
+            summary["duration"] = time.monotonic() - check_start
+            return ok
+
         logger.info("Starting repository check")
         assert not self._active_txn
         try:
@@ -1086,14 +1819,14 @@ def report_error(msg, *args):
             transaction_id = self.io.get_latest_segment()
         if transaction_id is None:
             report_error("This repository contains no valid data.")
-            return False
+            return finished(False)
         if repair:
             self.io.cleanup(transaction_id)
         segments_transaction_id = self.io.get_segments_transaction_id()
         logger.debug("Segment transaction is    %s", segments_transaction_id)
         logger.debug("Determined transaction is %s", transaction_id)
         self.prepare_txn(None)  # self.index, self.compact, self.segments, self.shadow_index all empty now!
-        segment_count = sum(1 for _ in self.io.segment_iterator())
+        segment_count = summary["segments"] = sum(1 for _ in self.io.segment_iterator())
         logger.debug("Found %d segments", segment_count)
 
         partial = bool(max_duration)
@@ -1109,30 +1842,37 @@ def report_error(msg, *args):
             self.config.remove_option("repository", "last_segment_checked")
             self.save_config(self.path, self.config)
         t_start = time.monotonic()
+        # the progress (also sent to the client of borg serve) shows that a slow check is still alive.
         pi = ProgressIndicatorPercent(
-            total=segment_count, msg="Checking segments %3.1f%%", step=0.1, msgid="repository.check"
+            total=segment_count,
+            msg="Checking segments %3.1f%% (segment %s, %s errors)",
+            step=0.1,
+            msgid="repository.check",
+            interval=60,
         )
         segment = -1  # avoid uninitialized variable if there are no segment files at all
         for i, (segment, filename) in enumerate(self.io.segment_iterator()):
-            pi.show(i)
+            pi.show(i, info=[str(segment), str(summary["errors"])])
             self._send_log()
             if segment <= last_segment_checked:
                 continue
             if segment > transaction_id:
                 continue
             logger.debug("Checking segment file %s...", filename)
+            summary["segments_checked"] += 1
             try:
                 objects = list(self.io.iter_objects(segment))
             except IntegrityError as err:
                 report_error(str(err))
+                summary["damaged_segments"].append(segment)
                 objects = []
                 if repair:
-                    self.io.recover_segment(segment, filename)
-                    objects = list(self.io.iter_objects(segment))
+                    objects = self._repair_segment(segment, filename)
             if not partial:
                 self._update_index(segment, objects, report_error)
             if partial and time.monotonic() > t_start + max_duration:
                 logger.info("Finished partial segment check, last segment checked is %d", segment)
+                summary["last_segment_checked"] = segment
                 self.config.set("repository", "last_segment_checked", str(segment))
                 self.save_config(self.path, self.config)
                 break
@@ -1184,7 +1924,53 @@ def report_error(msg, *args):
                 logger.error("Finished %s repository check, errors found.", mode)
         else:
             logger.info("Finished %s repository check, no problems found.", mode)
-        return not error_found or repair
+        return finished(not error_found or repair)
+
+    def check_summary(self):
+        """return a summary (dict) of the last check, see check"""
+        return self.last_check_summary
+
+    def _repair_segment(self, segment, filename):
+        """repair a damaged segment, return its objects"""
+        if self.io.rebuild_segment(segment, filename):
+            try:
+                return list(self.io.iter_objects(segment))
+            except IntegrityError as err:
+                logger.warning("Segment %d could not be rebuilt completely from parity data [%s].", segment, err)
+        self.io.recover_segment(segment, filename)
+        return list(self.io.iter_objects(segment))
+
+    def scrub(self):
+        """Scrub the segments using their parity files
+
+        This verifies all committed segments, rebuilds damaged ones from their parity data and
+        creates missing parity files. Returns False if damage was found that could not be repaired.
+        """
+        logger.info("Starting repository scrub")
+        assert not self._active_txn
+        transaction_id = self.get_transaction_id()
+        if transaction_id is None:
+            logger.info("Finished repository scrub, no segments found.")
+            return True
+        segment_count = sum(1 for _ in self.io.segment_iterator(end_segment=transaction_id))
+        pi = ProgressIndicatorPercent(
+            total=segment_count, msg="Scrubbing segments %3.1f%%", step=0.1, msgid="repository.scrub"
+        )
+        results = defaultdict(int)
+        for i, (segment, filename) in enumerate(self.io.segment_iterator(end_segment=transaction_id)):
+            pi.show(i)
+            self._send_log()
+            results[self.io.scrub_segment(segment, filename)] += 1
+        pi.finish()
+        self._send_log()
+        summary = "%d segments ok, %d rebuilt, %d damaged, %d parity files updated" % tuple(
+            results[status] for status in ("ok", "rebuilt", "damaged", "parity")
+        )
+        if results["damaged"]:
+            logger.error("Finished repository scrub, %s. Run borg check --repair.", summary)
+        else:
+            logger.info("Finished repository scrub, %s.", summary)
+        return not results["damaged"]
 
     def scan_low_level(self, segment=None, offset=None):
         """Very low level scan over all segment file entries.
@@ -1211,25 +1997,252 @@ def scan_low_level(self, segment=None, offset=None):
                     "Segment %d (%s) has IntegrityError(s) [%s] - skipping." % (current_segment, filename, str(err))
                 )
 
+    def retention_lock(self, ids, until, flush=True):
+        """
+        Lock the objects *ids* until *until* (unix timestamp), see "Retention locks" above.
+
+        To lock many objects, call this in batches with flush=False, except for the last one: the locks are
+        collected and the retention file is only written (once) by the call with flush=True.
+        """
+        if until > time.time() + MAX_RETENTION:
+            raise ValueError("retention lock until %s is too far in the future" % format_retention(until))
+        for id in ids:
+            if self.retention_pending.get(id, 0) < until:
+                self.retention_pending[id] = until
+        if not flush:
+            return
+        commit_lock = None
+        if not self.exclusive and self.commit_lock is None:
+            # concurrent writers check the locks while holding the commit lock, see commit_concurrent.
+            commit_lock = self.store.get_exclusive_lock("lock.commit", timeout=self.lock_wait).acquire()
+        try:
+            current = self._load_retention()
+            now = time.time()
+            locks = RetentionIndex(usable=len(current) + len(self.retention_pending))
+            for id, until in current.iteritems():
+                if until > now:  # expired locks are dropped
+                    locks[id] = until
+            for id, until in self.retention_pending.iteritems():
+                if locks.get(id, 0) < until:
+                    locks[id] = until
+            data = io.BytesIO()
+            locks.write(data)
+            self.store.store(RETENTION_FILE, data.getvalue())
+            self.retention = locks
+            self.retention_pending.clear()
+        finally:
+            if commit_lock is not None:
+                commit_lock.release()
+
+    def get_retention(self, ids):
+        """Return until when the objects *ids* are retention locked (0 if they are not)."""
+        self.retention = self._load_retention()
+        return [self._retention_until(id) for id in ids]
+
+    def _load_retention(self):
+        """load the retention locks (expired ones included, see _retention_until)"""
+        try:
+            with self.store.open_read(RETENTION_FILE) as fd:
+                return RetentionIndex.read(fd)
+        except FileNotFoundError:
+            return RetentionIndex()
+
+    def _retention_until(self, id):
+        if self.retention is None:
+            self.retention = self._load_retention()
+        until = self.retention.get(id, 0)
+        return until if until > time.time() else 0
+
+    def _check_retention(self, ids):
+        for id in ids:
+            if until := self._retention_until(id):
+                raise self.RetentionLocked("object " + bin_to_hex(id), format_retention(until))
+
+    def _check_mirror_retention(self, transaction_id):
+        """raise RetentionLocked if the staged mirrored transaction drops or changes a retention locked object"""
+        locks = self._load_retention()
+        if not len(locks):
+            return
+        if self.index is None:
+            self._load_index()
+        with self.store.open_read("%s/index.%d" % (MIRROR_STAGING, transaction_id)) as fd:
+            index = NSIndex.read(fd)
+        now = time.time()
+        for id, until in locks.iteritems():
+            if until <= now:
+                continue
+            entry = self.index.get(id)
+            if entry is None:
+                continue
+            mirrored = index.get(id)
+            if mirrored == entry:
+                continue
+            if mirrored is not None:
+                # compaction of the mirrored repository moved the object, it must still be the same object.
+                io = self.mirror_staging if mirrored.segment in self.mirror_staged else self.io
+                if io.read(mirrored.segment, mirrored.offset, id) == self.io.read(entry.segment, entry.offset, id):
+                    continue
+            raise self.RetentionLocked("object " + bin_to_hex(id), format_retention(until))
+
+    def mirror_info(self):
+        """return the committed state of the repository, see borg.mirror"""
+        transaction_id = self.get_transaction_id()
+        segments = []
+        if transaction_id is not None:
+            for segment, _ in self.io.segment_iterator(end_segment=transaction_id):
+                if self.io.segment_exists(segment):
+                    segments.append((segment, self.io.segment_size(segment)))
+        return dict(id=self.id, version=self.version, transaction_id=transaction_id, segments=segments)
+
+    def mirror_read(self, kind, number, offset, size):
+        """read up to *size* bytes at *offset* of segment *number* or of the index, hints or integrity file *number*"""
+        size = min(size, MAX_OBJECT_SIZE)
+        if kind == "segment":
+            fd = self.io.get_fd(number)
+            fd.seek(offset)
+            return fd.read(size)
+        if kind not in ("index", "hints", "integrity"):
+            raise ValueError("invalid kind of file: %r" % kind)
+        with self.store.open_read("%s.%d" % (kind, number)) as fd:
+            fd.seek(offset)
+            return fd.read(size)
+
+    def mirror_write(self, kind, number, offset, data):
+        """write *data* at *offset* of a file of a mirrored transaction (files are written sequentially)
+
+        The files are staged until mirror_commit. Starting to write a file (offset 0) finishes the previous one.
+        """
+        if kind == "segment":
+            name = None  # known after we have the LoggedIO of the staging area
+        elif kind in ("index", "hints", "integrity"):
+            name = "%s/%s.%d" % (MIRROR_STAGING, kind, number)
+        else:
+            raise ValueError("invalid kind of file: %r" % kind)
+        if self.mirror_staging is None:
+            self._remove_tree(MIRROR_STAGING)  # leftovers of an interrupted run
+            if self.storage_quota:
+                self._load_hints()  # the received segments are accounted on top of the current quota use
+            self.mirror_staging = LoggedIO(
+                self.store, self.max_segment_size, self.segments_per_dir, data_dir=MIRROR_STAGING
+            )
+        if name is None:
+            if self.append_only and self.io.segment_exists(number):
+                raise ValueError(self.path + " is in append-only mode")
+            name = self.mirror_staging.segment_name(number)
+            if self.storage_quota and self.storage_quota_use + len(data) > self.storage_quota:
+                raise self.StorageQuotaExceeded(
+                    format_file_size(self.storage_quota), format_file_size(self.storage_quota_use + len(data))
+                )
+            self.storage_quota_use += len(data)
+        if offset == 0:
+            self._close_mirror_file()
+            self.store.makedirs(name.rpartition("/")[0])
+            self.mirror_file = name, self.store.open_write(name), 0
+            if kind == "segment":
+                self.mirror_staged.add(number)
+        elif self.mirror_file is None or self.mirror_file[0] != name or self.mirror_file[2] != offset:
+            raise ValueError("%s is not written sequentially" % name)
+        name, fd, position = self.mirror_file
+        fd.write(data)
+        self.mirror_file = name, fd, position + len(data)
+
+    def mirror_commit(self, id, transaction_id, segments):
+        """
+        Make transaction *transaction_id* of the mirrored repository *id* the current transaction.
+
+        *segments* is the list of (segment, size) of this transaction. Segments which are not already present
+        with the right size and the index, hints and integrity files must have been written by mirror_write.
+        The segments are verified before the repository is touched, segments not in the list are deleted.
+        """
+        self._close_mirror_file()
+        staging = self.mirror_staging
+        if staging is None:
+            raise ValueError("nothing to commit, mirror_write the index, hints and integrity files first")
+        segments = dict(segments)
+        for segment, size in segments.items():
+            io = staging if segment in self.mirror_staged else self.io
+            if not io.segment_exists(segment) or io.segment_size(segment) != size:
+                raise IntegrityError(f"Mirrored segment {segment} is missing or has the wrong size")
+            if io is staging:
+                for _ in staging.iter_objects(segment):
+                    pass
+        io = staging if transaction_id in self.mirror_staged else self.io
+        if not io.is_committed_segment(transaction_id):
+            raise IntegrityError(f"Mirrored segment {transaction_id} is not committed")
+        obsolete = [segment for segment, _ in self.io.segment_iterator() if segment not in segments]
+        if self.append_only and (obsolete or (self.get_transaction_id() or -1) > transaction_id):
+            raise ValueError(self.path + " is in append-only mode")
+        quota_use = sum(segments.values())
+        if self.storage_quota and quota_use > self.storage_quota:
+            raise self.StorageQuotaExceeded(format_file_size(self.storage_quota), format_file_size(quota_use))
+        self._check_mirror_retention(transaction_id)
+        if id != self.id:
+            if self.get_index_transaction_id() is not None or self.io.get_latest_segment() is not None:
+                raise ValueError(self.path + " is not empty, it can not become a mirror of another repository")
+            self.config.set("repository", "id", bin_to_hex(id))
+            self.save_config(self.path, self.config)
+            self.id = id
+        staging.close()
+        for segment in sorted(self.mirror_staged):
+            if self.io.segment_exists(segment):
+                name = self.io.segment_name(segment)  # it had the wrong size, replace it in its data root
+            else:
+                name = self.io.new_segment_name(segment)
+            if segment in self.io.fds:
+                del self.io.fds[segment]
+            self.store.makedirs(name.rpartition("/")[0])
+            self.io.move_file(staging.segment_name(segment), name)
+            self.io.write_parity(segment)
+        # like write_index: the integrity file first, then the others
+        for kind in ("integrity", "hints", "index"):
+            self.store.replace("%s/%s.%d" % (MIRROR_STAGING, kind, transaction_id), "%s.%d" % (kind, transaction_id))
+            if kind == "integrity":
+                self.store.sync_dir()
+        self.store.sync_dir()
+        self._release_index()
+        self.index = self.open_index(transaction_id, auto_recover=False)  # also verifies the mirrored index
+        self._load_hints()
+        # do not trust the quota use of the mirrored hints, account for the segments we actually store.
+        self.storage_quota_use = quota_use
+        for segment in obsolete:
+            self.io.delete_segment(segment)
+        self.io.clear_empty_dirs()
+        current = ".%d" % transaction_id
+        for entry in self.store.list():
+            if entry.name.startswith(("index.", "hints.", "integrity.")) and not entry.name.endswith(current):
+                self.store.delete(entry.name)
+        self._remove_tree(MIRROR_STAGING)
+        self.mirror_staging = None
+        self.mirror_staged.clear()
+
+    def _close_mirror_file(self):
+        if self.mirror_file is not None:
+            name, fd, position = self.mirror_file
+            self.mirror_file = None
+            fd.close()
+
     def _rollback(self, *, cleanup):
         if cleanup:
             self.io.cleanup(self.io.get_segments_transaction_id())
-        self.index = None
+        self._release_index()
         self._active_txn = False
         self.transaction_doomed = None
 
     def rollback(self):
         # note: when used in remote mode, this is time limited, see RemoteRepository.shutdown_time.
         self._rollback(cleanup=False)
+        self._discard_staging()
 
     def __len__(self):
         if not self.index:
-            self.index = self.open_index(self.get_transaction_id())
+            self._load_index()
         return len(self.index)
 
     def __contains__(self, id):
+        if id in self.staged or id in self.staged_deletes:
+            return id in self.staged
         if not self.index:
-            self.index = self.open_index(self.get_transaction_id())
+            self._load_index()
         return id in self.index
 
     def list(self, limit=None, marker=None, mask=0, value=0):
@@ -1239,7 +2252,7 @@ def list(self, limit=None, marker=None, mask=0, value=0):
         if mask and value are given, only return IDs where flags & mask == value (default: all IDs).
         """
         if not self.index:
-            self.index = self.open_index(self.get_transaction_id())
+            self._load_index()
         return [id_ for id_, _ in islice(self.index.iteritems(marker=marker, mask=mask, value=value), limit)]
 
     def scan(self, limit=None, state=None):
@@ -1263,6 +2276,7 @@ def scan(self, limit=None, state=None):
             raise ValueError("please use limit > 0 or limit = None")
         transaction_id = self.get_transaction_id()
         if not self.index:
+            self._release_index()
             self.index = self.open_index(transaction_id)
         # smallest valid seg is <uint32> 0, smallest valid offs is <uint32> 8
         start_segment, start_offset, end_segment = state if state is not None else (0, 0, transaction_id)
@@ -1307,22 +2321,39 @@ def flags(self, id, mask=0xFFFFFFFF, value=None):
         :return: (previous) flags value (only masked bits)
         """
         if not self.index:
-            self.index = self.open_index(self.get_transaction_id())
+            self._load_index()
         return self.index.flags(id, mask, value)
 
     def flags_many(self, ids, mask=0xFFFFFFFF, value=None):
         return [self.flags(id_, mask, value) for id_ in ids]
 
-    def get(self, id, read_data=True):
+    def get(self, id, read_data=True, metadata=False):
+        # *metadata* is a hint for the client side MetadataCache of RemoteRepository, not used here.
+        if id in self.staged:
+            in_staging = self.staged[id]
+            return self.staging.read(
+                in_staging.segment, in_staging.offset, id, expected_size=in_staging.size, read_data=read_data
+            )
+        if id in self.staged_deletes:
+            raise self.ObjectNotFound(id, self.path)
         if not self.index:
-            self.index = self.open_index(self.get_transaction_id())
+            self._load_index()
+        try:
+            return self._get(id, read_data)
+        except (FileNotFoundError, IntegrityError):
+            # an online compaction might have moved the object since we loaded the index.
+            if not self._index_moved(id):
+                raise
+            return self._get(id, read_data)
+
+    def _get(self, id, read_data):
         try:
             in_index = NSIndexEntry(*((self.index[id] + (None,))[:3]))  # legacy: index entries have no size element
             return self.io.read(in_index.segment, in_index.offset, id, expected_size=in_index.size, read_data=read_data)
         except KeyError:
             raise self.ObjectNotFound(id, self.path) from None
 
-    def get_many(self, ids, read_data=True, is_preloaded=False):
+    def get_many(self, ids, read_data=True, is_preloaded=False, metadata=False):
         for id_ in ids:
             yield self.get(id_, read_data=read_data)
 
@@ -1332,6 +2363,20 @@ def put(self, id, data, wait=True):
         Note: when doing calls with wait=False this gets async and caller must
               deal with async results / exceptions later.
         """
+        if self.concurrent:
+            if self.staging is None:
+                self._prepare_staging()
+            committed = id not in self.staged and id not in self.staged_deletes and id in self.index
+            if committed and self._retention_until(id):
+                return  # a locked object is never replaced, see below
+            if id in self.staged or id not in self.staged_deletes and id in self.index:
+                # like below, log a DELETE first, the bookkeeping is done when replaying it at commit time.
+                self.staging.write_delete(id)
+            segment, offset = self.staging.write_put(id, data)
+            self.staged[id] = NSIndexEntry(segment, offset, len(data))
+            self.staged_deletes.discard(id)
+            self._use_storage_quota(header_size(TAG_PUT2) + len(data))
+            return
         if not self._active_txn:
             self.prepare_txn(self.get_transaction_id())
         try:
@@ -1339,16 +2384,23 @@ def put(self, id, data, wait=True):
         except KeyError:
             pass
         else:
+            if self._retention_until(id):
+                # a locked object is never replaced. for the same id, a client would put the same data anyway.
+                return
             # this put call supersedes a previous put to same id.
             # it is essential to do a delete first to get correct quota bookkeeping
             # and also a correctly updated shadow_index, so that the compaction code
             # does not wrongly resurrect an old PUT by dropping a DEL that is still needed.
             self._delete(id, in_index.segment, in_index.offset, in_index.size)
         segment, offset = self.io.write_put(id, data)
-        self.storage_quota_use += header_size(TAG_PUT2) + len(data)
         self.segments.setdefault(segment, 0)
         self.segments[segment] += 1
         self.index[id] = NSIndexEntry(segment, offset, len(data))
+        self._use_storage_quota(header_size(TAG_PUT2) + len(data))
+
+    def _use_storage_quota(self, size):
+        """account for *size* bytes written in this transaction, doom the transaction if the quota is exceeded"""
+        self.storage_quota_use += size
         if self.storage_quota and self.storage_quota_use > self.storage_quota:
             self.transaction_doomed = self.StorageQuotaExceeded(
                 format_file_size(self.storage_quota), format_file_size(self.storage_quota_use)
@@ -1361,8 +2413,20 @@ def delete(self, id, wait=True):
         Note: when doing calls with wait=False this gets async and caller must
               deal with async results / exceptions later.
         """
+        if self.concurrent:
+            if self.staging is None:
+                self._prepare_staging()
+            self._check_retention((id,))
+            if id not in self.staged and (id in self.staged_deletes or id not in self.index):
+                raise self.ObjectNotFound(id, self.path)
+            self.staging.write_delete(id)
+            self.staged.pop(id, None)
+            if id in self.index:
+                self.staged_deletes.add(id)
+            return
         if not self._active_txn:
             self.prepare_txn(self.get_transaction_id())
+        self._check_retention((id,))
         try:
             in_index = self.index.pop(id)
         except KeyError:
@@ -1414,8 +2478,26 @@ class SegmentFull(Exception):
     HEADER_ID_SIZE = header_fmt.size + 32
     ENTRY_HASH_SIZE = 8
 
-    def __init__(self, path, limit, segments_per_dir, capacity=90):
-        self.path = path
+    def __init__(
+        self,
+        store,
+        limit,
+        segments_per_dir,
+        capacity=90,
+        data_dir="data",
+        parity=None,
+        data_roots=(),
+        draining_data_roots=(),
+        placement=DEFAULT_DATA_PLACEMENT,
+    ):
+        self.store = store
+        self.data_dir = data_dir
+        # see "Data roots" in Repository: new segments go to data_dir or data_roots, draining data roots are only read.
+        self.writable_roots = [data_dir] + list(data_roots)
+        self.roots = self.writable_roots + list(draining_data_roots)
+        self.placement = placement
+        self.segment_roots = {}  # segment -> data root, if there are multiple data roots
+        self.parity = parity  # ReedSolomon code for the parity files of the segments, None: no parity files
         self.fds = LRUCache(capacity, dispose=self._close_fd)
         self.segment = 0
         self.limit = limit
@@ -1431,28 +2513,29 @@ def close(self):
 
     def _close_fd(self, ts_fd):
         ts, fd = ts_fd
-        safe_fadvise(fd.fileno(), 0, 0, "DONTNEED")
+        if self.store.is_local:
+            safe_fadvise(fd.fileno(), 0, 0, "DONTNEED")
         fd.close()
 
     def get_segment_dirs(self, data_dir, start_index=MIN_SEGMENT_DIR_INDEX, end_index=MAX_SEGMENT_DIR_INDEX):
-        """Returns generator yielding required segment dirs in data_dir as `os.DirEntry` objects.
+        """Returns generator yielding names of required segment dirs in data_dir (store names).
         Start and end are inclusive.
         """
         segment_dirs = (
-            f
-            for f in os.scandir(data_dir)
-            if f.is_dir() and f.name.isdigit() and start_index <= int(f.name) <= end_index
+            f"{data_dir}/{f.name}"
+            for f in self.store.list(data_dir)
+            if f.is_dir and f.name.isdigit() and start_index <= int(f.name) <= end_index
         )
         return segment_dirs
 
     def get_segment_files(self, segment_dir, start_index=MIN_SEGMENT_INDEX, end_index=MAX_SEGMENT_INDEX):
-        """Returns generator yielding required segment files in segment_dir as `os.DirEntry` objects.
+        """Returns generator yielding required segment numbers in segment_dir (a store name).
         Start and end are inclusive.
         """
         segment_files = (
-            f
-            for f in os.scandir(segment_dir)
-            if f.is_file() and f.name.isdigit() and start_index <= int(f.name) <= end_index
+            int(f.name)
+            for f in self.store.list(segment_dir)
+            if not f.is_dir and f.name.isdigit() and start_index <= int(f.name) <= end_index
         )
         return segment_files
 
@@ -1461,24 +2544,32 @@ def segment_iterator(self, start_segment=None, end_segment=None, reverse=False):
             start_segment = MIN_SEGMENT_INDEX if not reverse else MAX_SEGMENT_INDEX
         if end_segment is None:
             end_segment = MAX_SEGMENT_INDEX if not reverse else MIN_SEGMENT_INDEX
-        data_path = os.path.join(self.path, "data")
         start_segment_dir = start_segment // self.segments_per_dir
         end_segment_dir = end_segment // self.segments_per_dir
-        if not reverse:
-            dirs = self.get_segment_dirs(data_path, start_index=start_segment_dir, end_index=end_segment_dir)
-        else:
-            dirs = self.get_segment_dirs(data_path, start_index=end_segment_dir, end_index=start_segment_dir)
-        dirs = sorted(dirs, key=lambda dir: int(dir.name), reverse=reverse)
-        for dir in dirs:
+        dirs = defaultdict(list)  # segment dir index -> segment dirs with that index (one per data root)
+        for data_path in self.roots:
             if not reverse:
-                files = self.get_segment_files(dir, start_index=start_segment, end_index=end_segment)
+                root_dirs = self.get_segment_dirs(data_path, start_index=start_segment_dir, end_index=end_segment_dir)
             else:
-                files = self.get_segment_files(dir, start_index=end_segment, end_index=start_segment)
-            files = sorted(files, key=lambda file: int(file.name), reverse=reverse)
-            for file in files:
+                root_dirs = self.get_segment_dirs(data_path, start_index=end_segment_dir, end_index=start_segment_dir)
+            for dir in root_dirs:
+                dirs[int(dir.rpartition("/")[2])].append(dir)
+        for index in sorted(dirs, reverse=reverse):
+            segments = {}  # segment -> data root
+            for dir in dirs[index]:
+                if not reverse:
+                    dir_segments = self.get_segment_files(dir, start_index=start_segment, end_index=end_segment)
+                else:
+                    dir_segments = self.get_segment_files(dir, start_index=end_segment, end_index=start_segment)
+                for segment in dir_segments:
+                    # a segment might be in 2 data roots after draining one got interrupted, prefer the first root.
+                    segments.setdefault(segment, dir.rpartition("/")[0])
+            for segment in sorted(segments, reverse=reverse):
+                if len(self.roots) > 1:
+                    self.segment_roots[segment] = segments[segment]
                 # Note: Do not filter out logically deleted segments  (see "File system interaction" above),
                 # since this is used by cleanup and txn state detection as well.
-                yield int(file.name), file.path
+                yield segment, self.segment_filename(segment)
 
     def get_latest_segment(self):
         for segment, filename in self.segment_iterator(reverse=True):
@@ -1511,7 +2602,7 @@ def is_committed_segment(self, segment):
             iterator = self.iter_objects(segment)
         except IntegrityError:
             return False
-        with open(self.segment_filename(segment), "rb") as fd:
+        with self.store.open_read(self.segment_name(segment)) as fd:
             try:
                 fd.seek(-self.header_fmt.size, os.SEEK_END)
             except OSError as e:
@@ -1536,8 +2627,83 @@ def is_committed_segment(self, segment):
                 return False
         return seen_commit
 
+    def segment_name(self, segment):
+        """return the store name of the segment file"""
+        return self.segment_name_in(self.segment_root(segment), segment)
+
+    def segment_name_in(self, root, segment):
+        """return the store name of the segment file in data root *root*"""
+        return "%s/%d/%d" % (root, segment // self.segments_per_dir, segment)
+
+    def segment_root(self, segment):
+        """return the data root of the segment, data_dir for segments which do not exist (yet)"""
+        if len(self.roots) == 1:
+            return self.data_dir
+        root = self.segment_roots.get(segment)
+        if root is None:
+            for root in self.roots:
+                if self.store.has(self.segment_name_in(root, segment)):
+                    self.segment_roots[segment] = root
+                    break
+            else:
+                root = self.data_dir
+        return root
+
+    def place_segment(self, segment, roots=None):
+        """choose the data root for the new *segment* (out of *roots*, default: all writable data roots)"""
+        roots = self.writable_roots if roots is None else roots
+        if len(roots) == 1:
+            return roots[0]
+        if self.placement == "round-robin":
+            return roots[segment % len(roots)]
+        return max(roots, key=lambda root: self.store.free_space(root) or 0)
+
+    def new_segment_name(self, segment):
+        """choose the data root for the new *segment* (see place_segment), return the store name of its file"""
+        root = self.place_segment(segment)
+        if len(self.roots) > 1:
+            self.segment_roots[segment] = root
+        return self.segment_name_in(root, segment)
+
+    def move_segment(self, segment, root):
+        """move the (committed) segment and its parity file to data root *root*"""
+        src = self.segment_name(segment)
+        dst = self.segment_name_in(root, segment)
+        if src == dst:
+            return
+        if segment in self.fds:
+            del self.fds[segment]
+        self.store.makedirs(dst.rpartition("/")[0])
+        names = [(src, dst)]
+        if self.store.has(src + ".parity"):
+            names.append((src + ".parity", dst + ".parity"))
+        for src_name, dst_name in names:
+            self.copy_file(src_name, dst_name)
+        self.segment_roots[segment] = root
+        for src_name, _ in names:
+            self.store.delete(src_name)
+
+    def copy_file(self, src, dst):
+        """copy *src* to *dst* via a temporary file, so *dst* is either complete or missing"""
+        with self.store.open_read(src) as src_fd:
+            with self.store.open_write(dst + ".tmp") as dst_fd:
+                while data := src_fd.read(BUFSIZE):
+                    dst_fd.write(data)
+        self.store.replace(dst + ".tmp", dst)
+
+    def move_file(self, src, dst):
+        """move *src* to *dst*, which may be in a data root on another file system (then it gets copied)"""
+        try:
+            self.store.replace(src, dst)
+        except OSError as exc:
+            if exc.errno != errno.EXDEV:
+                raise
+            self.copy_file(src, dst)
+            self.store.delete(src)
+
     def segment_filename(self, segment):
-        return os.path.join(self.path, "data", str(segment // self.segments_per_dir), str(segment))
+        """return the file name of the segment file (a local path for local stores), for humans"""
+        return self.store.filename(self.segment_name(segment))
 
     def get_write_fd(self, no_new=False, want_new=False, raise_full=False):
         if not no_new and (want_new or self.offset and self.offset > self.limit):
@@ -1545,12 +2711,10 @@ def get_write_fd(self, no_new=False, want_new=False, raise_full=False):
                 raise self.SegmentFull
             self.close_segment()
         if not self._write_fd:
-            if self.segment % self.segments_per_dir == 0:
-                dirname = os.path.join(self.path, "data", str(self.segment // self.segments_per_dir))
-                if not os.path.exists(dirname):
-                    os.mkdir(dirname)
-                    sync_dir(os.path.join(self.path, "data"))
-            self._write_fd = SyncFile(self.segment_filename(self.segment), binary=True)
+            name = self.new_segment_name(self.segment)
+            if self.segment % self.segments_per_dir == 0 or len(self.roots) > 1:
+                self.store.makedirs(name.rpartition("/")[0])
+            self._write_fd = self.store.open_segment(name)
             self._write_fd.write(MAGIC)
             self.offset = MAGIC_LEN
             if self.segment in self.fds:
@@ -1567,7 +2731,7 @@ def get_fd(self, segment):
         now = time.monotonic()
 
         def open_fd():
-            fd = open(self.segment_filename(segment), "rb")
+            fd = self.store.open_read(self.segment_name(segment))
             self.fds[segment] = (now, fd)
             return fd
 
@@ -1600,41 +2764,135 @@ def close_segment(self):
         # set self._write_fd to None early to guard against reentry from error handling code paths:
         fd, self._write_fd = self._write_fd, None
         if fd is not None:
+            segment = self.segment
             self.segment += 1
             self.offset = 0
             fd.close()
+            if self.parity is not None:
+                try:
+                    self.write_parity(segment)
+                except OSError as err:
+                    # not fatal, borg check --scrub creates missing parity files.
+                    logger.warning("Could not write parity file of segment %d: %s", segment, err)
 
     def delete_segment(self, segment):
         if segment in self.fds:
             del self.fds[segment]
         try:
-            safe_unlink(self.segment_filename(segment))
+            self.store.delete(self.segment_name(segment))
         except FileNotFoundError:
             pass
+        self.delete_parity(segment)
+        self.segment_roots.pop(segment, None)
+
+    def parity_name(self, segment):
+        """return the store name of the parity file of the segment"""
+        return self.segment_name(segment) + ".parity"
+
+    def write_parity(self, segment):
+        """(re)create the parity file of *segment*, or delete it if we do not keep parity files"""
+        if self.parity is None:
+            self.delete_parity(segment)
+            return
+        name = self.segment_name(segment)
+        with self.store.open_read(name) as segment_fd:
+            with self.store.open_write(name + ".parity.tmp") as parity_fd:
+                write_parity(self.parity, segment_fd, self.store.size(name), parity_fd)
+        self.store.replace(name + ".parity.tmp", self.parity_name(segment))
+
+    def delete_parity(self, segment):
+        try:
+            self.store.delete(self.parity_name(segment))
+        except FileNotFoundError:
+            pass
+
+    def rebuild_segment(self, segment, filename):
+        """rebuild the damaged parts of *segment* from its parity file, return False if there is no (valid) one"""
+        name = self.segment_name(segment)
+        try:
+            with self.store.open_read(self.parity_name(segment)) as parity_fd:
+                parity = SegmentParity(parity_fd)
+                logger.info("Rebuilding %s from parity data", filename)
+                if segment in self.fds:
+                    del self.fds[segment]
+                with self.store.open_read(name) as segment_fd:
+                    with self.store.open_write(name + ".tmp") as dst_fd:
+                        unrecoverable = parity.rebuild(segment_fd, dst_fd)
+        except FileNotFoundError:
+            return False
+        except ParityError as err:
+            logger.warning("%s: invalid parity file [%s]", filename, err)
+            return False
+        self.store.replace(name + ".tmp", name)
+        if unrecoverable:
+            logger.warning("%s: %d damaged stripe(s) could not be rebuilt from parity data", filename, unrecoverable)
+        return True
+
+    def scrub_segment(self, segment, filename):
+        """
+        Verify *segment* using its parity file, rebuild it if damaged and (re)create the parity file if needed.
+
+        Returns "ok", "rebuilt", "parity" (the parity file was (re)created or deleted) or "damaged" (not repairable).
+        """
+        name = self.segment_name(segment)
+        report = params = None
+        try:
+            with self.store.open_read(self.parity_name(segment)) as parity_fd:
+                parity = SegmentParity(parity_fd)
+                params = parity.data_shards, parity.parity_shards
+                with self.store.open_read(name) as segment_fd:
+                    report = parity.verify(segment_fd, self.store.size(name))
+        except FileNotFoundError:
+            pass
+        except ParityError as err:
+            logger.warning("%s: invalid parity file [%s]", filename, err)
+        wanted = None if self.parity is None else (self.parity.data_shards, self.parity.parity_shards)
+        if report is not None and report.damaged:
+            logger.warning("%s: %d damaged stripe(s) found", filename, report.damaged)
+            self.rebuild_segment(segment, filename)
+            status = "rebuilt"
+        elif report is not None and not report.parity_damaged and (wanted is None or params == wanted):
+            return "ok"
+        elif report is None and wanted is None:
+            return "ok"
+        else:
+            status = "parity"
+        # the segment entry checksums decide whether the segment is fine now (parity data might be damaged, too).
+        try:
+            for _ in self.iter_objects(segment):
+                pass
+        except IntegrityError as err:
+            logger.error("%s: %s", filename, err)
+            return "damaged"
+        if status == "parity" or (wanted is not None and (report.parity_damaged or params != wanted)):
+            self.write_parity(segment)
+        return status
 
     def clear_empty_dirs(self):
         """Delete empty segment dirs, i.e those with no segment files."""
-        data_dir = os.path.join(self.path, "data")
-        segment_dirs = self.get_segment_dirs(data_dir)
-        for segment_dir in segment_dirs:
-            try:
-                # os.rmdir will only delete the directory if it is empty
-                # so we don't need to explicitly check for emptiness first.
-                os.rmdir(segment_dir)
-            except OSError:
-                # OSError is raised by os.rmdir if directory is not empty. This is expected.
-                # Its subclass FileNotFoundError may be raised if the directory already does not exist. Ignorable.
-                pass
-        sync_dir(data_dir)
+        for data_dir in self.roots:
+            segment_dirs = self.get_segment_dirs(data_dir)
+            for segment_dir in segment_dirs:
+                try:
+                    # rmdir will only delete the directory if it is empty
+                    # so we don't need to explicitly check for emptiness first.
+                    self.store.rmdir(segment_dir)
+                except OSError:
+                    # OSError is raised by rmdir if directory is not empty. This is expected.
+                    # Its subclass FileNotFoundError may be raised if the directory already does not exist. Ignorable.
+                    pass
+            self.store.sync_dir(data_dir)
 
     def segment_exists(self, segment):
-        filename = self.segment_filename(segment)
         # When deleting segments, they are first truncated. If truncate(2) and unlink(2) are split
         # across FS transactions, then logically deleted segments will show up as truncated.
-        return os.path.exists(filename) and os.path.getsize(filename)
+        try:
+            return self.store.size(self.segment_name(segment)) > 0
+        except FileNotFoundError:
+            return False
 
     def segment_size(self, segment):
-        return os.path.getsize(self.segment_filename(segment))
+        return self.store.size(self.segment_name(segment))
 
     def get_segment_magic(self, segment):
         fd = self.get_fd(segment)
@@ -1681,52 +2939,53 @@ def recover_segment(self, segment, filename):
         logger.info("Attempting to recover " + filename)
         if segment in self.fds:
             del self.fds[segment]
-        if os.path.getsize(filename) < MAGIC_LEN + self.header_fmt.size:
+        name = self.segment_name(segment)
+        if self.store.size(name) < MAGIC_LEN + self.header_fmt.size:
             # this is either a zero-byte file (which would crash mmap() below) or otherwise
             # just too small to be a valid non-empty segment file, so do a shortcut here:
-            with SaveFile(filename, binary=True) as fd:
-                fd.write(MAGIC)
+            self.store.store(name, MAGIC)
+            self.write_parity(segment)
             return
-        with SaveFile(filename, binary=True) as dst_fd:
-            with open(filename, "rb") as src_fd:
-                # note: file must not be 0 size or mmap() will crash.
-                with mmap.mmap(src_fd.fileno(), 0, access=mmap.ACCESS_READ) as mm:
-                    # memoryview context manager is problematic, see https://bugs.python.org/issue35686
-                    data = memoryview(mm)
-                    d = data
-                    try:
-                        dst_fd.write(MAGIC)
-                        while len(d) >= self.header_fmt.size:
-                            crc, size, tag = self.header_fmt.unpack(d[: self.header_fmt.size])
-                            size_invalid = size > MAX_OBJECT_SIZE or size < self.header_fmt.size or size > len(d)
-                            if size_invalid or tag > MAX_TAG_ID:
+        with self.store.open_write(name + ".tmp") as dst_fd:
+            with self.store.mapped(name) as mm:
+                # memoryview context manager is problematic, see https://bugs.python.org/issue35686
+                data = memoryview(mm)
+                d = data
+                try:
+                    dst_fd.write(MAGIC)
+                    while len(d) >= self.header_fmt.size:
+                        crc, size, tag = self.header_fmt.unpack(d[: self.header_fmt.size])
+                        size_invalid = size > MAX_OBJECT_SIZE or size < self.header_fmt.size or size > len(d)
+                        if size_invalid or tag > MAX_TAG_ID:
+                            d = d[1:]
+                            continue
+                        if tag == TAG_PUT2:
+                            c_offset = self.HEADER_ID_SIZE + self.ENTRY_HASH_SIZE
+                            # skip if header is invalid
+                            if crc32(d[4:c_offset]) & 0xFFFFFFFF != crc:
+                                d = d[1:]
+                                continue
+                            # skip if content is invalid
+                            if (
+                                self.entry_hash(d[4 : self.HEADER_ID_SIZE], d[c_offset:size])
+                                != d[self.HEADER_ID_SIZE : c_offset]
+                            ):
                                 d = d[1:]
                                 continue
-                            if tag == TAG_PUT2:
-                                c_offset = self.HEADER_ID_SIZE + self.ENTRY_HASH_SIZE
-                                # skip if header is invalid
-                                if crc32(d[4:c_offset]) & 0xFFFFFFFF != crc:
-                                    d = d[1:]
-                                    continue
-                                # skip if content is invalid
-                                if (
-                                    self.entry_hash(d[4 : self.HEADER_ID_SIZE], d[c_offset:size])
-                                    != d[self.HEADER_ID_SIZE : c_offset]
-                                ):
-                                    d = d[1:]
-                                    continue
-                            elif tag in (TAG_DELETE, TAG_COMMIT, TAG_PUT):
-                                if crc32(d[4:size]) & 0xFFFFFFFF != crc:
-                                    d = d[1:]
-                                    continue
-                            else:  # tag unknown
+                        elif tag in (TAG_DELETE, TAG_COMMIT, TAG_PUT):
+                            if crc32(d[4:size]) & 0xFFFFFFFF != crc:
                                 d = d[1:]
                                 continue
-                            dst_fd.write(d[:size])
-                            d = d[size:]
-                    finally:
-                        del d
-                        data.release()
+                        else:  # tag unknown
+                            d = d[1:]
+                            continue
+                        dst_fd.write(d[:size])
+                        d = d[size:]
+                finally:
+                    del d
+                    data.release()
+        self.store.replace(name + ".tmp", name)
+        self.write_parity(segment)
 
     def entry_hash(self, *data):
         h = StreamingXXH64()
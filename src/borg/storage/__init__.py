@@ -0,0 +1,22 @@
+"""
+Storage backends for borg repositories.
+
+A store is where a Repository keeps its files (config, segments, index, hints, lock, ...),
+see base.Store for the API the Repository and LoggedIO use.
+"""
+
+from .base import Store, StoreEntry, ObjectStore, BackendUnavailable
+from .posix import PosixStore
+
+
+def get_store(location, args=None):
+    """return the Store for a (non-ssh) repository *location*"""
+    if location.proto in ("s3", "s3+http"):
+        from .s3 import S3Store
+
+        return S3Store(location)
+    if location.proto == "sftp":
+        from .sftp import SFTPStore
+
+        return SFTPStore(location, rsh=getattr(args, "rsh", None))
+    return PosixStore(location.path)
@@ -0,0 +1,692 @@
+import errno
+import io
+import json
+import os
+import tempfile
+import threading
+from collections import namedtuple
+from contextlib import contextmanager
+
+from .. import platform
+from ..helpers import Error
+from ..locking import Lock, LockFailed, LockTimeout, NotLocked, NotMyLock, TimeoutTimer, SHARED, EXCLUSIVE
+from ..locking import ADD, REMOVE, REMOVE2
+from ..logger import create_logger
+
+logger = create_logger(__name__)
+
+
+class BackendUnavailable(Error):
+    """The {} storage backend is not available: {}."""
+
+    exit_mcode = 22
+
+
+# an entry found when listing a "directory" of a store.
+StoreEntry = namedtuple("StoreEntry", "name is_dir")
+
+# how much to read ahead when reading from a remote object store.
+# segment entries are usually much smaller than this, so a single request gets a whole entry.
+READAHEAD = 1024 * 1024
+
+
+class Store:
+    """
+    A storage backend beneath the Repository.
+
+    The Repository (and LoggedIO) only ever deal with *names* of blobs relative to the root of
+    the store, e.g. "config", "index.123" or "data/0/123". Name components are separated by "/",
+    regardless of the platform. A store might have real directories (POSIX, SFTP) or just emulate
+    them using name prefixes (object stores), so the Repository must not rely on empty directories
+    being persistent.
+
+    Local stores (see local_path()) also accept absolute names (starting with "/") for files outside of the
+    store root, the Repository uses these for additional data roots.
+
+    Stores raise the usual OSError subclasses (FileNotFoundError, FileExistsError, ...), so the
+    Repository can treat all stores alike.
+    """
+
+    # True if the store root is a local directory (see local_path()).
+    is_local = False
+
+    def __init__(self, location):
+        self.location = location
+
+    def __repr__(self):
+        return f"<{self.__class__.__name__} {self.path}>"
+
+    @property
+    def path(self):
+        """the store root, as shown to the user"""
+        raise NotImplementedError
+
+    def filename(self, name):
+        """return a human readable file name (a local path, if possible) for *name*"""
+        return self.path.rstrip("/") + "/" + name
+
+    def local_path(self, name=""):
+        """return the local filesystem path of *name* or None if the store is not local"""
+        return None
+
+    # store root
+
+    def exists(self):
+        """return whether something exists at the store root"""
+        raise NotImplementedError
+
+    def isdir(self):
+        """return whether the store root can hold a repository (e.g. is a directory or bucket)"""
+        raise NotImplementedError
+
+    def create(self, make_parent_dirs=False):
+        """create the store root, raise FileNotFoundError if its parent does not exist"""
+        raise NotImplementedError
+
+    def destroy(self):
+        """remove the store root and everything below it"""
+        raise NotImplementedError
+
+    def free_space(self, name=""):
+        """return the free space in bytes available to the store (at *name*) or None if unknown / unlimited"""
+        return None
+
+    # namespace
+
+    def list(self, name=""):
+        """return a list of StoreEntry for the "directory" *name*"""
+        raise NotImplementedError
+
+    def size(self, name):
+        """return the size of blob *name*, raise FileNotFoundError if it does not exist"""
+        raise NotImplementedError
+
+    def has(self, name):
+        try:
+            self.size(name)
+        except FileNotFoundError:
+            return False
+        return True
+
+    def makedirs(self, name):
+        raise NotImplementedError
+
+    def rmdir(self, name):
+        """remove "directory" *name*, raise OSError if it is not empty"""
+        raise NotImplementedError
+
+    def sync_dir(self, name=""):
+        """make changes to the directory entries in *name* durable"""
+
+    # blobs
+
+    def load(self, name):
+        """return the complete contents of blob *name*"""
+        with self.open_read(name) as fd:
+            return fd.read()
+
+    def store(self, name, data):
+        """atomically create or replace blob *name* with *data*"""
+        raise NotImplementedError
+
+    def create_exclusive(self, name, data):
+        """atomically create blob *name* with *data*, raise FileExistsError if it exists already"""
+        raise NotImplementedError
+
+    def append(self, name, data):
+        """append *data* to blob *name*, creating it if it does not exist"""
+        raise NotImplementedError
+
+    def delete(self, name):
+        raise NotImplementedError
+
+    def delete_if_unchanged(self, name, data):
+        """
+        delete blob *name* if its contents are still *data*, return whether it was deleted.
+
+        Raise FileNotFoundError if it does not exist. Nobody else can change the blob in between, as it is
+        atomically renamed away first (see replace).
+        """
+        tmp_name = "%s.%s.tmp" % (name, os.urandom(8).hex())
+        self.replace(name, tmp_name)
+        current = self.load(tmp_name)
+        if current != data:
+            # it was replaced by somebody else meanwhile, put it back. if there is a newer one already, we can only
+            # drop it: then its owner finds out when releasing it (NotMyLock, in the case of a lock).
+            try:
+                self.create_exclusive(name, current)
+            except FileExistsError:
+                pass
+        self.delete(tmp_name)
+        return current == data
+
+    def replace(self, src, dst):
+        """
+        rename blob *src* to *dst*, replacing *dst* if it exists.
+
+        This is atomic for stores having a real rename (POSIX, SFTP), but not for object stores: they copy *src*
+        (*dst* is replaced atomically, though) and then delete it, so a crash can leave *src* behind. Thus, only
+        temporary blobs are replaced, which are never read (see Repository "File system interaction").
+        """
+        raise NotImplementedError
+
+    def open_read(self, name):
+        """return a seekable, binary file-like object for reading blob *name*"""
+        raise NotImplementedError
+
+    def open_write(self, name):
+        """
+        return a binary file-like object for writing blob *name* (creating or truncating it).
+
+        The blob contents are durable after close(). The object supports tell() and seek(0, SEEK_END),
+        so it can be used as backing file of an IntegrityCheckedFile.
+        """
+        raise NotImplementedError
+
+    def open_segment(self, name):
+        """
+        return a SyncFile-like object (write, sync, close) for a new segment file *name*.
+
+        Raise FileExistsError if *name* already exists. The segment must be readable via open_read()
+        while it is written (for everything written prior to sync()).
+        """
+        raise NotImplementedError
+
+    @contextmanager
+    def mapped(self, name):
+        """context manager giving a read-only buffer with the complete contents of *name*"""
+        yield self.load(name)
+
+    def get_lock(self, exclusive=False, timeout=None, id=None):
+        """return a (not yet acquired) locking.Lock-like object for the store"""
+        return StoreLock(self, exclusive=exclusive, timeout=timeout, id=id)
+
+    def get_exclusive_lock(self, name, timeout=None, id=None):
+        """return a (not yet acquired) locking.ExclusiveLock-like object named *name*"""
+        return StoreExclusiveLock(self, name, timeout=timeout, id=id)
+
+    def close(self):
+        """release any resources (e.g. connections) held by the store"""
+
+
+class ObjectReader:
+    """
+    A seekable, read-only file-like object for a blob of an ObjectStore.
+
+    Data is fetched using ranged reads with some read-ahead, which is well suited for the
+    access patterns of LoggedIO (small header reads followed by data reads at increasing offsets).
+    """
+
+    def __init__(self, store, name, readahead=READAHEAD):
+        self.store = store
+        self.name = name
+        self.readahead = readahead
+        self.pos = 0
+        self.buf = b""
+        self.buf_offset = 0
+        self._size = None
+        self.closed = False
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    @property
+    def size(self):
+        size = self.store.pending_size(self.name)
+        if size is not None:
+            # the blob is still being written, so its size is not fixed yet.
+            return size
+        if self._size is None:
+            self._size = self.store.size(self.name)
+        return self._size
+
+    def read(self, n=-1):
+        if n is None or n < 0:
+            n = max(self.size - self.pos, 0)
+        if n == 0:
+            return b""
+        start = self.pos - self.buf_offset
+        if 0 <= start and start + n <= len(self.buf):
+            data = self.buf[start : start + n]
+        else:
+            self.buf = self.store.read_range(self.name, self.pos, max(n, self.readahead))
+            self.buf_offset = self.pos
+            data = self.buf[:n]
+        self.pos += len(data)
+        return data
+
+    def seek(self, offset, whence=io.SEEK_SET):
+        if whence == io.SEEK_SET:
+            pos = offset
+        elif whence == io.SEEK_CUR:
+            pos = self.pos + offset
+        elif whence == io.SEEK_END:
+            pos = self.size + offset
+        else:
+            raise ValueError("invalid whence (%r)" % whence)
+        if pos < 0:
+            raise OSError(errno.EINVAL, "Invalid argument")
+        self.pos = pos
+        return pos
+
+    def tell(self):
+        return self.pos
+
+    def close(self):
+        self.buf = b""
+        self.closed = True
+
+
+class ObjectWriter:
+    """
+    A write-only file-like object for a blob of an ObjectStore.
+
+    Objects can not be appended to, so data is spooled to a local temporary file and
+    the object is stored when the writer is closed. If the writer is left due to an
+    exception, the data is discarded and the object is not created or modified.
+    """
+
+    def __init__(self, store, name, exclusive=False):
+        self.store = store
+        self.name = name
+        self.exclusive = exclusive
+        self.f = tempfile.TemporaryFile(prefix="borg-store-")
+        self.closed = False
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        if exc_type is None:
+            self.close()
+        else:
+            self.discard()
+
+    def write(self, data):
+        self.f.write(data)
+        return len(data)
+
+    def tell(self):
+        return self.f.tell()
+
+    def seek(self, offset, whence=io.SEEK_SET):
+        return self.f.seek(offset, whence)
+
+    def flush(self):
+        self.f.flush()
+
+    def sync(self):
+        # we can not make partial objects durable, this happens on close().
+        self.f.flush()
+
+    @property
+    def size(self):
+        self.f.flush()
+        return os.fstat(self.f.fileno()).st_size
+
+    def pread(self, offset, size):
+        self.f.flush()
+        return os.pread(self.f.fileno(), size, offset)
+
+    def close(self):
+        if self.closed:
+            return
+        try:
+            self.f.flush()
+            self.f.seek(0)
+            self.store.put(self.name, self.f, exclusive=self.exclusive)
+        finally:
+            self.discard()
+
+    def discard(self):
+        self.closed = True
+        self.store.pending_done(self)
+        self.f.close()
+
+
+class ObjectStore(Store):
+    """
+    Base class for flat key/value object stores (e.g. S3).
+
+    Subclasses implement the primitives get, put, head, remove, copy and listdir, working on
+    keys (names prefixed with the store prefix). "Directories" only exist as name prefixes,
+    thus creating them is a no-op and they vanish when their last blob is deleted.
+
+    Segments are spooled locally while they are written and stored when closed (see ObjectWriter).
+    Until then, reads of such a segment are served from the local spool file.
+    """
+
+    def __init__(self, location):
+        super().__init__(location)
+        self._pending = {}
+        self._pending_lock = threading.Lock()
+
+    # primitives to be implemented by subclasses
+
+    def get(self, name, offset=0, size=None):
+        """return (part of) the contents of *name*, raise FileNotFoundError if it does not exist"""
+        raise NotImplementedError
+
+    def put(self, name, fileobj, exclusive=False):
+        """store the contents of file object *fileobj* as *name*"""
+        raise NotImplementedError
+
+    def head(self, name):
+        """return the size of *name*, raise FileNotFoundError if it does not exist"""
+        raise NotImplementedError
+
+    def remove(self, name):
+        raise NotImplementedError
+
+    def copy(self, src, dst):
+        raise NotImplementedError
+
+    def listdir(self, name):
+        """return (directory names, blob names) directly below "directory" *name*"""
+        raise NotImplementedError
+
+    # pending (still written) blobs
+
+    def pending_size(self, name):
+        writer = self._pending.get(name)
+        return writer.size if writer is not None else None
+
+    def pending_done(self, writer):
+        with self._pending_lock:
+            if self._pending.get(writer.name) is writer:
+                del self._pending[writer.name]
+
+    def read_range(self, name, offset, size):
+        writer = self._pending.get(name)
+        if writer is not None:
+            try:
+                return writer.pread(offset, size)
+            except ValueError:
+                # the writer was closed meanwhile (I/O operation on closed file), so the object exists now.
+                pass
+        return self.get(name, offset, size)
+
+    # Store API
+
+    def list(self, name=""):
+        dirs, blobs = self.listdir(name)
+        pending = [n.rpartition("/")[2] for n in list(self._pending) if n.rpartition("/")[0] == name.strip("/")]
+        entries = [StoreEntry(d, True) for d in dirs]
+        entries += [StoreEntry(b, False) for b in sorted(set(blobs) | set(pending))]
+        return entries
+
+    def size(self, name):
+        size = self.pending_size(name)
+        if size is not None:
+            return size
+        return self.head(name)
+
+    def makedirs(self, name):
+        pass
+
+    def rmdir(self, name):
+        dirs, blobs = self.listdir(name)
+        if dirs or blobs:
+            raise OSError(errno.ENOTEMPTY, os.strerror(errno.ENOTEMPTY), self.filename(name))
+
+    def load(self, name):
+        return self.get(name)
+
+    def store(self, name, data):
+        self.put(name, io.BytesIO(data))
+
+    def create_exclusive(self, name, data):
+        self.put(name, io.BytesIO(data), exclusive=True)
+
+    def append(self, name, data):
+        # objects are immutable, so we need to rewrite them. only used for small blobs.
+        try:
+            old = self.get(name)
+        except FileNotFoundError:
+            old = b""
+        self.store(name, old + data)
+
+    def delete(self, name):
+        self.remove(name)
+
+    def delete_if_unchanged(self, name, data):
+        # there is no rename, so this is not atomic here: subclasses should use a conditional delete.
+        if self.load(name) != data:
+            return False
+        self.remove(name)
+        return True
+
+    def replace(self, src, dst):
+        # not atomic, see Store.replace
+        self.copy(src, dst)
+        self.remove(src)
+
+    def open_read(self, name):
+        # fail early, like open() does.
+        self.size(name)
+        return ObjectReader(self, name)
+
+    def open_write(self, name):
+        return ObjectWriter(self, name)
+
+    def open_segment(self, name):
+        if self.has(name):
+            raise FileExistsError(errno.EEXIST, os.strerror(errno.EEXIST), self.filename(name))
+        writer = ObjectWriter(self, name, exclusive=True)
+        with self._pending_lock:
+            self._pending[name] = writer
+        return writer
+
+
+class StoreExclusiveLock:
+    """
+    An exclusive lock based on atomically creating a lock blob (see Store.create_exclusive).
+
+    This is the counterpart of locking.ExclusiveLock (which is based on mkdir) for stores
+    which are not a local directory. The lock blob contains the id of the lock owner.
+    """
+
+    def __init__(self, store, name, timeout=None, sleep=None, id=None):
+        self.store = store
+        self.name = name
+        self.path = store.filename(name)
+        self.timeout = timeout
+        self.sleep = sleep
+        self.id = id or platform.get_process_id()
+        self.kill_stale_locks = True
+        self.stale_warning_printed = False
+
+    def __enter__(self):
+        return self.acquire()
+
+    def __exit__(self, *exc):
+        self.release()
+
+    def __repr__(self):
+        return f"<{self.__class__.__name__}: {self.path!r}>"
+
+    def _dump_id(self, id):
+        return json.dumps(list(id)).encode()
+
+    def _owner(self):
+        try:
+            return tuple(json.loads(self.store.load(self.name)))
+        except FileNotFoundError:
+            return None
+        except ValueError:
+            return ()  # malformed
+
+    def acquire(self, timeout=None, sleep=None):
+        if timeout is None:
+            timeout = self.timeout
+        if sleep is None:
+            sleep = self.sleep
+        timer = TimeoutTimer(timeout, sleep).start()
+        while True:
+            try:
+                self.store.create_exclusive(self.name, self._dump_id(self.id))
+            except FileExistsError:
+                if self.kill_stale_lock():
+                    continue
+                if timer.timed_out_or_sleep():
+                    raise LockTimeout(self.path) from None
+            except OSError as err:
+                raise LockFailed(self.path, str(err)) from None
+            else:
+                return self
+
+    def release(self):
+        if not self.is_locked():
+            raise NotLocked(self.path)
+        if not self.by_me():
+            raise NotMyLock(self.path)
+        try:
+            self.store.delete(self.name)
+        except FileNotFoundError:
+            pass
+
+    def is_locked(self):
+        return self.store.has(self.name)
+
+    def by_me(self):
+        return self._owner() == tuple(self.id)
+
+    def kill_stale_lock(self):
+        try:
+            data = self.store.load(self.name)
+        except FileNotFoundError:
+            return True  # lock went away meanwhile
+        try:
+            host, pid, thread = json.loads(data)
+        except (ValueError, TypeError):
+            logger.error("Found malformed lock %s. Please check/fix manually.", self.path)
+            return False
+        if platform.process_alive(host, pid, thread):
+            return False
+        if not self.kill_stale_locks:
+            if not self.stale_warning_printed:
+                logger.warning(
+                    "Found stale lock %s, but not deleting because self.kill_stale_locks = False.", self.path
+                )
+                self.stale_warning_printed = True
+            return False
+        try:
+            killed = self.store.delete_if_unchanged(self.name, data)
+        except FileNotFoundError:
+            return True
+        if not killed:
+            # somebody else killed it and got the lock meanwhile, so we must wait (acquire raises LockTimeout).
+            return False
+        logger.warning("Killed stale lock %s.", self.path)
+        return True
+
+    def break_lock(self):
+        try:
+            self.store.delete(self.name)
+        except FileNotFoundError:
+            pass
+
+    def migrate_lock(self, old_id, new_id):
+        """migrate the lock ownership from old_id to new_id"""
+        assert self.id == old_id
+        if self.is_locked() and self.by_me():
+            self.store.store(self.name, self._dump_id(new_id))
+        self.id = new_id
+
+
+class StoreLockRoster:
+    """A LockRoster (see locking.LockRoster) kept in a blob of a store."""
+
+    def __init__(self, store, name, id=None):
+        self.store = store
+        self.name = name
+        self.id = id or platform.get_process_id()
+        self.kill_stale_locks = True
+
+    def load(self):
+        try:
+            data = json.loads(self.store.load(self.name))
+            if self.kill_stale_locks:
+                for key in (SHARED, EXCLUSIVE):
+                    try:
+                        entries = data[key]
+                    except KeyError:
+                        continue
+                    elements = set()
+                    for host, pid, thread in entries:
+                        if platform.process_alive(host, pid, thread):
+                            elements.add((host, pid, thread))
+                        else:
+                            logger.warning(
+                                "Removed stale %s roster lock for host %s pid %d thread %d.", key, host, pid, thread
+                            )
+                    data[key] = list(elements)
+        except (FileNotFoundError, ValueError):
+            data = {}
+        return data
+
+    def save(self, data):
+        self.store.store(self.name, json.dumps(data).encode())
+
+    def remove(self):
+        try:
+            self.store.delete(self.name)
+        except FileNotFoundError:
+            pass
+
+    def get(self, key):
+        roster = self.load()
+        return {tuple(e) for e in roster.get(key, [])}
+
+    def empty(self, *keys):
+        return all(not self.get(key) for key in keys)
+
+    def modify(self, key, op):
+        roster = self.load()
+        elements = {tuple(e) for e in roster.get(key, [])}
+        if op == ADD:
+            elements.add(self.id)
+        elif op == REMOVE:
+            elements.discard(self.id)
+        elif op == REMOVE2:
+            elements.remove(self.id)
+        else:
+            raise ValueError("Unknown LockRoster op %r" % op)
+        roster[key] = list(list(e) for e in elements)
+        self.save(roster)
+
+    def migrate_lock(self, key, old_id, new_id):
+        """migrate the lock ownership from old_id to new_id"""
+        assert self.id == old_id
+        killing, self.kill_stale_locks = self.kill_stale_locks, False
+        try:
+            try:
+                self.modify(key, REMOVE2)
+            except KeyError:
+                self.id = new_id
+            else:
+                self.id = new_id
+                self.modify(key, ADD)
+        finally:
+            self.kill_stale_locks = killing
+
+
+class StoreLock(Lock):
+    """
+    A shared/exclusive repository Lock (see locking.Lock) for stores which are not a local directory.
+
+    It uses the same protocol as locking.Lock, just with lock and roster kept as blobs in the store.
+    """
+
+    def __init__(self, store, exclusive=False, sleep=None, timeout=None, id=None):
+        self.store = store
+        self.path = store.filename("lock")
+        self.is_exclusive = exclusive
+        self.sleep = sleep
+        self.timeout = timeout
+        self.id = id or platform.get_process_id()
+        self._roster = StoreLockRoster(store, "lock.roster", id=id)
+        self._lock = StoreExclusiveLock(store, "lock.exclusive", id=id, timeout=timeout)
@@ -0,0 +1,157 @@
+import mmap
+import os
+import shutil
+from contextlib import contextmanager
+
+from .base import Store, StoreEntry
+from ..helpers import Location, safe_unlink
+from ..locking import Lock, ExclusiveLock
+from ..platform import SaveFile, SyncFile, sync_dir
+
+
+class SyncedWriter:
+    """A binary file opened for writing, which is flushed and fsync'ed on close()."""
+
+    def __init__(self, path):
+        self.path = path
+        self.f = open(path, "wb")
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    def write(self, data):
+        return self.f.write(data)
+
+    def tell(self):
+        return self.f.tell()
+
+    def seek(self, offset, whence=os.SEEK_SET):
+        return self.f.seek(offset, whence)
+
+    def flush(self):
+        self.f.flush()
+
+    def fileno(self):
+        return self.f.fileno()
+
+    def close(self):
+        if self.f.closed:
+            return
+        try:
+            self.f.flush()
+            os.fsync(self.f.fileno())
+        finally:
+            self.f.close()
+
+
+class PosixStore(Store):
+    """
+    A store in a local directory (or a network filesystem mounted there).
+
+    This is how borg repositories always have been stored: names map to files and
+    directories below the store root, segment files are written using SyncFile and
+    atomic updates are done via os.replace.
+    """
+
+    is_local = True
+
+    def __init__(self, path, location=None):
+        self.root = os.path.abspath(path)
+        super().__init__(location or Location("file://%s" % self.root))
+
+    @property
+    def path(self):
+        return self.root
+
+    def local_path(self, name=""):
+        if not name:
+            return self.root
+        if name.startswith("/"):
+            # an absolute name, e.g. of a segment in a data root outside of the store root (see Repository)
+            return os.path.join("/", *name.split("/"))
+        return os.path.join(self.root, *name.split("/"))
+
+    filename = local_path
+
+    def exists(self):
+        return os.path.exists(self.root)
+
+    def isdir(self):
+        return os.path.isdir(self.root)
+
+    def create(self, make_parent_dirs=False):
+        if make_parent_dirs:
+            os.makedirs(os.path.join(self.root, os.pardir), exist_ok=True)
+        if not os.path.exists(self.root):
+            os.mkdir(self.root)
+
+    def destroy(self):
+        shutil.rmtree(self.root)
+
+    def free_space(self, name=""):
+        return shutil.disk_usage(self.local_path(name)).free
+
+    def list(self, name=""):
+        return [StoreEntry(e.name, e.is_dir()) for e in os.scandir(self.local_path(name))]
+
+    def size(self, name):
+        return os.path.getsize(self.local_path(name))
+
+    def makedirs(self, name):
+        path = self.local_path(name)
+        if not os.path.exists(path):
+            os.makedirs(path)
+            sync_dir(os.path.dirname(path))
+
+    def rmdir(self, name):
+        os.rmdir(self.local_path(name))
+
+    def sync_dir(self, name=""):
+        sync_dir(self.local_path(name))
+
+    def load(self, name):
+        with open(self.local_path(name), "rb") as fd:
+            return fd.read()
+
+    def store(self, name, data):
+        with SaveFile(self.local_path(name), binary=True) as fd:
+            fd.write(data)
+
+    def create_exclusive(self, name, data):
+        with SyncFile(self.local_path(name), binary=True) as fd:
+            fd.write(data)
+
+    def append(self, name, data):
+        with open(self.local_path(name), "ab") as fd:
+            fd.write(data)
+
+    def delete(self, name):
+        safe_unlink(self.local_path(name))
+
+    def replace(self, src, dst):
+        os.replace(self.local_path(src), self.local_path(dst))
+
+    def open_read(self, name):
+        return open(self.local_path(name), "rb")
+
+    def open_write(self, name):
+        return SyncedWriter(self.local_path(name))
+
+    def open_segment(self, name):
+        return SyncFile(self.local_path(name), binary=True)
+
+    @contextmanager
+    def mapped(self, name):
+        with open(self.local_path(name), "rb") as fd:
+            # note: file must not be 0 size or mmap() will crash.
+            with mmap.mmap(fd.fileno(), 0, access=mmap.ACCESS_READ) as mm:
+                yield mm
+
+    def get_lock(self, exclusive=False, timeout=None, id=None):
+        return Lock(self.local_path("lock"), exclusive, timeout=timeout, id=id)
+
+    def get_exclusive_lock(self, name, timeout=None, id=None):
+        return ExclusiveLock(self.local_path(name), timeout=timeout, id=id)
@@ -0,0 +1,170 @@
+import errno
+import os
+
+from .base import ObjectStore, BackendUnavailable
+from ..logger import create_logger
+
+logger = create_logger(__name__)
+
+# S3 DeleteObjects accepts at most 1000 keys per request.
+DELETE_BATCH_SIZE = 1000
+
+
+class S3Store(ObjectStore):
+    """
+    A store in a bucket of an S3-compatible object storage (AWS S3, MinIO, Ceph RGW, ...).
+
+    Location: s3://[PROFILE@]HOST[:PORT]/BUCKET[/PREFIX] (use s3+http:// for plain http endpoints).
+
+    Credentials are looked up by boto3 as usual (environment, shared credentials file,
+    instance metadata, ...), using PROFILE from the location if given.
+
+    Exclusive creation (needed for locking and for not overwriting segments) uses conditional
+    writes (If-None-Match: *), so the storage must support them. Killing a stale lock uses a
+    conditional delete (If-Match: ETag), storages not supporting it ignore the condition.
+    """
+
+    def __init__(self, location):
+        super().__init__(location)
+        bucket, _, prefix = location.path.lstrip("/").partition("/")
+        self.bucket = bucket
+        self.prefix = prefix.strip("/")
+        self._client = None
+
+    @property
+    def path(self):
+        return self.location.canonical_path()
+
+    @property
+    def client(self):
+        if self._client is None:
+            try:
+                import boto3
+            except ImportError:
+                raise BackendUnavailable(self.location.proto, 'the Python package "boto3" is not installed') from None
+            session = boto3.session.Session(profile_name=self.location.user)
+            scheme = "http" if self.location.proto == "s3+http" else "https"
+            port = f":{self.location.port}" if self.location.port else ""
+            self._client = session.client("s3", endpoint_url=f"{scheme}://{self.location._host}{port}")
+        return self._client
+
+    def close(self):
+        if self._client is not None:
+            self._client.close()
+            self._client = None
+
+    def _key(self, name):
+        return f"{self.prefix}/{name}" if self.prefix else name
+
+    def _oserror(self, exc, name):
+        """map a botocore ClientError to the corresponding OSError"""
+        error = exc.response.get("Error", {})
+        code = str(error.get("Code", ""))
+        status = exc.response.get("ResponseMetadata", {}).get("HTTPStatusCode")
+        if code in ("NoSuchKey", "NoSuchBucket", "404", "NotFound") or status == 404:
+            err = errno.ENOENT
+        elif code in ("PreconditionFailed", "412", "ConditionalRequestConflict") or status in (409, 412):
+            err = errno.EEXIST
+        elif code in ("AccessDenied", "403", "Forbidden") or status == 403:
+            err = errno.EACCES
+        else:
+            err = errno.EIO
+        msg = "{} ({})".format(os.strerror(err), error.get("Message") or code)
+        return OSError(err, msg, self.filename(name))
+
+    def _call(self, name, func, **kwargs):
+        from botocore.exceptions import ClientError
+
+        try:
+            return func(Bucket=self.bucket, **kwargs)
+        except ClientError as exc:
+            raise self._oserror(exc, name) from None
+
+    def get(self, name, offset=0, size=None):
+        kwargs = {}
+        if offset or size is not None:
+            end = "" if size is None else offset + size - 1
+            kwargs["Range"] = f"bytes={offset}-{end}"
+        from botocore.exceptions import ClientError
+
+        try:
+            response = self.client.get_object(Bucket=self.bucket, Key=self._key(name), **kwargs)
+        except ClientError as exc:
+            if exc.response.get("Error", {}).get("Code") == "InvalidRange":
+                return b""  # reading at / beyond the end of the object
+            raise self._oserror(exc, name) from None
+        with response["Body"] as body:
+            return body.read()
+
+    def put(self, name, fileobj, exclusive=False):
+        if exclusive:
+            self._call(name, self.client.put_object, Key=self._key(name), Body=fileobj, IfNoneMatch="*")
+        else:
+            # managed transfer, uses multipart uploads for big objects.
+            self._call(name, self.client.upload_fileobj, Fileobj=fileobj, Key=self._key(name))
+
+    def head(self, name):
+        return self._call(name, self.client.head_object, Key=self._key(name))["ContentLength"]
+
+    def remove(self, name):
+        # note: S3 does not tell us whether the object existed.
+        self._call(name, self.client.delete_object, Key=self._key(name))
+
+    def delete_if_unchanged(self, name, data):
+        response = self._call(name, self.client.get_object, Key=self._key(name))
+        with response["Body"] as body:
+            if body.read() != data:
+                return False
+        try:
+            self._call(name, self.client.delete_object, Key=self._key(name), IfMatch=response["ETag"])
+        except FileExistsError:
+            return False  # precondition failed: it was replaced meanwhile
+        return True
+
+    def copy(self, src, dst):
+        source = {"Bucket": self.bucket, "Key": self._key(src)}
+        self._call(src, self.client.copy_object, Key=self._key(dst), CopySource=source)
+
+    def _list(self, name, delimiter=None):
+        prefix = self._key(name).strip("/")
+        prefix = prefix + "/" if prefix else ""
+        kwargs = dict(Prefix=prefix)
+        if delimiter:
+            kwargs["Delimiter"] = delimiter
+        paginator = self.client.get_paginator("list_objects_v2")
+        from botocore.exceptions import ClientError
+
+        try:
+            for page in paginator.paginate(Bucket=self.bucket, **kwargs):
+                yield prefix, page
+        except ClientError as exc:
+            raise self._oserror(exc, name) from None
+
+    def listdir(self, name):
+        dirs, blobs = [], []
+        for prefix, page in self._list(name, delimiter="/"):
+            dirs.extend(p["Prefix"][len(prefix) :].rstrip("/") for p in page.get("CommonPrefixes", []))
+            blobs.extend(o["Key"][len(prefix) :] for o in page.get("Contents", []) if o["Key"] != prefix)
+        return dirs, blobs
+
+    def exists(self):
+        try:
+            self._call("", self.client.head_bucket)
+        except FileNotFoundError:
+            return False
+        return True
+
+    def isdir(self):
+        return True
+
+    def create(self, make_parent_dirs=False):
+        if not self.exists():
+            if not make_parent_dirs:
+                raise FileNotFoundError(errno.ENOENT, "bucket does not exist", self.bucket)
+            self._call("", self.client.create_bucket)
+
+    def destroy(self):
+        keys = [o["Key"] for _, page in self._list("") for o in page.get("Contents", [])]
+        for i in range(0, len(keys), DELETE_BATCH_SIZE):
+            objects = [{"Key": key} for key in keys[i : i + DELETE_BATCH_SIZE]]
+            self._call("", self.client.delete_objects, Delete={"Objects": objects, "Quiet": True})
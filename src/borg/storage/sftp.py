@@ -0,0 +1,574 @@
+import errno
+import io
+import os
+import shlex
+import stat
+import struct
+import uuid
+from collections import deque
+from subprocess import Popen, PIPE
+
+from .base import Store, StoreEntry, BackendUnavailable, READAHEAD
+from ..helpers import prepare_subprocess_env
+from ..helpers.process import ignore_sigint
+from ..logger import create_logger
+
+logger = create_logger(__name__)
+
+# SFTP protocol version 3, see draft-ietf-secsh-filexfer-02 and OpenSSH's PROTOCOL file for the extensions.
+SFTP_VERSION = 3
+
+FXP_INIT = 1
+FXP_VERSION = 2
+FXP_OPEN = 3
+FXP_CLOSE = 4
+FXP_READ = 5
+FXP_WRITE = 6
+FXP_LSTAT = 7
+FXP_FSTAT = 8
+FXP_OPENDIR = 11
+FXP_READDIR = 12
+FXP_REMOVE = 13
+FXP_MKDIR = 14
+FXP_RMDIR = 15
+FXP_STAT = 17
+FXP_RENAME = 18
+FXP_STATUS = 101
+FXP_HANDLE = 102
+FXP_DATA = 103
+FXP_NAME = 104
+FXP_ATTRS = 105
+FXP_EXTENDED = 200
+FXP_EXTENDED_REPLY = 201
+
+FXF_READ = 0x01
+FXF_WRITE = 0x02
+FXF_APPEND = 0x04
+FXF_CREAT = 0x08
+FXF_TRUNC = 0x10
+FXF_EXCL = 0x20
+
+FILEXFER_ATTR_SIZE = 0x01
+FILEXFER_ATTR_UIDGID = 0x02
+FILEXFER_ATTR_PERMISSIONS = 0x04
+FILEXFER_ATTR_ACMODTIME = 0x08
+FILEXFER_ATTR_EXTENDED = 0x80000000
+
+FX_OK = 0
+FX_EOF = 1
+FX_NO_SUCH_FILE = 2
+FX_PERMISSION_DENIED = 3
+FX_FAILURE = 4
+FX_OP_UNSUPPORTED = 8
+
+STATUS_ERRNO = {FX_NO_SUCH_FILE: errno.ENOENT, FX_PERMISSION_DENIED: errno.EACCES, FX_OP_UNSUPPORTED: errno.ENOTSUP}
+
+EXT_POSIX_RENAME = "posix-rename@openssh.com"
+EXT_FSYNC = "fsync@openssh.com"
+EXT_STATVFS = "statvfs@openssh.com"
+
+# sftp-server implementations limit the size of READ/WRITE requests, 32kiB is what everybody supports.
+MAX_IO_SIZE = 32 * 1024
+# how many READ/WRITE requests we keep in flight, so we do not wait for a round trip after each request.
+MAX_IN_FLIGHT = 64
+
+
+def pack_str(s):
+    if isinstance(s, str):
+        s = s.encode("utf-8", errors="surrogateescape")
+    return struct.pack(">I", len(s)) + s
+
+
+class Reader:
+    """parse SFTP packet payloads"""
+
+    def __init__(self, data):
+        self.data = memoryview(data)
+        self.pos = 0
+
+    def u32(self):
+        (value,) = struct.unpack_from(">I", self.data, self.pos)
+        self.pos += 4
+        return value
+
+    def u64(self):
+        (value,) = struct.unpack_from(">Q", self.data, self.pos)
+        self.pos += 8
+        return value
+
+    def bytes(self):
+        length = self.u32()
+        value = bytes(self.data[self.pos : self.pos + length])
+        self.pos += length
+        return value
+
+    def str(self):
+        return self.bytes().decode("utf-8", errors="surrogateescape")
+
+    def attrs(self):
+        flags = self.u32()
+        attrs = {}
+        if flags & FILEXFER_ATTR_SIZE:
+            attrs["size"] = self.u64()
+        if flags & FILEXFER_ATTR_UIDGID:
+            attrs["uid"], attrs["gid"] = self.u32(), self.u32()
+        if flags & FILEXFER_ATTR_PERMISSIONS:
+            attrs["mode"] = self.u32()
+        if flags & FILEXFER_ATTR_ACMODTIME:
+            attrs["atime"], attrs["mtime"] = self.u32(), self.u32()
+        if flags & FILEXFER_ATTR_EXTENDED:
+            for _ in range(self.u32()):
+                self.bytes(), self.bytes()
+        return attrs
+
+
+NO_ATTRS = struct.pack(">I", 0)
+
+
+class SFTPClient:
+    """
+    A minimal SFTP (version 3) client, talking to a sftp-server via the stdin/stdout of a subprocess
+    (usually "ssh -s HOST sftp").
+
+    Requests and responses are matched by id, so multiple requests can be in flight.
+    """
+
+    def __init__(self, cmd, env=None):
+        logger.debug("SFTP command line: %s", cmd)
+        self.p = Popen(cmd, bufsize=0, stdin=PIPE, stdout=PIPE, env=env, preexec_fn=ignore_sigint)
+        self.next_id = 0
+        self.responses = {}
+        self._send(FXP_INIT, struct.pack(">I", SFTP_VERSION))
+        ptype, r = self._recv()
+        if ptype != FXP_VERSION:
+            self.close()
+            raise ConnectionError(f"SFTP: unexpected packet type {ptype} (expected VERSION)")
+        self.version = r.u32()
+        self.extensions = {}
+        while r.pos < len(r.data):
+            name = r.str()
+            self.extensions[name] = r.bytes()
+        logger.debug("SFTP server version %d, extensions: %s", self.version, ", ".join(sorted(self.extensions)))
+
+    def close(self):
+        if self.p is not None:
+            self.p.stdin.close()
+            self.p.stdout.close()
+            self.p.wait()
+            self.p = None
+
+    def _send(self, ptype, payload):
+        if self.p is None:
+            raise ConnectionError("SFTP connection is closed")
+        packet = struct.pack(">IB", len(payload) + 1, ptype) + payload
+        try:
+            self.p.stdin.write(packet)
+        except BrokenPipeError:
+            raise ConnectionError("SFTP connection closed by server") from None
+
+    def _read_exactly(self, n):
+        data = b""
+        while len(data) < n:
+            chunk = self.p.stdout.read(n - len(data))
+            if not chunk:
+                raise ConnectionError("SFTP connection closed by server")
+            data += chunk
+        return data
+
+    def _recv(self):
+        (length,) = struct.unpack(">I", self._read_exactly(4))
+        data = self._read_exactly(length)
+        return data[0], Reader(data[1:])
+
+    def send_request(self, ptype, payload):
+        """send a request, return its id (use wait() to get the response)"""
+        self.next_id = (self.next_id + 1) & 0xFFFFFFFF
+        req_id = self.next_id
+        self._send(ptype, struct.pack(">I", req_id) + payload)
+        return req_id
+
+    def wait(self, req_id):
+        """return (type, reader) of the response to request *req_id*"""
+        while req_id not in self.responses:
+            ptype, r = self._recv()
+            self.responses[r.u32()] = ptype, r
+        return self.responses.pop(req_id)
+
+    def request(self, ptype, *fields, path=None):
+        response = self.wait(self.send_request(ptype, b"".join(fields)))
+        return self.check(response, path)
+
+    def check(self, response, path=None):
+        """raise OSError for error status responses, return (type, reader) otherwise"""
+        ptype, r = response
+        if ptype == FXP_STATUS:
+            code = r.u32()
+            if code not in (FX_OK, FX_EOF):
+                message = r.str() if r.pos < len(r.data) else ""
+                err = STATUS_ERRNO.get(code, errno.EIO)
+                raise OSError(err, message or os.strerror(err), path)
+        return ptype, r
+
+    # high level operations
+
+    def stat(self, path):
+        ptype, r = self.request(FXP_STAT, pack_str(path), path=path)
+        return r.attrs()
+
+    def open(self, path, pflags):
+        ptype, r = self.request(FXP_OPEN, pack_str(path), struct.pack(">I", pflags), NO_ATTRS, path=path)
+        return r.bytes()
+
+    def close_handle(self, handle, path=None):
+        self.request(FXP_CLOSE, pack_str(handle), path=path)
+
+    def fstat(self, handle, path=None):
+        ptype, r = self.request(FXP_FSTAT, pack_str(handle), path=path)
+        return r.attrs()
+
+    def listdir(self, path):
+        handle = self.request(FXP_OPENDIR, pack_str(path), path=path)[1].bytes()
+        entries = []
+        try:
+            while True:
+                ptype, r = self.request(FXP_READDIR, pack_str(handle), path=path)
+                if ptype != FXP_NAME:
+                    break  # EOF
+                for _ in range(r.u32()):
+                    name, _longname, attrs = r.str(), r.str(), r.attrs()
+                    if name not in (".", ".."):
+                        entries.append((name, attrs))
+        finally:
+            self.close_handle(handle, path)
+        return entries
+
+    def mkdir(self, path):
+        self.request(FXP_MKDIR, pack_str(path), NO_ATTRS, path=path)
+
+    def rmdir(self, path):
+        self.request(FXP_RMDIR, pack_str(path), path=path)
+
+    def remove(self, path):
+        self.request(FXP_REMOVE, pack_str(path), path=path)
+
+    def rename(self, src, dst):
+        """atomically replace *dst* by *src*, like rename(2)"""
+        if EXT_POSIX_RENAME not in self.extensions:
+            # a plain SFTP rename fails if dst exists and removing dst first is not crash safe.
+            raise BackendUnavailable("sftp", f"the server does not support {EXT_POSIX_RENAME} (atomic rename)")
+        self.request(FXP_EXTENDED, pack_str(EXT_POSIX_RENAME), pack_str(src), pack_str(dst), path=src)
+
+    def fsync(self, handle, path=None):
+        if EXT_FSYNC in self.extensions:
+            self.request(FXP_EXTENDED, pack_str(EXT_FSYNC), pack_str(handle), path=path)
+
+    def statvfs(self, path):
+        """return the available space in bytes, None if the server does not support statvfs"""
+        if EXT_STATVFS not in self.extensions:
+            return None
+        ptype, r = self.request(FXP_EXTENDED, pack_str(EXT_STATVFS), pack_str(path), path=path)
+        _bsize, frsize, _blocks, _bfree, bavail = (r.u64() for _ in range(5))
+        return frsize * bavail
+
+
+class SFTPFile:
+    """
+    A file on the SFTP server, opened either for reading (seekable, with read-ahead)
+    or for sequential writing (buffered, with pipelined WRITE requests).
+    """
+
+    def __init__(self, client, path, pflags, durable=False):
+        self.client = client
+        self.path = path
+        self.writing = bool(pflags & FXF_WRITE)
+        self.durable = durable
+        self.handle = client.open(path, pflags)
+        self.pos = 0
+        self.buf = b""  # read: read-ahead buffer starting at buf_offset, write: not yet sent data
+        self.buf_offset = 0
+        self.in_flight = deque()
+        self.closed = False
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    # reading
+
+    def _read_range(self, offset, size):
+        chunks = []
+        in_flight = deque()
+        next_offset, end = offset, offset + size
+        eof = False
+        while in_flight or next_offset < end and not eof:
+            while next_offset < end and not eof and len(in_flight) < MAX_IN_FLIGHT:
+                length = min(MAX_IO_SIZE, end - next_offset)
+                payload = pack_str(self.handle) + struct.pack(">QI", next_offset, length)
+                in_flight.append((self.client.send_request(FXP_READ, payload), length))
+                next_offset += length
+            req_id, length = in_flight.popleft()
+            ptype, r = self.client.check(self.client.wait(req_id), self.path)
+            if eof:
+                continue  # just collect the responses to requests beyond the end of file
+            chunk = r.bytes() if ptype == FXP_DATA else b""
+            chunks.append(chunk)
+            # for regular files, sftp-server only returns less than requested at the end of the file.
+            eof = len(chunk) < length
+        return b"".join(chunks)
+
+    def read(self, n=-1):
+        if n is None or n < 0:
+            n = max(self.size - self.pos, 0)
+        if n == 0:
+            return b""
+        start = self.pos - self.buf_offset
+        if 0 <= start and start + n <= len(self.buf):
+            data = self.buf[start : start + n]
+        else:
+            self.buf = self._read_range(self.pos, max(n, READAHEAD))
+            self.buf_offset = self.pos
+            data = self.buf[:n]
+        self.pos += len(data)
+        return data
+
+    @property
+    def size(self):
+        if self.writing:
+            return self.pos
+        return self.client.fstat(self.handle, self.path)["size"]
+
+    def seek(self, offset, whence=io.SEEK_SET):
+        if whence == io.SEEK_SET:
+            pos = offset
+        elif whence == io.SEEK_CUR:
+            pos = self.pos + offset
+        elif whence == io.SEEK_END:
+            pos = self.size + offset
+        else:
+            raise ValueError("invalid whence (%r)" % whence)
+        if pos < 0:
+            raise OSError(errno.EINVAL, "Invalid argument")
+        if self.writing and pos != self.pos:
+            raise io.UnsupportedOperation("SFTP files are written sequentially")
+        self.pos = pos
+        return pos
+
+    def tell(self):
+        return self.pos
+
+    # writing
+
+    def write(self, data):
+        self.buf += data
+        self.pos += len(data)
+        if len(self.buf) >= MAX_IO_SIZE:
+            self._send_writes(flush=False)
+        return len(data)
+
+    def _send_writes(self, flush):
+        while len(self.buf) >= MAX_IO_SIZE or flush and self.buf:
+            chunk, self.buf = self.buf[:MAX_IO_SIZE], self.buf[MAX_IO_SIZE:]
+            payload = pack_str(self.handle) + struct.pack(">Q", self.buf_offset) + pack_str(chunk)
+            self.in_flight.append(self.client.send_request(FXP_WRITE, payload))
+            self.buf_offset += len(chunk)
+            while len(self.in_flight) > MAX_IN_FLIGHT:
+                self.client.check(self.client.wait(self.in_flight.popleft()), self.path)
+
+    def flush(self):
+        if self.writing:
+            self._send_writes(flush=True)
+            while self.in_flight:
+                self.client.check(self.client.wait(self.in_flight.popleft()), self.path)
+
+    def sync(self):
+        self.flush()
+        self.client.fsync(self.handle, self.path)
+
+    def close(self):
+        if self.closed:
+            return
+        self.closed = True
+        try:
+            if self.writing:
+                if self.durable:
+                    self.sync()
+                else:
+                    self.flush()
+        finally:
+            self.buf = b""
+            self.client.close_handle(self.handle, self.path)
+
+
+class SFTPStore(Store):
+    """
+    A store in a directory on a SFTP server, accessed via "ssh -s HOST sftp" (so no borg is needed there).
+
+    Location: sftp://[USER@]HOST[:PORT]/PATH (/./PATH and /~/PATH are relative to the login directory).
+
+    Atomic replacement of files needs the posix-rename@openssh.com extension, durability uses the
+    fsync@openssh.com extension, if the server supports it (OpenSSH supports both). Locking works like for
+    local repositories, but uses exclusive file creation (O_EXCL) instead of mkdir.
+
+    *sftp_cmd* replaces the ssh command line, it is started without adapting the environment for
+    system binaries (the tests use it to talk to a local sftp server stand-in).
+    """
+
+    def __init__(self, location, rsh=None, sftp_cmd=None):
+        super().__init__(location)
+        path = location.path
+        if path.startswith(("/./", "/~/")):
+            path = path[3:]  # relative to the login (home) directory
+        self.root = path.rstrip("/") or "/"
+        self.rsh = rsh
+        self._sftp_cmd = sftp_cmd
+        self._client = None
+
+    @property
+    def path(self):
+        return self.location.canonical_path()
+
+    def sftp_cmd(self):
+        """return the command line to start a sftp session"""
+        if self._sftp_cmd is not None:
+            return self._sftp_cmd
+        rsh = self.rsh or os.environ.get("BORG_RSH", "ssh")
+        args = shlex.split(rsh)
+        if self.location.port:
+            args += ["-p", str(self.location.port)]
+        args.append("-s")
+        if self.location.user:
+            args.append(f"{self.location.user}@{self.location.host}")
+        else:
+            args.append("%s" % self.location.host)
+        return args + ["sftp"]
+
+    @property
+    def client(self):
+        if self._client is None:
+            env = prepare_subprocess_env(system=self._sftp_cmd is None)
+            self._client = SFTPClient(self.sftp_cmd(), env=env)
+        return self._client
+
+    def close(self):
+        if self._client is not None:
+            self._client.close()
+            self._client = None
+
+    def _path(self, name):
+        if not name:
+            return self.root
+        return self.root.rstrip("/") + "/" + name
+
+    def exists(self):
+        try:
+            self.client.stat(self.root)
+        except FileNotFoundError:
+            return False
+        return True
+
+    def isdir(self):
+        return stat.S_ISDIR(self.client.stat(self.root).get("mode", 0))
+
+    def create(self, make_parent_dirs=False):
+        if make_parent_dirs:
+            parent = self.root.rpartition("/")[0]
+            if parent:
+                self._makedirs(parent)
+        if not self.exists():
+            self.client.mkdir(self.root)
+
+    def destroy(self):
+        def remove_tree(path):
+            for name, attrs in self.client.listdir(path):
+                if stat.S_ISDIR(attrs.get("mode", 0)):
+                    remove_tree(f"{path}/{name}")
+                else:
+                    self.client.remove(f"{path}/{name}")
+            self.client.rmdir(path)
+
+        remove_tree(self.root)
+
+    def free_space(self, name=""):
+        return self.client.statvfs(self._path(name))
+
+    def list(self, name=""):
+        return [
+            StoreEntry(entry_name, stat.S_ISDIR(attrs.get("mode", 0)))
+            for entry_name, attrs in self.client.listdir(self._path(name))
+        ]
+
+    def size(self, name):
+        return self.client.stat(self._path(name))["size"]
+
+    def _makedirs(self, path):
+        current = ""
+        for part in path.split("/"):
+            current = f"{current}/{part}" if current or path.startswith("/") else part
+            if not part:
+                continue
+            try:
+                self.client.mkdir(current)
+            except OSError:
+                # most servers just give a generic failure if it exists already, so check:
+                if not stat.S_ISDIR(self.client.stat(current).get("mode", 0)):
+                    raise
+
+    def makedirs(self, name):
+        self._makedirs(self._path(name))
+
+    def rmdir(self, name):
+        self.client.rmdir(self._path(name))
+
+    def _open(self, name, pflags, durable=False):
+        path = self._path(name)
+        try:
+            return SFTPFile(self.client, path, pflags, durable=durable)
+        except OSError:
+            if pflags & FXF_EXCL and self.has(name):
+                raise FileExistsError(errno.EEXIST, os.strerror(errno.EEXIST), path) from None
+            raise
+
+    def load(self, name):
+        with self._open(name, FXF_READ) as fd:
+            return fd.read()
+
+    def store(self, name, data):
+        # like SaveFile: write to a temporary file, make it durable, then rename it over the target.
+        tmp_name = f"{name}-{uuid.uuid4().hex[:8]}.tmp"
+        try:
+            with self._open(tmp_name, FXF_WRITE | FXF_CREAT | FXF_EXCL, durable=True) as fd:
+                fd.write(data)
+            self.replace(tmp_name, name)
+        except BaseException:
+            try:
+                self.delete(tmp_name)
+            except OSError:
+                pass
+            raise
+
+    def create_exclusive(self, name, data):
+        with self._open(name, FXF_WRITE | FXF_CREAT | FXF_EXCL, durable=True) as fd:
+            fd.write(data)
+
+    def append(self, name, data):
+        with self._open(name, FXF_WRITE | FXF_CREAT, durable=True) as fd:
+            # do not rely on FXF_APPEND, not all servers support it. we are the only writer (locked).
+            fd.pos = fd.buf_offset = self.client.fstat(fd.handle, fd.path)["size"]
+            fd.write(data)
+
+    def delete(self, name):
+        self.client.remove(self._path(name))
+
+    def replace(self, src, dst):
+        self.client.rename(self._path(src), self._path(dst))
+
+    def open_read(self, name):
+        return self._open(name, FXF_READ)
+
+    def open_write(self, name):
+        return self._open(name, FXF_WRITE | FXF_CREAT | FXF_TRUNC, durable=True)
+
+    def open_segment(self, name):
+        return self._open(name, FXF_WRITE | FXF_CREAT | FXF_EXCL, durable=True)
@@ -0,0 +1,172 @@
+import json
+import os
+import sqlite3
+from configparser import ConfigParser
+from contextlib import closing
+
+import pytest
+
+from ...cache import Cache
+from ...crypto.file_integrity import IntegrityCheckedFile, EncryptedFile
+from ...hashindex import ChunkIndex
+from ...helpers import EXIT_ERROR, hex_to_bin
+from . import cmd, create_src_archive, open_repository, RK_ENCRYPTION, generate_archiver_tests
+from .corruption import corrupt
+
+pytest_generate_tests = lambda metafunc: generate_archiver_tests(metafunc, kinds="local,remote,binary")  # NOQA
+
+
+def cache_archiver(archiver, monkeypatch, impl="local"):
+    monkeypatch.setenv("BORG_CACHE_IMPL", impl)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    create_src_archive(archiver, "test")
+    return json.loads(cmd(archiver, "rinfo", "--json"))["cache"]["path"]
+
+
+def test_cache_check(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    cache_path = cache_archiver(archiver, monkeypatch)
+    output = cmd(archiver, "cache", "check", "-v")
+    assert "Cache check complete, no problems found." in output
+
+    # wrong refcounts in the chunks index, with valid integrity data
+    chunks_path = os.path.join(cache_path, "chunks")
+    chunks = ChunkIndex(path=chunks_path)
+    chunks_before = set(chunks.iteritems())
+    ids = [id for id, _ in chunks.iteritems()]
+    chunks[ids[0]] = (chunks[ids[0]].refcount + 1, chunks[ids[0]].size)
+    del chunks[ids[1]]
+    chunks[bytes(32)] = (1, 1)
+    with IntegrityCheckedFile(path=chunks_path, write=True) as fd:
+        chunks.write(fd)
+    config = ConfigParser(interpolation=None)
+    config.read(os.path.join(cache_path, "config"))
+    config.set("integrity", "chunks", fd.integrity_data)
+    with open(os.path.join(cache_path, "config"), "w") as fd:
+        config.write(fd)
+    # an archive index of an archive not in the repository anymore
+    with open(os.path.join(cache_path, "chunks.archive.d", "00" * 32 + ".compact"), "wb") as fd:
+        fd.write(b"stale")
+
+    output = cmd(archiver, "cache", "check", exit_code=1)
+    assert "The chunks index has 1 missing and 1 superfluous chunks and 1 wrong reference counts or sizes." in output
+    assert "Stale archive index" in output
+    cmd(archiver, "cache", "check", "--repair")
+    assert set(ChunkIndex(path=chunks_path).iteritems()) == chunks_before
+    output = cmd(archiver, "cache", "check", "-v")
+    assert "Cache check complete, no problems found." in output
+
+
+def test_cache_check_damaged_archive(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    cache_archiver(archiver, monkeypatch)
+    (archive,) = json.loads(cmd(archiver, "rlist", "--json"))["archives"]
+    with open_repository(archiver) as repository:
+        repository.delete(hex_to_bin(archive["id"]))
+        repository.commit(compact=False)
+    # the cache can not be repaired without the chunks referenced by the archive, borg check is needed.
+    output = cmd(archiver, "cache", "check", "--repair", exit_code=1)
+    assert "Archive test is damaged, run borg check." in output
+    assert "Not all problems could be repaired." in output
+
+
+def test_cache_check_files_cache(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    cache_path = cache_archiver(archiver, monkeypatch)
+    (name,) = [name for name in os.listdir(cache_path) if name.startswith("files")]
+    corrupt(os.path.join(cache_path, name))
+    output = cmd(archiver, "cache", "check", exit_code=1)
+    assert "The files cache %s is damaged" % name in output
+    cmd(archiver, "cache", "check", "--repair")
+    cmd(archiver, "cache", "check")
+    create_src_archive(archiver, "test2")
+
+
+def test_cache_check_sqlite(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    cache_path = cache_archiver(archiver, monkeypatch, impl="sqlite")
+    cmd(archiver, "cache", "check")
+
+    # wrong rows in the chunks table
+    db = sqlite3.connect(os.path.join(cache_path, "cache.db"), isolation_level=None)
+    with closing(db):
+        rows_before = set(db.execute("SELECT id, refcount, size FROM chunks"))
+        ids = sorted(id for id, _, _ in rows_before)
+        db.execute("UPDATE chunks SET refcount = refcount + 1 WHERE id = ?", (ids[0],))
+        db.execute("DELETE FROM chunks WHERE id = ?", (ids[1],))
+        db.execute("INSERT INTO chunks VALUES (?, 1, 1)", (bytes(32),))
+    output = cmd(archiver, "cache", "check", exit_code=1)
+    assert "The chunks index in the cache database has 1 missing and 1 superfluous chunks" in output
+    cmd(archiver, "cache", "check", "--repair")
+    db = sqlite3.connect(os.path.join(cache_path, "cache.db"))
+    with closing(db):
+        assert set(db.execute("SELECT id, refcount, size FROM chunks")) == rows_before
+    cmd(archiver, "cache", "check")
+
+    with open(os.path.join(cache_path, "cache.db"), "r+b") as fd:
+        fd.write(b"X" * 100)
+    output = cmd(archiver, "cache", "check", exit_code=1)
+    assert "The cache database is damaged" in output
+    cmd(archiver, "cache", "check", "--repair")
+    cmd(archiver, "cache", "check")
+    cmd(archiver, "rinfo")
+
+
+def assert_cache_encrypted(cache_path):
+    names = ["config", "chunks"] + [name for name in os.listdir(cache_path) if name.startswith("files")]
+    archive_path = os.path.join(cache_path, "chunks.archive.d")
+    names += [os.path.join(archive_path, fn) for fn in os.listdir(archive_path) if not fn.endswith(".integrity")]
+    for name in names:
+        with open(os.path.join(cache_path, name), "rb") as fd:
+            assert fd.read(len(EncryptedFile.MAGIC)) == EncryptedFile.MAGIC, name
+
+
+def test_cache_encryption(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    monkeypatch.setenv("BORG_CACHE_ENCRYPTION", "yes")
+    cache_path = cache_archiver(archiver, monkeypatch)
+    assert_cache_encrypted(cache_path)
+    create_src_archive(archiver, "test2")
+    cmd(archiver, "cache", "check")
+
+    # unencrypted files are rejected, anybody could have written them.
+    with IntegrityCheckedFile(path=os.path.join(cache_path, "chunks"), write=True) as fd:
+        ChunkIndex().write(fd)
+    with open(os.path.join(cache_path, "config"), "w") as fd:
+        fd.write("[cache]\nversion = 1\n")
+    output = cmd(archiver, "cache", "check", exit_code=1)
+    assert "The cache config is damaged or not encrypted." in output
+    assert "The chunks index is damaged" in output
+    cmd(archiver, "cache", "check", "--repair")
+    assert_cache_encrypted(cache_path)
+    create_src_archive(archiver, "test3")
+    cmd(archiver, "cache", "check")
+
+    monkeypatch.setenv("BORG_CACHE_IMPL", "sqlite")
+    if archiver.FORK_DEFAULT:
+        cmd(archiver, "rinfo", exit_code=EXIT_ERROR)
+    else:
+        with pytest.raises(Cache.EncryptionNotSupported):
+            cmd(archiver, "rinfo")
+
+
+def test_cache_encryption_migration(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    cache_path = cache_archiver(archiver, monkeypatch)
+    create_src_archive(archiver, "test2")
+    cmd(archiver, "rinfo")
+    with open(os.path.join(cache_path, "config"), "rb") as fd:
+        assert fd.read(len(EncryptedFile.MAGIC)) != EncryptedFile.MAGIC
+    # the cache written before BORG_CACHE_ENCRYPTION was enabled gets encrypted once
+    monkeypatch.setenv("BORG_CACHE_ENCRYPTION", "yes")
+    cmd(archiver, "rinfo")
+    assert_cache_encrypted(cache_path)
+    cmd(archiver, "cache", "check")
+    # but no unencrypted files are accepted afterwards
+    monkeypatch.delenv("BORG_CACHE_ENCRYPTION")
+    cmd(archiver, "rinfo")
+    monkeypatch.setenv("BORG_CACHE_ENCRYPTION", "yes")
+    cmd(archiver, "cache", "check", exit_code=1)
+    cmd(archiver, "rinfo")
+    cmd(archiver, "cache", "check", "--repair")
+    cmd(archiver, "cache", "check")
@@ -5,6 +5,7 @@
 import pytest
 
 from ...archive import ChunkBuffer
+from ...chunkindex import RepositoryChunkIndex
 from ...constants import *  # NOQA
 from ...helpers import bin_to_hex, msgpack
 from ...manifest import Manifest
@@ -359,6 +360,27 @@ def test_extra_chunks(archivers, request):
     cmd(archiver, "extract", "archive1", "--dry-run", exit_code=0)
 
 
+def test_chunk_index(archivers, request):
+    archiver = request.getfixturevalue(archivers)
+    check_cmd_setup(archiver)
+    output = cmd(archiver, "check", "-v", exit_code=0)
+    assert "Repository chunk index is stale" not in output
+    archive, repository = open_archive(archiver.repository_path, "archive1")
+    with repository:
+        manifest = Manifest.load(repository, Manifest.NO_OPERATION_CHECK)
+        # a delta with a refcount change that is not in the archives
+        delta_id = RepositoryChunkIndex(manifest).put_entries([(archive.id, 1, 0)])
+        manifest.config["chunk_index"]["deltas"].append(delta_id)
+        manifest.write()
+        repository.commit(compact=False)
+    output = cmd(archiver, "check", exit_code=1)
+    assert "Repository chunk index does not match the archives." in output
+    cmd(archiver, "check", "--repair", exit_code=0)
+    output = cmd(archiver, "check", "-v", exit_code=0)
+    assert "Repository chunk index is stale (or not present)" in output
+    assert "orphaned (unused) objects found." not in output
+
+
 @pytest.mark.parametrize("init_args", [["--encryption=repokey-aes-ocb"], ["--encryption", "none"]])
 def test_verify_data(archivers, request, init_args):
     archiver = request.getfixturevalue(archivers)
@@ -62,3 +62,24 @@ def test_config(archivers, request):
     else:
         with pytest.raises(Error):
             cmd(archiver, "config", "invalid-option")
+
+
+def test_config_data_roots(archivers, request):
+    archiver = request.getfixturevalue(archivers)
+    data_root = os.fspath(archiver.tmpdir / "data2")
+    create_test_files(archiver.input_path)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "config", "--add-data-root", data_root)
+    output = cmd(archiver, "config", "--list")
+    assert f"data_roots = {data_root}" in output
+    assert "data_placement = free-space" in output
+    cmd(archiver, "config", "data_placement", "round-robin")
+    cmd(archiver, "create", "test", "input")
+    cmd(archiver, "create", "test2", "input")
+    assert os.listdir(data_root) != ["repository-id"]
+    cmd(archiver, "check")
+    cmd(archiver, "config", "--drain-data-root", data_root)
+    assert "data_roots" not in cmd(archiver, "config", "--list")
+    assert not os.path.exists(data_root)
+    cmd(archiver, "check")
+    assert "input/file1" in cmd(archiver, "list", "--short", "test2")
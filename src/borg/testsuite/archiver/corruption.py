@@ -85,7 +85,8 @@ def test_cache_files(archiver):
         pytest.skip("no cache path for this kind of Cache implementation")
 
     cmd(archiver, "create", "test", "input")
-    corrupt(os.path.join(archiver.cache_path, "files"))
+    (files_cache,) = [fn for fn in os.listdir(archiver.cache_path) if fn.startswith("files.scope-")]
+    corrupt(os.path.join(archiver.cache_path, files_cache))
     out = cmd(archiver, "create", "test1", "input")
     # borg warns about the corrupt files cache, but then continues without files cache.
     assert "files cache is corrupted" in out
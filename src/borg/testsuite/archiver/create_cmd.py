@@ -708,6 +708,25 @@ def test_file_status(archivers, request):
     assert "A input/file2" in output
 
 
+def test_file_status_sqlite_cache(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    monkeypatch.setenv("BORG_CACHE_IMPL", "sqlite")
+    create_regular_file(archiver.input_path, "file1", size=1024 * 80)
+    time.sleep(1)  # file2 must have newer timestamps than file1
+    create_regular_file(archiver.input_path, "file2", size=1024 * 80)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    output = cmd(archiver, "create", "--list", "test", "input")
+    assert "A input/file1" in output
+    assert "A input/file2" in output
+    output = cmd(archiver, "create", "--list", "test2", "input")
+    assert "U input/file1" in output
+    assert "A input/file2" in output
+    cmd(archiver, "delete", "-a", "test")
+    cmd(archiver, "check")
+    output = cmd(archiver, "create", "--list", "test3", "input")
+    assert "U input/file1" in output
+
+
 @pytest.mark.skipif(
     is_win32, reason="ctime attribute is file creation time on Windows"
 )  # see https://docs.python.org/3/library/os.html#os.stat_result.st_ctime
@@ -780,6 +799,24 @@ def test_file_status_excluded(archivers, request):
         assert "- input/file3" in output
 
 
+def test_files_cache_per_series(archivers, request, monkeypatch):
+    """test that backups of different data sets do not evict each other's files cache entries"""
+    archiver = request.getfixturevalue(archivers)
+    monkeypatch.setenv("BORG_FILES_CACHE_TTL", "1")
+    for name in "home", "srv":
+        create_regular_file(archiver.input_path, f"{name}/file1", size=10)
+    time.sleep(1)  # file2 must have newer timestamps than file1
+    for name in "home", "srv":
+        create_regular_file(archiver.input_path, f"{name}/file2", size=10)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "create", "home-1", "input/home")
+    cmd(archiver, "create", "srv-1", "input/srv")
+    output = cmd(archiver, "create", "--list", "home-2", "input/home")
+    assert "U input/home/file1" in output
+    output = cmd(archiver, "create", "--list", "srv-2", "input/srv")
+    assert "U input/srv/file1" in output
+
+
 def test_file_status_counters(archivers, request):
     """Test file status counters in the stats of `borg create --stats`"""
     archiver = request.getfixturevalue(archivers)
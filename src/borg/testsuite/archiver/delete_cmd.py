@@ -79,3 +79,21 @@ def test_delete_double_force(archivers, request):
     cmd(archiver, "check", "--repair")
     output = cmd(archiver, "rlist")
     assert "test" not in output
+
+
+def test_delete_retention_locked(archivers, request):
+    archiver = request.getfixturevalue(archivers)
+    create_regular_file(archiver.input_path, "file1", size=1024 * 80)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "create", "--retention-lock", "1d", "locked", "input")
+    cmd(archiver, "create", "unlocked", "input")
+    output = cmd(archiver, "delete", "-a", "sh:*", exit_code=EXIT_WARNING)
+    assert "Archive locked is retention locked until" in output
+    output = cmd(archiver, "rlist", "--short")
+    assert "locked" in output and "unlocked" not in output
+    cmd(archiver, "compact")
+    cmd(archiver, "extract", "locked", "--dry-run")
+    cmd(archiver, "create", "newer", "input")
+    output = cmd(archiver, "prune", "--list", "--keep-last=1")
+    assert "Keeping archive (rule: retention-lock #1)" in output
+    assert "locked" in cmd(archiver, "rlist", "--short")
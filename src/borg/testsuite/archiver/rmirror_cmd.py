@@ -0,0 +1,37 @@
+import os
+
+from ...constants import *  # NOQA
+from . import cmd, create_test_files, RK_ENCRYPTION, generate_archiver_tests
+
+pytest_generate_tests = lambda metafunc: generate_archiver_tests(metafunc, kinds="local,remote,binary")  # NOQA
+
+
+def test_rmirror(archivers, request, monkeypatch):
+    archiver = request.getfixturevalue(archivers)
+    original_location, input_path = archiver.repository_location, archiver.input_path
+    # the mirror has the repository id of the original repository, so borg considers it relocated
+    monkeypatch.setenv("BORG_RELOCATED_REPO_ACCESS_IS_OK", "yes")
+    create_test_files(input_path)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "create", "arch1", "input")
+
+    archiver.repository_location = original_location + "-mirror"
+    other_repo = f"--other-repo={original_location}"
+    cmd(archiver, "rmirror", other_repo, "--create")
+    assert "arch1" in cmd(archiver, "rlist", "--short")
+
+    archiver.repository_location = original_location
+    cmd(archiver, "create", "arch2", "input")
+    cmd(archiver, "delete", "-a", "arch1")
+    cmd(archiver, "compact")
+
+    archiver.repository_location = original_location + "-mirror"
+    output = cmd(archiver, "rmirror", other_repo, "--dry-run", "--info")
+    assert "Would mirror transaction" in output
+    cmd(archiver, "rmirror", other_repo)
+    listing = cmd(archiver, "rlist", "--short")
+    assert "arch1" not in listing
+    assert "arch2" in listing
+    assert "input/file1" in cmd(archiver, "list", "--short", "arch2")
+    cmd(archiver, "check")
+    assert not os.path.exists(os.path.join(archiver.repository_path + "-mirror", "staging"))
@@ -0,0 +1,65 @@
+import json
+
+from ...constants import *  # NOQA
+from . import cmd, create_regular_file, generate_archiver_tests, RK_ENCRYPTION
+
+pytest_generate_tests = lambda metafunc: generate_archiver_tests(metafunc, kinds="local,remote,binary")  # NOQA
+
+
+def test_rspace(archivers, request):
+    archiver = request.getfixturevalue(archivers)
+    create_regular_file(archiver.input_path, "file1", size=1024 * 80)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "create", "test", "input")
+    output = cmd(archiver, "rspace")
+    assert "file_data" in output
+    assert "Unique size of archives:" in output
+
+
+def test_rspace_json(archivers, request):
+    archiver = request.getfixturevalue(archivers)
+    create_regular_file(archiver.input_path, "file1", size=1024 * 80)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "create", "host1-a", "input", "--compression=none")
+    cmd(archiver, "create", "host1-b", "input", "--compression=none")
+    create_regular_file(archiver.input_path, "file2", size=1024 * 40)
+    cmd(archiver, "create", "host2-a", "input", "--compression=lz4")
+    cmd(archiver, "delete", "-a", "host1-b")
+
+    space = json.loads(cmd(archiver, "rspace", "--json", "--group=sh:host1-*", "--group=sh:host2-*"))["space"]
+    types = space["types"]
+    assert types["file_data"]["count"] == 2
+    assert types["file_data"]["size"] == 1024 * 120
+    assert types["manifest"]["count"] == 1
+    assert types["chunk_index"]["count"] >= 1
+    assert types["item_metadata"]["count"] >= 2
+    assert types["archive_metadata"]["count"] >= 2
+    assert {"none", "lz4"} <= set(space["compression"])
+    assert space["compression"]["none"]["ratio"] >= 1.0
+    assert space["unreferenced"]["count"] == 0
+
+    archives = {a["name"]: a for a in space["archives"]}
+    assert set(archives) == {"host1-a", "host2-a"}
+    # file1 is in both archives, only file2 and the archive metadata are unique
+    assert 1024 * 40 <= archives["host2-a"]["unique_size"] < 1024 * 80
+
+    groups = space["groups"]
+    host1, host2 = groups["sh:host1-*"], groups["sh:host2-*"]
+    assert host1["shared_with"] == {"sh:host2-*": host1["shared_size"]}
+    assert host2["shared_with"] == {"sh:host1-*": host2["shared_size"]}
+    assert host1["shared_size"] == host2["shared_size"] >= 1024 * 80
+    assert host2["unique_size"] >= 1024 * 40
+    assert host1["referenced_size"] == host1["unique_size"] + host1["shared_size"]
+    assert space["segments"]["reclaimable_size"] >= 0
+
+
+def test_rspace_archive_filter(archivers, request):
+    archiver = request.getfixturevalue(archivers)
+    create_regular_file(archiver.input_path, "file1", size=1024 * 80)
+    cmd(archiver, "rcreate", RK_ENCRYPTION)
+    cmd(archiver, "create", "test1", "input")
+    cmd(archiver, "create", "test2", "input")
+    space = json.loads(cmd(archiver, "rspace", "--json", "--last=1"))["space"]
+    assert [a["name"] for a in space["archives"]] == ["test2"]
+    # everything is shared with test1, except the archive metadata
+    assert space["archives"][0]["unique_size"] < 1024 * 80
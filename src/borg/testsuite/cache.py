@@ -1,15 +1,15 @@
 import io
 import os.path
 
-from ..helpers.msgpack import packb
+from ..helpers.msgpack import packb, unpackb, int_to_timestamp
 
 import pytest
 
 from .hashindex import H
 from .key import TestKey
 from ..archive import Statistics
-from ..cache import AdHocCache
-from ..crypto.key import AESOCBRepoKey
+from ..cache import AdHocCache, SQLiteCache, files_cache_scope
+from ..crypto.key import AESOCBRepoKey, PlaintextKey
 from ..hashindex import ChunkIndex, CacheSynchronizer
 from ..manifest import Manifest
 from ..repository import Repository
@@ -226,3 +226,82 @@ def test_existing_incref_after_add_chunk(self, cache):
         """This case occurs with part files, see Archive.chunk_file."""
         assert cache.add_chunk(H(1), {}, b"5678", stats=Statistics()) == (H(1), 4)
         assert cache.chunk_incref(H(1), 4, Statistics()) == (H(1), 4)
+
+
+def test_files_cache_scope():
+    scope = files_cache_scope("host-home-2024-01-05T10:00:00", ["/home", "/root"])
+    assert files_cache_scope("host-home-2024-01-06T10:00:01", ["/root", "/home"]) == scope
+    assert files_cache_scope("host-srv-2024-01-05T10:00:00", ["/home", "/root"]) != scope
+    assert files_cache_scope("host-home-2024-01-05T10:00:00", ["/home"]) != scope
+    assert files_cache_scope("test", ["input"]) == files_cache_scope("test2", ["input"])
+
+
+@pytest.fixture
+def plaintext_repository(tmpdir, monkeypatch):
+    monkeypatch.setenv("BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK", "yes")
+    with Repository(os.path.join(str(tmpdir), "repository"), exclusive=True, create=True) as repository:
+        Manifest(PlaintextKey(repository), repository).write()
+        repository.commit(compact=False)
+        yield repository
+
+
+def commit_archive(cache, name):
+    manifest = cache.manifest
+    manifest.archives[name] = (H(100 + len(name)), "2024-01-01T00:00:00.000000")
+    manifest.write()
+    manifest.repository.commit(compact=False)
+    cache.commit()
+
+
+def open_sqlite_cache(repository, tmpdir, **kw):
+    manifest = Manifest.load(repository, Manifest.NO_OPERATION_CHECK, key=PlaintextKey(repository))
+    return SQLiteCache(manifest, path=str(tmpdir.join("cache")), **kw)
+
+
+def test_sqlite_cache_chunks(plaintext_repository, tmpdir):
+    with open_sqlite_cache(plaintext_repository, tmpdir) as cache:
+        cache.begin_txn()
+        cache.add_chunk(H(1), {}, b"1234", stats=Statistics())
+        cache.add_chunk(H(2), {}, b"56789", stats=Statistics())
+        commit_archive(cache, "a")
+
+    with open_sqlite_cache(plaintext_repository, tmpdir) as cache:
+        assert cache.cache_config.manifest_id == cache.manifest.id
+        assert {id: tuple(entry) for id, entry in cache.chunks.iteritems()} == {H(1): (1, 4), H(2): (1, 5)}
+        cache.begin_txn()
+        cache.chunk_incref(H(1), 4, Statistics())
+        cache.rollback()
+        assert cache.chunks[H(1)] == (1, 4)
+        # only the changed entries get written
+        cache.begin_txn()
+        cache.chunk_incref(H(1), 4, Statistics())
+        cache.chunk_decref(H(2), 5, Statistics())
+        assert sorted(set(cache.chunks.journal_keys())) == [H(1), H(2)]
+        commit_archive(cache, "bb")
+
+    with open_sqlite_cache(plaintext_repository, tmpdir) as cache:
+        assert {id: tuple(entry) for id, entry in cache.chunks.iteritems()} == {H(1): (2, 4)}
+
+
+def test_sqlite_cache_files(plaintext_repository, tmpdir, monkeypatch):
+    monkeypatch.setenv("BORG_FILES_CACHE_TTL", "2")
+
+    def entry(cmtime):
+        return packb((0, 1, 2, int_to_timestamp(cmtime), []))
+
+    with open_sqlite_cache(plaintext_repository, tmpdir, cache_mode="cis") as cache:
+        cache.begin_txn()
+        cache.files[H(1)] = entry(1)
+        cache.files[H(2)] = entry(3)  # not older than the newest cmtime, not kept
+        cache._newest_cmtime = 3
+        commit_archive(cache, "a")
+
+    for age, name in enumerate(["bb", "ccc"], start=1):
+        with open_sqlite_cache(plaintext_repository, tmpdir, cache_mode="cis") as cache:
+            assert {path_hash: unpackb(item)[0] for path_hash, item in cache.files.items()} == {H(1): age}
+            cache.begin_txn()
+            commit_archive(cache, name)
+
+    with open_sqlite_cache(plaintext_repository, tmpdir, cache_mode="cis") as cache:
+        # not seen in BORG_FILES_CACHE_TTL backups
+        assert cache.files == {}
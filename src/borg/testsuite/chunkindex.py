@@ -0,0 +1,173 @@
+import os
+
+import pytest
+
+from .hashindex import H
+from ..archive import Statistics
+from ..cache import AdHocCache, LocalCache
+from ..chunkindex import RepositoryChunkIndex, archives_digest
+from ..crypto.key import PlaintextKey
+from ..manifest import Manifest
+from ..repository import Repository
+
+
+@pytest.fixture
+def repository(tmpdir, monkeypatch):
+    monkeypatch.setenv("BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK", "yes")
+    with Repository(os.path.join(str(tmpdir), "repository"), exclusive=True, create=True) as repository:
+        key = PlaintextKey(repository)
+        Manifest(key, repository).write()
+        repository.commit(compact=False)
+        yield repository
+
+
+def load_manifest(repository):
+    return Manifest.load(repository, Manifest.NO_OPERATION_CHECK, key=PlaintextKey(repository))
+
+
+def commit(manifest, cache=None, archives=(), deleted=()):
+    for name in archives:
+        manifest.archives[name] = (H(100 + len(name)), "2024-01-01T00:00:00.000000")
+    for name in deleted:
+        del manifest.archives[name]
+    manifest.write()
+    manifest.repository.commit(compact=False)
+    if cache is not None:
+        cache.commit()
+
+
+def load_index(repository):
+    chunk_index = RepositoryChunkIndex(load_manifest(repository))
+    head = chunk_index.head()
+    return head, head and {id: tuple(entry) for id, entry in chunk_index.load(head).iteritems()}
+
+
+def test_write_and_load(repository):
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(1), {}, b"1234", stats=Statistics())
+    cache.add_chunk(H(1), {}, b"1234", stats=Statistics())
+    cache.add_chunk(H(2), {}, b"56789", stats=Statistics())
+    commit(manifest, cache, archives=["a"])
+    head, index = load_index(repository)
+    assert head["seq"] == 1 and len(head["deltas"]) == 1
+    assert index == {H(1): (2, 4), H(2): (1, 5)}
+
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.chunk_decref(H(1), 4, Statistics())
+    cache.chunk_decref(H(2), 5, Statistics())
+    commit(manifest, cache, archives=["bb"])
+    head, index = load_index(repository)
+    assert head["seq"] == 2 and len(head["deltas"]) == 2
+    assert index == {H(1): (1, 4)}
+
+
+def test_update_deltas(repository):
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(1), {}, b"1234", stats=Statistics())
+    commit(manifest, cache, archives=["a"])
+    head, index = load_index(repository)
+    position = RepositoryChunkIndex.position_of(head)
+
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(2), {}, b"5678", stats=Statistics())
+    commit(manifest, cache, archives=["bb"])
+    chunk_index = RepositoryChunkIndex(load_manifest(repository))
+    head = chunk_index.head()
+    chunks = chunk_index.load(dict(head, deltas=head["deltas"][:1]))
+    assert chunk_index.update(chunks, position, head)
+    assert {id: tuple(entry) for id, entry in chunks.iteritems()} == {H(1): (1, 4), H(2): (1, 4)}
+    # a position from another epoch (snapshot) can not be updated
+    assert not chunk_index.update(chunks, (os.urandom(16), 1), head)
+
+
+def test_large_delta(repository, monkeypatch):
+    monkeypatch.setattr("borg.chunkindex.CHUNK_INDEX_PART_ENTRIES", 2)
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(0), {}, b"1234", stats=Statistics())
+    commit(manifest, cache, archives=["a"])
+    head, index = load_index(repository)
+    position = RepositoryChunkIndex.position_of(head)
+    chunks = RepositoryChunkIndex(load_manifest(repository)).load(head)
+
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    for i in range(1, 6):
+        cache.add_chunk(H(i), {}, b"1234", stats=Statistics())
+    commit(manifest, cache, archives=["bb"])
+    head, index = load_index(repository)
+    # the changes of a transaction are split into deltas of at most CHUNK_INDEX_PART_ENTRIES entries
+    assert head["seq"] == 4 and len(head["deltas"]) == 4
+    assert index == {H(i): (1, 4) for i in range(6)}
+    assert RepositoryChunkIndex(load_manifest(repository)).update(chunks, position, head)
+    assert {id: tuple(entry) for id, entry in chunks.iteritems()} == index
+
+
+def test_stale(repository):
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(1), {}, b"1234", stats=Statistics())
+    commit(manifest, cache, archives=["a"])
+    # a client not maintaining the chunk index changes the archives
+    commit(load_manifest(repository), archives=["bb"])
+    head, index = load_index(repository)
+    assert head is None
+    # an ad-hoc cache can not update a stale index
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(2), {}, b"5678", stats=Statistics())
+    commit(manifest, cache, archives=["ccc"])
+    head, index = load_index(repository)
+    assert head is None
+
+
+def test_snapshot(repository, monkeypatch):
+    monkeypatch.setattr("borg.chunkindex.CHUNK_INDEX_MAX_DELTAS", 2)
+    for i in range(3):
+        manifest = load_manifest(repository)
+        cache = AdHocCache(manifest)
+        cache.add_chunk(H(i), {}, b"1234", stats=Statistics())
+        commit(manifest, cache, archives=["a" * (i + 1)])
+    head, index = load_index(repository)
+    assert head["seq"] == 3 and len(head["snapshot"]) == 1 and head["deltas"] == []
+    assert index == {H(0): (1, 4), H(1): (1, 4), H(2): (1, 4)}
+    # the objects of the previous snapshot and deltas were deleted
+    assert len(repository) == 1 + 3 + 1
+
+
+def test_local_cache(repository, tmpdir):
+    manifest = load_manifest(repository)
+    with LocalCache(manifest, path=str(tmpdir.join("cache1"))) as cache:
+        cache.add_chunk(H(1), {}, b"1234", stats=Statistics())
+        commit(manifest, cache, archives=["a"])
+
+    # another client adds a chunk
+    manifest = load_manifest(repository)
+    cache = AdHocCache(manifest)
+    cache.add_chunk(H(2), {}, b"56789", stats=Statistics())
+    commit(manifest, cache, archives=["bb"])
+
+    # a new local cache is filled from the repository chunk index, without syncing the archives
+    for path in "cache1", "cache2":
+        manifest = load_manifest(repository)
+        with LocalCache(manifest, path=str(tmpdir.join(path))) as cache:
+            assert {id: tuple(entry) for id, entry in cache.chunks.iteritems()} == {H(1): (1, 4), H(2): (1, 5)}
+            assert cache.chunk_index.position == RepositoryChunkIndex.position_of(manifest.config["chunk_index"])
+
+    # a local cache in sync with the archives writes a new snapshot if the index is stale
+    commit(load_manifest(repository), deleted=["bb"])
+    manifest = load_manifest(repository)
+    with LocalCache(manifest, path=str(tmpdir.join("cache3")), sync=False) as cache:
+        # what syncing the chunks index from the archives would result in
+        cache.chunks.clear()
+        cache.chunks.add(H(1), 1, 4)
+        cache.chunk_index.digest = archives_digest(manifest, manifest.base_archives)
+        cache.add_chunk(H(3), {}, b"abcd", stats=Statistics())
+        commit(manifest, cache, archives=["ccc"])
+    head, index = load_index(repository)
+    assert len(head["snapshot"]) == 1 and head["deltas"] == []
+    assert index == {H(1): (1, 4), H(3): (1, 4)}
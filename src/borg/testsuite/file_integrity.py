@@ -1,6 +1,9 @@
+import os
+
 import pytest
 
-from ..crypto.file_integrity import DetachedIntegrityCheckedFile, FileIntegrityError
+from ..crypto.file_integrity import IntegrityCheckedFile, DetachedIntegrityCheckedFile, EncryptedFile
+from ..crypto.file_integrity import FileIntegrityError
 
 
 class TestReadIntegrityFile:
@@ -130,3 +133,85 @@ def test_part_independence(self, integrity_protected_file, partial_read):
                 if not partial_read:
                     fd.read()
                 # But overall it explodes with the final digest. Neat, eh?
+
+
+class TestEncryptedIntegrityCheckedFile:
+    key = bytes(range(32))
+
+    @pytest.fixture
+    def data(self, monkeypatch):
+        monkeypatch.setattr(EncryptedFile, "BLOCK_SIZE", 100)
+        return os.urandom(250)
+
+    @pytest.fixture
+    def encrypted_file(self, tmpdir, data):
+        path = str(tmpdir.join("file"))
+        with DetachedIntegrityCheckedFile(path, write=True, encryption_key=self.key) as fd:
+            fd.write(data[:10])
+            fd.write(data[10:])
+        return path
+
+    @pytest.mark.parametrize("size", (0, 1, 100, 101, 250))
+    def test_simple(self, tmpdir, data, size):
+        path = str(tmpdir.join("file"))
+        with DetachedIntegrityCheckedFile(path, write=True, encryption_key=self.key) as fd:
+            fd.write(data[:size])
+        with open(path, "rb") as fd:
+            assert fd.read().startswith(EncryptedFile.MAGIC)
+        with DetachedIntegrityCheckedFile(path, write=False, encryption_key=self.key) as fd:
+            assert fd.encrypted
+            assert fd.read(7) == data[:size][:7]
+            assert fd.read() == data[7:size]
+
+    def test_not_plaintext(self, encrypted_file, data):
+        with open(encrypted_file, "rb") as fd:
+            assert data[:16] not in fd.read()
+
+    def test_unencrypted_file(self, tmpdir):
+        path = str(tmpdir.join("file"))
+        with DetachedIntegrityCheckedFile(path, write=True) as fd:
+            fd.write(b"foo and bar")
+        # anybody could have written it
+        with pytest.raises(FileIntegrityError):
+            with DetachedIntegrityCheckedFile(path, write=False, encryption_key=self.key) as fd:
+                fd.read()
+        # unless it gets migrated
+        with DetachedIntegrityCheckedFile(path, write=False, encryption_key=self.key, allow_plaintext=True) as fd:
+            assert not fd.encrypted
+            assert fd.read() == b"foo and bar"
+
+    def test_encrypt(self, tmpdir, data):
+        path = str(tmpdir.join("file"))
+        with open(path, "wb") as fd:
+            fd.write(EncryptedFile.encrypt(self.key, data, path))
+        with IntegrityCheckedFile(path, write=False, encryption_key=self.key) as fd:
+            assert fd.encrypted and fd.read() == data
+
+    @pytest.mark.parametrize("key", (None, bytes(32)))
+    def test_wrong_key(self, encrypted_file, key):
+        with pytest.raises(FileIntegrityError):
+            with DetachedIntegrityCheckedFile(encrypted_file, write=False, encryption_key=key) as fd:
+                fd.read()
+
+    @pytest.mark.parametrize("length", (-1, -17, -66, -116))
+    def test_truncated_file(self, encrypted_file, length):
+        os.truncate(encrypted_file, os.path.getsize(encrypted_file) + length)
+        with pytest.raises(FileIntegrityError):
+            with DetachedIntegrityCheckedFile(encrypted_file, write=False, encryption_key=self.key) as fd:
+                fd.read()
+
+    def test_corrupted_file(self, encrypted_file):
+        with open(encrypted_file, "r+b") as fd:
+            fd.seek(EncryptedFile.HEADER_SIZE + 20)
+            byte = fd.read(1)
+            fd.seek(-1, os.SEEK_CUR)
+            fd.write(bytes([byte[0] ^ 1]))
+        with pytest.raises(FileIntegrityError):
+            with DetachedIntegrityCheckedFile(encrypted_file, write=False, encryption_key=self.key) as fd:
+                fd.read(10)
+
+    def test_renamed_file(self, tmpdir, encrypted_file):
+        tmpdir.join("file").move(tmpdir.join("other_file"))
+        with pytest.raises(FileIntegrityError):
+            with DetachedIntegrityCheckedFile(str(tmpdir.join("other_file")), write=False, encryption_key=self.key):
+                pass
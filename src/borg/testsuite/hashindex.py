@@ -8,7 +8,7 @@
 import tempfile
 import zlib
 
-from ..hashindex import NSIndex, ChunkIndex
+from ..hashindex import NSIndex, ChunkIndex, RetentionIndex
 from ..crypto.file_integrity import IntegrityCheckedFile, FileIntegrityError
 from . import BaseTestCase, unopened_tempfile
 
@@ -571,6 +571,23 @@ def test_nsindex_segment_limit(self):
         assert H(2) in idx
 
 
+class RetentionIndexTestCase(BaseTestCase):
+    def test_retention_index(self):
+        idx = RetentionIndex()
+        # timestamps beyond 32 bits and ones with all bits of the low half set
+        untils = [0, 1700000000, 2**32 - 1, 2**32 + 1, 2**33 - 1]
+        for x, until in enumerate(untils):
+            idx[H(x)] = until
+        with unopened_tempfile() as filepath:
+            idx.write(filepath)
+            idx = RetentionIndex.read(filepath)
+        assert len(idx) == len(untils)
+        assert [idx[H(x)] for x in range(len(untils))] == untils
+        assert sorted(until for id, until in idx.iteritems()) == untils
+        with self.assert_raises(AssertionError):
+            idx[H(9)] = 2**64 - 1
+
+
 class AllIndexTestCase(BaseTestCase):
     def test_max_load_factor(self):
         assert NSIndex.MAX_LOAD_FACTOR < 1.0
@@ -616,3 +633,111 @@ def HH(x, y, z):
 
         # this will fail at HH(600, 259) if the bug is present.
         assert [idx.get(HH(600, y, 0)) for y in range(330)] == [(600, y, 0) for y in range(330)]
+
+
+class MappedIndexTestCase(BaseTestCase):
+    def test_create_mapped(self):
+        with unopened_tempfile() as filepath:
+            idx = ChunkIndex.create_mapped(filepath)
+            assert idx.mapped
+            for x in range(1000):  # grows the table a few times
+                idx[H(x)] = x, x * 2
+            idx.flush()
+            del idx
+            idx = ChunkIndex.read(filepath)
+            assert not idx.mapped
+            assert len(idx) == 1000
+            assert all(idx[H(x)] == (x, x * 2) for x in range(1000))
+
+    def test_map(self):
+        idx = NSIndex()
+        for x in range(100):
+            idx[H(x)] = x, x, x
+        with unopened_tempfile() as filepath:
+            idx.write(filepath)
+            idx = NSIndex.map(filepath)
+            assert len(idx) == 100
+            assert idx[H(42)] == (42, 42, 42)
+            for x in range(90):  # shrinks the table
+                del idx[H(x)]
+            idx[H(1000)] = 1, 2, 3
+            idx.flush()
+            del idx
+            idx = NSIndex.read(filepath)
+            assert len(idx) == 11
+            assert idx[H(1000)] == (1, 2, 3)
+            assert H(0) not in idx
+
+    def test_map_private(self):
+        idx = ChunkIndex()
+        idx[H(1)] = 1, 1
+        with unopened_tempfile() as filepath:
+            idx.write(filepath)
+            with open(filepath, "rb") as fd:
+                data = fd.read()
+            idx = ChunkIndex.map(filepath, shared=False)
+            idx[H(1)] = 2, 2
+            for x in range(2, 1000):
+                idx[H(x)] = x, x
+            assert idx[H(1)] == (2, 2)
+            assert len(idx) == 999
+            with open(filepath, "rb") as fd:
+                assert fd.read() == data
+
+    def test_journal(self):
+        with unopened_tempfile() as filepath:
+            idx = ChunkIndex.create_mapped(filepath)
+            idx[H(1)] = 1, 1
+            idx[H(2)] = 2, 2
+            idx.start_journal()
+            idx[H(1)] = 10, 10
+            del idx[H(2)]
+            idx[H(3)] = 3, 3
+            idx.incref(H(1))
+            # H(1) was changed twice
+            assert sorted(idx.journal_keys()) == [H(1), H(1), H(2), H(3)]
+            idx.undo_journal()
+            assert len(idx) == 2
+            assert idx[H(1)] == (1, 1)
+            assert idx[H(2)] == (2, 2)
+            assert H(3) not in idx
+            idx.start_journal()
+            idx[H(3)] = 3, 3
+            idx.stop_journal()
+            assert idx[H(3)] == (3, 3)
+
+    def test_journal_file(self):
+        with unopened_tempfile() as filepath:
+            journal_path = filepath + ".journal"
+            idx = ChunkIndex.create_mapped(filepath)
+            for x in range(10):
+                idx[H(x)] = x, x
+            idx.flush()
+            idx.start_journal(journal_path)
+            del idx[H(0)]
+            for x in range(10, 1000):  # grows the table
+                idx[H(x)] = x, x
+            idx.stop_journal()
+            # like after a crash: the index file is from before growing the table, the journal has all changes.
+            crashed = ChunkIndex.map(filepath)
+            crashed.start_journal(journal_path)
+            assert len(list(crashed.journal_keys())) == 991
+            crashed.undo_journal()
+            crashed.flush()
+            del crashed
+            idx = ChunkIndex.read(filepath)
+            assert len(idx) == 10
+            assert idx[H(0)] == (0, 0)
+            assert H(10) not in idx
+            assert sorted(os.listdir(os.path.dirname(filepath))) == ["file", "file.journal"]
+
+    def test_not_mapped(self):
+        idx = ChunkIndex()
+        with self.assert_raises(ValueError):
+            idx.flush()
+
+    def test_compact_raises(self):
+        with unopened_tempfile() as filepath:
+            idx = ChunkIndex.create_mapped(filepath)
+            with self.assert_raises(ValueError):
+                idx.compact()
@@ -5,6 +5,7 @@
 import os
 import shutil
 import sys
+import time
 from argparse import ArgumentTypeError
 from datetime import datetime, timezone, timedelta
 from io import StringIO, BytesIO
@@ -27,6 +28,7 @@
 )
 from ..helpers import remove_dotdot_prefixes, make_path_safe, clean_lines
 from ..helpers import interval
+from ..helpers import parse_rpc_compression, parse_ratelimit, parse_listen_address, parse_serve_hook
 from ..helpers import get_base_dir, get_cache_dir, get_keys_dir, get_security_dir, get_config_dir, get_runtime_dir
 from ..helpers import is_slow_msgpack
 from ..helpers import msgpack
@@ -195,6 +197,46 @@ def test_socket(self, monkeypatch, keys_dir):
         )
         assert Location("socket:///some/path").to_key_filename() == keys_dir + "some_path"
 
+    def test_s3(self, monkeypatch, keys_dir):
+        monkeypatch.delenv("BORG_REPO", raising=False)
+        assert (
+            repr(Location("s3://minio.example.org:9000/bucket/some/path"))
+            == "Location(proto='s3', user=None, host='minio.example.org', port=9000, path='/bucket/some/path')"
+        )
+        assert (
+            repr(Location("s3+http://profile@127.0.0.1/bucket"))
+            == "Location(proto='s3+http', user='profile', host='127.0.0.1', port=None, path='/bucket')"
+        )
+        assert (
+            Location("s3://minio.example.org:9000/bucket/some/path").to_key_filename()
+            == keys_dir + "minio_example_org__bucket_some_path"
+        )
+
+    def test_http(self, monkeypatch, keys_dir):
+        monkeypatch.delenv("BORG_REPO", raising=False)
+        assert (
+            repr(Location("https://backup.example.org:8443/srv/borg/repo"))
+            == "Location(proto='https', user=None, host='backup.example.org', port=8443, path='/srv/borg/repo')"
+        )
+        assert (
+            repr(Location("http://[::1]/~/repo"))
+            == "Location(proto='http', user=None, host='::1', port=None, path='/~/repo')"
+        )
+        assert Location("https://host/some/path").to_key_filename() == keys_dir + "host__some_path"
+
+    def test_sftp(self, monkeypatch, keys_dir):
+        monkeypatch.delenv("BORG_REPO", raising=False)
+        assert (
+            repr(Location("sftp://user@host:1234/some/path"))
+            == "Location(proto='sftp', user='user', host='host', port=1234, path='/some/path')"
+        )
+        assert (
+            repr(Location("sftp://host/./some/path"))
+            == "Location(proto='sftp', user=None, host='host', port=None, path='/./some/path')"
+        )
+        assert Location("sftp://user@host:1234/some/path").to_key_filename() == keys_dir + "host__some_path"
+        assert Location("sftp://host/~/some/path").canonical_path() == "sftp://host/~/some/path"
+
     def test_file(self, monkeypatch, keys_dir):
         monkeypatch.delenv("BORG_REPO", raising=False)
         assert (
@@ -289,6 +331,12 @@ def test_canonical_path(self, monkeypatch):
             "socket:///some/path",
             "ssh://host/some/path",
             "ssh://user@host:1234/some/path",
+            "s3://host:1234/bucket/some/path",
+            "s3+http://profile@host/bucket",
+            "sftp://user@host:1234/some/path",
+            "sftp://host/./some/path",
+            "https://host:8443/some/path",
+            "http://host/./some/path",
         ]
         for location in locations:
             assert (
@@ -909,6 +957,61 @@ def test_parse_file_size_invalid(string):
         parse_file_size(string)
 
 
+@pytest.mark.parametrize("string, value", [("none", None), ("zstd", ("zstd", 3)), ("zstd,19", ("zstd", 19))])
+def test_parse_rpc_compression(string, value):
+    assert parse_rpc_compression(string) == value
+
+
+@pytest.mark.parametrize("string", ("", "lz4", "zstd,0", "zstd,23", "zstd,x", "none,1"))
+def test_parse_rpc_compression_invalid(string):
+    with pytest.raises(ArgumentTypeError):
+        parse_rpc_compression(string)
+
+
+@pytest.mark.parametrize(
+    "string, value",
+    [
+        ("0", (0, ())),
+        ("100", (100, ())),
+        ("0,08:00-18:00=1000", (0, ((480, 1080, 1000),))),
+        ("500,22:00-06:00=0,12:00-13:00=10", (500, ((1320, 360, 0), (720, 780, 10)))),
+        ("0,18:00-24:00=1", (0, ((1080, 1440, 1),))),
+    ],
+)
+def test_parse_ratelimit(string, value):
+    assert parse_ratelimit(string) == value
+
+
+@pytest.mark.parametrize(
+    "string", ("", "-1", "1,", "1,08:00=5", "1,8-18=5", "1,08:00-25:00=5", "1,08:00-08:00=5", "1,08:00-18:00=-5")
+)
+def test_parse_ratelimit_invalid(string):
+    with pytest.raises(ArgumentTypeError):
+        parse_ratelimit(string)
+
+
+@pytest.mark.parametrize(
+    "string, value",
+    [("8080", ("localhost", 8080)), ("0.0.0.0:443", ("0.0.0.0", 443)), ("[::1]:0", ("::1", 0))],
+)
+def test_parse_listen_address(string, value):
+    assert parse_listen_address(string) == value
+
+
+@pytest.mark.parametrize("string", ("", "host", "host:", "host:65536", ":-1"))
+def test_parse_listen_address_invalid(string):
+    with pytest.raises(ArgumentTypeError):
+        parse_listen_address(string)
+
+
+def test_parse_serve_hook():
+    assert parse_serve_hook("commit=echo a=b") == ("commit", "echo a=b")
+    assert parse_serve_hook("quota-exceeded=true") == ("quota-exceeded", "true")
+    for string in "commit", "commit=", "commited=true", "=true":
+        with pytest.raises(ArgumentTypeError):
+            parse_serve_hook(string)
+
+
 def expected_py_mp_slow_combination():
     """do we expect msgpack to be slow in this environment?"""
     # we need to import upstream msgpack package here, not helpers.msgpack:
@@ -1127,6 +1230,24 @@ def test_progress_percentage_step(capfd):
     assert err == "  2%\n"
 
 
+def test_progress_percentage_interval(capfd, monkeypatch):
+    now = 0
+    monkeypatch.setattr(time, "monotonic", lambda: now)
+    pi = ProgressIndicatorPercent(1000, step=5, start=0, msg="%3.0f%%", interval=60)
+    pi.logger.setLevel("INFO")
+    pi.show()
+    out, err = capfd.readouterr()
+    assert err == "  0%\n"
+    now = 30
+    pi.show()
+    out, err = capfd.readouterr()
+    assert err == ""
+    now = 90
+    pi.show()
+    out, err = capfd.readouterr()
+    assert err == "  0%\n"  # no step reached, but a minute passed
+
+
 def test_progress_percentage_quiet(capfd):
     pi = ProgressIndicatorPercent(1000, step=5, start=0, msg="%3.0f%%")
     pi.logger.setLevel("WARN")
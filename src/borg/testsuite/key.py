@@ -6,7 +6,7 @@
 
 from ..crypto.key import PlaintextKey, AuthenticatedKey, Blake2AuthenticatedKey
 from ..crypto.key import RepoKey, KeyfileKey, Blake2RepoKey, Blake2KeyfileKey
-from ..crypto.key import AEADKeyBase
+from ..crypto.key import AEADKeyBase, AuthenticatedKeyBase
 from ..crypto.key import AESOCBRepoKey, AESOCBKeyfileKey, CHPORepoKey, CHPOKeyfileKey
 from ..crypto.key import Blake2AESOCBRepoKey, Blake2AESOCBKeyfileKey, Blake2CHPORepoKey, Blake2CHPOKeyfileKey
 from ..crypto.key import ID_HMAC_SHA_256, ID_BLAKE2b_256
@@ -232,6 +232,13 @@ def test_assert_id(self, key):
             with pytest.raises(IntegrityError):
                 key.assert_id(id, plaintext_changed)
 
+    def test_cache_encryption_key(self, key):
+        cache_key = key.cache_encryption_key()
+        if isinstance(key, (PlaintextKey, AuthenticatedKeyBase)):
+            assert cache_key is None
+        else:
+            assert len(cache_key) == 32 and cache_key != key.crypt_key[:32]
+
     def test_authenticated_encrypt(self, monkeypatch):
         monkeypatch.setenv("BORG_PASSPHRASE", "test")
         key = AuthenticatedKey.create(self.MockRepository(), self.MockArgs())
@@ -0,0 +1,94 @@
+import io
+import os
+
+import pytest
+
+from ..parity import ReedSolomon, SegmentParity, ParityError, SHARD_SIZE, gf_mul, gf_inv, write_parity
+
+
+def test_gf():
+    for a in range(1, 256):
+        assert gf_mul(a, gf_inv(a)) == 1
+        assert gf_mul(a, 1) == a
+        assert gf_mul(a, 0) == 0
+    assert gf_mul(2, 0x80) == 0x1D  # reduced by the polynomial
+
+
+@pytest.mark.parametrize("data_shards, parity_shards", [(1, 1), (4, 2), (8, 3), (250, 6)])
+def test_reed_solomon(data_shards, parity_shards):
+    rs = ReedSolomon(data_shards, parity_shards)
+    data = [os.urandom(64) for _ in range(data_shards)]
+    parity = rs.encode(data)
+    assert len(parity) == parity_shards
+    shards = data + parity
+    assert rs.reconstruct(shards) == data
+    # lose as many data shards as there are parity shards
+    for missing in ([0], list(range(parity_shards)), list(range(data_shards - 1, -1, -1))[:parity_shards]):
+        damaged = [None if i in missing else shard for i, shard in enumerate(shards)]
+        assert rs.reconstruct(damaged) == data
+    too_many = [None] * (parity_shards + 1) + shards[parity_shards + 1 :]
+    with pytest.raises(ValueError):
+        rs.reconstruct(too_many)
+
+
+def test_reed_solomon_invalid():
+    for data_shards, parity_shards in ((0, 1), (1, 0), (200, 57)):
+        with pytest.raises(ValueError):
+            ReedSolomon(data_shards, parity_shards)
+
+
+def make_parity(segment, data_shards=4, parity_shards=2):
+    parity = io.BytesIO()
+    write_parity(ReedSolomon(data_shards, parity_shards), io.BytesIO(segment), len(segment), parity)
+    parity.seek(0)
+    return SegmentParity(parity)
+
+
+def rebuild(parity, segment):
+    dst = io.BytesIO()
+    unrecoverable = parity.rebuild(io.BytesIO(segment), dst)
+    return dst.getvalue(), unrecoverable
+
+
+def test_segment_parity():
+    segment = os.urandom(10 * SHARD_SIZE + 123)  # 3 stripes, the last one padded
+    parity = make_parity(segment)
+    assert parity.verify(io.BytesIO(segment), len(segment)) == (0, 0, 0)
+    assert rebuild(parity, segment) == (segment, 0)
+    damaged = bytearray(segment)
+    damaged[100:110] = bytes(10)  # stripe 0, shard 0
+    damaged[SHARD_SIZE + 5] ^= 1  # stripe 0, shard 1
+    damaged[-1] ^= 0xFF  # stripe 2, shard 2
+    damaged = bytes(damaged)
+    assert parity.verify(io.BytesIO(damaged), len(damaged)) == (2, 0, 0)
+    assert rebuild(parity, damaged) == (segment, 0)
+    # a truncated segment is rebuilt, too
+    assert parity.verify(io.BytesIO(segment[:-50]), len(segment) - 50) == (1, 0, 0)
+    assert rebuild(parity, segment[:-50]) == (segment, 0)
+
+
+def test_segment_parity_unrecoverable():
+    segment = os.urandom(8 * SHARD_SIZE)  # 2 stripes
+    parity = make_parity(segment)
+    damaged = bytearray(segment)
+    for shard in (0, 1, 2, 4):
+        damaged[shard * SHARD_SIZE] ^= 1
+    damaged = bytes(damaged)
+    assert parity.verify(io.BytesIO(damaged), len(damaged)) == (2, 1, 0)
+    rebuilt, unrecoverable = rebuild(parity, damaged)
+    assert unrecoverable == 1
+    assert rebuilt[: 4 * SHARD_SIZE] == damaged[: 4 * SHARD_SIZE]  # left as it was
+    assert rebuilt[4 * SHARD_SIZE :] == segment[4 * SHARD_SIZE :]
+
+
+def test_segment_parity_damaged():
+    segment = os.urandom(4 * SHARD_SIZE)
+    parity = make_parity(segment)
+    data = bytearray(parity.fd.getvalue())
+    data[-1] ^= 1  # a parity shard
+    parity = SegmentParity(io.BytesIO(bytes(data)))
+    assert parity.verify(io.BytesIO(segment), len(segment)) == (0, 0, 1)
+    assert rebuild(parity, segment) == (segment, 0)
+    data[0] ^= 1  # the header
+    with pytest.raises(ParityError):
+        SegmentParity(io.BytesIO(bytes(data)))
@@ -6,11 +6,13 @@
 
 import pytest
 
+from ..compress import LZMA
 from ..constants import ROBJ_FILE_STREAM
-from ..remote import SleepingBandwidthLimiter, RepositoryCache, cache_if_remote
+from ..remote import SleepingBandwidthLimiter, RepositoryCache, cache_if_remote, RPCCompression, MetadataCache
+from ..remote import RATELIMIT_SCHEDULE_INTERVAL, MAX_RPC_FRAME_SIZE
 from ..repository import Repository
 from ..crypto.key import PlaintextKey
-from ..helpers import IntegrityError
+from ..helpers import IntegrityError, parse_ratelimit
 from ..repoobj import RepoObj
 from .hashindex import H
 from .repository import fchunk, pdchunk
@@ -70,6 +72,67 @@ def test_write(self, monkeypatch):
         self.expect_write(5, b"1")
         it.write(5, b"1")
 
+    def test_read(self, monkeypatch):
+        monkeypatch.setattr(os, "read", lambda fd, size: b"x" * size)
+        monkeypatch.setattr(time, "monotonic", lambda: now)
+        monkeypatch.setattr(time, "sleep", lambda x: None)
+
+        now = 100
+
+        it = SleepingBandwidthLimiter(100)
+        assert it.read(5, 4) == b"xxxx"
+        # only partial read
+        assert it.read(5, 10) == b"xxxxxx"
+        assert it.read(5, 10) == b"x" * 10
+
+    def test_schedule(self, monkeypatch):
+        monkeypatch.setattr(time, "monotonic", lambda: now)
+        monkeypatch.setattr(time, "localtime", lambda: time.struct_time((2024, 1, 1, hour, 30, 0, 0, 1, 0)))
+
+        now, hour = 100, 12
+        # 10 kiB/s from 08:00 to 18:00, 20 kiB/s from 22:00 to 06:00, unlimited otherwise
+        it = SleepingBandwidthLimiter.from_ratelimit(parse_ratelimit("0,08:00-18:00=10,22:00-06:00=20"))
+        assert it.ratelimit == 1024
+        assert it.quota(4096) == 1024
+
+        # the schedule is only checked every RATELIMIT_SCHEDULE_INTERVAL seconds
+        hour = 19
+        assert it.current_ratelimit() is None
+        now += 1
+        it.quota(0)
+        assert it.ratelimit == 1024
+        now += RATELIMIT_SCHEDULE_INTERVAL
+        assert it.quota(4096) == 4096
+        assert it.ratelimit is None
+
+        # wrapping around midnight
+        hour = 3
+        now += RATELIMIT_SCHEDULE_INTERVAL
+        assert it.quota(4096) == 2048
+
+
+def test_rpc_compression():
+    sender, receiver = RPCCompression(3), RPCCompression(3)
+    messages = [b"x" * 100000, os.urandom(1000), b""]
+    stream = b"".join(sender.compress(msg) for msg in messages)
+    # frames might be received in pieces
+    received = [receiver.decompress(stream[i : i + 777]) for i in range(0, len(stream), 777)]
+    assert b"".join(received) == b"".join(messages)
+    assert receiver.received == b""
+
+
+def test_rpc_compression_invalid():
+    with pytest.raises(ValueError):
+        RPCCompression(3).decompress(RPCCompression.header.pack(2**32 - 1, 0, 0))
+    # only zstd (or stored) frames
+    with pytest.raises(ValueError):
+        RPCCompression(3).decompress(RPCCompression.header.pack(3, LZMA.ID, 6) + b"foo")
+    # a small frame which would decompress to too much data
+    frame = RPCCompression(3).compress(bytes(MAX_RPC_FRAME_SIZE + 1))
+    assert len(frame) < 1024 * 1024
+    with pytest.raises(ValueError):
+        RPCCompression(3).decompress(frame)
+
 
 class TestRepositoryCache:
     @pytest.fixture
@@ -241,3 +304,61 @@ def test_cache_corruption(self, decrypted_cache: RepositoryCache, H1, H2, H3):
 
         with pytest.raises(IntegrityError):
             assert next(iterator) == (4, b"5678")
+
+
+class FetchCounter:
+    def __init__(self, objects):
+        self.objects = objects
+        self.fetched = []
+
+    def __call__(self, ids):
+        for id in ids:
+            self.fetched.append(id)
+            yield self.objects[id]
+
+
+def test_metadata_cache(tmpdir):
+    objects = {H(i): b"object %d" % i for i in range(4)}
+    fetch_many = FetchCounter(objects)
+    cache = MetadataCache(H(100), 1024 * 1024, path=str(tmpdir))
+    assert list(cache.get_many([H(0), H(1)], fetch_many)) == [objects[H(0)], objects[H(1)]]
+    assert fetch_many.fetched == [H(0), H(1)]
+    # a new cache instance (a later borg invocation) finds the cached objects, the result keeps the order
+    fetch_many.fetched.clear()
+    cache = MetadataCache(H(100), 1024 * 1024, path=str(tmpdir))
+    ids = [H(2), H(1), H(3), H(0), H(2)]
+    assert list(cache.get_many(ids, fetch_many)) == [objects[id] for id in ids]
+    assert fetch_many.fetched == [H(2), H(3)]  # H(2) only once
+    assert (cache.hits, cache.misses) == (3, 2)
+
+
+def test_metadata_cache_corruption(tmpdir):
+    objects = {H(0): b"foo", H(1): b"bar"}
+    fetch_many = FetchCounter(objects)
+    cache = MetadataCache(H(100), 1024 * 1024, path=str(tmpdir))
+    list(cache.get_many([H(0), H(1)], fetch_many))
+    with open(cache.object_path(H(1)), "r+b") as fd:
+        fd.seek(-1, io.SEEK_END)
+        fd.write(b"X")
+    fetch_many.fetched.clear()
+    # the corrupted object is discarded and fetched again
+    assert list(cache.get_many([H(0), H(1)], fetch_many)) == [b"foo", b"bar"]
+    assert fetch_many.fetched == [H(1)]
+    assert cache.corrupted == 1
+    assert cache.load(H(1)) == b"bar"
+
+
+def test_metadata_cache_eviction(tmpdir):
+    objects = {H(i): bytes(1000) for i in range(10)}
+    fetch_many = FetchCounter(objects)
+    cache = MetadataCache(H(100), 5000, path=str(tmpdir))
+    for i in range(10):
+        list(cache.get_many([H(i)], fetch_many))
+        os.utime(cache.object_path(H(i)), (i, i))
+        list(cache.get_many([H(0)], fetch_many))  # keep H(0) recently used
+        assert cache.size <= 5000
+    assert cache.evictions > 0
+    assert sum(size for _, size, _ in cache.scan()) == cache.size
+    assert cache.load(H(0)) is not None
+    assert cache.load(H(9)) is not None
+    assert cache.load(H(1)) is None
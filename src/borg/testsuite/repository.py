@@ -1,18 +1,29 @@
+import base64
+import errno
+import hashlib
+import json
 import logging
 import os
+import shutil
+import socket
+import subprocess
 import sys
+import time
+from contextlib import contextmanager
 from typing import Optional
 from unittest.mock import patch
 
 import pytest
 
-from ..hashindex import NSIndex
+from ..hashindex import NSIndex, RetentionIndex
 from ..helpers import Location
-from ..helpers import IntegrityError
+from ..helpers import Error, IntegrityError
 from ..helpers import msgpack
 from ..locking import Lock, LockFailed
 from ..platformflags import is_win32
-from ..remote import RemoteRepository, InvalidRPCMethod, PathNotAllowed
+from .. import remote
+from ..remote import RemoteRepository, RepositoryServer, InvalidRPCMethod, PathNotAllowed, ConnectionClosed
+from ..remote import PolicyDenied, ServePolicy, read_http_head, ssh_key_fingerprints, is_loopback
 from ..repository import Repository, LoggedIO, MAGIC, MAX_DATA_SIZE, TAG_DELETE, TAG_PUT2, TAG_PUT, TAG_COMMIT
 from ..repoobj import RepoObj
 from .hashindex import H
@@ -431,6 +442,26 @@ def test_crash_before_write_index(repository):
         assert repository.check() is True
 
 
+def test_half_done_replace(repository):
+    # like an object store crashing after copying the source of a replace, before deleting it (see Store.replace)
+    with repository:
+        store = repository.store
+        store.replace = lambda src, dst: store.store(dst, store.load(src))
+        add_keys(repository)
+        del store.replace
+        # the leftovers are deleted together with the files of the previous transaction
+        assert not [name for name in os.listdir(repository.path) if name.endswith(".tmp")]
+        # crashing before that leaves them around
+        transaction_id = repository.get_transaction_id()
+        for name in ("index.%d", "hints.%d", "integrity.%d"):
+            store.store(name % transaction_id + ".tmp", store.load(name % transaction_id))
+    with reopen(repository) as repository:
+        assert len(repository) == 3
+        repository.put(H(3), fchunk(b"data"))
+        repository.commit(compact=False)
+        check(repository, repository.path)
+
+
 def test_replay_lock_upgrade_old(repository):
     with repository:
         add_keys(repository)
@@ -953,6 +984,24 @@ def test_repair_corrupted_segment(repo_fixtures, request):
         assert {1, 2, 3, 4, 6} == list_objects(repository)
 
 
+def test_check_summary(repo_fixtures, request):
+    with get_repository_from_fixture(repo_fixtures, request) as repository:
+        repo_path = get_path(repository)
+        add_objects(repository, [[1, 2, 3], [4, 5], [6]])
+        check(repository, repo_path, status=True)
+        summary = repository.check_summary()
+        assert summary["ok"] and summary["mode"] == "full" and not summary["repair"]
+        assert summary["segments_checked"] == summary["segments"] > 0
+        assert list(summary["damaged_segments"]) == [] and summary["errors"] == 0
+        segment = open_index(repo_path)[H(5)][0]
+        corrupt_object(repo_path, 5)
+        check(repository, repo_path, status=False)
+        summary = repository.check_summary()
+        assert not summary["ok"]
+        assert list(summary["damaged_segments"]) == [segment]
+        assert summary["errors"] >= 1
+
+
 def test_repair_missing_segment(repository):
     # only test on local repo - files in RemoteRepository cannot be deleted
     with repository:
@@ -1030,6 +1079,68 @@ def test_repair_index_too_new(repo_fixtures, request):
         assert {1, 2, 3, 4, 5, 6} == list_objects(repository)
 
 
+def enable_parity(repository, parity_shards=2, data_shards=4):
+    repository.config.set("repository", "parity_shards", str(parity_shards))
+    repository.config.set("repository", "parity_data_shards", str(data_shards))
+    repository.save_config(repository.path, repository.config)
+
+
+def parity_path(repo_path, id_):
+    segment = open_index(repo_path)[H(id_)].segment
+    return os.path.join(repo_path, "data", "0", f"{segment}.parity")
+
+
+def test_repair_corrupted_segment_with_parity(repository):
+    with repository:
+        enable_parity(repository)
+    with reopen(repository) as repository:
+        repo_path = repository.path
+        add_objects(repository, [[1, 2, 3], [4, 5], [6]])
+        files = os.listdir(os.path.join(repo_path, "data", "0"))
+        assert sorted(n + ".parity" for n in files if n.isdigit()) == sorted(n for n in files if n.endswith(".parity"))
+        corrupt_object(repo_path, 5)
+        with pytest.raises(IntegrityError):
+            get_objects(repository, 5)
+        repository.rollback()
+        check(repository, repo_path, status=False)
+        # the repair rebuilds the segment, nothing is lost
+        check(repository, repo_path, repair=True, status=True)
+        check(repository, repo_path, status=True)
+        get_objects(repository, 4, 5)
+        assert {1, 2, 3, 4, 5, 6} == list_objects(repository)
+
+
+def test_scrub(repository):
+    with repository:
+        add_objects(repository, [[1, 2, 3], [4, 5], [6]])
+        assert repository.scrub()
+        assert not os.path.exists(parity_path(repository.path, 1))
+        enable_parity(repository)
+    with reopen(repository) as repository:
+        repo_path = repository.path
+        # creates the parity files of the existing segments
+        assert repository.scrub()
+        with open(parity_path(repo_path, 1), "rb") as fd:
+            parity = fd.read()
+        # rebuilds damaged segments
+        corrupt_object(repo_path, 5)
+        assert repository.scrub()
+        check(repository, repo_path, status=True)
+        get_objects(repository, 5)
+        # recreates damaged parity files
+        with open(parity_path(repo_path, 1), "r+b") as fd:
+            fd.seek(-1, os.SEEK_END)
+            fd.write(b"X")
+        assert repository.scrub()
+        with open(parity_path(repo_path, 1), "rb") as fd:
+            assert fd.read() == parity
+        # damage without parity data is left for check --repair
+        os.unlink(parity_path(repo_path, 6))
+        corrupt_object(repo_path, 6)
+        assert not repository.scrub()
+        check(repository, repo_path, status=False)
+
+
 def test_crash_before_compact(repository):
     # only test on local repo - we can't mock-patch a RemoteRepository class in another process!
     with repository:
@@ -1214,3 +1325,1112 @@ def test_remote_borg_cmd(remote_repository):
         args.rsh = "ssh -i foo"
         remote_repository._args = args
         assert remote_repository.ssh_cmd(Location("ssh://example.com/foo")) == ["ssh", "-i", "foo", "example.com"]
+
+
+def open_remote_repository(tmp_path):
+    if is_win32:
+        pytest.skip("Remote repository does not yet work on Windows.")
+    repository_location = Location("ssh://__testsuite__" + os.fspath(tmp_path / "repository"))
+    return RemoteRepository(repository_location, exclusive=True, create=True)
+
+
+def test_remote_streaming(tmp_path, monkeypatch):
+    # a window that only allows a few responses in flight
+    monkeypatch.setattr(remote, "RPC_WINDOW", 10000)
+    data = {H(i): os.urandom(1000 + i) for i in range(200)}
+    with open_remote_repository(tmp_path) as repository:
+        assert repository.protocol == remote.RPC_PROTOCOL
+        for id, chunk in data.items():
+            repository.put(id, fchunk(chunk), wait=False)
+        repository.commit(compact=False)
+        ids = list(data)
+        # preloaded responses are not consumed while other gets are streamed, this must not stall.
+        repository.preload(ids[:100])
+        assert [pdchunk(chunk) for chunk in repository.get_many(ids[100:])] == [data[id] for id in ids[100:]]
+        preloaded = repository.get_many(ids[:100], is_preloaded=True)
+        assert [pdchunk(chunk) for chunk in preloaded] == [data[id] for id in ids[:100]]
+        # a non-get request waits for the gets before it, even if they are not consumed yet.
+        outer = repository.get_many(ids)
+        assert pdchunk(next(outer)) == data[ids[0]]
+        assert len(repository) == len(ids)
+        assert [pdchunk(chunk) for chunk in outer] == [data[id] for id in ids[1:]]
+        for stream in repository.streams.values():
+            assert stream.outstanding == 0
+            assert stream.received == stream.consumed
+        assert not repository.stream_msgids and not repository.put_sizes and repository.put_unacked == 0
+
+
+def test_remote_rpc_compression(tmp_path, monkeypatch):
+    with open_remote_repository(tmp_path) as repository:
+        assert repository.rpc_compression is not None
+        repository.put(H(0), fchunk(b"foo" * 10000))
+        assert pdchunk(repository.get(H(0))) == b"foo" * 10000
+        repository.commit(compact=False)
+    # it can be switched off (--rpc-compression none)
+    monkeypatch.setattr(remote, "RPC_COMPRESSION", None)
+    with RemoteRepository(repository.location) as repository:
+        assert repository.rpc_compression is None
+        assert pdchunk(repository.get(H(0))) == b"foo" * 10000
+
+
+def test_remote_metadata_cache(tmp_path):
+    with open_remote_repository(tmp_path) as repository:
+        repository.put(H(0), fchunk(b"meta"))
+        repository.put(H(1), fchunk(b"data"))
+        repository.commit(compact=False)
+        repository.metadata_cache = remote.MetadataCache(repository.id, 1024 * 1024, path=str(tmp_path / "cache"))
+        assert pdchunk(repository.get(H(0), metadata=True)) == b"meta"
+        assert pdchunk(repository.get(H(1))) == b"data"
+        # only metadata objects are cached
+        assert repository.metadata_cache.load(H(0)) is not None
+        assert repository.metadata_cache.load(H(1)) is None
+        repository.delete(H(0))
+        repository.commit(compact=False)
+        # ... and served from the cache later on (they never change)
+        assert [pdchunk(chunk) for chunk in repository.get_many([H(0)], metadata=True)] == [b"meta"]
+        with pytest.raises(Repository.ObjectNotFound):
+            repository.get(H(0))
+
+
+def test_remote_audit_log(tmp_path):
+    audit_log = tmp_path / "audit.log"
+    with patch.object(RemoteRepository, "extra_test_args", ["--audit-log", os.fspath(audit_log)]):
+        with open_remote_repository(tmp_path) as repository:
+            repository.put(H(0), fchunk(b"foo"))
+            repository.put(H(1), fchunk(b"bar"))
+            repository.commit(compact=False)
+            repository.delete(H(0))
+            assert pdchunk(repository.get(H(1))) == b"bar"
+            repository.commit(compact=False)
+    allowed = os.fspath(tmp_path / "other")
+    with patch.object(
+        RemoteRepository, "extra_test_args", ["--audit-log", os.fspath(audit_log), "--restrict-to-path", allowed]
+    ):
+        with pytest.raises(PathNotAllowed):
+            RemoteRepository(repository.location)
+    entries = [json.loads(line) for line in audit_log.read_text().splitlines()]
+    first, second = ([e for e in entries if e["session"] == s] for s in dict.fromkeys(e["session"] for e in entries))
+    path = os.path.realpath(repository.location.path)
+    assert [e["event"] for e in first] == ["start", "open", "commit", "commit", "end"]
+    assert first[0]["pid"] and first[1]["path"] == path
+    assert first[2]["path"] == first[3]["path"] == path
+    end = first[-1]
+    assert end["paths"] == [path] and not end["disconnected"]
+    assert end["methods"]["put"]["objects"] == 2 and end["methods"]["put"]["bytes"] > 6
+    assert end["methods"]["delete"] == dict(calls=1, objects=1, bytes=0, errors=0)
+    assert end["methods"]["get"]["objects"] == 1
+    assert end["client_version"] == second[-1]["client_version"] is not None
+    assert [e["event"] for e in second] == ["start", "denied", "end"]
+    assert second[-1]["paths"] == [] and second[-1]["methods"]["open"]["errors"] == 1
+    denied = second[1]
+    assert denied["method"] == "open" and denied["reason"] == "PathNotAllowed"
+    assert denied["path"] == repository.location.path
+
+
+def test_remote_policy(tmp_path):
+    policy = tmp_path / "policy.ini"
+    repository_path = os.path.realpath(tmp_path / "repository")
+    policy.write_text(
+        f"""
+[DEFAULT]
+repositories = {repository_path}
+
+[alice]
+role = full
+
+[bob]
+"""
+    )
+
+    def client(identity, **kw):
+        args = ["--policy", os.fspath(policy), "--client-id", identity]
+        with patch.object(RemoteRepository, "extra_test_args", args):
+            return RemoteRepository(location, **kw)
+
+    with patch.object(RemoteRepository, "extra_test_args", ["--policy", os.fspath(policy), "--client-id", "alice"]):
+        with open_remote_repository(tmp_path) as repository:
+            repository.put(H(0), fchunk(b"foo"))
+            repository.commit(compact=False)
+    location = repository.location
+    with pytest.raises(PolicyDenied):
+        client("bob")  # read-only, needs to open without a lock
+    with client("bob", lock=False) as repository:
+        assert pdchunk(repository.get(H(0))) == b"foo"
+        with pytest.raises(PolicyDenied):
+            repository.put(H(1), fchunk(b"bar"))
+    with pytest.raises(PolicyDenied):
+        client("carol")  # unknown client
+    location = Location("ssh://__testsuite__" + os.fspath(tmp_path / "other"))
+    with pytest.raises(PathNotAllowed):
+        client("alice", create=True)
+
+
+def test_remote_policy_ssh_key(tmp_path, monkeypatch):
+    alice_key, bob_key = base64.b64encode(b"alice's key").decode(), base64.b64encode(b"bob's key").decode()
+    fingerprint = "SHA256:" + base64.b64encode(hashlib.sha256(b"alice's key").digest()).decode().rstrip("=")
+    policy = tmp_path / "policy.ini"
+    repository_path = os.path.realpath(tmp_path / "repository")
+    policy.write_text(f"[alice]\nrepositories = {repository_path}\nrole = full\nssh_keys = {fingerprint}\n")
+    # written by sshd (ExposeAuthInfo yes) for the session, see ssh_key_fingerprints
+    user_auth = tmp_path / "user_auth"
+    monkeypatch.setenv("SSH_USER_AUTH", os.fspath(user_auth))
+    user_auth.write_text(f"publickey ssh-ed25519 {alice_key}\n")
+    with patch.object(RemoteRepository, "extra_test_args", ["--policy", os.fspath(policy)]):
+        with open_remote_repository(tmp_path) as repository:
+            repository.put(H(0), fchunk(b"foo"))
+            repository.commit(compact=False)
+        location = repository.location
+        user_auth.write_text(f"publickey ssh-ed25519 {bob_key}\n")
+        with pytest.raises(PolicyDenied):
+            RemoteRepository(location)
+    # --client-id overrides the key
+    with patch.object(RemoteRepository, "extra_test_args", ["--policy", os.fspath(policy), "--client-id", "alice"]):
+        with RemoteRepository(location) as repository:
+            assert pdchunk(repository.get(H(0))) == b"foo"
+
+
+def test_serve_policy(tmp_path, monkeypatch):
+    policy = tmp_path / "policy.ini"
+    policy.write_text("[alice]\nrepositories = /srv/borg/alice*\nrole = append-only\nstorage_quota = 1G\n[bob]\n")
+    alice = ServePolicy(os.fspath(policy)).client("alice")
+    assert alice.storage_quota == 1000**3
+    assert alice.allows_repository("/srv/borg/alice") and not alice.allows_repository("/srv/borg/bob")
+    alice.check_call("put", {"id": H(0), "data": b""})
+    with pytest.raises(PolicyDenied):
+        alice.check_call("check", {"repair": True})
+    bob = ServePolicy(os.fspath(policy)).client("bob")
+    assert bob.role == "read-only" and not bob.allows_repository("/srv/borg/alice")
+    bob.check_call("open", {"path": "/srv/borg/bob", "lock": False})
+    for method, args in (
+        ("open", {"create": True, "lock": False}),
+        ("open", {"exclusive": True, "lock": False}),
+        ("open", {"path": "/srv/borg/bob"}),
+        ("commit", {}),
+        ("flags", {"id": H(0), "value": 1}),
+        ("inject_exception", {"kind": "divide"}),
+    ):
+        with pytest.raises(PolicyDenied):
+            bob.check_call(method, args)
+    assert ServePolicy(os.fspath(policy)).client("carol") is None
+    monkeypatch.delenv("SSH_USER_AUTH", raising=False)
+    with pytest.raises(Error):  # ssh clients need an explicit identity or a key identifying them
+        RepositoryServer(None, None, False, None, False, policy=os.fspath(policy))
+    RepositoryServer(None, None, False, None, False, policy=os.fspath(policy), client_id="alice")
+    policy.write_text("[alice]\nrole = admin\n")
+    with pytest.raises(Error):
+        ServePolicy(os.fspath(policy))
+    policy.write_text("[alice]\nssh_keys = SHA256:key1\n  SHA256:key2\n[bob]\nssh_keys = SHA256:key3\n")
+    assert ServePolicy(os.fspath(policy)).ssh_key_identity(["SHA256:key0", "SHA256:key2"]) == "alice"
+    assert ServePolicy(os.fspath(policy)).ssh_key_identity(["SHA256:key0"]) is None
+    policy.write_text("[alice]\nssh_keys = SHA256:key1\n[bob]\nssh_keys = SHA256:key1\n")
+    with pytest.raises(Error):  # a key must identify one client
+        ServePolicy(os.fspath(policy))
+
+
+def test_ssh_key_fingerprints(tmp_path):
+    user_auth = tmp_path / "user_auth"
+    key = base64.b64encode(b"key").decode()
+    user_auth.write_text(f"keyboard-interactive\npublickey ssh-ed25519 {key}\npublickey ssh-rsa %%%\n")
+    digest = base64.b64encode(hashlib.sha256(b"key").digest()).decode().rstrip("=")
+    assert ssh_key_fingerprints(user_auth) == ["SHA256:" + digest]
+
+
+def wait_for_hook(path, timeout=30):
+    """return the environment a (detached) hook command wrote to *path*"""
+    deadline = time.monotonic() + timeout
+    while not path.exists():
+        assert time.monotonic() < deadline, f"hook did not run: {path}"
+        time.sleep(0.1)
+    return dict(line.split("=", 1) for line in path.read_text().splitlines() if line.startswith("BORG_HOOK_"))
+
+
+def test_remote_hooks(tmp_path):
+    hook = 'env > {0}/$BORG_HOOK_EVENT.tmp && mv {0}/$BORG_HOOK_EVENT.tmp {0}/$BORG_HOOK_EVENT'.format(tmp_path)
+    args = ["--storage-quota", "10M"]
+    for event in "commit", "rollback", "quota-exceeded":
+        args += ["--hook", f"{event}={hook}"]
+    with patch.object(RemoteRepository, "extra_test_args", args):
+        with open_remote_repository(tmp_path) as repository:
+            repository.put(H(0), fchunk(b"foo"))
+            repository.put(H(1), fchunk(b"bar"))
+            repository.delete(H(1))
+            repository.commit(compact=False)
+            env = wait_for_hook(tmp_path / "commit")
+            assert env["BORG_HOOK_REPOSITORY"] == os.path.realpath(repository.location.path)
+            assert env["BORG_HOOK_OBJECTS_WRITTEN"] == "2" and env["BORG_HOOK_OBJECTS_DELETED"] == "1"
+            assert int(env["BORG_HOOK_BYTES_WRITTEN"]) > 6 and int(env["BORG_HOOK_QUOTA_USED"]) > 0
+            assert env["BORG_HOOK_QUOTA"] == str(10 * 1000**2) and env["BORG_HOOK_TRANSACTION_ID"].isdigit()
+            assert not (tmp_path / "rollback").exists()
+            with pytest.raises(RemoteRepository.RPCError) as excinfo:
+                repository.put(H(2), fchunk(os.urandom(11 * 1000**2)))
+            assert excinfo.value.exception_class == "StorageQuotaExceeded"
+            env = wait_for_hook(tmp_path / "quota-exceeded")
+            assert "quota" in env["BORG_HOOK_ERROR"]
+            with pytest.raises(RemoteRepository.RPCError):
+                repository.commit(compact=False)
+        env = wait_for_hook(tmp_path / "rollback")
+        assert env["BORG_HOOK_OBJECTS_WRITTEN"] == "0" and "quota" in env["BORG_HOOK_ERROR"]
+
+
+@contextmanager
+def http_server(*args):
+    """run borg serve --http on a free localhost port, yield its base URL"""
+    if is_win32:
+        pytest.skip("Remote repository does not yet work on Windows.")
+    cmd = [sys.executable, "-m", "borg", "serve", "--http", "127.0.0.1:0", *args]
+    p = subprocess.Popen(cmd, stderr=subprocess.PIPE, stdout=subprocess.DEVNULL, text=True)
+    try:
+        line = p.stderr.readline()
+        assert "listening on" in line, line
+        yield line.split("listening on ")[1].split()[0]
+    finally:
+        p.terminate()
+        p.wait()
+
+
+def make_cert(tmp_path, name, ca=None, ip=None):
+    """create a key and a certificate (signed by *ca* or self-signed) with CN *name*, return their paths"""
+    if shutil.which("openssl") is None:
+        pytest.skip("openssl is needed to create test certificates.")
+    key, cert = os.fspath(tmp_path / f"{name}.key"), os.fspath(tmp_path / f"{name}.pem")
+    newkey = ["-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:prime256v1", "-nodes", "-keyout", key]
+    if ca is None:
+        cmd = ["openssl", "req", "-x509", *newkey, "-out", cert, "-subj", f"/CN={name}", "-days", "1"]
+        subprocess.run(cmd, check=True, capture_output=True)
+        return key, cert
+    csr, ext = os.fspath(tmp_path / f"{name}.csr"), tmp_path / f"{name}.ext"
+    ext.write_text(f"subjectAltName=IP:{ip}\n" if ip else "basicConstraints=CA:FALSE\n")
+    subprocess.run(["openssl", "req", *newkey, "-out", csr, "-subj", f"/CN={name}"], check=True, capture_output=True)
+    cmd = ["openssl", "x509", "-req", "-in", csr, "-CA", ca[1], "-CAkey", ca[0], "-CAcreateserial", "-days", "1"]
+    subprocess.run([*cmd, "-out", cert, "-extfile", os.fspath(ext)], check=True, capture_output=True)
+    return key, cert
+
+
+def test_remote_http(tmp_path, monkeypatch):
+    tokens = tmp_path / "tokens"
+    tokens.write_text("# ID TOKEN\nalice s3cret\n")
+    with http_server("--http-tokens", os.fspath(tokens)) as url:
+        location = Location(url + os.fspath(tmp_path / "repository"))
+        monkeypatch.setenv("BORG_HTTP_TOKEN", "s3cret")
+        with RemoteRepository(location, exclusive=True, create=True) as repository:
+            repository.put(H(0), fchunk(b"foo"))
+            repository.commit(compact=False)
+        with RemoteRepository(location) as repository:
+            assert pdchunk(repository.get(H(0))) == b"foo"
+        monkeypatch.setenv("BORG_HTTP_TOKEN", "wrong")
+        with pytest.raises(Error) as excinfo:
+            RemoteRepository(location)
+        assert "401" in excinfo.value.args[0]
+
+
+def test_remote_http_slow_client(tmp_path, monkeypatch):
+    tokens = tmp_path / "tokens"
+    tokens.write_text("alice s3cret\n")
+    with http_server("--http-tokens", os.fspath(tokens)) as url:
+        host, port = url.split("://")[1].rstrip("/").rsplit(":", 1)
+        # a client that does not complete its handshake does not block the others
+        with socket.create_connection((host, int(port))) as slow:
+            slow.sendall(b"GET / HTTP/1.1\r\n")
+            monkeypatch.setenv("BORG_HTTP_TOKEN", "s3cret")
+            location = Location(url + os.fspath(tmp_path / "repository"))
+            with RemoteRepository(location, exclusive=True, create=True) as repository:
+                repository.put(H(0), fchunk(b"foo"))
+                repository.commit(compact=False)
+
+
+def test_read_http_head():
+    head = b"GET / HTTP/1.1\r\nUpgrade: borg-rpc\r\n\r\n"
+    for data, exception in (
+        (head, None),
+        (head[:-2], TimeoutError),  # incomplete
+        (head + b"rpc", ValueError),  # data after the head
+        (b"X: " + b"x" * 20000, ValueError),  # too large
+    ):
+        a, b = socket.socketpair()
+        with a, b:
+            a.sendall(data)
+            if exception is None:
+                start, headers = read_http_head(b, time.monotonic() + 10, exact=False)
+                assert start == "GET / HTTP/1.1" and headers == {"upgrade": "borg-rpc"}
+            else:
+                with pytest.raises(exception):
+                    read_http_head(b, time.monotonic() + 0.5, exact=False)
+
+
+def test_remote_https_client_cert(tmp_path, monkeypatch):
+    ca = make_cert(tmp_path, "ca")
+    server_key, server_cert = make_cert(tmp_path, "server", ca, ip="127.0.0.1")
+    client_key, client_cert = make_cert(tmp_path, "alice", ca)
+    policy = tmp_path / "policy.ini"
+    policy.write_text(f"[alice]\nrepositories = {os.path.realpath(tmp_path)}/*\nrole = full\n")
+    args = ["--tls-cert", server_cert, "--tls-key", server_key, "--tls-client-ca", ca[1], "--policy", os.fspath(policy)]
+    with http_server(*args) as url:
+        assert url.startswith("https://")
+        location = Location(url + os.fspath(tmp_path / "repository"))
+        monkeypatch.setenv("BORG_HTTP_CA_CERT", ca[1])
+        with pytest.raises(Error):
+            RemoteRepository(location, exclusive=True, create=True)  # no client certificate
+        monkeypatch.setenv("BORG_HTTP_CLIENT_CERT", client_cert)
+        monkeypatch.setenv("BORG_HTTP_CLIENT_KEY", client_key)
+        data = os.urandom(3 * 1024 * 1024)  # more than the TLS relay buffers at once
+        with RemoteRepository(location, exclusive=True, create=True) as repository:
+            repository.put(H(0), fchunk(data))
+            repository.commit(compact=False)
+            assert pdchunk(repository.get(H(0))) == data
+
+
+def test_remote_https_client_cert_and_token(tmp_path, monkeypatch):
+    ca = make_cert(tmp_path, "ca")
+    server_key, server_cert = make_cert(tmp_path, "server", ca, ip="127.0.0.1")
+    client_key, client_cert = make_cert(tmp_path, "alice", ca)
+    tokens = tmp_path / "tokens"
+    tokens.write_text("alice s3cret\nbob 0ther\n")
+    args = ["--tls-cert", server_cert, "--tls-key", server_key, "--tls-client-ca", ca[1], "--http-tokens", tokens]
+    with http_server(*map(os.fspath, args)) as url:
+        location = Location(url + os.fspath(tmp_path / "repository"))
+        monkeypatch.setenv("BORG_HTTP_CA_CERT", ca[1])
+        monkeypatch.setenv("BORG_HTTP_CLIENT_CERT", client_cert)
+        monkeypatch.setenv("BORG_HTTP_CLIENT_KEY", client_key)
+        monkeypatch.setenv("BORG_HTTP_TOKEN", "0ther")
+        with pytest.raises(Error) as excinfo:
+            RemoteRepository(location, exclusive=True, create=True)  # the token of another client
+        assert "401" in excinfo.value.args[0]
+        monkeypatch.setenv("BORG_HTTP_TOKEN", "s3cret")
+        with RemoteRepository(location, exclusive=True, create=True) as repository:
+            repository.put(H(0), fchunk(b"foo"))
+            repository.commit(compact=False)
+
+
+def test_serve_http_tokens_plain(tmp_path):
+    tokens = tmp_path / "tokens"
+    tokens.write_text("alice s3cret\n")
+
+    def server(host):
+        return RepositoryServer(None, None, False, None, False, http=(host, 0), http_tokens=os.fspath(tokens))
+
+    for host in ("localhost", "127.0.0.1", "::1"):
+        server(host)
+    for host in ("0.0.0.0", "192.0.2.1", "::"):
+        with pytest.raises(Error):  # tokens would be sent in plain text over the network
+            server(host)
+    assert is_loopback("127.0.0.2") and not is_loopback("192.0.2.1") and not is_loopback("")
+
+
+def test_remote_protocol_1(tmp_path, monkeypatch):
+    # clients that only talk protocol 1 (like old ones) are still supported
+    monkeypatch.setattr(remote, "RPC_PROTOCOL", 1)
+    with open_remote_repository(tmp_path) as repository:
+        assert repository.protocol == 1 and not repository.streams
+        repository.put(H(0), fchunk(b"foo"))
+        repository.preload([H(0)])
+        assert [pdchunk(chunk) for chunk in repository.get_many([H(0)], is_preloaded=True)] == [b"foo"]
+        assert pdchunk(repository.get(H(0))) == b"foo"
+
+
+def test_remote_queued_requests(monkeypatch):
+    # a client which sends more requests than it may and gives no credit for the responses is disconnected
+    monkeypatch.setattr(remote, "MAX_QUEUED_REQUESTS", 10)
+    client_data = {"client_version": remote.BORG_VERSION, "rpc_protocol": 2, "window": 0}
+    requests = [{remote.MSGID: 1, remote.MSG: "negotiate", remote.ARGS: {"client_data": client_data}}]
+    for i in range(2, 20):
+        requests.append({remote.MSGID: i, remote.MSG: "get", remote.ARGS: {"id": H(i)}, remote.CHANNEL: 1})
+    stdin_r, stdin_w = os.pipe()
+    stdout_r, stdout_w = os.pipe()
+    os.write(stdin_w, b"".join(msgpack.packb(request) for request in requests))
+    server = RepositoryServer(
+        restrict_to_paths=(), restrict_to_repositories=(), append_only=False, storage_quota=None, use_socket=False
+    )
+    with open(stdin_r, "rb") as stdin, open(stdout_w, "wb") as stdout:
+        with patch.object(sys, "stdin", stdin), patch.object(sys, "stdout", stdout):
+            with pytest.raises(remote.UnexpectedRPCDataFormatFromClient):
+                server.serve()
+    os.close(stdin_w)
+    os.close(stdout_r)
+
+
+def test_concurrent_writers(repository):
+    with repository:
+        repository.put(H(0), fchunk(b"foo"))
+        repository.put(H(1), fchunk(b"bar"))
+        repository.commit(compact=False)
+    path = repository.path
+    with Repository(path, exclusive=True, concurrent=True) as writer1:
+        with Repository(path, exclusive=True, concurrent=True) as writer2:
+            assert not writer1.lock.got_exclusive_lock()
+            writer1.put(H(2), fchunk(b"one"))
+            writer1.delete(H(0))
+            writer2.put(H(3), fchunk(b"two"))
+            writer2.put(H(1), fchunk(b"bar2"))
+            # writers only see their own changes and what is committed
+            assert pdchunk(writer1.get(H(2))) == b"one"
+            assert H(2) in writer1 and H(0) not in writer1
+            with pytest.raises(Repository.ObjectNotFound):
+                writer1.get(H(0))
+            with pytest.raises(Repository.ObjectNotFound):
+                writer1.get(H(3))
+            assert pdchunk(writer2.get(H(0))) == b"foo"
+            assert pdchunk(writer2.get(H(1))) == b"bar2"
+            writer1.commit(compact=False)
+            assert pdchunk(writer1.get(H(1))) == b"bar"
+            with pytest.raises(Repository.ObjectNotFound):
+                writer2.delete(H(4))
+            writer2.commit(compact=False)
+            with pytest.raises(Repository.ObjectNotFound):
+                writer1.get(H(3))  # writer1 still has the index of its own commit
+            writer1.begin_commit()  # now, it gets the most recent state
+            assert pdchunk(writer1.get(H(3))) == b"two"
+            writer1.rollback()
+    with reopen(repository) as repository:
+        assert len(repository) == 3
+        assert pdchunk(repository.get(H(1))) == b"bar2"
+        assert pdchunk(repository.get(H(2))) == b"one"
+        assert pdchunk(repository.get(H(3))) == b"two"
+        assert not list(repository.store.list("staging"))
+        assert repository.check()
+
+
+def test_concurrent_writers_readded_object(repository):
+    with repository:
+        repository.put(H(0), fchunk(b"foo"))
+        repository.commit(compact=False)
+    path = repository.path
+    with Repository(path, exclusive=True, concurrent=True) as writer1:
+        writer1.delete(H(0))
+        with Repository(path, exclusive=True, concurrent=True) as writer2:
+            # writer2 does not know about the delete and puts the object again
+            writer2.put(H(0), fchunk(b"foo"))
+            writer2.commit(compact=False)
+        writer1.commit(compact=False)
+    with reopen(repository) as repository:
+        assert pdchunk(repository.get(H(0))) == b"foo"
+        assert repository.check()
+        # replaying the segments must give the same result
+        repository.store.delete("index.%d" % repository.get_transaction_id())
+    with reopen(repository) as repository:
+        assert pdchunk(repository.get(H(0))) == b"foo"
+
+
+def test_concurrent_writer_rollback(repository):
+    with repository:
+        repository.put(H(0), fchunk(b"foo"))
+        repository.commit(compact=False)
+    with Repository(repository.path, exclusive=True, concurrent=True) as writer:
+        writer.put(H(1), fchunk(b"bar"))
+        writer.rollback()
+        writer.put(H(2), fchunk(b"baz"))
+    with reopen(repository) as repository:
+        assert len(repository) == 1
+        assert not list(repository.store.list("staging"))
+
+
+def test_concurrent_writer_exceed_quota(repository):
+    ch1, ch2 = fchunk(b"x" * 7), fchunk(b"y" * 13)
+    with repository:
+        repository.put(H(1), ch1)
+        repository.commit(compact=False)
+    with Repository(repository.path, exclusive=True, concurrent=True) as writer:
+        writer.storage_quota = 80
+        with pytest.raises(Repository.StorageQuotaExceeded):
+            writer.put(H(2), ch2)
+        assert writer.storage_quota_use == len(ch1) + len(ch2) + (41 + 8) * 2
+        with pytest.raises(Repository.StorageQuotaExceeded):
+            writer.commit(compact=False)
+    with reopen(repository) as repository:
+        assert len(repository) == 1
+        assert not list(repository.store.list("staging"))
+
+
+def test_concurrent_writer_leftovers(repository):
+    with repository:
+        repository.put(H(0), fchunk(b"foo"))
+        repository.commit(compact=False)
+        # a concurrent writer crashed before committing
+        repository.store.makedirs("staging/0123456789abcdef/0")
+        repository.store.store("staging/0123456789abcdef/0/0", MAGIC)
+        repository.put(H(1), fchunk(b"bar"))
+        repository.commit(compact=False)
+        assert "staging" not in [entry.name for entry in repository.store.list()]
+
+
+def test_concurrent_writers_manifest(repository):
+    from ..crypto.key import PlaintextKey
+    from ..manifest import Manifest
+
+    with repository:
+        key = PlaintextKey(repository)
+        manifest = Manifest(key, repository)
+        manifest.archives["old"] = (H(10), "2024-01-01T00:00:00.000000")
+        manifest.archives["older"] = (H(11), "2023-01-01T00:00:00.000000")
+        manifest.write()
+        repository.commit(compact=False)
+    path = repository.path
+    with Repository(path, exclusive=True, concurrent=True) as writer1:
+        with Repository(path, exclusive=True, concurrent=True) as writer2:
+            manifest1 = Manifest.load(writer1, Manifest.NO_OPERATION_CHECK, key=key)
+            manifest2 = Manifest.load(writer2, Manifest.NO_OPERATION_CHECK, key=key)
+            manifest1.archives["one"] = (H(1), "2024-02-01T00:00:00.000000")
+            del manifest1.archives["older"]
+            manifest1.write()
+            writer1.commit(compact=False)
+            assert not manifest1.foreign_changes
+            manifest2.archives["two"] = (H(2), "2024-02-01T00:00:00.000000")
+            manifest2.write()
+            writer2.commit(compact=False)
+            assert manifest2.foreign_changes
+            assert sorted(manifest2.archives) == ["old", "one", "two"]
+            manifest1.archives["two"] = (H(3), "2024-03-01T00:00:00.000000")
+            from ..archive import Archive
+
+            with pytest.raises(Archive.AlreadyExists):
+                manifest1.write()
+    with reopen(repository) as repository:
+        manifest = Manifest.load(repository, Manifest.NO_OPERATION_CHECK, key=key)
+        assert sorted(manifest.archives) == ["old", "one", "two"]
+        assert manifest.archives["two"].id == H(2)
+
+
+def test_remote_concurrent_writers(remote_repository):
+    with remote_repository:
+        remote_repository.put(H(0), fchunk(b"foo"))
+        remote_repository.commit(compact=False)
+    location = remote_repository.location
+    with RemoteRepository(location, exclusive=True, concurrent=True) as writer1:
+        with RemoteRepository(location, exclusive=True, concurrent=True) as writer2:
+            writer1.put(H(1), fchunk(b"one"))
+            writer2.put(H(2), fchunk(b"two"))
+            writer1.commit(compact=False)
+            writer2.begin_commit()
+            assert pdchunk(writer2.get(H(1))) == b"one"
+            writer2.commit(compact=False)
+    with reopen(remote_repository) as repository:
+        assert len(repository) == 3
+
+
+def test_resume_session(repository):
+    with repository:
+        repository.put(H(0), fchunk(b"foo"))
+        repository.put(H(1), fchunk(b"bar"))
+        repository.commit(compact=False)
+    session = "0123456789abcdef"
+    with Repository(repository.path, concurrent=True, session=session) as writer:
+        writer.put(H(2), fchunk(b"new"))
+        writer.put(H(1), fchunk(b"bar2"))
+        writer.delete(H(0))
+        assert writer.suspend()
+    # the transaction of the session is still in progress for others
+    with Repository(repository.path, concurrent=True) as other:
+        assert other._other_writers()
+    with Repository(repository.path, concurrent=True, session=session) as writer:
+        writer.resume()
+        assert pdchunk(writer.get(H(2))) == b"new"
+        assert pdchunk(writer.get(H(1))) == b"bar2"
+        assert H(0) not in writer
+        writer.put(H(3), fchunk(b"more"))
+        writer.commit(compact=False)
+    with reopen(repository) as repository:
+        assert H(0) not in repository
+        assert [pdchunk(repository.get(H(i))) for i in (1, 2, 3)] == [b"bar2", b"new", b"more"]
+    with Repository(repository.path, concurrent=True, session=session) as writer:
+        with pytest.raises(Repository.SessionNotResumable):
+            writer.resume()
+
+
+def drop_connection(repository):
+    # borg serve gets EOF on stdin like when the ssh connection drops, the client notices when it gets EOF, too.
+    with open(os.devnull, "wb") as devnull:
+        os.dup2(devnull.fileno(), repository.stdin_fd)
+
+
+def test_remote_reconnect(remote_repository):
+    with remote_repository:
+        remote_repository.put(H(0), fchunk(b"foo"))
+        remote_repository.commit(compact=False)
+        pid = remote_repository.p.pid
+        drop_connection(remote_repository)
+        assert pdchunk(remote_repository.get(H(0))) == b"foo"
+        assert remote_repository.p.pid != pid
+
+
+def test_remote_resume(remote_repository):
+    with remote_repository:
+        remote_repository.put(H(0), fchunk(b"foo"))
+        remote_repository.commit(compact=False)
+    with RemoteRepository(remote_repository.location, concurrent=True) as writer:
+        writer.put(H(1), fchunk(b"one"))
+        writer.delete(H(0))
+        drop_connection(writer)
+        writer.put(H(2), fchunk(b"two"))
+        assert pdchunk(writer.get(H(1))) == b"one"
+        writer.commit(compact=False)
+    with reopen(remote_repository) as repository:
+        assert len(repository) == 2
+        assert [pdchunk(repository.get(H(i))) for i in (1, 2)] == [b"one", b"two"]
+
+
+def test_remote_reconnect_unsafe(remote_repository):
+    # the uncommitted changes of a writer with the exclusive lock can not be resumed
+    with remote_repository:
+        remote_repository.put(H(0), fchunk(b"foo"))
+        drop_connection(remote_repository)
+        with pytest.raises(ConnectionClosed):
+            remote_repository.get(H(0))
+
+
+def mirror_repositories(repository, tmp_path, remote=False):
+    # the source repository and an empty target repository (local or via ssh://__testsuite__)
+    if remote:
+        location = Location("ssh://__testsuite__" + os.fspath(tmp_path / "mirror"))
+        target = RemoteRepository(location, exclusive=True, create=True)
+    else:
+        target = Repository(os.fspath(tmp_path / "mirror"), exclusive=True, create=True)
+    return repository, target
+
+
+@pytest.mark.parametrize("remote", [False, True])
+def test_mirror(repository, tmp_path, remote):
+    from ..mirror import mirror_repository
+
+    if remote and is_win32:
+        pytest.skip("Remote repository does not yet work on Windows.")
+    # mirror_write and mirror_commit need borg serve --allow-mirror
+    with patch.object(RemoteRepository, "extra_test_args", ["--allow-mirror"]):
+        source, target = mirror_repositories(repository, tmp_path, remote)
+        with source:
+            add_objects(source, [[1, 2, 3], [4, 5], [6]])
+            with target:
+                stats = mirror_repository(source, target)
+                assert stats["copied_segments"] > 0
+                assert stats["deleted_segments"] == 0
+                if not remote:  # the mirrored index is the index now
+                    assert target.index is not None and len(target.index) == len(source)
+                assert target.mirror_info()["id"] == source.id
+                assert target.mirror_info()["transaction_id"] == source.get_transaction_id()
+                assert sorted(target.list()) == sorted(source.list())
+                # nothing to do if it is up to date
+                stats = mirror_repository(source, target)
+                assert stats["copied_segments"] == stats["deleted_segments"] == 0
+            source.delete(H(1))
+            source.delete(H(4))
+            source.put(H(7), fchunk(b"seven"))
+            source.commit(compact=True)
+            with reopen(target) as target:
+                stats = mirror_repository(source, target, dry_run=True)
+                assert stats["copied_segments"] > 0 and stats["deleted_segments"] > 0
+                assert target.mirror_info()["transaction_id"] < source.get_transaction_id()
+                stats = mirror_repository(source, target)
+                assert target.mirror_info()["transaction_id"] == source.get_transaction_id()
+                assert sorted(target.list()) == sorted(source.list())
+                assert pdchunk(target.get(H(7))) == b"seven"
+                assert [tuple(s) for s in target.mirror_info()["segments"]] == source.mirror_info()["segments"]
+                assert target.check()
+        if not remote:
+            assert not os.path.exists(tmp_path / "mirror" / "staging")
+
+
+def test_mirror_not_a_mirror(repository, tmp_path):
+    from ..mirror import mirror_repository, NotAMirror
+
+    source, target = mirror_repositories(repository, tmp_path)
+    with source, target:
+        add_objects(source, [[1]])
+        add_objects(target, [[2]])
+        with pytest.raises(NotAMirror):
+            mirror_repository(source, target)
+        assert sorted(target.list()) == [H(2)]
+
+
+def test_mirror_not_allowed(repository, tmp_path):
+    from ..mirror import mirror_repository
+
+    if is_win32:
+        pytest.skip("Remote repository does not yet work on Windows.")
+    source, target = mirror_repositories(repository, tmp_path, remote=True)
+    with source, target:
+        add_objects(source, [[1]])
+        with pytest.raises(PolicyDenied):
+            mirror_repository(source, target)
+        assert target.mirror_info()["transaction_id"] is None
+
+
+def test_mirror_append_only(repository, tmp_path):
+    from ..mirror import mirror_repository
+
+    source, target = mirror_repositories(repository, tmp_path)
+    with source:
+        add_objects(source, [[1, 2], [3]])
+        with target:
+            mirror_repository(source, target)
+        source.delete(H(1))
+        source.commit(compact=True)
+        with Repository(target.path, exclusive=True, append_only=True) as target:
+            transaction_id = target.get_transaction_id()
+            with pytest.raises(ValueError):
+                mirror_repository(source, target)  # would delete the compacted segments
+            assert target.get_transaction_id() == transaction_id
+            assert H(1) in target
+
+
+def test_mirror_storage_quota(repository, tmp_path):
+    from ..mirror import mirror_repository
+
+    source, target = mirror_repositories(repository, tmp_path)
+    with source:
+        add_objects(source, [[1, 2], [3]])
+        source.put(H(4), fchunk(b"x" * 20000))
+        source.commit(compact=False)
+        with Repository(target.path, exclusive=True, create=True, storage_quota=10000) as target:
+            with pytest.raises(Repository.StorageQuotaExceeded):
+                mirror_repository(source, target)
+            assert target.get_transaction_id() is None
+
+
+def test_mirror_interrupted(repository, tmp_path):
+    from ..mirror import mirror_repository
+
+    source, target = mirror_repositories(repository, tmp_path)
+    with source:
+        add_objects(source, [[1, 2], [3]])
+        with target:
+            mirror_repository(source, target)
+            add_objects(source, [[4]])
+            transaction_id = target.get_transaction_id()
+            with patch.object(Repository, "mirror_commit", side_effect=KeyboardInterrupt):
+                with pytest.raises(KeyboardInterrupt):
+                    mirror_repository(source, target)
+        with reopen(target) as target:
+            assert target.get_transaction_id() == transaction_id
+            assert H(4) not in target
+            mirror_repository(source, target)
+            assert H(4) in target
+
+
+def test_retention_lock(repo_fixtures, request):
+    with get_repository_from_fixture(repo_fixtures, request) as repository:
+        add_objects(repository, [[1, 2, 3]])
+        until = int(time.time()) + 3600
+        repository.retention_lock([H(1), H(2)], until)
+        assert list(repository.get_retention([H(1), H(2), H(3)])) == [until, until, 0]
+        # locks can only be extended
+        repository.retention_lock([H(1)], until - 60)
+        repository.retention_lock([H(2)], until + 60)
+        assert list(repository.get_retention([H(1), H(2)])) == [until, until + 60]
+        with pytest.raises(Repository.RetentionLocked):
+            repository.delete(H(1))
+        # a PUT does not replace a locked object
+        repository.put(H(2), fchunk(b"other"))
+        repository.delete(H(3))
+        repository.commit(compact=True)
+        assert pdchunk(repository.get(H(2))) == b"data"
+        assert H(1) in repository.list() and H(3) not in repository.list()
+        with pytest.raises(Repository.RetentionLocked):
+            repository.destroy()
+        # expired locks do not lock anything
+        repository.retention_lock([H(4)], int(time.time()) - 1)
+        assert list(repository.get_retention([H(4)])) == [0]
+
+
+def test_retention_lock_concurrent(repository):
+    with repository:
+        add_objects(repository, [[1, 2]])
+    path = repository.path
+    with Repository(path, exclusive=True, concurrent=True) as writer1:
+        with Repository(path, exclusive=True, concurrent=True) as writer2:
+            writer1.delete(H(1))
+            writer2.put(H(3), fchunk(b"three"))
+            writer2.commit(compact=False)
+            # locked after writer1 deleted it, but before writer1 commits
+            writer2.retention_lock([H(1)], int(time.time()) + 3600)
+            with pytest.raises(Repository.RetentionLocked):
+                writer1.commit(compact=False)
+    with reopen(repository) as repository:
+        assert sorted(repository.list()) == sorted([H(1), H(2), H(3)])
+        assert pdchunk(repository.get(H(1))) == b"data"
+
+
+def test_retention_lock_batches(repository):
+    with repository:
+        add_objects(repository, [[1, 2, 3]])
+        until = int(time.time()) + 3600
+        repository.retention_lock([H(1)], until, flush=False)
+        repository.retention_lock([H(2)], until, flush=False)
+        assert not os.path.exists(os.path.join(repository.path, "retention"))
+        repository.retention_lock([H(3)], until)
+        assert list(repository.get_retention([H(1), H(2), H(3)])) == [until, until, until]
+        with pytest.raises(ValueError):
+            repository.retention_lock([H(1)], int(time.time()) + 1000 * 365 * 24 * 3600)
+
+
+def test_retention_file(repository):
+    with repository:
+        add_objects(repository, [[1, 2]])
+        until = int(time.time()) + 3600
+        repository.retention_lock([H(1)], int(time.time()) - 1)
+        repository.retention_lock([H(2)], until)
+        # an index of the locks, without the expired ones
+        locks = RetentionIndex.read(os.path.join(repository.path, "retention"))
+        assert len(locks) == 1
+        assert locks[H(2)] == until
+
+
+def test_mirror_retention_lock(repository, tmp_path):
+    from ..mirror import mirror_repository
+
+    source, target = mirror_repositories(repository, tmp_path)
+    with source:
+        add_objects(source, [[1, 2], [3]])
+        with target:
+            mirror_repository(source, target)
+            target.retention_lock([H(1)], int(time.time()) + 3600)
+        # compaction moves H(2), which is not a problem, but H(1) is gone
+        source.delete(H(1))
+        source.delete(H(3))
+        source.commit(compact=True)
+        with reopen(target) as target:
+            transaction_id = target.get_transaction_id()
+            with pytest.raises(Repository.RetentionLocked):
+                mirror_repository(source, target)
+            assert target.get_transaction_id() == transaction_id
+            assert pdchunk(target.get(H(1))) == b"data"
+        with reopen(target) as target:
+            target.store.delete("retention")  # lock H(2) instead of H(1)
+            target.retention_lock([H(2)], int(time.time()) + 3600)
+            mirror_repository(source, target)
+            assert sorted(target.list()) == [H(2)]
+
+
+def sparse_repository(repository):
+    with repository:
+        add_objects(repository, [[1, 2, 3], [4, 5, 6], [7, 8, 9]])
+        for id_ in (1, 2, 4, 5, 7, 8):
+            repository.delete(H(id_))
+        repository.commit(compact=False)
+        return len(list(repository.io.segment_iterator()))
+
+
+def test_compact_slice(repository):
+    segments_before = sparse_repository(repository)
+    path = repository.path
+    with Repository(path, exclusive=False) as reader:
+        assert pdchunk(reader.get(H(3))) == b"data"
+        with Repository(path, exclusive=True, concurrent=True) as compactor:
+            assert not compactor.lock.got_exclusive_lock()
+            # every slice compacts at least one segment
+            slices = 1
+            while compactor.compact_slice(0.1, max_duration=0):
+                slices += 1
+            assert slices > 1
+            assert not compactor.compact_slice(0.1)
+        # the reader loaded the index before, it gets the moved objects nevertheless
+        assert pdchunk(reader.get(H(6))) == b"data"
+        assert pdchunk(reader.get(H(9))) == b"data"
+    with reopen(repository) as repository:
+        assert len(list(repository.io.segment_iterator())) < segments_before
+        assert sorted(repository.list()) == sorted([H(3), H(6), H(9)])
+        check(repository, repository.path)
+
+
+def test_compact_slice_concurrent_writer(repository):
+    sparse_repository(repository)
+    path = repository.path
+    with Repository(path, exclusive=True, concurrent=True) as writer:
+        writer.delete(H(3))
+        with Repository(path, exclusive=True, concurrent=True) as compactor:
+            # it waits for the transaction of the writer
+            assert compactor.compact_slice(0.1)
+        writer.commit(compact=False)
+    # leftovers of crashed writers do not make it wait
+    with Repository(path, exclusive=True) as repository:
+        repository.store.makedirs("staging/crashed")
+        repository.store.store("staging/crashed/owner", b'["somehost@0", 12345, 0]')
+    with Repository(path, exclusive=True, concurrent=True) as compactor:
+        assert not compactor.compact_slice(0.1)
+        assert not compactor.compact_slice(0.1)
+    with reopen(repository) as repository:
+        assert sorted(repository.list()) == sorted([H(6), H(9)])
+        check(repository, repository.path)
+
+
+def test_server_auto_compact(repository):
+    segments_before = sparse_repository(repository)
+    server = RepositoryServer(None, None, False, None, False, auto_compact=0.1)
+    server.open(repository.path)
+    path = server.repository.path
+    server.repository.put(H(10), fchunk(b"data"))
+    server.commit(compact=False)
+    server.close()
+    assert server.auto_compact_paths == {path}
+    server.auto_compact()
+    assert not server.auto_compact_paths
+    with reopen(repository) as repository:
+        assert len(list(repository.io.segment_iterator())) < segments_before
+        assert sorted(repository.list()) == sorted([H(3), H(6), H(9), H(10)])
+
+
+def segments_in(root):
+    dirs = [os.path.join(root, dir) for dir in os.listdir(root) if dir.isdigit()]
+    return sorted(int(name) for dir in dirs for name in os.listdir(dir) if name.isdigit())
+
+
+def test_data_roots(repository, tmp_path):
+    root = str(tmp_path / "data2")
+    with repository:
+        add_objects(repository, [[1, 2]])
+        repository.add_data_root(root)
+        with pytest.raises(Error):
+            repository.add_data_root(root)
+        repository.config.set("repository", "data_placement", "round-robin")
+        repository.save_config(repository.path, repository.config)
+    with reopen(repository) as repository:
+        assert repository.data_roots == [root]
+        add_objects(repository, [[3], [4], [5, 6]])
+        new_segments = segments_in(root)
+        assert new_segments
+        assert set(new_segments).isdisjoint(segments_in(os.path.join(repository.path, "data")))
+        for id_ in range(1, 7):
+            assert pdchunk(repository.get(H(id_))) == b"data"
+        check(repository, repository.path)
+        repository.drain_data_root(root)
+        assert repository.data_roots == [] and repository.draining_data_roots == []
+        assert not os.path.exists(root)
+        assert set(new_segments) <= set(segments_in(os.path.join(repository.path, "data")))
+    with reopen(repository) as repository:
+        for id_ in range(1, 7):
+            assert pdchunk(repository.get(H(id_))) == b"data"
+        check(repository, repository.path)
+
+
+def test_data_roots_concurrent_writer(repository, tmp_path):
+    root = str(tmp_path / "data2")
+    with repository:
+        add_objects(repository, [[1]])
+        repository.add_data_root(root)
+        repository.config.set("repository", "data_placement", "round-robin")
+        repository.save_config(repository.path, repository.config)
+        # the data root is on the same file system, its free space must not be counted twice
+        assert repository._free_space() == repository.store.free_space()
+    with Repository(repository.path, exclusive=True, concurrent=True) as writer:
+        for id_ in range(2, 6):
+            writer.put(H(id_), fchunk(b"data" * 1000))
+            writer.staging.close_segment()  # one segment per object
+        writer.commit(compact=False)
+        assert segments_in(root)
+    with reopen(repository) as repository:
+        for id_ in range(2, 6):
+            assert pdchunk(repository.get(H(id_))) == b"data" * 1000
+        check(repository, repository.path)
+
+
+def test_data_roots_other_file_system(repository, tmp_path, monkeypatch):
+    root = str(tmp_path / "data2")
+    os_replace = os.replace
+
+    def replace(src, dst):
+        # the data root is on another file system
+        if str(src).startswith(root + os.sep) != str(dst).startswith(root + os.sep):
+            raise OSError(errno.EXDEV, os.strerror(errno.EXDEV))
+        os_replace(src, dst)
+
+    with repository:
+        add_objects(repository, [[1]])
+        repository.add_data_root(root)
+        repository.config.set("repository", "data_placement", "round-robin")
+        repository.save_config(repository.path, repository.config)
+    monkeypatch.setattr(os, "replace", replace)
+    with Repository(repository.path, exclusive=True, concurrent=True) as writer:
+        for id_ in range(2, 6):
+            writer.put(H(id_), fchunk(b"data" * 1000))
+            writer.staging.close_segment()  # one segment per object
+        writer.commit(compact=False)
+        assert segments_in(root)
+    with reopen(repository) as repository:
+        for id_ in range(2, 6):
+            assert pdchunk(repository.get(H(id_))) == b"data" * 1000
+        check(repository, repository.path)
+
+
+def test_data_root_unavailable(repository, tmp_path):
+    root = str(tmp_path / "data2")
+    with repository:
+        repository.add_data_root(root)
+        add_objects(repository, [[1]])
+    os.rename(root, root + ".unmounted")
+    with pytest.raises(Repository.DataRootUnavailable):
+        with reopen(repository):
+            pass
+    os.rename(root + ".unmounted", root)
+    with reopen(repository) as repository:
+        assert pdchunk(repository.get(H(1))) == b"data"
+
+
+def use_mapped_index(repository):
+    with repository:
+        repository.config.set("repository", "index_mode", "mapped")
+        repository.save_config(repository.path, repository.config)
+    return reopen(repository)
+
+
+def index_files(repo_path):
+    return sorted(name for name in os.listdir(repo_path) if name.startswith("index."))
+
+
+def test_mapped_index(repository):
+    with use_mapped_index(repository) as repository:
+        add_objects(repository, [[1, 2], [3]])
+        repository.delete(H(1))
+        repository.put(H(4), fchunk(b"data"))
+        journal = "index.mapped.journal.%d" % repository.get_transaction_id()
+        assert index_files(repository.path) == ["index.mapped", journal]
+        repository.rollback()
+        assert index_files(repository.path) == ["index.%d" % repository.get_transaction_id()]
+        repository.delete(H(2))
+        repository.commit(compact=True)
+    with reopen(repository) as repository:
+        assert repository.index_mode == "mapped"
+        assert len(repository) == 2
+        get_objects(repository, 1, 3)
+        with pytest.raises(Repository.ObjectNotFound):
+            repository.get(H(2))
+        with pytest.raises(Repository.ObjectNotFound):
+            repository.get(H(4))
+        check(repository, repository.path)
+
+
+def test_mapped_index_crash(repository, tmp_path, monkeypatch):
+    crashed_path = str(tmp_path / "crashed")
+    with use_mapped_index(repository) as repository:
+        add_objects(repository, [[1, 2]])
+        transaction_id = repository.get_transaction_id()
+        repository.put(H(3), fchunk(b"data"))
+        repository.delete(H(1))
+        assert index_files(repository.path) == ["index.mapped", "index.mapped.journal.%d" % transaction_id]
+        # a copy of the repository in the middle of a transaction is like a crash.
+        shutil.copytree(repository.path, crashed_path, ignore=shutil.ignore_patterns("lock*"))
+    replayed = []
+    replay_segments = Repository.replay_segments
+    monkeypatch.setattr(
+        Repository,
+        "replay_segments",
+        lambda self, index_transaction_id, segments_transaction_id: (
+            replayed.append(index_transaction_id),
+            replay_segments(self, index_transaction_id, segments_transaction_id),
+        ),
+    )
+    with Repository(crashed_path, exclusive=True) as repository:
+        assert len(repository) == 2
+        # the journal was undone, instead of rebuilding the index from all segments
+        assert None not in replayed
+        assert index_files(crashed_path) == ["index.%d" % transaction_id]
+        get_objects(repository, 1, 2)
+        repository.put(H(3), fchunk(b"data"))
+        repository.commit(compact=False)
+        assert "index.mapped" not in index_files(crashed_path)
+        check(repository, crashed_path)
+
+
+def test_invalid_index_mode(repository):
+    with repository:
+        repository.config.set("repository", "index_mode", "mmap")
+        repository.save_config(repository.path, repository.config)
+    with pytest.raises(Repository.InvalidRepositoryConfig):
+        with reopen(repository):
+            pass
@@ -0,0 +1,180 @@
+"""
+A minimal SFTP (version 3) server, serving the local filesystem via stdin/stdout.
+
+This is a stand-in for OpenSSH's sftp-server, used by the tests (see sftp_store_for() in storage.py),
+so they do not need sshd. It supports what SFTPStore uses, including the posix-rename, fsync and
+statvfs extensions. Run it with: python -m borg.testsuite.sftp_server
+"""
+
+import errno
+import os
+import stat
+import struct
+import sys
+
+from ..storage.sftp import Reader, pack_str, SFTP_VERSION, EXT_POSIX_RENAME, EXT_FSYNC, EXT_STATVFS
+from ..storage.sftp import FXP_INIT, FXP_VERSION, FXP_OPEN, FXP_CLOSE, FXP_READ, FXP_WRITE, FXP_LSTAT, FXP_FSTAT
+from ..storage.sftp import FXP_OPENDIR, FXP_READDIR, FXP_REMOVE, FXP_MKDIR, FXP_RMDIR, FXP_STAT, FXP_RENAME
+from ..storage.sftp import FXP_STATUS, FXP_HANDLE, FXP_DATA, FXP_NAME, FXP_ATTRS, FXP_EXTENDED, FXP_EXTENDED_REPLY
+from ..storage.sftp import FXF_READ, FXF_WRITE, FXF_APPEND, FXF_CREAT, FXF_TRUNC, FXF_EXCL
+from ..storage.sftp import FILEXFER_ATTR_SIZE, FILEXFER_ATTR_PERMISSIONS, FILEXFER_ATTR_ACMODTIME
+from ..storage.sftp import FX_OK, FX_EOF, FX_NO_SUCH_FILE, FX_PERMISSION_DENIED, FX_FAILURE, FX_OP_UNSUPPORTED
+
+# like OpenSSH's sftp-server, everything else is a generic failure (e.g. EEXIST, ENOTEMPTY).
+ERRNO_STATUS = {errno.ENOENT: FX_NO_SUCH_FILE, errno.EACCES: FX_PERMISSION_DENIED, errno.EPERM: FX_PERMISSION_DENIED}
+
+FX_BAD_MESSAGE = 5
+
+
+def pack_attrs(st):
+    flags = FILEXFER_ATTR_SIZE | FILEXFER_ATTR_PERMISSIONS | FILEXFER_ATTR_ACMODTIME
+    return struct.pack(">IQIII", flags, st.st_size, st.st_mode, int(st.st_atime), int(st.st_mtime))
+
+
+class SFTPServer:
+    def __init__(self, stdin, stdout):
+        self.stdin = stdin
+        self.stdout = stdout
+        self.handles = {}  # handle -> fd (int) or list of remaining directory entries
+        self.next_handle = 0
+
+    def send(self, ptype, payload):
+        self.stdout.write(struct.pack(">IB", len(payload) + 1, ptype) + payload)
+        self.stdout.flush()
+
+    def recv(self):
+        header = self.stdin.read(4)
+        if len(header) < 4:
+            return None, None
+        (length,) = struct.unpack(">I", header)
+        data = self.stdin.read(length)
+        return data[0], Reader(data[1:])
+
+    def status(self, req_id, code, message=""):
+        payload = struct.pack(">II", req_id, code) + pack_str(message) + pack_str("")
+        self.send(FXP_STATUS, payload)
+
+    def new_handle(self, obj):
+        self.next_handle += 1
+        handle = b"%d" % self.next_handle
+        self.handles[handle] = obj
+        return handle
+
+    def serve(self):
+        ptype, r = self.recv()
+        if ptype != FXP_INIT:
+            return
+        extensions = b"".join(pack_str(name) + pack_str("1") for name in (EXT_POSIX_RENAME, EXT_FSYNC, EXT_STATVFS))
+        self.send(FXP_VERSION, struct.pack(">I", SFTP_VERSION) + extensions)
+        while True:
+            ptype, r = self.recv()
+            if ptype is None:
+                break
+            req_id = r.u32()
+            try:
+                self.handle_request(ptype, req_id, r)
+            except OSError as e:
+                self.status(req_id, ERRNO_STATUS.get(e.errno, FX_FAILURE), e.strerror or "")
+            except (KeyError, struct.error):
+                self.status(req_id, FX_BAD_MESSAGE, "bad message")
+        for obj in self.handles.values():
+            if isinstance(obj, int):
+                os.close(obj)
+
+    def handle_request(self, ptype, req_id, r):
+        if ptype == FXP_OPEN:
+            path, pflags = r.str(), r.u32()
+            r.attrs()
+            flags = {FXF_READ: os.O_RDONLY, FXF_WRITE: os.O_WRONLY, FXF_READ | FXF_WRITE: os.O_RDWR}[pflags & 3]
+            for fxf, o in ((FXF_APPEND, os.O_APPEND), (FXF_CREAT, os.O_CREAT), (FXF_TRUNC, os.O_TRUNC)):
+                if pflags & fxf:
+                    flags |= o
+            if pflags & FXF_EXCL:
+                flags |= os.O_EXCL
+            fd = os.open(path, flags | getattr(os, "O_BINARY", 0), 0o666)
+            self.send(FXP_HANDLE, struct.pack(">I", req_id) + pack_str(self.new_handle(fd)))
+        elif ptype == FXP_CLOSE:
+            obj = self.handles.pop(r.bytes())
+            if isinstance(obj, int):
+                os.close(obj)
+            self.status(req_id, FX_OK)
+        elif ptype == FXP_READ:
+            fd = self.handles[r.bytes()]
+            offset, length = r.u64(), r.u32()
+            data = os.pread(fd, length, offset)
+            if data:
+                self.send(FXP_DATA, struct.pack(">I", req_id) + pack_str(data))
+            else:
+                self.status(req_id, FX_EOF, "EOF")
+        elif ptype == FXP_WRITE:
+            fd = self.handles[r.bytes()]
+            offset, data = r.u64(), r.bytes()
+            os.pwrite(fd, data, offset)
+            self.status(req_id, FX_OK)
+        elif ptype in (FXP_STAT, FXP_LSTAT, FXP_FSTAT):
+            if ptype == FXP_FSTAT:
+                st = os.fstat(self.handles[r.bytes()])
+            else:
+                st = (os.stat if ptype == FXP_STAT else os.lstat)(r.str())
+            self.send(FXP_ATTRS, struct.pack(">I", req_id) + pack_attrs(st))
+        elif ptype == FXP_OPENDIR:
+            path = r.str()
+            entries = [(".", os.stat(path)), ("..", os.stat(os.path.join(path, "..")))]
+            with os.scandir(path) as it:
+                entries += [(entry.name, entry.stat(follow_symlinks=False)) for entry in it]
+            self.send(FXP_HANDLE, struct.pack(">I", req_id) + pack_str(self.new_handle(entries)))
+        elif ptype == FXP_READDIR:
+            entries = self.handles[r.bytes()]
+            if not entries:
+                self.status(req_id, FX_EOF, "EOF")
+                return
+            batch, entries[:] = entries[:100], entries[100:]
+            payload = struct.pack(">II", req_id, len(batch))
+            for name, st in batch:
+                payload += pack_str(name) + pack_str(name) + pack_attrs(st)
+            self.send(FXP_NAME, payload)
+        elif ptype == FXP_REMOVE:
+            path = r.str()
+            if stat.S_ISDIR(os.lstat(path).st_mode):
+                raise OSError(errno.EISDIR, os.strerror(errno.EISDIR))
+            os.unlink(path)
+            self.status(req_id, FX_OK)
+        elif ptype == FXP_MKDIR:
+            path = r.str()
+            r.attrs()
+            os.mkdir(path)
+            self.status(req_id, FX_OK)
+        elif ptype == FXP_RMDIR:
+            os.rmdir(r.str())
+            self.status(req_id, FX_OK)
+        elif ptype == FXP_RENAME:
+            src, dst = r.str(), r.str()
+            if os.path.lexists(dst):
+                raise OSError(errno.EEXIST, os.strerror(errno.EEXIST))
+            os.rename(src, dst)
+            self.status(req_id, FX_OK)
+        elif ptype == FXP_EXTENDED:
+            name = r.str()
+            if name == EXT_POSIX_RENAME:
+                os.replace(r.str(), r.str())
+                self.status(req_id, FX_OK)
+            elif name == EXT_FSYNC:
+                os.fsync(self.handles[r.bytes()])
+                self.status(req_id, FX_OK)
+            elif name == EXT_STATVFS:
+                st = os.statvfs(r.str())
+                fields = (st.f_bsize, st.f_frsize, st.f_blocks, st.f_bfree, st.f_bavail)
+                fields += (st.f_files, st.f_ffree, st.f_favail, 0, st.f_flag, st.f_namemax)
+                self.send(FXP_EXTENDED_REPLY, struct.pack(">I11Q", req_id, *fields))
+            else:
+                self.status(req_id, FX_OP_UNSUPPORTED, "unsupported extension")
+        else:
+            self.status(req_id, FX_OP_UNSUPPORTED, "unsupported request")
+
+
+def main():
+    SFTPServer(sys.stdin.buffer, sys.stdout.buffer).serve()
+
+
+if __name__ == "__main__":
+    main()
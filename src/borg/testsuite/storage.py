@@ -0,0 +1,317 @@
+import io
+import json
+import os
+import sys
+
+import pytest
+
+from .. import platform
+from ..helpers import Location
+from ..locking import LockTimeout, NotMyLock
+from ..repository import Repository
+from ..storage import get_store, PosixStore, StoreEntry
+from ..storage.base import BackendUnavailable, StoreExclusiveLock
+from ..storage.sftp import SFTPStore, EXT_POSIX_RENAME
+from .hashindex import H
+from .repository import fchunk, pdchunk
+
+
+@pytest.fixture(scope="module")
+def s3_server():
+    # an in-process stand-in for an S3-compatible storage (like MinIO)
+    server_module = pytest.importorskip("moto.server")
+    pytest.importorskip("boto3")
+    server = server_module.ThreadedMotoServer(ip_address="127.0.0.1", port=0, verbose=False)
+    server.start()
+    host, port = server.get_host_and_port()
+    yield f"{host}:{port}"
+    server.stop()
+
+
+@pytest.fixture()
+def s3_location(s3_server, monkeypatch, tmp_path):
+    monkeypatch.setenv("AWS_ACCESS_KEY_ID", "testing")
+    monkeypatch.setenv("AWS_SECRET_ACCESS_KEY", "testing")
+    monkeypatch.setenv("AWS_DEFAULT_REGION", "us-east-1")
+    # every test gets its own prefix in the (shared) bucket
+    return Location(f"s3+http://{s3_server}/borg-test/{tmp_path.name}/repository")
+
+
+@pytest.fixture()
+def posix_store(tmp_path):
+    store = PosixStore(os.fspath(tmp_path / "store"))
+    store.create()
+    yield store
+    store.close()
+
+
+@pytest.fixture()
+def s3_store(s3_location):
+    store = get_store(s3_location)
+    store.create(make_parent_dirs=True)
+    yield store
+    store.close()
+
+
+def sftp_store_for(location):
+    # talk to a local sftp-server stand-in (see sftp_server.py) instead of running ssh
+    return SFTPStore(location, sftp_cmd=[sys.executable, "-m", "borg.testsuite.sftp_server"])
+
+
+@pytest.fixture()
+def sftp_location(tmp_path):
+    return Location("sftp://localhost" + os.fspath(tmp_path / "repository"))
+
+
+@pytest.fixture()
+def sftp_store(sftp_location):
+    store = sftp_store_for(sftp_location)
+    store.create()
+    yield store
+    store.close()
+
+
+def pytest_generate_tests(metafunc):
+    # Generates tests that run on all stores
+    if "store_fixture" in metafunc.fixturenames:
+        metafunc.parametrize("store_fixture", ["posix_store", "s3_store", "sftp_store"])
+
+
+def test_get_store(tmp_path):
+    store = get_store(Location(os.fspath(tmp_path / "repo")))
+    assert isinstance(store, PosixStore)
+    assert store.is_local
+    assert store.path == os.fspath(tmp_path / "repo")
+    assert store.local_path("data/0/1") == os.path.join(store.path, "data", "0", "1")
+
+
+def test_s3_store_location(s3_location):
+    store = get_store(s3_location)
+    assert not store.is_local
+    assert store.bucket == "borg-test"
+    assert store.prefix.endswith("/repository")
+    assert store.path == s3_location.canonical_path()
+    assert store.filename("config") == store.path + "/config"
+
+
+def test_sftp_store_location(sftp_location, monkeypatch):
+    store = get_store(sftp_location)
+    assert isinstance(store, SFTPStore)
+    assert not store.is_local
+    assert store.path == sftp_location.canonical_path()
+    assert store.root == sftp_location.path
+    monkeypatch.setenv("BORG_RSH", "ssh -i key")
+    store = SFTPStore(Location("sftp://user@host:2222/./repo"))
+    assert store.root == "repo"
+    assert store.sftp_cmd() == ["ssh", "-i", "key", "-p", "2222", "-s", "user@host", "sftp"]
+    store = SFTPStore(Location("sftp://host/~/repo"), rsh="ssh -v")
+    assert store.root == "repo"
+    assert store.sftp_cmd() == ["ssh", "-v", "-s", "host", "sftp"]
+
+
+def test_blobs(store_fixture, request):
+    store = request.getfixturevalue(store_fixture)
+    assert store.exists() and store.isdir()
+    assert not store.has("config")
+    with pytest.raises(FileNotFoundError):
+        store.load("config")
+    store.store("config", b"foo")
+    assert store.load("config") == b"foo"
+    assert store.size("config") == 3
+    store.store("config", b"foobar")
+    assert store.load("config") == b"foobar"
+    store.append("transactions", b"1\n")
+    store.append("transactions", b"2\n")
+    assert store.load("transactions") == b"1\n2\n"
+    store.replace("config", "config.new")
+    assert not store.has("config")
+    assert store.load("config.new") == b"foobar"
+    store.delete("config.new")
+    assert not store.has("config.new")
+    with pytest.raises(FileExistsError):
+        store.create_exclusive("transactions", b"")
+
+
+def test_list(store_fixture, request):
+    store = request.getfixturevalue(store_fixture)
+    store.store("README", b"")
+    store.makedirs("data/0")
+    store.store("data/0/1", b"x")
+    store.store("data/0/2", b"y")
+    assert sorted(store.list()) == [StoreEntry("README", False), StoreEntry("data", True)]
+    assert sorted(store.list("data")) == [StoreEntry("0", True)]
+    assert sorted(store.list("data/0")) == [StoreEntry("1", False), StoreEntry("2", False)]
+    with pytest.raises(OSError):
+        store.rmdir("data/0")
+    store.delete("data/0/1")
+    store.delete("data/0/2")
+    store.rmdir("data/0")
+    assert store.list("data") == []
+
+
+def test_read_write(store_fixture, request):
+    store = request.getfixturevalue(store_fixture)
+    with store.open_write("index.1.tmp") as fd:
+        fd.write(b"0123456789" * 1000)
+        assert fd.tell() == 10000
+    with store.open_read("index.1.tmp") as fd:
+        assert fd.read(4) == b"0123"
+        fd.seek(-3, io.SEEK_END)
+        assert fd.read() == b"789"
+        fd.seek(5)
+        assert fd.read(5) == b"56789"
+        assert fd.tell() == 10
+    with pytest.raises(FileNotFoundError):
+        store.open_read("index.2")
+    with store.mapped("index.1.tmp") as data:
+        assert bytes(data[:10]) == b"0123456789"
+
+
+def test_segment_readable_while_written(store_fixture, request):
+    store = request.getfixturevalue(store_fixture)
+    store.makedirs("data/0")
+    fd = store.open_segment("data/0/1")
+    fd.write(b"BORG_SEG")
+    fd.write(b"entry")
+    fd.sync()
+    assert store.size("data/0/1") == 13
+    with store.open_read("data/0/1") as rfd:
+        assert rfd.read() == b"BORG_SEGentry"
+    assert StoreEntry("1", False) in store.list("data/0")
+    fd.close()
+    assert store.load("data/0/1") == b"BORG_SEGentry"
+    with pytest.raises(FileExistsError):
+        store.open_segment("data/0/1")
+
+
+def test_lock(store_fixture, request):
+    store = request.getfixturevalue(store_fixture)
+    lock1 = store.get_lock(exclusive=False, id=("host", 1, 1)).acquire()
+    lock2 = store.get_lock(exclusive=False, id=("host", 2, 2)).acquire()
+    with pytest.raises(LockTimeout):
+        store.get_lock(exclusive=True, id=("host", 3, 3), timeout=0.2).acquire()
+    lock1.release()
+    lock2.release()
+    lock3 = store.get_lock(exclusive=True, id=("host", 3, 3), timeout=1).acquire()
+    assert lock3.got_exclusive_lock()
+    with pytest.raises(LockTimeout):
+        store.get_lock(exclusive=False, id=("host", 4, 4), timeout=0.2).acquire()
+    lock3.release()
+    store.get_lock(exclusive=True, id=("host", 5, 5)).acquire()
+    store.get_lock().break_lock()
+    store.get_lock(exclusive=True, id=("host", 6, 6), timeout=1).acquire().release()
+
+
+def test_delete_if_unchanged(store_fixture, request):
+    store = request.getfixturevalue(store_fixture)
+    store.store("blob", b"old")
+    assert store.delete_if_unchanged("blob", b"new") is False
+    assert store.load("blob") == b"old"
+    assert store.delete_if_unchanged("blob", b"old") is True
+    assert not store.has("blob")
+    with pytest.raises(FileNotFoundError):
+        store.delete_if_unchanged("blob", b"old")
+    assert [entry.name for entry in store.list()] == []
+
+
+def test_kill_stale_lock_changed(store_fixture, request, monkeypatch):
+    store = request.getfixturevalue(store_fixture)
+    StoreExclusiveLock(store, "lock.test", id=("host", 1, 1)).acquire()
+
+    def process_alive(host, pid, thread):
+        # while we find the lock of pid 1 stale, pid 2 kills it, too, and gets the lock.
+        store.delete("lock.test")
+        StoreExclusiveLock(store, "lock.test", id=("host", 2, 2)).acquire()
+        return False
+
+    monkeypatch.setattr(platform, "process_alive", process_alive)
+    lock = StoreExclusiveLock(store, "lock.test", id=("host", 3, 3))
+    assert lock.kill_stale_lock() is False
+    assert json.loads(store.load("lock.test")) == ["host", 2, 2]
+
+
+def test_big_blob(store_fixture, request):
+    # more than MAX_IN_FLIGHT * MAX_IO_SIZE, so SFTP requests can not all be in flight at the same time
+    store = request.getfixturevalue(store_fixture)
+    data = os.urandom(5 * 1024 * 1024 + 123)
+    store.store("big", data)
+    assert store.size("big") == len(data)
+    assert store.load("big") == data
+    with store.open_read("big") as fd:
+        fd.seek(3 * 1024 * 1024 + 1)
+        assert fd.read(100) == data[3 * 1024 * 1024 + 1 : 3 * 1024 * 1024 + 101]
+
+
+def test_lock_not_mine(s3_store):
+    lock1 = s3_store.get_lock(exclusive=True, id=("host", 1, 1)).acquire()
+    lock2 = s3_store.get_lock(exclusive=True, id=("host", 2, 2))
+    lock2.is_exclusive = True
+    with pytest.raises(NotMyLock):
+        lock2._lock.release()
+    lock1.release()
+
+
+def test_s3_repository(s3_location):
+    with Repository(
+        s3_location.path, exclusive=True, create=True, make_parent_dirs=True, store=get_store(s3_location)
+    ) as repository:
+        assert repository.path == s3_location.canonical_path()
+        for x in range(10):
+            repository.put(H(x), fchunk(b"SOMEDATA"))
+        # objects are readable before the segment is stored
+        assert pdchunk(repository.get(H(3))) == b"SOMEDATA"
+        repository.commit(compact=False)
+        repository.put(H(10), fchunk(b"MOREDATA"))
+        repository.delete(H(3))
+        repository.commit(compact=True)
+    with Repository(s3_location.path, exclusive=True, store=get_store(s3_location)) as repository:
+        assert len(repository) == 10
+        assert pdchunk(repository.get(H(10))) == b"MOREDATA"
+        with pytest.raises(Repository.ObjectNotFound):
+            repository.get(H(3))
+        assert repository.check()
+    with Repository(s3_location.path, exclusive=True, store=get_store(s3_location)) as repository:
+        repository.destroy()
+    assert not get_store(s3_location).list()
+
+
+def test_sftp_repository(sftp_location):
+    with Repository(
+        sftp_location.path, exclusive=True, create=True, make_parent_dirs=True, store=sftp_store_for(sftp_location)
+    ) as repository:
+        assert repository.path == sftp_location.canonical_path()
+        for x in range(10):
+            repository.put(H(x), fchunk(b"SOMEDATA"))
+        repository.commit(compact=False)
+        repository.put(H(10), fchunk(b"MOREDATA"))
+        repository.delete(H(3))
+        repository.commit(compact=True)
+        repository.config.set("repository", "parity_shards", "1")
+        repository.save_config(repository.path, repository.config)
+    with Repository(sftp_location.path, exclusive=True, store=sftp_store_for(sftp_location)) as repository:
+        assert len(repository) == 10
+        assert pdchunk(repository.get(H(10))) == b"MOREDATA"
+        with pytest.raises(Repository.ObjectNotFound):
+            repository.get(H(3))
+        assert repository.scrub()  # creates the parity files
+        assert repository.store.has(repository.io.parity_name(repository.get_transaction_id()))
+        assert repository.check()
+    with Repository(sftp_location.path, exclusive=True, store=sftp_store_for(sftp_location)) as repository:
+        repository.destroy()
+    assert not sftp_store_for(sftp_location).exists()
+
+
+def test_sftp_no_atomic_rename(sftp_store):
+    sftp_store.store("config", b"1")
+    del sftp_store.client.extensions[EXT_POSIX_RENAME]
+    with pytest.raises(BackendUnavailable):
+        sftp_store.store("config", b"2")
+    assert sftp_store.load("config") == b"1"
+
+
+def test_s3_repository_already_exists(s3_location):
+    with Repository(s3_location.path, exclusive=True, create=True, make_parent_dirs=True, store=get_store(s3_location)):
+        pass
+    with pytest.raises(Repository.AlreadyExists):
+        with Repository(s3_location.path, exclusive=True, create=True, store=get_store(s3_location)):
+            pass